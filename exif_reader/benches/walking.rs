@@ -0,0 +1,118 @@
+// Import necessary modules from the library and external crates.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use exif_reader::config::{FileSinkConfig, SinkConfig};
+use exif_reader::directory_reader::{walking_with, WalkOptions};
+use exif_reader::exif_writer::{write_geotag, Geotag, WriteOptions};
+use exif_reader::file_filter::FilterOptions;
+use exif_reader::media_source::Sources;
+use exif_reader::privacy::PrivacyZones;
+use exif_reader::scan::{scan, walk_options, Files, ScanEnrichers, ScanSettings};
+use exif_reader::sink::{Sinks, FILE};
+
+use std::path::Path;
+use std::sync::Arc;
+
+// Directory with the test photos.
+const TEST_DATA: &str = "../test_data/";
+
+// synthetic_photos is a function that fills a directory with geotagged photos,
+// for checkouts without the test data.
+fn synthetic_photos(directory: &Path) {
+    for index in 0..500 {
+        let path = directory.join(format!("photo_{:03}.jpg", index));
+        std::fs::write(&path, [0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+        let geotag = Geotag {
+            lat: 45.0 + index as f64 / 1000.0,
+            lon: 39.0,
+            altitude: Some(25.0),
+            offset_time: None,
+        };
+        write_geotag(&path, &geotag, &WriteOptions::default()).unwrap();
+    }
+}
+
+// photo_directory is a function that returns the test data, or synthetic photos written to a temporary directory.
+fn photo_directory(synthetic: &Path) -> String {
+    if Path::new(TEST_DATA).is_dir() {
+        TEST_DATA.to_string()
+    } else {
+        synthetic_photos(synthetic);
+        synthetic.display().to_string()
+    }
+}
+
+// bench_walking is a function that compares sequential and parallel extraction of the test data.
+fn bench_walking(c: &mut Criterion) {
+    let synthetic = tempfile::tempdir().unwrap();
+    let directory = photo_directory(synthetic.path());
+
+    let mut group = c.benchmark_group("walking");
+    for (name, threads, ordered) in [
+        ("sequential", 1, true),
+        ("parallel_ordered", 0, true),
+        ("parallel_unordered", 0, false),
+    ] {
+        let options = WalkOptions {
+            threads,
+            ordered,
+            ..WalkOptions::default()
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(name), &options, |b, options| {
+            b.iter(|| walking_with(&directory, &[], options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// bench_scan is a function that measures whole scans of the test data, as the service and the scan command run them:
+// extraction streams the messages through the bounded channel into an NDJSON file sink.
+fn bench_scan(c: &mut Criterion) {
+    let synthetic = tempfile::tempdir().unwrap();
+    let directory = photo_directory(synthetic.path());
+    let output = tempfile::tempdir().unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("scan");
+    for (name, threads, buffer) in [("sequential", 1, 1024), ("parallel", 0, 1024), ("parallel_buffer_1", 0, 1)] {
+        // The file is dropped once it grows past its size, so that repeated scans don't fill the disk.
+        let sink = SinkConfig {
+            default: Some(FILE.to_string()),
+            file: Some(FileSinkConfig {
+                path: output.path().join(format!("{}.ndjson", name)).display().to_string(),
+                max_bytes: 64 * 1024 * 1024,
+                max_files: 0,
+            }),
+            webhook: None,
+            postgis: None,
+        };
+        let settings = Arc::new(ScanSettings {
+            walk_options: WalkOptions {
+                threads,
+                ..WalkOptions::default()
+            },
+            filter: FilterOptions::default(),
+            buffer,
+            report_dir: None,
+            privacy: PrivacyZones::default(),
+            sources: Sources::default(),
+            sinks: Sinks::from_config(&sink, None).unwrap(),
+            spool: None,
+        });
+        let sink = settings.sinks.get(None).unwrap();
+        let enrichers = ScanEnrichers {
+            track: None,
+            configured: Arc::new(Vec::new()),
+        };
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let walk_options = walk_options(&settings, &FilterOptions::default(), true).unwrap();
+                let scanned = scan(&directory, Files::Directory, enrichers.clone(), None, walk_options, sink.as_ref(), &settings);
+                runtime.block_on(scanned)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_walking, bench_scan);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/exif_readers_grpc.proto");
+    tonic_build::compile_protos("proto/exif_readers_grpc.proto")?;
     Ok(())
 }
\ No newline at end of file
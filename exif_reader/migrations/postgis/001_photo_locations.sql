@@ -0,0 +1,20 @@
+-- The position of every photo, keyed by the SHA-256 of its contents, so that moved
+-- and renamed files keep their row.
+CREATE EXTENSION IF NOT EXISTS postgis;
+
+CREATE TABLE IF NOT EXISTS photo_locations (
+    content_hash TEXT PRIMARY KEY,
+    path TEXT NOT NULL,
+    -- NULL for photos without a position.
+    location geography(PointZ, 4326),
+    altitude_source TEXT,
+    position_source TEXT,
+    accuracy REAL,
+    taken_at TIMESTAMPTZ,
+    -- The message as produced to Kafka.
+    payload JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS photo_locations_path ON photo_locations (path);
+CREATE INDEX IF NOT EXISTS photo_locations_location ON photo_locations USING GIST (location);
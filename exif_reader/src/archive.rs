@@ -0,0 +1,247 @@
+// Import necessary modules from the standard library and external crates.
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use chrono::NaiveDate;
+use flate2::read::GzDecoder;
+
+// Separator between the path of an archive and the path of one of its entries in a virtual path,
+// such as backup.zip!/2014/IMG_001.jpg.
+pub const SEPARATOR: &str = "!/";
+// Largest entry read into memory, so that a damaged or malicious archive can't exhaust it.
+const MAX_ENTRY_SIZE: u64 = 256 * 1024 * 1024;
+
+// Define the archive formats the walker descends into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+// kind is a function that recognises an archive by its file name.
+pub fn kind(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if name.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else {
+        None
+    }
+}
+
+// virtual_path is a function that returns the path of an entry inside an archive.
+pub fn virtual_path(archive: &str, entry: &str) -> String {
+    format!("{}{}{}", archive, SEPARATOR, entry.trim_start_matches("./"))
+}
+
+// archive_of is a function that returns the path of the archive holding an entry,
+// or the path itself if it isn't a virtual path.
+pub fn archive_of(path: &str) -> &str {
+    path.split_once(SEPARATOR)
+        .map_or(path, |(archive, _)| archive)
+}
+
+// is_entry is a function that checks whether a path is the virtual path of an archive entry.
+pub fn is_entry(path: &str) -> bool {
+    path.contains(SEPARATOR)
+}
+
+// Define a file read from an archive.
+#[derive(Debug)]
+pub struct Entry {
+    // The virtual path of the entry.
+    pub path: String,
+    // The uncompressed size of the entry, in bytes.
+    pub size: u64,
+    // The modification time of the entry, in nanoseconds since the Unix epoch.
+    pub mtime: i64,
+    // The contents of the entry, or why they couldn't be read.
+    pub contents: io::Result<Vec<u8>>,
+}
+
+// read_contents is a function that reads an entry into memory, refusing oversized ones.
+fn read_contents<R: Read>(reader: R, size: u64) -> io::Result<Vec<u8>> {
+    if size > MAX_ENTRY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Entry larger than {} bytes", MAX_ENTRY_SIZE),
+        ));
+    }
+    let mut contents = Vec::with_capacity(size as usize);
+    reader.take(MAX_ENTRY_SIZE).read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+// zip_mtime is a function that converts the MS-DOS modification time of a ZIP entry,
+// in local time of unknown zone, to nanoseconds as if it were UTC.
+fn zip_mtime(time: zip::DateTime) -> i64 {
+    NaiveDate::from_ymd_opt(time.year() as i32, time.month() as u32, time.day() as u32)
+        .and_then(|date| {
+            date.and_hms_opt(
+                time.hour() as u32,
+                time.minute() as u32,
+                time.second() as u32,
+            )
+        })
+        .and_then(|time| time.and_utc().timestamp_nanos_opt())
+        .unwrap_or_default()
+}
+
+// for_each_entry is a function that streams the files of a ZIP or TAR archive one by one,
+// without unpacking them to disk.
+// Parameters:
+// - archive: The path of the archive.
+// - keep: Whether an entry, given its virtual path and size, has to be read; skipped entries aren't read.
+// - each: The consumer of the entries read; returning false stops the reading.
+// Returns:
+// - io::Result<()>: Ok once every entry is read or the consumer stopped, or an std::io::Error
+//   if the archive can't be opened or listed.
+pub fn for_each_entry<K, F>(archive: &str, keep: K, mut each: F) -> io::Result<()>
+where
+    K: Fn(&str, u64) -> bool,
+    F: FnMut(Entry) -> bool,
+{
+    let Some(kind) = kind(Path::new(archive)) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not an archive: {}", archive),
+        ));
+    };
+    let file = BufReader::new(File::open(archive)?);
+
+    match kind {
+        // ZIP archives have a central directory, so entries are read in place.
+        Kind::Zip => {
+            let mut zip = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+            for index in 0..zip.len() {
+                let entry = zip.by_index(index).map_err(io::Error::other)?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let path = virtual_path(archive, entry.name());
+                let size = entry.size();
+                if !keep(&path, size) {
+                    continue;
+                }
+                let mtime = zip_mtime(entry.last_modified());
+                let contents = read_contents(entry, size);
+                if !each(Entry {
+                    path,
+                    size,
+                    mtime,
+                    contents,
+                }) {
+                    break;
+                }
+            }
+        }
+        // TAR archives are a stream of entries, compressed as a whole or not.
+        Kind::Tar | Kind::TarGz => {
+            let reader: Box<dyn Read> = match kind {
+                Kind::TarGz => Box::new(GzDecoder::new(file)),
+                _ => Box::new(file),
+            };
+            let mut tar = tar::Archive::new(reader);
+            for entry in tar.entries()? {
+                let entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let path = virtual_path(archive, &entry.path()?.to_string_lossy());
+                let size = entry.size();
+                if !keep(&path, size) {
+                    continue;
+                }
+                let mtime = entry.header().mtime().unwrap_or_default() as i64 * 1_000_000_000;
+                let contents = read_contents(entry, size);
+                if !each(Entry {
+                    path,
+                    size,
+                    mtime,
+                    contents,
+                }) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    // entries is a helper that lists the virtual paths and contents of the kept entries of an archive.
+    fn entries(archive: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut entries = Vec::new();
+        for_each_entry(
+            archive.to_str().unwrap(),
+            |path, _| !path.ends_with(".txt"),
+            |entry| {
+                entries.push((entry.path, entry.contents.unwrap()));
+                true
+            },
+        )
+        .unwrap();
+        entries
+    }
+
+    // Define a test function for reading ZIP and compressed TAR archives.
+    #[test]
+    fn test_for_each_entry() {
+        let directory = tempfile::tempdir().unwrap();
+        let photo = [0xFF, 0xD8, 0xFF, 0xD9];
+
+        let zip_path = directory.path().join("backup.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        zip.add_directory("2014/", Default::default()).unwrap();
+        zip.start_file("2014/IMG_001.jpg", Default::default())
+            .unwrap();
+        zip.write_all(&photo).unwrap();
+        zip.start_file("notes.txt", Default::default()).unwrap();
+        zip.write_all(b"not a photo").unwrap();
+        zip.finish().unwrap();
+
+        let tar_path = directory.path().join("backup.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&tar_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(photo.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "./2015/IMG_002.jpg", &photo[..])
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        // Assert that the kept files are read with their virtual path.
+        let zip_path = zip_path.to_str().unwrap();
+        assert_eq!(
+            entries(Path::new(zip_path)),
+            vec![(format!("{}!/2014/IMG_001.jpg", zip_path), photo.to_vec())]
+        );
+        let tar_path = tar_path.to_str().unwrap();
+        assert_eq!(
+            entries(Path::new(tar_path)),
+            vec![(format!("{}!/2015/IMG_002.jpg", tar_path), photo.to_vec())]
+        );
+        assert_eq!(
+            archive_of(&format!("{}!/2015/IMG_002.jpg", tar_path)),
+            tar_path
+        );
+
+        // Assert that other files aren't archives.
+        assert_eq!(kind(Path::new("photo.jpg")), None);
+        assert!(for_each_entry("photo.jpg", |_, _| true, |_| true).is_err());
+    }
+}
@@ -0,0 +1,658 @@
+// Import necessary modules from the project.
+use crate::exif_writer::{Geotag, WriteOptions};
+use crate::file_filter::FilterOptions;
+use crate::redaction::{RedactMode, RedactOptions};
+use crate::track::TrackOptions;
+
+// Import necessary modules from the standard library and clap.
+use std::ffi::OsString;
+
+use clap::{value_parser, Arg, ArgMatches, Command as ClapCommand};
+
+// Define the commands the binary can run.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Start the gRPC server (the default).
+    Serve,
+    // Scan a single directory, produce its messages and exit.
+    Scan {
+        directory: String,
+        track: TrackOptions,
+        // Write inferred positions back into the photos, if set.
+        write_back: Option<WriteOptions>,
+        // Number of extraction threads, overriding the configuration.
+        threads: Option<usize>,
+        // Keep the messages in file name order, overriding the configuration either way.
+        ordered: Option<bool>,
+        // Stop at the first unreadable entry, overriding the configuration either way.
+        strict: Option<bool>,
+        // Directory receiving the JSON report of the scan, overriding the configuration.
+        report: Option<String>,
+        // Extract and produce the files that didn't change since the last scan again.
+        full: bool,
+        // The files to extract, overriding the configuration.
+        filter: FilterOptions,
+        // The sink the messages are written to, overriding the configuration.
+        sink: Option<String>,
+    },
+    // Watch a directory and produce the messages of its files as they change, until stopped.
+    Watch {
+        directory: String,
+        // How long a changed file must stay unchanged, in milliseconds, overriding the configuration.
+        settle_ms: Option<u64>,
+        // Number of extraction threads, overriding the configuration.
+        threads: Option<usize>,
+        // The files to extract, overriding the configuration.
+        filter: FilterOptions,
+        // The sink the messages are written to, overriding the configuration.
+        sink: Option<String>,
+    },
+    // Write a position into a single photo and exit.
+    Geotag {
+        path: String,
+        geotag: Geotag,
+        options: WriteOptions,
+    },
+    // Write copies of the photos of a directory without their location and exit.
+    Redact {
+        source: String,
+        destination: String,
+        options: RedactOptions,
+        // Where to write the JSON report, if set.
+        report: Option<String>,
+    },
+}
+
+// write_args is a function that returns the arguments controlling how geotags are written.
+fn write_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("sidecar")
+            .long("sidecar")
+            .help("Write an XMP sidecar instead of rewriting the photo"),
+        Arg::new("backup")
+            .long("backup")
+            .help("Keep the original photo with a .bak suffix"),
+        Arg::new("dry-run")
+            .long("dry-run")
+            .help("Show the changes without writing anything"),
+    ]
+}
+
+// write_options is a function that reads the write arguments from the matches.
+fn write_options(matches: &ArgMatches) -> WriteOptions {
+    WriteOptions {
+        sidecar: matches.is_present("sidecar"),
+        backup: matches.is_present("backup"),
+        dry_run: matches.is_present("dry-run"),
+    }
+}
+
+// sink_arg is a function that returns the argument choosing the sink of the messages.
+fn sink_arg() -> Arg<'static> {
+    Arg::new("sink")
+        .long("sink")
+        .takes_value(true)
+        .help("Write the messages to this sink: stdout, file, kafka, webhook or postgis")
+}
+
+// filter_args is a function that returns the arguments selecting the files of a scan.
+fn filter_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("include")
+            .long("include")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .help("Glob pattern of the files to extract, relative to the directory"),
+        Arg::new("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .help("Glob pattern of the files and directories to skip"),
+        Arg::new("extension")
+            .long("extension")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .help("Extension of the files to extract"),
+        Arg::new("skip-hidden")
+            .long("skip-hidden")
+            .help("Skip the files and directories whose name starts with a dot"),
+        Arg::new("no-skip-hidden")
+            .long("no-skip-hidden")
+            .conflicts_with("skip-hidden")
+            .help("Extract hidden files and directories, even if SCAN.SKIP_HIDDEN is set"),
+        Arg::new("max-depth")
+            .long("max-depth")
+            .takes_value(true)
+            .value_parser(value_parser!(usize))
+            .help("Largest directory depth to descend into"),
+        Arg::new("follow-symlinks")
+            .long("follow-symlinks")
+            .help("Follow symbolic links"),
+        Arg::new("no-follow-symlinks")
+            .long("no-follow-symlinks")
+            .conflicts_with("follow-symlinks")
+            .help("Don't follow symbolic links, even if SCAN.FOLLOW_SYMLINKS is set"),
+        Arg::new("same-file-system")
+            .long("same-file-system")
+            .help("Stay on the file system of the directory"),
+        Arg::new("no-same-file-system")
+            .long("no-same-file-system")
+            .conflicts_with("same-file-system")
+            .help("Descend into other file systems, even if SCAN.SAME_FILE_SYSTEM is set"),
+        Arg::new("min-size")
+            .long("min-size")
+            .takes_value(true)
+            .value_parser(value_parser!(u64))
+            .help("Smallest size of the files to extract, in bytes"),
+        Arg::new("max-size")
+            .long("max-size")
+            .takes_value(true)
+            .value_parser(value_parser!(u64))
+            .help("Largest size of the files to extract, in bytes"),
+        Arg::new("no-sniff")
+            .long("no-sniff")
+            .help("Extract files without checking that they look like media files"),
+        Arg::new("archives")
+            .long("archives")
+            .help("Descend into ZIP and TAR archives"),
+        Arg::new("no-archives")
+            .long("no-archives")
+            .conflicts_with("archives")
+            .help("Don't descend into archives, even if SCAN.ARCHIVES is set"),
+        Arg::new("max-archive-size")
+            .long("max-archive-size")
+            .takes_value(true)
+            .value_parser(value_parser!(u64))
+            .help("Largest archive to descend into, in bytes"),
+    ]
+}
+
+// filter_options is a function that reads the filter arguments from the matches;
+// flags that aren't given, nor their --no- counterpart, are left unset so that the configuration applies.
+fn filter_options(matches: &ArgMatches) -> FilterOptions {
+    let list = |name: &str| {
+        matches
+            .get_many::<String>(name)
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default()
+    };
+    let flag = |name: &str, value: bool| matches.is_present(name).then_some(value);
+    FilterOptions {
+        include: list("include"),
+        exclude: list("exclude"),
+        extensions: list("extension"),
+        skip_hidden: toggle(matches, "skip-hidden"),
+        max_depth: matches.get_one::<usize>("max-depth").copied(),
+        follow_symlinks: toggle(matches, "follow-symlinks"),
+        same_file_system: toggle(matches, "same-file-system"),
+        min_size: matches.get_one::<u64>("min-size").copied(),
+        max_size: matches.get_one::<u64>("max-size").copied(),
+        sniff: flag("no-sniff", false),
+        archives: toggle(matches, "archives"),
+        max_archive_size: matches.get_one::<u64>("max-archive-size").copied(),
+    }
+}
+
+// toggle is a function that reads a flag given with its --no- counterpart: Some(true) or Some(false)
+// when one of them is given, None otherwise so that the configuration applies.
+fn toggle(matches: &ArgMatches, name: &str) -> Option<bool> {
+    if matches.is_present(name) {
+        Some(true)
+    } else {
+        matches.is_present(format!("no-{}", name)).then_some(false)
+    }
+}
+
+// parse is a function that reads the command from the process arguments.
+pub fn parse() -> Command {
+    parse_from(std::env::args_os())
+}
+
+// parse_from is a function that reads the command from the provided arguments.
+// Parameters:
+// - args: The arguments, starting with the binary name.
+// Returns:
+// - Command: The command to run; clap exits the process on invalid arguments.
+pub fn parse_from<I, T>(args: I) -> Command
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    command(clap_command().get_matches_from(args))
+}
+
+// clap_command is a function that defines the subcommands and arguments of the binary.
+fn clap_command() -> ClapCommand<'static> {
+    ClapCommand::new("exif_reader")
+        .about("Extracts photo coordinates from EXIF data and produces them to Kafka")
+        .subcommand(ClapCommand::new("serve").about("Start the gRPC server (default)"))
+        .subcommand(
+            ClapCommand::new("scan")
+                .about("Scan a directory once and produce its messages")
+                .arg(
+                    Arg::new("directory")
+                        .required(true)
+                        .help("Directory to scan"),
+                )
+                .arg(
+                    Arg::new("track")
+                        .long("track")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .help("GPX, KML or FIT track log used to geotag photos without GPS"),
+                )
+                .arg(
+                    Arg::new("clock-offset")
+                        .long("clock-offset")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .value_parser(value_parser!(i64))
+                        .default_value("0")
+                        .help("Seconds added to the camera clock to get UTC"),
+                )
+                .arg(
+                    Arg::new("max-gap")
+                        .long("max-gap")
+                        .takes_value(true)
+                        .value_parser(value_parser!(i64).range(0..))
+                        .help("Largest gap between two track points to interpolate over, in seconds"),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .long("threads")
+                        .takes_value(true)
+                        .value_parser(value_parser!(usize))
+                        .help("Number of extraction threads, 0 for one per CPU core"),
+                )
+                .arg(
+                    Arg::new("ordered")
+                        .long("ordered")
+                        .help("Produce the messages in file name order"),
+                )
+                .arg(
+                    Arg::new("no-ordered")
+                        .long("no-ordered")
+                        .conflicts_with("ordered")
+                        .help("Produce the messages as they are extracted, even if SCAN.ORDERED is set"),
+                )
+                .arg(
+                    Arg::new("strict")
+                        .long("strict")
+                        .help("Stop at the first unreadable file or directory instead of skipping it"),
+                )
+                .arg(
+                    Arg::new("no-strict")
+                        .long("no-strict")
+                        .conflicts_with("strict")
+                        .help("Skip unreadable files and directories, even if SCAN.STRICT is set"),
+                )
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .takes_value(true)
+                        .help("Write the JSON report of the scan into this directory"),
+                )
+                .arg(
+                    Arg::new("full")
+                        .long("full")
+                        .help("Extract and produce the files that didn't change since the last scan again"),
+                )
+                .arg(
+                    Arg::new("write-back")
+                        .long("write-back")
+                        .help("Write positions inferred from track logs or location history into the photos"),
+                )
+                .arg(sink_arg())
+                .args(write_args())
+                .args(filter_args()),
+        )
+        .subcommand(
+            ClapCommand::new("watch")
+                .about("Scan a directory, then produce the messages of its files as they change")
+                .arg(
+                    Arg::new("directory")
+                        .required(true)
+                        .help("Directory to watch"),
+                )
+                .arg(
+                    Arg::new("settle-ms")
+                        .long("settle-ms")
+                        .takes_value(true)
+                        .value_parser(value_parser!(u64))
+                        .help("How long a changed file must stay unchanged before it's extracted, in milliseconds"),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .long("threads")
+                        .takes_value(true)
+                        .value_parser(value_parser!(usize))
+                        .help("Number of extraction threads, 0 for one per CPU core"),
+                )
+                .arg(sink_arg())
+                .args(filter_args()),
+        )
+        .subcommand(
+            ClapCommand::new("geotag")
+                .about("Write a position into a photo")
+                .arg(Arg::new("path").required(true).help("Photo to geotag"))
+                .arg(
+                    Arg::new("lat")
+                        .long("lat")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .value_parser(value_parser!(f64))
+                        .help("Latitude in decimal degrees"),
+                )
+                .arg(
+                    Arg::new("lon")
+                        .long("lon")
+                        .required(true)
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .value_parser(value_parser!(f64))
+                        .help("Longitude in decimal degrees"),
+                )
+                .arg(
+                    Arg::new("altitude")
+                        .long("altitude")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .value_parser(value_parser!(f64))
+                        .help("Altitude above mean sea level, in metres"),
+                )
+                .arg(
+                    Arg::new("offset-time")
+                        .long("offset-time")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .help("UTC offset of the capture time, such as +03:00"),
+                )
+                .args(write_args()),
+        )
+        .subcommand(
+            ClapCommand::new("redact")
+                .about("Write copies of a directory's photos with their location removed")
+                .arg(Arg::new("source").required(true).help("Directory to redact"))
+                .arg(
+                    Arg::new("destination")
+                        .required(true)
+                        .help("Directory receiving the copies"),
+                )
+                .arg(
+                    Arg::new("coarsen")
+                        .long("coarsen")
+                        .takes_value(true)
+                        .value_parser(value_parser!(f64))
+                        .help("Snap coordinates to a grid with this cell size, in degrees, instead of removing them"),
+                )
+                .arg(
+                    Arg::new("strip-serials")
+                        .long("strip-serials")
+                        .help("Also remove serial numbers and maker notes"),
+                )
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .takes_value(true)
+                        .help("Write the report of the changes to this JSON file"),
+                ),
+        )
+}
+
+// command is a function that builds the command to run from the parsed arguments.
+fn command(matches: ArgMatches) -> Command {
+    match matches.subcommand() {
+        Some(("scan", scan)) => Command::Scan {
+            directory: scan.get_one::<String>("directory").unwrap().clone(),
+            track: TrackOptions {
+                files: scan
+                    .get_many::<String>("track")
+                    .map(|files| files.cloned().collect())
+                    .unwrap_or_default(),
+                clock_offset: *scan.get_one::<i64>("clock-offset").unwrap(),
+                max_gap: scan.get_one::<i64>("max-gap").copied(),
+            },
+            write_back: scan
+                .is_present("write-back")
+                .then(|| write_options(scan)),
+            threads: scan.get_one::<usize>("threads").copied(),
+            ordered: toggle(scan, "ordered"),
+            strict: toggle(scan, "strict"),
+            report: scan.get_one::<String>("report").cloned(),
+            full: scan.is_present("full"),
+            filter: filter_options(scan),
+            sink: scan.get_one::<String>("sink").cloned(),
+        },
+        Some(("watch", watch)) => Command::Watch {
+            directory: watch.get_one::<String>("directory").unwrap().clone(),
+            settle_ms: watch.get_one::<u64>("settle-ms").copied(),
+            threads: watch.get_one::<usize>("threads").copied(),
+            filter: filter_options(watch),
+            sink: watch.get_one::<String>("sink").cloned(),
+        },
+        Some(("geotag", geotag)) => Command::Geotag {
+            path: geotag.get_one::<String>("path").unwrap().clone(),
+            geotag: Geotag {
+                lat: *geotag.get_one::<f64>("lat").unwrap(),
+                lon: *geotag.get_one::<f64>("lon").unwrap(),
+                altitude: geotag.get_one::<f64>("altitude").copied(),
+                offset_time: geotag.get_one::<String>("offset-time").cloned(),
+            },
+            options: write_options(geotag),
+        },
+        Some(("redact", redact)) => Command::Redact {
+            source: redact.get_one::<String>("source").unwrap().clone(),
+            destination: redact.get_one::<String>("destination").unwrap().clone(),
+            options: RedactOptions {
+                mode: match redact.get_one::<f64>("coarsen") {
+                    Some(grid) => RedactMode::Coarsen(*grid),
+                    None => RedactMode::Strip,
+                },
+                strip_serials: redact.is_present("strip-serials"),
+            },
+            report: redact.get_one::<String>("report").cloned(),
+        },
+        _ => Command::Serve,
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Define a test function for the default command.
+    #[test]
+    fn test_parse_serve() {
+        assert_eq!(parse_from(["exif_reader"]), Command::Serve);
+        assert_eq!(parse_from(["exif_reader", "serve"]), Command::Serve);
+    }
+
+    // Define a test function for the scan command with track files.
+    #[test]
+    fn test_parse_scan() {
+        let command = parse_from([
+            "exif_reader",
+            "scan",
+            "../test_data/",
+            "--track",
+            "day1.gpx",
+            "--track",
+            "day2.fit",
+            "--clock-offset",
+            "-10800",
+            "--threads",
+            "4",
+            "--exclude",
+            "**/.thumbnails",
+            "--extension",
+            "jpg",
+            "--extension",
+            "heic",
+            "--no-sniff",
+            "--no-strict",
+        ]);
+
+        assert_eq!(
+            command,
+            Command::Scan {
+                directory: "../test_data/".to_string(),
+                track: TrackOptions {
+                    files: vec!["day1.gpx".to_string(), "day2.fit".to_string()],
+                    clock_offset: -10800,
+                    max_gap: None,
+                },
+                write_back: None,
+                threads: Some(4),
+                ordered: None,
+                strict: Some(false),
+                report: None,
+                full: false,
+                filter: FilterOptions {
+                    exclude: vec!["**/.thumbnails".to_string()],
+                    extensions: vec!["jpg".to_string(), "heic".to_string()],
+                    sniff: Some(false),
+                    ..FilterOptions::default()
+                },
+                sink: None,
+            }
+        );
+
+        // Assert that a negative gap is refused.
+        let negative = clap_command().try_get_matches_from(["exif_reader", "scan", "../test_data/", "--max-gap=-5"]);
+        assert!(negative.is_err());
+    }
+
+    // Define a test function for the watch command.
+    #[test]
+    fn test_parse_watch() {
+        let command = parse_from([
+            "exif_reader",
+            "watch",
+            "/photos",
+            "--settle-ms",
+            "5000",
+            "--skip-hidden",
+            "--archives",
+            "--sink",
+            "stdout",
+        ]);
+
+        assert_eq!(
+            command,
+            Command::Watch {
+                directory: "/photos".to_string(),
+                settle_ms: Some(5000),
+                threads: None,
+                filter: FilterOptions {
+                    skip_hidden: Some(true),
+                    archives: Some(true),
+                    ..FilterOptions::default()
+                },
+                sink: Some("stdout".to_string()),
+            }
+        );
+
+        // Assert that the --no- flags turn off what the configuration enables, and conflict with their flag.
+        let command = parse_from([
+            "exif_reader",
+            "watch",
+            "/photos",
+            "--no-skip-hidden",
+            "--no-follow-symlinks",
+            "--no-same-file-system",
+            "--no-archives",
+        ]);
+        let Command::Watch { filter, .. } = command else {
+            panic!("Expected a watch command");
+        };
+        assert_eq!(
+            filter,
+            FilterOptions {
+                skip_hidden: Some(false),
+                follow_symlinks: Some(false),
+                same_file_system: Some(false),
+                archives: Some(false),
+                ..FilterOptions::default()
+            }
+        );
+        let conflicting = ["exif_reader", "watch", "/photos", "--archives", "--no-archives"];
+        assert!(clap_command().try_get_matches_from(conflicting).is_err());
+    }
+
+    // Define a test function for the geotag command and the write-back option of scans.
+    #[test]
+    fn test_parse_geotag() {
+        let command = parse_from([
+            "exif_reader",
+            "geotag",
+            "photo.jpg",
+            "--lat",
+            "-33.85",
+            "--lon",
+            "151.2",
+            "--offset-time",
+            "+10:00",
+            "--backup",
+        ]);
+
+        assert_eq!(
+            command,
+            Command::Geotag {
+                path: "photo.jpg".to_string(),
+                geotag: Geotag {
+                    lat: -33.85,
+                    lon: 151.2,
+                    altitude: None,
+                    offset_time: Some("+10:00".to_string()),
+                },
+                options: WriteOptions {
+                    sidecar: false,
+                    backup: true,
+                    dry_run: false,
+                },
+            }
+        );
+
+        let command = parse_from(["exif_reader", "scan", "photos", "--write-back", "--dry-run"]);
+        if let Command::Scan { write_back, .. } = command {
+            assert_eq!(
+                write_back,
+                Some(WriteOptions {
+                    dry_run: true,
+                    ..WriteOptions::default()
+                })
+            );
+        } else {
+            panic!("Expected a scan command");
+        }
+    }
+
+    // Define a test function for the redact command.
+    #[test]
+    fn test_parse_redact() {
+        let command = parse_from([
+            "exif_reader",
+            "redact",
+            "photos",
+            "shared",
+            "--coarsen",
+            "0.1",
+            "--strip-serials",
+        ]);
+
+        assert_eq!(
+            command,
+            Command::Redact {
+                source: "photos".to_string(),
+                destination: "shared".to_string(),
+                options: RedactOptions {
+                    mode: RedactMode::Coarsen(0.1),
+                    strip_serials: true,
+                },
+                report: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,133 @@
+// Import necessary modules from the project.
+use crate::archive;
+use crate::file_state;
+use crate::media_source;
+use crate::message::{EventType, Message};
+use crate::scan_report::{Failure, PublishFailure, ScanReport};
+
+// Import necessary modules from the standard library and external crates.
+use serde::Serialize;
+use std::path::Path;
+
+// Define the record of a failure sent to the dead-letter topic, so that broken files
+// can be triaged and undelivered messages published again.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetter {
+    // The path of the file, which is also the key of the record.
+    pub path: String,
+    // SHA-256 of the file contents, when it could be computed.
+    pub hash: Option<String>,
+    // "unsupported" or "error" for files that gave no message, "delivery" for undelivered messages.
+    pub error_kind: String,
+    pub error: String,
+    // The scan that met the failure.
+    pub scan_id: String,
+    // The original payload of an undelivered message; None for files and tombstones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+}
+
+impl DeadLetter {
+    // Build the Kafka message of the record, keyed by path, with the error kind in a header.
+    pub fn to_message(&self) -> Message {
+        Message {
+            key: self.path.clone(),
+            value: serde_json::to_value(self).unwrap_or_default(),
+            headers: vec![("error_kind".to_string(), self.error_kind.clone())],
+            source: None,
+            event: EventType::Created,
+            moved_from: None,
+        }
+    }
+}
+
+// local_hash is a function that hashes a local file, which archive entries and remote files aren't.
+pub fn local_hash(path: &str) -> Option<String> {
+    if archive::is_entry(path) || media_source::is_remote(path) {
+        return None;
+    }
+    file_state::content_hash(Path::new(path)).ok()
+}
+
+// from_report is a function that lists the dead letters of a scan: its files without message,
+// then its messages that failed to publish.
+// Parameters:
+// - report: The report of the scan.
+// Returns:
+// - Vec<DeadLetter>: The records of the failures.
+pub fn from_report(report: &ScanReport) -> Vec<DeadLetter> {
+    let files = report.failures.iter().map(|failure| DeadLetter {
+        path: failure.path.clone(),
+        hash: failure.hash.clone().or_else(|| local_hash(&failure.path)),
+        error_kind: match failure.failure {
+            Failure::Unsupported => "unsupported",
+            Failure::Error => "error",
+        }
+        .to_string(),
+        error: failure.reason.clone(),
+        scan_id: report.scan_id.clone(),
+        payload: None,
+    });
+    files.chain(from_publish_failures(&report.publish_failures, &report.scan_id)).collect()
+}
+
+// from_publish_failures is a function that lists the dead letters of messages that failed to publish.
+// Parameters:
+// - failures: The failed messages, with their payload.
+// - scan_id: The scan that produced them.
+// Returns:
+// - impl Iterator<Item = DeadLetter>: The records of the failures.
+pub fn from_publish_failures<'a>(
+    failures: &'a [PublishFailure],
+    scan_id: &'a str,
+) -> impl Iterator<Item = DeadLetter> + 'a {
+    failures.iter().map(move |failure| DeadLetter {
+        path: failure.key.clone(),
+        hash: None,
+        error_kind: "delivery".to_string(),
+        error: failure.reason.clone(),
+        scan_id: scan_id.to_string(),
+        payload: failure.payload.clone(),
+    })
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Define a test function for listing the dead letters of a scan.
+    #[test]
+    fn test_from_report() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("broken.jpg");
+        std::fs::write(&path, b"broken").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut report = ScanReport::new(directory.path().to_str().unwrap());
+        report.failed(path, Failure::Error, "Truncated".to_string());
+        report.publish_failures.push(PublishFailure {
+            key: "photos/a.jpg".to_string(),
+            reason: "Message timed out".to_string(),
+            payload: Some(serde_json::json!({"lat": 45.0})),
+        });
+        let letters = from_report(&report);
+
+        // Assert that local files are hashed, and undelivered messages keep their payload.
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0].error_kind, "error");
+        assert_eq!(
+            letters[0].hash,
+            file_state::content_hash(Path::new(path)).ok()
+        );
+        assert_eq!(letters[1].error_kind, "delivery");
+        assert_eq!(letters[1].scan_id, report.scan_id);
+
+        // Assert that the record is keyed by path and carries the payload.
+        let message = letters[1].to_message();
+        assert_eq!(message.key, "photos/a.jpg");
+        assert_eq!(message.value["payload"]["lat"], 45.0);
+        assert_eq!(message.value["error"], "Message timed out");
+        assert!(letters[0].to_message().value.get("payload").is_none());
+    }
+}
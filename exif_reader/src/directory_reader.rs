@@ -1,56 +1,661 @@
-use crate::message::{get_exif, Message};
+use crate::archive;
+use crate::enricher::{enrich, Enricher};
+use crate::file_filter::{FileFilter, SNIFF_LENGTH};
+use crate::file_state::{self, Check, FileState, StateCache};
+use crate::media_source::{self, LocalFs, MediaSource, RangeReader, SourceEntry};
+use crate::message::{get_exif, get_exif_from, EventType, Message, PhotoData};
 use crate::logger;
+use crate::scan_report::{Failure, ScanReport, WalkError};
 
-use walkdir::WalkDir;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
-// walking is a function that traverses the specified directory, extracts EXIF data from image files,
-// and returns a Result containing a vector of Message instances or a walkdir::Error.
+// Number of files extracted together per worker thread when the order is kept.
+const ORDERED_BATCH_PER_THREAD: usize = 16;
+
+// Define how the files of a directory are extracted.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    // Number of extraction threads; 0 uses one per CPU core.
+    pub threads: usize,
+    // Keep the messages in file name order instead of completion order.
+    pub ordered: bool,
+    // Stop at the first unreadable entry instead of skipping it.
+    pub strict: bool,
+    // The files to extract.
+    pub filter: FileFilter,
+    // The state of the files at the last scan, to skip the unchanged ones.
+    pub cache: Option<Arc<StateCache>>,
+    // Extract unchanged files again.
+    pub full: bool,
+    // Fingerprint of the enrichers and privacy zones of the scan, cached with the state of every file
+    // so that the files extracted with other ones are extracted again.
+    pub settings: String,
+    // Hash the contents of every file even without cache, for sinks keying their rows by it, such as PostGIS.
+    pub hash_contents: bool,
+}
+
+// file_iter is a function that lazily traverses the specified directory and yields the files it contains.
+// Parameters:
+// - directory: A string representing the directory path to traverse.
+// - sorted: Whether to list the entries of every directory in file name order.
+// - filter: The files and directories to visit.
+// Returns:
+// - impl Iterator<Item = Result<String, walkdir::Error>>: The paths of the files, or a walkdir::Error
+//   for every error that occurs during directory traversal.
+pub fn file_iter<'a>(
+    directory: &'a str,
+    sorted: bool,
+    filter: &'a FileFilter,
+) -> impl Iterator<Item = Result<String, walkdir::Error>> + 'a {
+    // The file system order isn't stable, so sort when a deterministic order is needed.
+    let mut walker = filter.walker(directory);
+    if sorted {
+        walker = walker.sort_by_file_name();
+    }
+    let root = Path::new(directory);
+
+    // Keep the files (i.e., the entries that are not directories) the filter accepts, and the errors.
+    walker
+        .into_iter()
+        .filter_entry(move |entry| filter.keep_entry(root, entry))
+        .filter_map(move |entry| match entry {
+            Ok(entry) if entry.file_type().is_dir() => None,
+            Ok(entry) if !filter.keep_file(root, &entry) => None,
+            Ok(entry) => Some(Ok(entry.path().display().to_string())),
+            Err(error) => {
+                // Log an error message and pass the encountered error on.
+                logger::log_error(&format!("Error while walking {}: {}", directory, error));
+                Some(Err(error))
+            }
+        })
+}
+
+// files is a function that traverses the specified directory and lists the files it contains.
+// Parameters:
+// - directory: A string representing the directory path to traverse.
+// - sorted: Whether to list the entries of every directory in file name order.
+// Returns:
+// - Result<Vec<String>, walkdir::Error>: The paths of the files, or a walkdir::Error
+//   if an error occurs during directory traversal.
+pub fn files(directory: &str, sorted: bool) -> Result<Vec<String>, walkdir::Error> {
+    file_iter(directory, sorted, &FileFilter::default()).collect()
+}
+
+// failure is a function that tells files without readable EXIF data from files that couldn't be read.
+fn failure(error: &exif::Error) -> Failure {
+    match error {
+        exif::Error::InvalidFormat("Unknown image format")
+        | exif::Error::NotFound(_)
+        | exif::Error::NotSupported(_) => Failure::Unsupported,
+        _ => Failure::Error,
+    }
+}
+
+// extract is a function that extracts the EXIF data of a single file and applies the enrichers to it.
+// Parameters:
+// - filename: The path of the file.
+// - enrichers: Enrichers applied to the extracted data before the Message is built.
+// - options: The cache of the file states, if any.
+// - report: The report of the scan, counting the outcome of the file.
+// Returns:
+// - Option<Message>: The Message of the file, or None if it has no readable EXIF data
+//   or didn't change since the last scan.
+fn extract(
+    filename: &str,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    report: &Mutex<ScanReport>,
+) -> Option<Message> {
+    let check = file_state::stat(filename).and_then(|(size, mtime)| {
+        check_state(filename, size, mtime, || file_state::content_hash(Path::new(filename)), options)
+    });
+    extract_with(filename, check, || get_exif(filename), &LocalFs, enrichers, options, report)
+}
+
+// check_state is a function that compares a file with its cached state. Without cache every file is new,
+// and its contents are only hashed when the sink keys its rows by the hash, such as PostGIS,
+// so that the file isn't read twice for nothing; its content hash is empty otherwise.
+// Parameters:
+// - path: The path or location of the file.
+// - size, mtime: The size of the file and its modification time in nanoseconds.
+// - hash: Fingerprints the contents; with a cache, only when the size or modification time changed.
+// - options: The cache of the scan, if any, and whether the contents are hashed without one.
+// Returns:
+// - io::Result<Check>: Whether the file changed since it was last scanned, or the error of the fingerprint.
+fn check_state<H>(path: &str, size: u64, mtime: i64, hash: H, options: &WalkOptions) -> io::Result<Check>
+where
+    H: FnOnce() -> io::Result<String>,
+{
+    match &options.cache {
+        Some(cache) => cache.check_with(path, size, mtime, options.full, &options.settings, hash),
+        None => Ok(Check::New(FileState {
+            path: path.to_string(),
+            size,
+            mtime,
+            content_hash: if options.hash_contents { hash()? } else { String::new() },
+            payload_hash: None,
+            settings: None,
+        })),
+    }
+}
+
+// extract_with is a function that builds the Message of a file from its cache check and EXIF data,
+// wherever the file is read from.
+// Parameters:
+// - filename: The path of the file, or the virtual path of an archive entry.
+// - check: The comparison of the file with its cached state.
+// - read: Reads the EXIF data of the file; only called for changed files.
+// - source: The source of the file, telling whether the previous path of a moved file was removed.
+// - enrichers, options, report: As for extract.
+// Returns:
+// - Option<Message>: The Message of the file, or None if it was skipped or couldn't be read.
+fn extract_with<R>(
+    filename: &str,
+    check: io::Result<Check>,
+    read: R,
+    source: &dyn MediaSource,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    report: &Mutex<ScanReport>,
+) -> Option<Message>
+where
+    R: FnOnce() -> Result<HashMap<String, PhotoData>, exif::Error>,
+{
+    // Skip the files that didn't change since the last scan.
+    let (state, event) = match check {
+        Ok(Check::Unchanged) => {
+            report.lock().unwrap().unchanged();
+            return None;
+        }
+        Ok(Check::Changed(state)) => (state, EventType::Updated),
+        Ok(Check::New(state)) => (state, EventType::Created),
+        Err(error) => {
+            report.lock().unwrap().failed(filename, Failure::Error, error.to_string());
+            return None;
+        }
+    };
+
+    // Attempt to extract EXIF data from the file.
+    match read() {
+        // If successful, log a debug message and return a new Message instance.
+        Ok(mut e) => {
+            e.values_mut().for_each(|data| enrich(enrichers, data));
+            logger::log_debug(&format!("Push new message for {}: {:?}", filename, e));
+            report.lock().unwrap().parsed(e.values().any(|data| data.has_position()));
+            let mut message = Message::new(e);
+            message.set_event(event);
+            // A new file with the contents of a removed one was moved.
+            if let (EventType::Created, Some(cache)) = (event, &options.cache) {
+                match cache.moved_from(&state, source) {
+                    Ok(Some(moved_from)) => {
+                        message.set_moved_from(&moved_from);
+                        report.lock().unwrap().moved += 1;
+                    }
+                    Ok(None) => (),
+                    Err(error) => logger::log_error(&format!("Error while looking for the previous path of {}: {}", filename, error)),
+                }
+            }
+            message.source = Some(state);
+            Some(message)
+        },
+        // If an error occurs during EXIF extraction, log the error message and report the file.
+        Err(error) => {
+            logger::log_debug(&error.to_string());
+            let failure = failure(&error);
+            // Unsupported files stay unsupported until they change, unlike unreadable ones.
+            if let (Failure::Unsupported, Some(cache)) = (failure, &options.cache) {
+                cache.save(&state);
+            }
+            let hash = (!state.content_hash.is_empty()).then_some(state.content_hash);
+            report.lock().unwrap().failed_with_hash(filename, failure, error.to_string(), hash);
+            None
+        },
+    }
+}
+
+// extract_archive is a function that extracts the entries of an archive as they are read,
+// and hands their Message to a consumer. The entries are filtered and cached like files,
+// under their virtual path. With a cache, an archive whose size and modification time didn't change
+// isn't read again, and the cached entries no longer in the archive are handed over as tombstones.
+// Parameters:
+// - archive: The path of the archive.
+// - root: The scanned directory, which the filter globs are relative to.
+// - enrichers, options, report: As for extract.
+// - each: The consumer; returning false stops the extraction.
+// Returns:
+// - bool: false once the consumer stopped the extraction.
+fn extract_archive<F>(
+    archive: &str,
+    root: &Path,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    report: &Mutex<ScanReport>,
+    each: &F,
+) -> bool
+where
+    F: Fn(Message) -> bool + ?Sized,
+{
+    // The archive itself is cached under the virtual path of its root.
+    let cache = options.cache.as_deref();
+    let archive_state = match cache.map(|cache| {
+        file_state::stat(archive).and_then(|(size, mtime)| {
+            let path = archive::virtual_path(archive, "");
+            cache.check_with(&path, size, mtime, options.full, &options.settings, || {
+                file_state::content_hash(Path::new(archive))
+            })
+        })
+    }) {
+        Some(Ok(Check::Unchanged)) => {
+            report.lock().unwrap().unchanged();
+            return true;
+        }
+        Some(Ok(Check::Changed(state) | Check::New(state))) => Some(state),
+        Some(Err(error)) => {
+            report.lock().unwrap().failed(archive, Failure::Error, error.to_string());
+            return true;
+        }
+        None => None,
+    };
+
+    // The archive is only cached once none of its entries changed, failed or disappeared,
+    // so that it is read again until every entry is delivered.
+    let mut settled = true;
+    let mut open = true;
+    let listed = RefCell::new(HashSet::new());
+    let keep = |path: &str, size: u64| {
+        listed.borrow_mut().insert(path.to_string());
+        options.filter.keep_archive_entry(root, path, size)
+    };
+    let read = archive::for_each_entry(archive, keep, |entry| {
+        let contents = match entry.contents {
+            Ok(contents) => contents,
+            Err(error) => {
+                settled = false;
+                report.lock().unwrap().failed(&entry.path, Failure::Error, error.to_string());
+                return true;
+            }
+        };
+        if !options.filter.keep_contents(&contents) {
+            return true;
+        }
+        let check = check_state(&entry.path, entry.size, entry.mtime, || Ok(file_state::contents_hash(&contents)), options);
+        settled &= matches!(check, Ok(Check::Unchanged));
+        let read = || get_exif_from(&entry.path, &mut Cursor::new(&contents));
+        if let Some(message) = extract_with(&entry.path, check, read, &LocalFs, enrichers, options, report) {
+            open = each(message);
+        }
+        open
+    });
+    // An archive that can't be listed is reported like a file that can't be read.
+    if let Err(error) = read {
+        logger::log_debug(&error.to_string());
+        report.lock().unwrap().failed(archive, Failure::Error, error.to_string());
+        return open;
+    }
+
+    // The entries removed from an archive that is still there are only told by its listing.
+    if let (Some(cache), true) = (cache, open) {
+        let listed = listed.into_inner();
+        match cache.entries(archive) {
+            Ok(entries) => {
+                for path in entries.iter().filter(|path| !listed.contains(*path)) {
+                    settled = false;
+                    open = each(Message::tombstone(path, EventType::Deleted));
+                    if !open {
+                        break;
+                    }
+                    report.lock().unwrap().deleted += 1;
+                }
+            }
+            Err(error) => {
+                settled = false;
+                logger::log_error(&format!("Error while looking for the removed entries of {}: {}", archive, error));
+            }
+        }
+    }
+    if let (Some(cache), Some(state), true) = (cache, archive_state, settled && open) {
+        cache.save(&state);
+    }
+    open
+}
+
+// extract_path is a function that extracts a file, or the entries of an archive, and hands
+// their Message to a consumer.
+// Returns:
+// - bool: false once the consumer stopped the extraction.
+fn extract_path<F>(
+    filename: &str,
+    root: &Path,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    report: &Mutex<ScanReport>,
+    each: &F,
+) -> bool
+where
+    F: Fn(Message) -> bool + ?Sized,
+{
+    if options.filter.is_archive(Path::new(filename)) {
+        return extract_archive(filename, root, enrichers, options, report, each);
+    }
+    extract(filename, enrichers, options, report).is_none_or(each)
+}
+
+// extract_listed is a function that extracts a file listed by a media source, reading only
+// the ranges of the file holding its EXIF data. The file is cached under its location.
+// Parameters:
+// - source: The source of the file.
+// - entry: The file, as listed by the source.
+// - enrichers, options, report: As for extract.
+// Returns:
+// - Option<Message>: The Message of the file, or None if it was skipped or couldn't be read.
+fn extract_listed(
+    source: &dyn MediaSource,
+    entry: &SourceEntry,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    report: &Mutex<ScanReport>,
+) -> Option<Message> {
+    // Only fetch the first bytes of the file when its contents are sniffed.
+    if options.filter.sniffs() {
+        let mut header = Vec::with_capacity(SNIFF_LENGTH);
+        let read = source
+            .open_range(&entry.path, 0, Some(SNIFF_LENGTH as u64))
+            .and_then(|mut reader| reader.read_to_end(&mut header));
+        if let Err(error) = read {
+            report.lock().unwrap().failed(&entry.path, Failure::Error, error.to_string());
+            return None;
+        }
+        if !options.filter.keep_contents(&header) {
+            return None;
+        }
+    }
+
+    // The fingerprint given by the source spares downloading the whole file to hash it;
+    // without one, the file is only downloaded when its hash is needed.
+    let check = check_state(&entry.path, entry.size, entry.mtime, || match &entry.etag {
+        Some(etag) => Ok(etag.clone()),
+        None => file_state::reader_hash(source.open_range(&entry.path, 0, None)?),
+    }, options);
+    let read = || get_exif_from(&entry.path, &mut BufReader::new(RangeReader::new(source, entry)));
+    extract_with(&entry.path, check, read, source, enrichers, options, report)
+}
+
+// Define why a streaming walk stopped early.
+enum Halt<E> {
+    // The directory traversal failed.
+    Walk(E),
+    // The consumer doesn't want more messages.
+    Closed,
+}
+
+// walking is a function that traverses the specified directory, extracts EXIF data from image files
+// on every CPU core, and returns a Result containing a vector of Message instances or a walkdir::Error.
 // Parameters:
 // - directory: A string representing the directory path to traverse and extract EXIF data from.
+// - enrichers: Enrichers applied to the extracted data before each Message is built.
 // Returns:
 // - Result<Vec<Message>, walkdir::Error>: A Result containing a vector of Message instances if successful,
 //   or a walkdir::Error if an error occurs during directory traversal.
-pub fn walking(directory: &str) -> Result<Vec<Message>, walkdir::Error> {
+pub fn walking(directory: &str, enrichers: &[&dyn Enricher]) -> Result<Vec<Message>, walkdir::Error> {
+    walking_with(directory, enrichers, &WalkOptions::default()).map(|(messages, _)| messages)
+}
+
+// walking_with is a function that traverses the specified directory and collects the Message of
+// every image file, extracted on a bounded pool of worker threads.
+// Parameters:
+// - directory: A string representing the directory path to traverse and extract EXIF data from.
+// - enrichers: Enrichers applied to the extracted data before each Message is built.
+// - options: The number of worker threads, whether the output order must be deterministic
+//   and whether unreadable entries stop the walk.
+// Returns:
+// - Result<(Vec<Message>, ScanReport), walkdir::Error>: A Result containing a vector of Message instances
+//   and the report of the walk if successful, or a walkdir::Error if an entry can't be read in strict mode.
+pub fn walking_with(
+    directory: &str,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+) -> Result<(Vec<Message>, ScanReport), walkdir::Error> {
     // Create an empty vector to store the extracted messages.
-    let mut messages: Vec<Message> = Vec::new();
-
-    // Iterate over entries (files and directories) in the specified directory.
-    for entry in WalkDir::new(directory) {
-        // Check if there was an error accessing the entry.
-        if entry.is_err() {
-            // Log an error message and return the encountered error.
-            logger::log_error("Bad directory {directory}");
-            return Err(entry.err().unwrap());
-        }
-
-        // Check if the entry is not a directory (i.e., it's a file).
-        if !entry.as_ref().unwrap().file_type().is_dir() {
-            // Get the filename from the entry.
-            let filename = entry?.path().display().to_string();
-
-            // Attempt to extract EXIF data from the file using the get_exif function.
-            match get_exif(&filename) {
-                // If successful, log a debug message and push a new Message instance to the messages vector.
-                Ok(e) => {
-                    logger::log_debug(&format!("Push new message for {}: {:?}", filename, e));
-                    messages.push(Message::new(e))
-                },
-                // If an error occurs during EXIF extraction, log the error message.
-                Err(error) => {
-                    logger::log_debug(&format!("{}", error.to_string()));
-                    ()
-                },
+    let messages: Mutex<Vec<Message>> = Mutex::new(Vec::new());
+
+    let report = walking_each(directory, enrichers, options, |message| {
+        messages.lock().unwrap().push(message);
+        true
+    })?;
+
+    // Return the vector of extracted messages and the report wrapped in a Result.
+    Ok((messages.into_inner().unwrap(), report))
+}
+
+// walking_each is a function that traverses the specified directory and hands the Message of every
+// image file to a consumer as soon as it is extracted, so that nothing waits for the whole walk.
+// Files are extracted on a bounded pool of worker threads while the directory is still being traversed.
+// Extraction blocks, so async callers should run it off the executor threads.
+// Parameters:
+// - directory: A string representing the directory path to traverse and extract EXIF data from.
+// - enrichers: Enrichers applied to the extracted data before each Message is built.
+// - options: The number of worker threads, whether the output order must be deterministic,
+//   whether unreadable entries stop the walk and the cache of the files that didn't change.
+// - each: The consumer, called from the worker threads; returning false stops the walk.
+// Returns:
+// - Result<ScanReport, walkdir::Error>: The report of the walk once it is over or stopped by the consumer,
+//   or a walkdir::Error if an entry can't be read in strict mode.
+pub fn walking_each<F>(
+    directory: &str,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    each: F,
+) -> Result<ScanReport, walkdir::Error>
+where
+    F: Fn(Message) -> bool + Send + Sync,
+{
+    // Unreadable entries are skipped and reported, unless the walk is strict.
+    // The scanned directory itself must always be readable.
+    let report = Mutex::new(ScanReport::new(directory));
+    let files = file_iter(directory, options.ordered, &options.filter).filter_map(|file| match file {
+        Err(error) if !options.strict && error.depth() > 0 => {
+            report.lock().unwrap().walk_errors.push(WalkError::new(&error));
+            None
+        }
+        file => Some(file),
+    });
+    let root = Path::new(directory);
+    let extract = |filename: &String, each: &dyn Fn(Message) -> bool| {
+        extract_path(filename, root, enrichers, options, &report, each)
+    };
+    extract_each(files, options, extract, each)?;
+
+    let mut report = report.into_inner().unwrap();
+    report.finish();
+    Ok(report)
+}
+
+// extracting_each is a function that extracts the files of a directory given by path, such as the files
+// a watcher saw changing, and hands their Message to a consumer like walking_each does.
+// The files are selected by the filter of the options like the files of a walk.
+// Parameters:
+// - directory: The directory of the files, which the filter globs are relative to.
+// - files: The paths of the files.
+// - enrichers: Enrichers applied to the extracted data before each Message is built.
+// - options: The number of worker threads, the filter and the cache of the files that didn't change.
+// - each: The consumer, called from the worker threads; returning false stops the extraction.
+// Returns:
+// - ScanReport: The report of the extraction.
+pub fn extracting_each<F>(
+    directory: &str,
+    files: &[String],
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    each: F,
+) -> ScanReport
+where
+    F: Fn(Message) -> bool + Send + Sync,
+{
+    let report = Mutex::new(ScanReport::new(directory));
+    let root = Path::new(directory);
+    let files = files
+        .iter()
+        .filter(|filename| options.filter.keep_path(root, Path::new(filename)))
+        .map(|filename| Ok::<_, walkdir::Error>(filename.clone()));
+    let extract = |filename: &String, each: &dyn Fn(Message) -> bool| {
+        extract_path(filename, root, enrichers, options, &report, each)
+    };
+    // Only a directory traversal can fail, and there is none.
+    let _ = extract_each(files, options, extract, each);
+
+    let mut report = report.into_inner().unwrap();
+    report.finish();
+    report
+}
+
+// walking_source_each is a function that lists the files under a location of a media source, such as
+// s3://bucket/prefix, and hands the Message of every image file to a consumer like walking_each does.
+// Only the ranges of the files holding their EXIF data are read.
+// Parameters:
+// - source: The source of the files.
+// - root: The scanned location; the filter globs are relative to it.
+// - enrichers: Enrichers applied to the extracted data before each Message is built.
+// - options: As for walking_each; the files are extracted in listing order when the order is kept.
+// - each: The consumer, called from the worker threads; returning false stops the walk.
+// Returns:
+// - io::Result<ScanReport>: The report of the walk once it is over or stopped by the consumer,
+//   or an std::io::Error if the location can't be listed, or a part of it in strict mode.
+pub fn walking_source_each<F>(
+    source: &dyn MediaSource,
+    root: &str,
+    enrichers: &[&dyn Enricher],
+    options: &WalkOptions,
+    each: F,
+) -> io::Result<ScanReport>
+where
+    F: Fn(Message) -> bool + Send + Sync,
+{
+    let report = Mutex::new(ScanReport::new(root));
+    let entries = source.list(root)?.filter_map(|entry| match entry {
+        Ok(entry) if media_source::keep(&options.filter, root, &entry) => Some(Ok(entry)),
+        Ok(_) => None,
+        Err(error) if !options.strict => {
+            logger::log_error(&format!("Error while listing {}: {}", root, error));
+            report.lock().unwrap().walk_errors.push(WalkError {
+                path: None,
+                error: error.to_string(),
+            });
+            None
+        }
+        Err(error) => Some(Err(error)),
+    });
+    let extract = |entry: &SourceEntry, each: &dyn Fn(Message) -> bool| {
+        extract_listed(source, entry, enrichers, options, &report).is_none_or(each)
+    };
+    extract_each(entries, options, extract, each)?;
+
+    let mut report = report.into_inner().unwrap();
+    report.finish();
+    Ok(report)
+}
+
+// extract_each is a function that extracts files on a bounded pool of worker threads and hands their
+// Message to a consumer.
+// Parameters:
+// - files: The files, such as paths or listed entries, or the errors of the traversal listing them.
+// - options: The number of worker threads and whether the output order must be deterministic.
+// - extract: Extracts a file and hands its messages to the consumer it is given; returns false once it stopped.
+// - each: The consumer, called from the worker threads; returning false stops the extraction.
+// Returns:
+// - Result<(), E>: Ok once every file is extracted or the consumer stopped the extraction,
+//   or the first error of the files.
+fn extract_each<T, E, I, X, F>(mut files: I, options: &WalkOptions, extract: X, each: F) -> Result<(), E>
+where
+    T: Send + Sync,
+    E: Send,
+    I: Iterator<Item = Result<T, E>> + Send,
+    X: Fn(&T, &dyn Fn(Message) -> bool) -> bool + Sync,
+    F: Fn(Message) -> bool + Send + Sync,
+{
+    // Create the worker pool; a single thread extracts on the calling thread.
+    let pool = match options.threads {
+        1 => None,
+        threads => match ThreadPoolBuilder::new().num_threads(threads).build() {
+            Ok(pool) => Some(pool),
+            Err(error) => {
+                logger::log_error(&format!("Error while creating the worker pool, extracting sequentially: {}", error));
+                None
+            }
+        },
+    };
+
+    let result = match pool {
+        // Extract and hand over the files one by one.
+        None => files.try_for_each(|file| {
+            match extract(&file.map_err(Halt::Walk)?, &each) {
+                true => Ok(()),
+                false => Err(Halt::Closed),
+            }
+        }),
+        // Extract small batches in parallel and hand them over in the order of the files.
+        Some(pool) if options.ordered => {
+            let batch_size = pool.current_num_threads() * ORDERED_BATCH_PER_THREAD;
+            loop {
+                let batch = files
+                    .by_ref()
+                    .take(batch_size)
+                    .collect::<Result<Vec<T>, E>>();
+                let batch = match batch {
+                    Ok(batch) if batch.is_empty() => break Ok(()),
+                    Ok(batch) => batch,
+                    Err(error) => break Err(Halt::Walk(error)),
+                };
+                // Collecting an indexed parallel iterator keeps the order of the files.
+                let messages: Vec<Message> = pool.install(|| {
+                    batch
+                        .par_iter()
+                        .flat_map_iter(|file| {
+                            let messages = RefCell::new(Vec::new());
+                            extract(file, &|message| {
+                                messages.borrow_mut().push(message);
+                                true
+                            });
+                            messages.into_inner()
+                        })
+                        .collect()
+                });
+                if !messages.into_iter().all(&each) {
+                    break Err(Halt::Closed);
+                }
             }
         }
-    }
+        // Otherwise the messages are handed over as soon as they are extracted.
+        Some(pool) => pool.install(|| {
+            files.par_bridge().try_for_each(|file| {
+                match extract(&file.map_err(Halt::Walk)?, &each) {
+                    true => Ok(()),
+                    false => Err(Halt::Closed),
+                }
+            })
+        }),
+    };
 
-    // Return the vector of extracted messages wrapped in a Result.
-    Ok(messages)
+    match result {
+        Err(Halt::Walk(error)) => Err(error),
+        Ok(()) | Err(Halt::Closed) => Ok(()),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::directory_reader::walking;
+    use crate::directory_reader::{walking, walking_each, walking_with, WalkOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::exif_writer::{write_geotag, Geotag, WriteOptions};
+    use crate::file_filter::{FileFilter, FilterOptions};
+    use crate::file_state::StateCache;
+    use std::io::Write;
+    use std::sync::Arc;
 
     #[test]
     fn test_walk_directory() {
@@ -58,10 +663,10 @@ mod test {
         let directory = "../test_data/";
 
         // Call the walking function with the specified directory and capture the result.
-        let result = walking(directory);
+        let result = walking(directory, &[]);
 
         // Assert that the result is Ok, indicating success.
-        assert!(matches!(result, Ok(_)));
+        assert!(result.is_ok());
     }
 
     #[test]
@@ -70,9 +675,236 @@ mod test {
         let directory = "../../test_data/";
 
         // Call the walking function with the specified directory and capture the result.
-        let result = walking(directory);
+        let result = walking(directory, &[]);
 
         // Assert that the result is Err, indicating an error due to the non-existent directory.
-        assert!(matches!(result, Err(_)));
+        assert!(result.is_err());
+    }
+
+    // photos is a helper that creates a directory with geotagged photos and a file without EXIF data.
+    fn photos() -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        for index in 0..20 {
+            let path = directory.path().join(format!("photo_{:02}.jpg", index));
+            std::fs::write(&path, [0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+            let geotag = Geotag {
+                lat: 45.0 + index as f64 / 100.0,
+                lon: 39.0,
+                altitude: None,
+                offset_time: None,
+            };
+            write_geotag(&path, &geotag, &WriteOptions::default()).unwrap();
+        }
+        std::fs::write(directory.path().join("notes.txt"), "not a photo").unwrap();
+        directory
+    }
+
+    #[test]
+    fn test_walk_parallel() {
+        let photos = photos();
+        let directory = photos.path().to_str().unwrap();
+
+        // Extract sequentially and on four threads, keeping the order.
+        let keys = |options: &WalkOptions| -> Vec<String> {
+            walking_with(directory, &[], options)
+                .unwrap()
+                .0
+                .into_iter()
+                .map(|message| message.key)
+                .collect()
+        };
+        let sequential = keys(&WalkOptions { threads: 1, ordered: true, ..WalkOptions::default() });
+        let parallel = keys(&WalkOptions { threads: 4, ordered: true, ..WalkOptions::default() });
+
+        // Assert that both runs return the photos in file name order.
+        assert_eq!(sequential.len(), 20);
+        assert!(sequential.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(parallel, sequential);
+
+        // Assert that an unordered run returns the same photos.
+        let mut unordered = keys(&WalkOptions { threads: 4, ordered: false, ..WalkOptions::default() });
+        unordered.sort();
+        assert_eq!(unordered, sequential);
+    }
+
+    #[test]
+    fn test_walk_each_stops() {
+        let photos = photos();
+        let directory = photos.path().to_str().unwrap();
+
+        // Hand the messages over one by one and stop after the third one.
+        for options in [
+            WalkOptions { threads: 1, ordered: false, ..WalkOptions::default() },
+            WalkOptions { threads: 2, ordered: true, ..WalkOptions::default() },
+        ] {
+            let received = AtomicUsize::new(0);
+            let result = walking_each(directory, &[], &options, |_| {
+                received.fetch_add(1, Ordering::SeqCst) + 1 < 3
+            });
+
+            // Assert that the walk ends without an error and without extracting the other photos.
+            assert!(result.is_ok());
+            assert_eq!(received.load(Ordering::SeqCst), 3);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_errors() {
+        let photos = photos();
+        let directory = photos.path().to_str().unwrap();
+
+        // A dangling symbolic link can't be followed.
+        let dangling = photos.path().join("dangling.jpg");
+        std::os::unix::fs::symlink(photos.path().join("missing.jpg"), &dangling).unwrap();
+        let filter = FileFilter::new(&FilterOptions {
+            follow_symlinks: Some(true),
+            sniff: Some(false),
+            ..FilterOptions::default()
+        })
+        .unwrap();
+
+        // Assert that the unreadable entry is reported and the other photos are still extracted and counted.
+        let options = WalkOptions { threads: 2, filter, ..WalkOptions::default() };
+        let received = AtomicUsize::new(0);
+        let report = walking_each(directory, &[], &options, |_| {
+            received.fetch_add(1, Ordering::SeqCst);
+            true
+        })
+        .unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 20);
+        assert_eq!(report.walk_errors.len(), 1);
+        assert_eq!(report.walk_errors[0].path.as_deref(), dangling.to_str());
+        assert_eq!((report.files_seen, report.with_gps, report.unsupported), (21, 20, 1));
+        assert!(report.failures[0].path.ends_with("notes.txt"));
+
+        // Assert that a strict walk stops at the unreadable entry.
+        let options = WalkOptions { strict: true, ..options };
+        assert!(walking_with(directory, &[], &options).is_err());
+    }
+
+    #[test]
+    fn test_walk_cache() {
+        let photos = photos();
+        let directory = photos.path().to_str().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let cache = StateCache::open(cache.path().join("cache.db").to_str().unwrap()).unwrap();
+        let options = WalkOptions { cache: Some(Arc::new(cache)), ..WalkOptions::default() };
+
+        // Save the state of the photos as if their messages were delivered.
+        let (messages, report) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!((messages.len(), report.unchanged), (20, 0));
+        for message in &messages {
+            options.cache.as_ref().unwrap().save(message.source.as_ref().unwrap());
+        }
+
+        // Assert that a rescan skips the unchanged photos and the unsupported file, but not a changed photo.
+        let changed = photos.path().join("photo_00.jpg");
+        let geotag = Geotag { lat: 46.0, lon: 39.0, altitude: None, offset_time: None };
+        write_geotag(&changed, &geotag, &WriteOptions::default()).unwrap();
+        let (messages, report) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!((messages.len(), report.unchanged), (1, 20));
+        assert_eq!(messages[0].key, changed.to_str().unwrap());
+
+        // Assert that a full rescan extracts every photo again.
+        let options = WalkOptions { full: true, ..options };
+        let (mut messages, _) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!(messages.len(), 20);
+
+        // Assert that a photo written back is cached as it is once rewritten, so a rescan skips it.
+        let index = messages.iter().position(|message| message.key == changed.to_str().unwrap()).unwrap();
+        let mut message = messages.remove(index);
+        message.value["position_source"] = serde_json::json!("track");
+        message.value["lat"] = serde_json::json!(47.0);
+        crate::exif_writer::write_back(&mut message, None, &WriteOptions::default());
+        options.cache.as_ref().unwrap().delivered(&message);
+        let options = WalkOptions { full: false, ..options };
+        let (messages, report) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!((messages.len(), report.unchanged), (0, 21));
+    }
+
+    // Define a test function for hashing the contents of the files only when the scan needs it.
+    #[test]
+    fn test_walk_hash() {
+        let photos = photos();
+        let directory = photos.path().to_str().unwrap();
+
+        // Assert that the files aren't hashed without cache, unless the sink keys its rows by the hash.
+        let (messages, _) = walking_with(directory, &[], &WalkOptions::default()).unwrap();
+        assert_eq!(messages.len(), 20);
+        assert!(messages.iter().all(|message| message.source.as_ref().unwrap().content_hash.is_empty()));
+        let options = WalkOptions { hash_contents: true, ..WalkOptions::default() };
+        let (messages, _) = walking_with(directory, &[], &options).unwrap();
+        for message in &messages {
+            let hash = crate::file_state::content_hash(std::path::Path::new(&message.key)).unwrap();
+            assert_eq!(message.source.as_ref().unwrap().content_hash, hash);
+        }
+    }
+
+    // Define a test function for extracting the photos of archives.
+    #[test]
+    fn test_walk_archives() {
+        let photos = photos();
+        let directory = photos.path().to_str().unwrap();
+        let archive = photos.path().join("backup.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive).unwrap());
+        for name in ["2014/IMG_001.jpg", "2014/notes.txt"] {
+            let source = match name.ends_with(".jpg") {
+                true => photos.path().join("photo_00.jpg"),
+                false => photos.path().join("notes.txt"),
+            };
+            zip.start_file(name, Default::default()).unwrap();
+            zip.write_all(&std::fs::read(source).unwrap()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        // Assert that archives are skipped unless asked for.
+        let (messages, _) = walking_with(directory, &[], &WalkOptions::default()).unwrap();
+        assert_eq!(messages.len(), 20);
+
+        // Assert that the photos of an archive are extracted under their virtual path, and its other files sniffed out.
+        let filter = FileFilter::new(&FilterOptions { archives: Some(true), ..FilterOptions::default() }).unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let cache = StateCache::open(cache.path().join("cache.db").to_str().unwrap()).unwrap();
+        let options = WalkOptions { filter, cache: Some(Arc::new(cache)), ..WalkOptions::default() };
+        let (messages, report) = walking_with(directory, &[], &options).unwrap();
+        let key = format!("{}!/2014/IMG_001.jpg", archive.to_str().unwrap());
+        assert_eq!((messages.len(), report.with_gps), (21, 21));
+        let lat = &messages.iter().find(|message| message.key.ends_with("photo_00.jpg")).unwrap().value["lat"];
+        assert_eq!(&messages.iter().find(|message| message.key == key).unwrap().value["lat"], lat);
+
+        // Assert that unchanged entries are skipped on rescans.
+        for message in &messages {
+            options.cache.as_ref().unwrap().save(message.source.as_ref().unwrap());
+        }
+        let (rescanned, report) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!((rescanned.len(), report.unchanged), (0, 21));
+
+        // Assert that an archive with the same size and modification time isn't read again.
+        let (before, modified) = (std::fs::read(&archive).unwrap(), std::fs::metadata(&archive).unwrap().modified().unwrap());
+        std::fs::write(&archive, vec![0; before.len()]).unwrap();
+        std::fs::File::options().write(true).open(&archive).unwrap().set_modified(modified).unwrap();
+        let (rescanned, report) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!((rescanned.len(), report.unchanged, report.errored), (0, 21, 0));
+
+        // Assert that the entries removed from an archive are tombstoned, once.
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive).unwrap());
+        zip.start_file("2014/notes.txt", Default::default()).unwrap();
+        zip.write_all(&std::fs::read(photos.path().join("notes.txt")).unwrap()).unwrap();
+        zip.finish().unwrap();
+        let (rescanned, report) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!(rescanned.iter().map(|message| (message.key.as_str(), message.is_tombstone())).collect::<Vec<_>>(), [(key.as_str(), true)]);
+        assert_eq!(report.deleted, 1);
+        options.cache.as_ref().unwrap().delivered(&rescanned[0]);
+        let (rescanned, report) = walking_with(directory, &[], &options).unwrap();
+        assert_eq!((rescanned.len(), report.deleted), (0, 0));
+        std::fs::write(&archive, &before).unwrap();
+
+        // Assert that write-back leaves archives alone.
+        let mut entry = messages.into_iter().find(|message| message.key == key).unwrap();
+        entry.value["position_source"] = serde_json::json!("track");
+        let before = std::fs::read(&archive).unwrap();
+        crate::exif_writer::write_back(&mut entry, None, &WriteOptions::default());
+        assert_eq!(std::fs::read(&archive).unwrap(), before);
     }
 }
@@ -0,0 +1,681 @@
+// Import necessary modules from the project.
+use crate::config::ElevationConfig;
+use crate::enricher::Enricher;
+use crate::file_state::settings_fingerprint;
+use crate::logger;
+use crate::message::{AltitudeSource, PhotoData};
+use crate::utils::invalid_data;
+
+// Import necessary modules from the standard library and external crates.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use regex::Regex;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+use walkdir::WalkDir;
+
+// Value marking a void sample in SRTM tiles.
+const HGT_VOID: i16 = -32768;
+// GeoKey holding the raster type of a GeoTIFF.
+const GT_RASTER_TYPE_GEO_KEY: u16 = 1025;
+// Raster type meaning that the tiepoint refers to the centre of a pixel.
+const RASTER_PIXEL_IS_POINT: u16 = 2;
+
+// Define the geographic bounds of a grid, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bounds {
+    south: f64,
+    north: f64,
+    west: f64,
+    east: f64,
+}
+
+impl Bounds {
+    // Check whether a point lies inside the bounds.
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        lat >= self.south && lat <= self.north && lon >= self.west && lon <= self.east
+    }
+}
+
+// Define a grid of values sampled on a regular latitude/longitude lattice.
+// Rows run from north to south and columns from west to east; voids are NaN.
+#[derive(Debug, Clone)]
+pub struct Grid {
+    north: f64,
+    west: f64,
+    lat_step: f64,
+    lon_step: f64,
+    rows: usize,
+    cols: usize,
+    values: Vec<f32>,
+}
+
+impl Grid {
+    // Get the bounds covered by the grid samples.
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            south: self.north - self.lat_step * (self.rows - 1) as f64,
+            north: self.north,
+            west: self.west,
+            east: self.west + self.lon_step * (self.cols - 1) as f64,
+        }
+    }
+
+    // Bilinearly interpolate the grid at the given point.
+    // Void samples are skipped and the weights of the remaining ones renormalised.
+    pub fn interpolate(&self, lat: f64, lon: f64) -> Option<f32> {
+        let row = (self.north - lat) / self.lat_step;
+        let col = (lon - self.west) / self.lon_step;
+        if row < 0.0 || col < 0.0 || row > (self.rows - 1) as f64 || col > (self.cols - 1) as f64 {
+            return None;
+        }
+
+        let (r0, c0) = (row.floor() as usize, col.floor() as usize);
+        let (r1, c1) = ((r0 + 1).min(self.rows - 1), (c0 + 1).min(self.cols - 1));
+        let (fr, fc) = (row - r0 as f64, col - c0 as f64);
+
+        let corners = [
+            (r0, c0, (1.0 - fr) * (1.0 - fc)),
+            (r0, c1, (1.0 - fr) * fc),
+            (r1, c0, fr * (1.0 - fc)),
+            (r1, c1, fr * fc),
+        ];
+
+        let (mut sum, mut weight) = (0.0, 0.0);
+        for (r, c, w) in corners {
+            let value = self.values[r * self.cols + c];
+            if w > 0.0 && !value.is_nan() {
+                sum += value as f64 * w;
+                weight += w;
+            }
+        }
+
+        if weight > 0.0 {
+            Some((sum / weight) as f32)
+        } else {
+            None
+        }
+    }
+}
+
+// hgt_origin is a function that parses the south-west corner of an SRTM tile from its name,
+// for example N45E039.hgt.
+fn hgt_origin(path: &Path) -> Option<(f64, f64)> {
+    let name = path.file_stem()?.to_str()?;
+    let re = Regex::new(r"^([NnSs])(\d{1,2})([EeWw])(\d{1,3})$").unwrap();
+    let caps = re.captures(name)?;
+
+    let lat: f64 = caps[2].parse().ok()?;
+    let lon: f64 = caps[4].parse().ok()?;
+    let lat = if caps[1].eq_ignore_ascii_case("s") {
+        -lat
+    } else {
+        lat
+    };
+    let lon = if caps[3].eq_ignore_ascii_case("w") {
+        -lon
+    } else {
+        lon
+    };
+
+    Some((lat, lon))
+}
+
+// read_hgt is a function that loads an SRTM .hgt tile.
+// Parameters:
+// - path: The path of the tile; its name gives the south-west corner.
+// Returns:
+// - io::Result<Grid>: The tile heights in metres above the EGM96 geoid, or an std::io::Error.
+pub fn read_hgt(path: &Path) -> io::Result<Grid> {
+    let (south, west) = hgt_origin(path)
+        .ok_or_else(|| invalid_data(format!("Bad SRTM tile name {}", path.display())))?;
+
+    // Tiles are square grids of big-endian 16-bit samples (1201 or 3601 per side).
+    let bytes = std::fs::read(path)?;
+    let samples = ((bytes.len() / 2) as f64).sqrt() as usize;
+    if samples < 2 || samples * samples * 2 != bytes.len() {
+        return Err(invalid_data(format!(
+            "Bad SRTM tile size {}",
+            path.display()
+        )));
+    }
+
+    let values = bytes
+        .chunks_exact(2)
+        .map(|c| match i16::from_be_bytes([c[0], c[1]]) {
+            HGT_VOID => f32::NAN,
+            v => v as f32,
+        })
+        .collect();
+
+    let step = 1.0 / (samples - 1) as f64;
+    Ok(Grid {
+        north: south + 1.0,
+        west,
+        lat_step: step,
+        lon_step: step,
+        rows: samples,
+        cols: samples,
+        values,
+    })
+}
+
+// geotiff_layout is a function that reads the georeference of a GeoTIFF without decoding it.
+// Only geographic (degree) rasters with a single band are supported.
+fn geotiff_layout<R: Read + Seek>(decoder: &mut Decoder<R>) -> io::Result<Grid> {
+    let (width, height) = decoder.dimensions().map_err(invalid_data)?;
+    let scale = decoder
+        .get_tag_f64_vec(Tag::ModelPixelScaleTag)
+        .map_err(invalid_data)?;
+    let tiepoint = decoder
+        .get_tag_f64_vec(Tag::ModelTiepointTag)
+        .map_err(invalid_data)?;
+    if scale.len() < 2 || tiepoint.len() < 6 || width < 2 || height < 2 {
+        return Err(invalid_data("Incomplete GeoTIFF georeference"));
+    }
+
+    // The raster type is stored as a key in the GeoKey directory; PixelIsArea is the default.
+    let keys = decoder
+        .find_tag_unsigned_vec::<u16>(Tag::GeoKeyDirectoryTag)
+        .map_err(invalid_data)?
+        .unwrap_or_default();
+    let pixel_is_point = keys
+        .chunks_exact(4)
+        .skip(1)
+        .any(|key| key[0] == GT_RASTER_TYPE_GEO_KEY && key[3] == RASTER_PIXEL_IS_POINT);
+
+    let (lon_step, lat_step) = (scale[0], scale[1]);
+    let mut west = tiepoint[3] - tiepoint[0] * lon_step;
+    let mut north = tiepoint[4] + tiepoint[1] * lat_step;
+    if !pixel_is_point {
+        // Shift to the centre of the first pixel.
+        west += lon_step / 2.0;
+        north -= lat_step / 2.0;
+    }
+
+    Ok(Grid {
+        north,
+        west,
+        lat_step,
+        lon_step,
+        rows: height as usize,
+        cols: width as usize,
+        values: Vec::new(),
+    })
+}
+
+// geotiff_bounds is a function that reads the area covered by a GeoTIFF DEM tile without decoding it.
+fn geotiff_bounds(path: &Path) -> io::Result<Bounds> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?)).map_err(invalid_data)?;
+    Ok(geotiff_layout(&mut decoder)?.bounds())
+}
+
+// read_geotiff is a function that loads a single-band GeoTIFF DEM tile.
+// Parameters:
+// - path: The path of the GeoTIFF file.
+// Returns:
+// - io::Result<Grid>: The tile heights, or an std::io::Error.
+pub fn read_geotiff(path: &Path) -> io::Result<Grid> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?)).map_err(invalid_data)?;
+    let mut grid = geotiff_layout(&mut decoder)?;
+
+    // GDAL stores the no-data value as an ASCII tag.
+    let nodata: Option<f32> = decoder
+        .find_tag(Tag::GdalNodata)
+        .map_err(invalid_data)?
+        .and_then(|value| value.into_string().ok())
+        .and_then(|value| value.trim_matches(char::from(0)).trim().parse().ok());
+
+    let values: Vec<f32> = match decoder.read_image().map_err(invalid_data)? {
+        DecodingResult::U8(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I8(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::U16(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I16(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::U32(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I32(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::U64(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I64(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::F32(v) => v,
+        DecodingResult::F64(v) => v.into_iter().map(|x| x as f32).collect(),
+    };
+    if values.len() != grid.rows * grid.cols {
+        return Err(invalid_data(format!(
+            "Unsupported GeoTIFF layout {}",
+            path.display()
+        )));
+    }
+
+    grid.values = values
+        .into_iter()
+        .map(|v| if Some(v) == nodata { f32::NAN } else { v })
+        .collect();
+    Ok(grid)
+}
+
+// Define the file formats a DEM tile can be stored in.
+#[derive(Debug, Clone, Copy)]
+enum TileFormat {
+    Hgt,
+    GeoTiff,
+}
+
+// Define a DEM tile found in the DEM directory; its samples are loaded on first use.
+#[derive(Debug)]
+struct TileEntry {
+    path: PathBuf,
+    format: TileFormat,
+    bounds: Bounds,
+}
+
+// Define an index over the DEM tiles of a directory.
+#[derive(Debug)]
+pub struct DemIndex {
+    tiles: Vec<TileEntry>,
+    loaded: Mutex<HashMap<usize, Option<Arc<Grid>>>>,
+}
+
+impl DemIndex {
+    // Index the .hgt and GeoTIFF tiles found under the given directory.
+    pub fn open(directory: &Path) -> io::Result<DemIndex> {
+        let mut tiles = Vec::new();
+
+        for entry in WalkDir::new(directory) {
+            let entry = entry.map_err(io::Error::from)?;
+            let path = entry.path();
+            let extension = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.to_ascii_lowercase());
+
+            let tile = match extension.as_deref() {
+                Some("hgt") => hgt_origin(path).map(|(south, west)| TileEntry {
+                    path: path.to_path_buf(),
+                    format: TileFormat::Hgt,
+                    bounds: Bounds {
+                        south,
+                        north: south + 1.0,
+                        west,
+                        east: west + 1.0,
+                    },
+                }),
+                // A TIFF that isn't a readable GeoTIFF, such as a plain image, is skipped.
+                Some("tif") | Some("tiff") => match geotiff_bounds(path) {
+                    Ok(bounds) => Some(TileEntry {
+                        path: path.to_path_buf(),
+                        format: TileFormat::GeoTiff,
+                        bounds,
+                    }),
+                    Err(error) => {
+                        logger::log_error(&format!("Skipping DEM tile {}: {}", path.display(), error));
+                        None
+                    }
+                },
+                _ => None,
+            };
+
+            if let Some(tile) = tile {
+                logger::log_debug(&format!("Indexed DEM tile {}", tile.path.display()));
+                tiles.push(tile);
+            }
+        }
+
+        Ok(DemIndex {
+            tiles,
+            loaded: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Load a tile by its position in the index, caching the result (including failures).
+    fn load(&self, index: usize) -> Option<Arc<Grid>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        loaded
+            .entry(index)
+            .or_insert_with(|| {
+                let tile = &self.tiles[index];
+                let grid = match tile.format {
+                    TileFormat::Hgt => read_hgt(&tile.path),
+                    TileFormat::GeoTiff => read_geotiff(&tile.path),
+                };
+                match grid {
+                    Ok(grid) => Some(Arc::new(grid)),
+                    Err(error) => {
+                        logger::log_error(&format!(
+                            "Error while loading DEM tile {}: {}",
+                            tile.path.display(),
+                            error
+                        ));
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+
+    // Get the ground elevation at the given point from the first tile that covers it.
+    pub fn elevation(&self, lat: f64, lon: f64) -> Option<f32> {
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter(|(_, tile)| tile.bounds.contains(lat, lon))
+            .find_map(|(index, _)| self.load(index)?.interpolate(lat, lon))
+    }
+}
+
+// Define a geoid undulation grid such as the EGM96 WW15MGH.GRD file.
+#[derive(Debug)]
+pub struct GeoidGrid {
+    grid: Grid,
+}
+
+impl GeoidGrid {
+    // Load a geoid grid from a file.
+    pub fn open(path: &Path) -> io::Result<GeoidGrid> {
+        GeoidGrid::parse(&std::fs::read_to_string(path)?)
+    }
+
+    // Parse a geoid grid in the NGA .GRD text layout: a header with
+    // south, north, west and east bounds and the latitude and longitude spacing,
+    // followed by the undulations row by row from north to south.
+    pub fn parse(text: &str) -> io::Result<GeoidGrid> {
+        let numbers = text
+            .split_whitespace()
+            .map(|token| token.parse::<f64>().map_err(invalid_data))
+            .collect::<io::Result<Vec<f64>>>()?;
+        if numbers.len() < 6 {
+            return Err(invalid_data("Missing geoid grid header"));
+        }
+
+        let (south, north, west, east, lat_step, lon_step) = (
+            numbers[0], numbers[1], numbers[2], numbers[3], numbers[4], numbers[5],
+        );
+        if lat_step <= 0.0 || lon_step <= 0.0 || north <= south || east <= west {
+            return Err(invalid_data("Bad geoid grid header"));
+        }
+
+        let rows = ((north - south) / lat_step).round() as usize + 1;
+        let cols = ((east - west) / lon_step).round() as usize + 1;
+        if numbers.len() - 6 != rows * cols {
+            return Err(invalid_data(format!(
+                "Geoid grid has {} values, expected {}",
+                numbers.len() - 6,
+                rows * cols
+            )));
+        }
+
+        Ok(GeoidGrid {
+            grid: Grid {
+                north,
+                west,
+                lat_step,
+                lon_step,
+                rows,
+                cols,
+                values: numbers[6..].iter().map(|&v| v as f32).collect(),
+            },
+        })
+    }
+
+    // Get the geoid undulation (height of the geoid above the ellipsoid) at the given point.
+    pub fn undulation(&self, lat: f64, lon: f64) -> Option<f32> {
+        // Bring the longitude into the range covered by the grid.
+        let bounds = self.grid.bounds();
+        let mut lon = lon;
+        while lon < bounds.west {
+            lon += 360.0;
+        }
+        while lon > bounds.east {
+            lon -= 360.0;
+        }
+        self.grid.interpolate(lat, lon)
+    }
+}
+
+// Define an enricher that backfills missing altitudes from DEM tiles and
+// optionally converts GPS ellipsoidal heights to heights above mean sea level.
+#[derive(Debug)]
+pub struct ElevationEnricher {
+    dem: DemIndex,
+    geoid: Option<GeoidGrid>,
+    ellipsoidal_gps: bool,
+    // The DEM tiles, geoid grid and settings, fingerprinted.
+    fingerprint: String,
+}
+
+impl ElevationEnricher {
+    // Create an ElevationEnricher from its configuration.
+    pub fn from_config(config: &ElevationConfig) -> io::Result<ElevationEnricher> {
+        let geoid = match &config.geoid_grid {
+            Some(path) => Some(GeoidGrid::open(Path::new(path))?),
+            None => None,
+        };
+        if config.ellipsoidal_gps && geoid.is_none() {
+            return Err(invalid_data(
+                "elevation.ellipsoidal_gps requires elevation.geoid_grid",
+            ));
+        }
+
+        let dem = DemIndex::open(Path::new(&config.dem_directory))?;
+        logger::log_info(&format!(
+            "Loaded {} DEM tiles from {}",
+            dem.tiles.len(),
+            config.dem_directory
+        ));
+
+        let mut files: Vec<&Path> = dem.tiles.iter().map(|tile| tile.path.as_path()).collect();
+        files.extend(config.geoid_grid.as_deref().map(Path::new));
+        let fingerprint = settings_fingerprint(&format!("elevation {}", config.ellipsoidal_gps), &files);
+        Ok(ElevationEnricher {
+            dem,
+            geoid,
+            ellipsoidal_gps: config.ellipsoidal_gps,
+            fingerprint,
+        })
+    }
+}
+
+impl Enricher for ElevationEnricher {
+    fn enrich(&self, data: &mut PhotoData) {
+        if !data.has_position() {
+            return;
+        }
+        let (lat, lon) = (data.lat() as f64, data.long() as f64);
+
+        match data.altitude_source() {
+            // SRTM heights are already relative to the EGM96 geoid.
+            None => {
+                if let Some(altitude) = self.dem.elevation(lat, lon) {
+                    data.set_dem_altitude(altitude);
+                }
+            }
+            Some(AltitudeSource::Gps) if self.ellipsoidal_gps => {
+                if let Some(undulation) = self.geoid.as_ref().and_then(|g| g.undulation(lat, lon)) {
+                    data.shift_altitude(-undulation);
+                }
+            }
+            Some(_) => (),
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ElevationConfig;
+    use std::io::Write;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    // write_hgt is a helper that writes a square SRTM tile into the given directory.
+    fn write_hgt(directory: &Path, name: &str, values: &[i16]) -> PathBuf {
+        let path = directory.join(name);
+        let mut file = File::create(&path).unwrap();
+        for value in values {
+            file.write_all(&value.to_be_bytes()).unwrap();
+        }
+        path
+    }
+
+    // Define a test function for reading and interpolating an SRTM tile.
+    #[test]
+    fn test_read_hgt() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = write_hgt(
+            directory.path(),
+            "N45E039.hgt",
+            &[100, 200, 300, 100, 200, 300, 100, 200, HGT_VOID],
+        );
+
+        let grid = read_hgt(&path).unwrap();
+
+        // Corners and centre of the tile.
+        assert_eq!(grid.interpolate(46.0, 39.0), Some(100.0));
+        assert_eq!(grid.interpolate(45.5, 39.5), Some(200.0));
+        assert_eq!(grid.interpolate(45.75, 39.25), Some(150.0));
+        // The void sample is skipped.
+        assert_eq!(grid.interpolate(45.0, 40.0), None);
+        assert_eq!(grid.interpolate(45.25, 39.5), Some(200.0));
+        // Outside of the tile.
+        assert_eq!(grid.interpolate(47.0, 39.5), None);
+    }
+
+    // Define a test function for parsing SRTM tile names.
+    #[test]
+    fn test_hgt_origin() {
+        assert_eq!(hgt_origin(Path::new("N45E039.hgt")), Some((45.0, 39.0)));
+        assert_eq!(hgt_origin(Path::new("s12w077.hgt")), Some((-12.0, -77.0)));
+        assert_eq!(hgt_origin(Path::new("tile.hgt")), None);
+    }
+
+    // Define a test function for reading a GeoTIFF DEM tile.
+    #[test]
+    fn test_read_geotiff() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("dem.tif");
+
+        {
+            let file = File::create(&path).unwrap();
+            let mut tiff = TiffEncoder::new(file).unwrap();
+            let mut image = tiff.new_image::<colortype::GrayI16>(2, 2).unwrap();
+            image
+                .encoder()
+                .write_tag(Tag::ModelPixelScaleTag, &[0.5f64, 0.5, 0.0][..])
+                .unwrap();
+            image
+                .encoder()
+                .write_tag(
+                    Tag::ModelTiepointTag,
+                    &[0.0f64, 0.0, 0.0, 10.0, 20.0, 0.0][..],
+                )
+                .unwrap();
+            image
+                .encoder()
+                .write_tag(Tag::GeoKeyDirectoryTag, &[1u16, 1, 0, 1, 1025, 0, 1, 2][..])
+                .unwrap();
+            image.write_data(&[10, 20, 30, 40]).unwrap();
+        }
+
+        let grid = read_geotiff(&path).unwrap();
+        assert_eq!(
+            grid.bounds(),
+            Bounds {
+                south: 19.5,
+                north: 20.0,
+                west: 10.0,
+                east: 10.5
+            }
+        );
+        assert_eq!(grid.interpolate(19.75, 10.25), Some(25.0));
+
+        // Assert that TIFF files without georeference, or not TIFF at all, are skipped when indexing.
+        {
+            let file = File::create(directory.path().join("scan.tif")).unwrap();
+            let mut tiff = TiffEncoder::new(file).unwrap();
+            tiff.write_image::<colortype::Gray8>(1, 1, &[0]).unwrap();
+        }
+        std::fs::write(directory.path().join("notes.tiff"), "not a tiff").unwrap();
+        let index = DemIndex::open(directory.path()).unwrap();
+        assert_eq!(index.tiles.len(), 1);
+        assert_eq!(index.elevation(19.75, 10.25), Some(25.0));
+    }
+
+    // Define a test function for parsing and sampling a geoid grid.
+    #[test]
+    fn test_geoid_grid() {
+        let geoid = GeoidGrid::parse("-10 10 0 360 10 180\n1 2 1\n3 4 3\n5 6 5\n").unwrap();
+
+        assert_eq!(geoid.undulation(0.0, 90.0), Some(3.5));
+        // Negative longitudes wrap around.
+        assert_eq!(geoid.undulation(0.0, -90.0), Some(3.5));
+        assert_eq!(geoid.undulation(-10.0, 180.0), Some(6.0));
+
+        assert!(GeoidGrid::parse("-10 10 0 360 10 180\n1 2\n").is_err());
+    }
+
+    // Define a test function for backfilling and correcting altitudes.
+    #[test]
+    fn test_enrich() {
+        let directory = tempfile::tempdir().unwrap();
+        write_hgt(
+            directory.path(),
+            "N45E039.hgt",
+            &[100, 200, 300, 100, 200, 300, 100, 200, 300],
+        );
+        let geoid_path = directory.path().join("geoid.grd");
+        std::fs::write(&geoid_path, "40 50 30 40 10 10\n20 20\n20 20\n").unwrap();
+
+        let enricher = ElevationEnricher::from_config(&ElevationConfig {
+            dem_directory: directory.path().display().to_string(),
+            geoid_grid: Some(geoid_path.display().to_string()),
+            ellipsoidal_gps: true,
+        })
+        .unwrap();
+
+        // A photo without GPSAltitude gets the DEM elevation.
+        let mut photo = PhotoData::default();
+        photo.build("GPSLatitude", "45 deg 45 min 0 sec");
+        photo.build("GPSLongitude", "39 deg 15 min 0 sec");
+        enricher.enrich(&mut photo);
+        assert_eq!(photo.altitude_source(), Some(AltitudeSource::Dem));
+        assert_eq!(
+            photo.to_string(),
+            "lat: 45.75, long: 39.25, alt: 150, name, path, "
+        );
+
+        // A GPS altitude is converted from the ellipsoid to the geoid.
+        let mut photo = PhotoData::default();
+        photo.build("GPSLatitude", "45 deg 45 min 0 sec");
+        photo.build("GPSLongitude", "39 deg 15 min 0 sec");
+        photo.build("GPSAltitude", "170 m");
+        enricher.enrich(&mut photo);
+        assert_eq!(photo.altitude_source(), Some(AltitudeSource::Gps));
+        assert_eq!(
+            photo.to_string(),
+            "lat: 45.75, long: 39.25, alt: 150, name, path, "
+        );
+
+        // Photos without a position are left alone.
+        let mut photo = PhotoData::default();
+        enricher.enrich(&mut photo);
+        assert_eq!(photo.altitude_source(), None);
+    }
+
+    // Define a test function for rejecting a geoid correction without a geoid grid.
+    #[test]
+    fn test_ellipsoidal_without_geoid() {
+        let directory = tempfile::tempdir().unwrap();
+        let enricher = ElevationEnricher::from_config(&ElevationConfig {
+            dem_directory: directory.path().display().to_string(),
+            geoid_grid: None,
+            ellipsoidal_gps: true,
+        });
+        assert!(enricher.is_err());
+    }
+}
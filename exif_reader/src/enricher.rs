@@ -0,0 +1,49 @@
+// Import necessary modules from the project.
+use crate::config::Config;
+use crate::elevation::ElevationEnricher;
+use crate::location_history::LocationHistoryEnricher;
+use crate::message::PhotoData;
+
+// Enricher is a trait for steps that fill in or correct PhotoData after EXIF extraction.
+pub trait Enricher: Send + Sync {
+    // Update the provided PhotoData in place.
+    fn enrich(&self, data: &mut PhotoData);
+
+    // A fingerprint of the settings and data files of the enricher: the cached files enriched
+    // with another one are extracted again.
+    fn fingerprint(&self) -> String;
+}
+
+// from_config is a function that builds the enrichers enabled in the configuration.
+// Parameters:
+// - config: The service configuration.
+// Returns:
+// - std::io::Result<Vec<Box<dyn Enricher>>>: The enrichers in the order they have to run,
+//   or an std::io::Error if a configured data file can't be loaded.
+pub fn from_config(config: &Config) -> std::io::Result<Vec<Box<dyn Enricher>>> {
+    let mut enrichers: Vec<Box<dyn Enricher>> = Vec::new();
+
+    // The location history is a fallback for photos without GPS or a track position.
+    if let Some(location_history) = &config.location_history {
+        enrichers.push(Box::new(LocationHistoryEnricher::from_config(
+            location_history,
+        )?));
+    }
+
+    // Elevation runs last, once the position of the photo is known.
+    if let Some(elevation) = &config.elevation {
+        enrichers.push(Box::new(ElevationEnricher::from_config(elevation)?));
+    }
+
+    Ok(enrichers)
+}
+
+// enrich is a function that runs every enricher over the provided PhotoData.
+// Parameters:
+// - enrichers: The enrichers to apply, in order.
+// - data: The PhotoData to update.
+pub fn enrich(enrichers: &[&dyn Enricher], data: &mut PhotoData) {
+    for enricher in enrichers {
+        enricher.enrich(data);
+    }
+}
@@ -0,0 +1,1492 @@
+// Import necessary modules from the project.
+use crate::archive;
+use crate::logger;
+use crate::media_source;
+use crate::message::Message;
+use crate::utils::invalid_data;
+
+// Import necessary modules from the standard library and external crates.
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+
+use exif::{In, Tag};
+
+// TIFF tags pointing to the Exif and GPS sub-IFDs.
+pub const TAG_EXIF_IFD: u16 = 0x8769;
+pub const TAG_GPS_IFD: u16 = 0x8825;
+// Exif tag pointing to the interoperability sub-IFD.
+const TAG_INTEROP_IFD: u16 = 0xA005;
+// Exif tag holding the UTC offset of DateTimeOriginal.
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+// TIFF tags locating image data, each with the tag holding the byte counts:
+// strips, tiles and the JPEG thumbnail of IFD1.
+const DATA_TAGS: [(u16, u16); 3] = [(0x0111, 0x0117), (0x0144, 0x0145), (0x0201, 0x0202)];
+// TIFF tag pointing to sub-IFDs whose layout the writer doesn't follow (DNG, raw files).
+const TAG_SUB_IFDS: u16 = 0x014A;
+// Exif tag holding the maker note, whose internal offsets can point anywhere in the structure.
+const TAG_MAKER_NOTE: u16 = 0x927C;
+// Deepest chain of IFDs followed, so that a looping structure can't hang the writer.
+const MAX_IFDS: usize = 64;
+
+// TIFF field types used by the writer.
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_IFD: u16 = 13;
+
+// Largest payload of a JPEG segment.
+const MAX_SEGMENT_SIZE: usize = 0xFFFF - 2;
+// Identifier of the Exif APP1 segment.
+pub const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+// Define the position written into a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Geotag {
+    pub lat: f64,
+    pub lon: f64,
+    // Altitude above mean sea level, in metres.
+    pub altitude: Option<f64>,
+    // UTC offset of the capture time, such as "+03:00".
+    pub offset_time: Option<String>,
+}
+
+// Define how a geotag is persisted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteOptions {
+    // Always write an XMP sidecar instead of rewriting the file.
+    pub sidecar: bool,
+    // Keep the original file next to the new one with a .bak suffix.
+    pub backup: bool,
+    // Only report the changes, don't write anything.
+    pub dry_run: bool,
+}
+
+// Define a field changed by the writer, with its old and new displayed values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: String,
+}
+
+// Define the outcome of writing a geotag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteReport {
+    // The file that was (or would have been) written: the image or its sidecar.
+    pub target: PathBuf,
+    pub changes: Vec<FieldChange>,
+    pub written: bool,
+    pub backup: Option<PathBuf>,
+}
+
+impl WriteReport {
+    // Render the changes as a diff, one line per field.
+    pub fn diff(&self) -> String {
+        self.changes
+            .iter()
+            .map(|change| {
+                format!(
+                    "{} {}: {} -> {}",
+                    self.target.display(),
+                    change.field,
+                    change.old.as_deref().unwrap_or("(none)"),
+                    change.new
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+// Define an IFD entry: either copied as is from the file, or a new value to be stored.
+#[derive(Debug, Clone)]
+enum EntryValue {
+    // The raw 4-byte value/offset field of an existing entry.
+    Raw([u8; 4]),
+    // A new value, stored inline or out of line depending on its size.
+    Data(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: EntryValue,
+}
+
+// Define a byte range of a TIFF structure referenced by an IFD: the IFD itself (no tag),
+// or the value or the image data of one of its entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Area {
+    start: usize,
+    end: usize,
+    ifd: u32,
+    tag: Option<u16>,
+}
+
+impl Area {
+    // Check whether two areas share bytes.
+    fn overlaps(&self, other: &Area) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+// Define a classic TIFF structure that is changed by appending new IFDs.
+// Existing bytes are never moved, so offsets used by other IFDs and maker notes stay valid;
+// the IFDs and values that are replaced are zeroed instead, and dropped when they end the structure.
+pub struct Tiff {
+    bytes: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Tiff {
+    // Parse the header of a TIFF structure.
+    pub fn parse(bytes: Vec<u8>) -> io::Result<Tiff> {
+        let big_endian = match bytes.get(0..4) {
+            Some(b"MM\0*") => true,
+            Some(b"II*\0") => false,
+            _ => return Err(invalid_data("Not a classic TIFF structure")),
+        };
+        if bytes.len() < 8 {
+            return Err(invalid_data("Truncated TIFF header"));
+        }
+        Ok(Tiff { bytes, big_endian })
+    }
+
+    // Create an empty TIFF structure with an IFD0 without entries.
+    fn empty() -> Tiff {
+        Tiff {
+            bytes: vec![b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0],
+            big_endian: true,
+        }
+    }
+
+    fn u16_at(&self, offset: usize) -> io::Result<u16> {
+        let raw = self
+            .bytes
+            .get(offset..offset + 2)
+            .ok_or_else(|| invalid_data("TIFF offset out of bounds"))?;
+        Ok(self.decode_u16([raw[0], raw[1]]))
+    }
+
+    fn u32_at(&self, offset: usize) -> io::Result<u32> {
+        let raw = self
+            .bytes
+            .get(offset..offset + 4)
+            .ok_or_else(|| invalid_data("TIFF offset out of bounds"))?;
+        Ok(self.decode_u32([raw[0], raw[1], raw[2], raw[3]]))
+    }
+
+    fn decode_u16(&self, raw: [u8; 2]) -> u16 {
+        if self.big_endian {
+            u16::from_be_bytes(raw)
+        } else {
+            u16::from_le_bytes(raw)
+        }
+    }
+
+    fn decode_u32(&self, raw: [u8; 4]) -> u32 {
+        if self.big_endian {
+            u32::from_be_bytes(raw)
+        } else {
+            u32::from_le_bytes(raw)
+        }
+    }
+
+    fn encode_u16(&self, value: u16) -> [u8; 2] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn encode_u32(&self, value: u32) -> [u8; 4] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    // Encode unsigned rationals in the byte order of the structure.
+    fn encode_rationals(&self, values: &[(u32, u32)]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|&(numerator, denominator)| {
+                let mut raw = self.encode_u32(numerator).to_vec();
+                raw.extend(self.encode_u32(denominator));
+                raw
+            })
+            .collect()
+    }
+
+    // Get the content of the structure.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    // Get the offset of IFD0.
+    pub fn ifd0(&self) -> io::Result<u32> {
+        self.u32_at(4)
+    }
+
+    // Read the entries of the IFD at the given offset, and the offset of the next IFD.
+    fn read_ifd(&self, offset: u32) -> io::Result<(Vec<Entry>, u32)> {
+        let offset = offset as usize;
+        let count = self.u16_at(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+
+        for index in 0..count {
+            let start = offset + 2 + index * 12;
+            let raw = self
+                .bytes
+                .get(start + 8..start + 12)
+                .ok_or_else(|| invalid_data("TIFF offset out of bounds"))?;
+            entries.push(Entry {
+                tag: self.u16_at(start)?,
+                kind: self.u16_at(start + 2)?,
+                count: self.u32_at(start + 4)?,
+                value: EntryValue::Raw([raw[0], raw[1], raw[2], raw[3]]),
+            });
+        }
+
+        let next = self.u32_at(offset + 2 + count * 12)?;
+        Ok((entries, next))
+    }
+
+    // Read the SHORT or LONG values of an entry, stored inline or out of line.
+    fn unsigned_values(&self, entry: &Entry) -> io::Result<Vec<u32>> {
+        let EntryValue::Raw(raw) = entry.value else {
+            return Ok(Vec::new());
+        };
+        let (unit, count) = match entry.kind {
+            TYPE_SHORT => (2, entry.count as usize),
+            TYPE_LONG => (4, entry.count as usize),
+            _ => return Ok(Vec::new()),
+        };
+        let start = if unit * count <= 4 {
+            None
+        } else {
+            Some(self.decode_u32(raw) as usize)
+        };
+        (0..count)
+            .map(|index| match (start, unit) {
+                (None, 2) => Ok(self.decode_u16([raw[index * 2], raw[index * 2 + 1]]) as u32),
+                (None, _) => Ok(self.decode_u32(raw)),
+                (Some(start), 2) => Ok(self.u16_at(start + index * 2)? as u32),
+                (Some(start), _) => self.u32_at(start + index * 4),
+            })
+            .collect()
+    }
+
+    // collect_areas is a method that lists the byte ranges referenced from an IFD: its table,
+    // the values of its entries, the image data they locate, its Exif, GPS and interoperability
+    // sub-IFDs and the IFDs chained after it.
+    // Parameters:
+    // - ifd: The offset of the IFD.
+    // - areas: Receives the areas.
+    // - visited: The IFDs already listed, which guards against loops.
+    // Returns:
+    // - io::Result<bool>: Whether every referenced byte is known; false when the structure has
+    //   sub-IFDs the writer doesn't follow or a maker note, or loops.
+    fn collect_areas(&self, ifd: u32, areas: &mut Vec<Area>, visited: &mut Vec<u32>) -> io::Result<bool> {
+        if visited.contains(&ifd) || visited.len() >= MAX_IFDS {
+            return Ok(false);
+        }
+        visited.push(ifd);
+        let (entries, next) = self.read_ifd(ifd)?;
+        let start = ifd as usize;
+        areas.push(Area {
+            start,
+            end: start + 2 + entries.len() * 12 + 4,
+            ifd,
+            tag: None,
+        });
+
+        let mut known = true;
+        for entry in &entries {
+            let size = value_size(entry.kind, entry.count);
+            if let (EntryValue::Raw(raw), true) = (&entry.value, size > 4) {
+                let start = self.decode_u32(*raw) as usize;
+                areas.push(Area {
+                    start,
+                    end: start + size,
+                    ifd,
+                    tag: Some(entry.tag),
+                });
+            }
+            match entry.tag {
+                TAG_EXIF_IFD | TAG_GPS_IFD | TAG_INTEROP_IFD => {
+                    if let Some(offset) = self.pointer(&entries, entry.tag) {
+                        known &= self.collect_areas(offset, areas, visited)?;
+                    }
+                }
+                // Maker notes often locate their own values relative to the structure, outside their value.
+                TAG_SUB_IFDS | TAG_MAKER_NOTE => known = false,
+                _ if entry.kind == TYPE_IFD => known = false,
+                _ => (),
+            }
+        }
+
+        // Strips, tiles and thumbnails are located by an offsets entry and a byte counts entry.
+        for (offsets_tag, counts_tag) in DATA_TAGS {
+            let find = |tag: u16| entries.iter().find(|entry| entry.tag == tag);
+            let (Some(offsets), Some(counts)) = (find(offsets_tag), find(counts_tag)) else {
+                continue;
+            };
+            let offsets = self.unsigned_values(offsets)?;
+            let counts = self.unsigned_values(counts)?;
+            areas.extend(offsets.iter().zip(&counts).map(|(&offset, &count)| Area {
+                start: offset as usize,
+                end: offset as usize + count as usize,
+                ifd,
+                tag: Some(offsets_tag),
+            }));
+        }
+
+        if next != 0 {
+            known &= self.collect_areas(next, areas, visited)?;
+        }
+        Ok(known)
+    }
+
+    // List the byte ranges referenced from IFD0, and whether every referenced byte is known.
+    fn areas(&self) -> io::Result<(Vec<Area>, bool)> {
+        let mut areas = Vec::new();
+        let known = self.collect_areas(self.ifd0()?, &mut areas, &mut Vec::new())?;
+        Ok((areas, known))
+    }
+
+    // Zero a byte range, as far as it lies inside the structure.
+    fn zero(&mut self, start: usize, end: usize) {
+        let end = end.min(self.bytes.len());
+        if start < end {
+            self.bytes[start..end].fill(0);
+        }
+    }
+
+    // release is a method that zeroes the areas that are about to be replaced, so that nothing of
+    // their values is left in the file, and drops them when nothing referenced follows them,
+    // so that rewriting a structure doesn't make it grow.
+    // Parameters:
+    // - areas: The areas referenced from IFD0, and whether every referenced byte is known.
+    // - replaced: Tells the areas that won't be referenced any more.
+    fn release<F: Fn(&Area) -> bool>(&mut self, (areas, known): (Vec<Area>, bool), replaced: F) {
+        let (replaced, kept): (Vec<Area>, Vec<Area>) = areas.into_iter().partition(|area| replaced(area));
+        // Bytes shared with a kept area are left alone.
+        for area in &replaced {
+            if !kept.iter().any(|other| other.overlaps(area)) {
+                self.zero(area.start, area.end);
+            }
+        }
+
+        // Unknown sub-IFDs may point anywhere, so only a fully known structure is shortened.
+        let end = kept.iter().map(|area| area.end).max().unwrap_or(0).max(8);
+        if known && end < self.bytes.len() && self.bytes[end..].iter().all(|&byte| byte == 0) {
+            self.bytes.truncate(end);
+        }
+    }
+
+    // scrub is a method that zeroes every byte of the structure that no IFD references, such as
+    // IFDs and values left behind by earlier edits.
+    // Returns:
+    // - io::Result<bool>: Whether data was cleared; nothing is cleared when the structure has
+    //   sub-IFDs the writer doesn't follow or a maker note, whose data would look unreferenced.
+    pub fn scrub(&mut self) -> io::Result<bool> {
+        let (mut areas, known) = self.areas()?;
+        if !known {
+            return Ok(false);
+        }
+        areas.sort_by_key(|area| area.start);
+
+        let mut cleared = false;
+        let mut position = 8;
+        for area in areas.iter().chain(std::iter::once(&Area {
+            start: self.bytes.len(),
+            end: self.bytes.len(),
+            ifd: 0,
+            tag: None,
+        })) {
+            let start = area.start.min(self.bytes.len());
+            if position < start {
+                cleared |= self.bytes[position..start].iter().any(|&byte| byte != 0);
+                self.zero(position, start);
+            }
+            position = position.max(area.end);
+        }
+        Ok(cleared)
+    }
+
+    // Get the offset stored in a LONG pointer entry.
+    fn pointer(&self, entries: &[Entry], tag: u16) -> Option<u32> {
+        entries.iter().find(|entry| entry.tag == tag).and_then(|entry| match entry.value {
+            EntryValue::Raw(raw) => Some(self.decode_u32(raw)),
+            EntryValue::Data(_) => None,
+        })
+    }
+
+    // Get the offset of the sub-IFD a pointer entry of an IFD points to.
+    pub fn sub_ifd(&self, ifd: u32, tag: u16) -> io::Result<Option<u32>> {
+        let (entries, _) = self.read_ifd(ifd)?;
+        Ok(self.pointer(&entries, tag))
+    }
+
+    // Remove the entries matching a predicate from an IFD in place, zeroing their values
+    // so that nothing of them is left in the file.
+    // Returns the removed tags.
+    pub fn remove_entries<F: Fn(u16) -> bool>(&mut self, ifd: u32, remove: F) -> io::Result<Vec<u16>> {
+        let (entries, next) = self.read_ifd(ifd)?;
+        let total = entries.len();
+        let (removed, kept): (Vec<Entry>, Vec<Entry>) =
+            entries.into_iter().partition(|entry| remove(entry.tag));
+        if removed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Clear the values stored out of line.
+        for entry in &removed {
+            let size = value_size(entry.kind, entry.count);
+            if let EntryValue::Raw(raw) = entry.value {
+                let offset = self.decode_u32(raw) as usize;
+                if size > 4 {
+                    if let Some(value) = self.bytes.get_mut(offset..offset + size) {
+                        value.fill(0);
+                    }
+                }
+            }
+        }
+
+        // Rewrite the table with the kept entries and clear what is left of the old one.
+        let mut table = self.encode_u16(kept.len() as u16).to_vec();
+        for entry in &kept {
+            table.extend(self.encode_u16(entry.tag));
+            table.extend(self.encode_u16(entry.kind));
+            table.extend(self.encode_u32(entry.count));
+            if let EntryValue::Raw(raw) = entry.value {
+                table.extend(raw);
+            }
+        }
+        table.extend(self.encode_u32(next));
+        table.resize(2 + total * 12 + 4, 0);
+        let start = ifd as usize;
+        self.bytes[start..start + table.len()].copy_from_slice(&table);
+
+        Ok(removed.iter().map(|entry| entry.tag).collect())
+    }
+
+    // Get the location of the RATIONAL values of an entry of an IFD, and their count.
+    fn rationals_at(&self, ifd: u32, tag: u16) -> io::Result<Option<(usize, usize)>> {
+        let (entries, _) = self.read_ifd(ifd)?;
+        let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.tag == tag && entry.kind == TYPE_RATIONAL)
+        else {
+            return Ok(None);
+        };
+        let EntryValue::Raw(raw) = entry.value else {
+            return Ok(None);
+        };
+        let offset = self.decode_u32(raw) as usize;
+        let count = entry.count as usize;
+        if offset + count * 8 > self.bytes.len() {
+            return Err(invalid_data("TIFF offset out of bounds"));
+        }
+        Ok(Some((offset, count)))
+    }
+
+    // Read the RATIONAL values of an entry of an IFD.
+    pub fn rationals(&self, ifd: u32, tag: u16) -> io::Result<Option<Vec<(u32, u32)>>> {
+        let Some((offset, count)) = self.rationals_at(ifd, tag)? else {
+            return Ok(None);
+        };
+        (0..count)
+            .map(|index| {
+                let start = offset + index * 8;
+                Ok((self.u32_at(start)?, self.u32_at(start + 4)?))
+            })
+            .collect::<io::Result<Vec<(u32, u32)>>>()
+            .map(Some)
+    }
+
+    // Overwrite the RATIONAL values of an entry of an IFD in place; the count can't change.
+    // Returns whether the entry was found.
+    pub fn set_rationals(&mut self, ifd: u32, tag: u16, values: &[(u32, u32)]) -> io::Result<bool> {
+        match self.rationals_at(ifd, tag)? {
+            Some((offset, count)) if count == values.len() => {
+                let raw = self.encode_rationals(values);
+                self.bytes[offset..offset + raw.len()].copy_from_slice(&raw);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Pad the structure to an even length, as TIFF offsets must be word aligned.
+    fn align(&mut self) {
+        if self.bytes.len() % 2 == 1 {
+            self.bytes.push(0);
+        }
+    }
+
+    // Append an IFD with the given entries and return its offset.
+    fn append_ifd(&mut self, mut entries: Vec<Entry>, next: u32) -> io::Result<u32> {
+        entries.sort_by_key(|entry| entry.tag);
+        self.align();
+
+        let offset = self.bytes.len();
+        let mut data_offset = offset + 2 + entries.len() * 12 + 4;
+        let mut data: Vec<u8> = Vec::new();
+        let mut ifd: Vec<u8> = self.encode_u16(entries.len() as u16).to_vec();
+
+        for entry in &entries {
+            ifd.extend(self.encode_u16(entry.tag));
+            ifd.extend(self.encode_u16(entry.kind));
+            ifd.extend(self.encode_u32(entry.count));
+            match &entry.value {
+                EntryValue::Raw(raw) => ifd.extend(raw),
+                EntryValue::Data(value) if value.len() <= 4 => {
+                    let mut raw = value.clone();
+                    raw.resize(4, 0);
+                    ifd.extend(raw);
+                }
+                EntryValue::Data(value) => {
+                    ifd.extend(self.encode_u32(data_offset as u32));
+                    data.extend(value);
+                    if value.len() % 2 == 1 {
+                        data.push(0);
+                    }
+                    data_offset += value.len() + value.len() % 2;
+                }
+            }
+        }
+        ifd.extend(self.encode_u32(next));
+
+        if data_offset > u32::MAX as usize {
+            return Err(invalid_data("TIFF structure larger than 4 GiB"));
+        }
+        self.bytes.extend(ifd);
+        self.bytes.extend(data);
+        Ok(offset as u32)
+    }
+
+    // Point the header at a new IFD0.
+    fn set_ifd0(&mut self, offset: u32) {
+        let raw = self.encode_u32(offset);
+        self.bytes[4..8].copy_from_slice(&raw);
+    }
+
+    // Write a new GPS IFD and, when given, the OffsetTimeOriginal of the Exif IFD,
+    // then relink IFD0 to them. The previous IFD0, GPS IFD and Exif IFD are released.
+    fn set_geotag(&mut self, geotag: &Geotag) -> io::Result<()> {
+        let ifd0_offset = self.ifd0()?;
+        let (mut ifd0, next) = self.read_ifd(ifd0_offset)?;
+        let gps_offset = self.pointer(&ifd0, TAG_GPS_IFD);
+        let exif_offset = self.pointer(&ifd0, TAG_EXIF_IFD);
+        let mut exif = match (&geotag.offset_time, exif_offset) {
+            (Some(_), Some(offset)) => self.read_ifd(offset)?.0,
+            _ => Vec::new(),
+        };
+
+        // The whole GPS IFD is replaced; IFD0 and the Exif IFD are copied, keeping the values of their entries.
+        let areas = self.areas()?;
+        let rewrites_exif = geotag.offset_time.is_some();
+        self.release(areas, |area| {
+            (area.ifd == ifd0_offset && area.tag.is_none())
+                || Some(area.ifd) == gps_offset
+                || (rewrites_exif
+                    && Some(area.ifd) == exif_offset
+                    && matches!(area.tag, None | Some(TAG_OFFSET_TIME_ORIGINAL)))
+        });
+
+        let gps = self.append_ifd(self.gps_entries(geotag), 0)?;
+        set_entry(&mut ifd0, pointer_entry(self, TAG_GPS_IFD, gps));
+
+        if let Some(offset_time) = &geotag.offset_time {
+            set_entry(&mut exif, ascii_entry(TAG_OFFSET_TIME_ORIGINAL, offset_time));
+            let exif = self.append_ifd(exif, 0)?;
+            set_entry(&mut ifd0, pointer_entry(self, TAG_EXIF_IFD, exif));
+        }
+
+        let ifd0 = self.append_ifd(ifd0, next)?;
+        self.set_ifd0(ifd0);
+        Ok(())
+    }
+
+    // Build the entries of the GPS IFD for a geotag.
+    fn gps_entries(&self, geotag: &Geotag) -> Vec<Entry> {
+        let mut entries = vec![
+            Entry {
+                tag: 0x0000,
+                kind: TYPE_BYTE,
+                count: 4,
+                value: EntryValue::Data(vec![2, 3, 0, 0]),
+            },
+            ascii_entry(0x0001, if geotag.lat < 0.0 { "S" } else { "N" }),
+            Entry {
+                tag: 0x0002,
+                kind: TYPE_RATIONAL,
+                count: 3,
+                value: EntryValue::Data(self.encode_rationals(&dms(geotag.lat))),
+            },
+            ascii_entry(0x0003, if geotag.lon < 0.0 { "W" } else { "E" }),
+            Entry {
+                tag: 0x0004,
+                kind: TYPE_RATIONAL,
+                count: 3,
+                value: EntryValue::Data(self.encode_rationals(&dms(geotag.lon))),
+            },
+            ascii_entry(0x0012, "WGS-84"),
+        ];
+
+        if let Some(altitude) = geotag.altitude {
+            entries.push(Entry {
+                tag: 0x0005,
+                kind: TYPE_BYTE,
+                count: 1,
+                value: EntryValue::Data(vec![u8::from(altitude < 0.0)]),
+            });
+            entries.push(Entry {
+                tag: 0x0006,
+                kind: TYPE_RATIONAL,
+                count: 1,
+                value: EntryValue::Data(
+                    self.encode_rationals(&[((altitude.abs() * 1000.0).round() as u32, 1000)]),
+                ),
+            });
+        }
+
+        entries
+    }
+}
+
+// value_size is a function that returns the size in bytes of the value of an entry.
+fn value_size(kind: u16, count: u32) -> usize {
+    let unit = match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    };
+    unit * count as usize
+}
+
+// pointer_entry is a function that builds a LONG entry pointing to a sub-IFD.
+fn pointer_entry(tiff: &Tiff, tag: u16, offset: u32) -> Entry {
+    Entry {
+        tag,
+        kind: TYPE_LONG,
+        count: 1,
+        value: EntryValue::Data(tiff.encode_u32(offset).to_vec()),
+    }
+}
+
+// ascii_entry is a function that builds a NUL-terminated ASCII entry.
+fn ascii_entry(tag: u16, text: &str) -> Entry {
+    let mut value = text.as_bytes().to_vec();
+    value.push(0);
+    Entry {
+        tag,
+        kind: TYPE_ASCII,
+        count: value.len() as u32,
+        value: EntryValue::Data(value),
+    }
+}
+
+// set_entry is a function that adds an entry to an IFD, replacing any entry with the same tag.
+fn set_entry(entries: &mut Vec<Entry>, entry: Entry) {
+    entries.retain(|existing| existing.tag != entry.tag);
+    entries.push(entry);
+}
+
+// dms is a function that converts decimal degrees to degree, minute and second rationals.
+// The value is rounded to a ten-thousandth of a second first, so that a rounded 60 seconds
+// carries into the minutes, and 60 minutes into the degrees.
+pub fn dms(value: f64) -> [(u32, u32); 3] {
+    let total = (value.abs() * 3600.0 * 10000.0).round() as u64;
+    [
+        ((total / (3600 * 10000)) as u32, 1),
+        ((total / (60 * 10000) % 60) as u32, 1),
+        ((total % (60 * 10000)) as u32, 10000),
+    ]
+}
+
+// Define the layout of a JPEG file around its Exif segment.
+pub struct JpegLayout {
+    // Marker and byte range, markers included, of every segment before the image data.
+    pub segments: Vec<(u8, usize, usize)>,
+    // Byte range of the existing Exif APP1 segment, markers included.
+    pub exif: Option<(usize, usize)>,
+    // Where to insert a new Exif segment: after SOI and any APP0 (JFIF) segment.
+    insert_at: usize,
+}
+
+// jpeg_layout is a function that locates the segments of a JPEG file.
+pub fn jpeg_layout(bytes: &[u8]) -> io::Result<JpegLayout> {
+    let mut layout = JpegLayout {
+        segments: Vec::new(),
+        exif: None,
+        insert_at: 2,
+    };
+    let mut position = 2;
+
+    while position + 4 <= bytes.len() {
+        if bytes[position] != 0xFF {
+            return Err(invalid_data("Bad JPEG marker"));
+        }
+        let marker = bytes[position + 1];
+        // Fill bytes and standalone markers carry no length.
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            position += 2;
+            continue;
+        }
+        // Entropy-coded data follows the start of scan.
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+
+        let length = u16::from_be_bytes([bytes[position + 2], bytes[position + 3]]) as usize;
+        let end = position + 2 + length;
+        if length < 2 || end > bytes.len() {
+            return Err(invalid_data("Truncated JPEG segment"));
+        }
+        layout.segments.push((marker, position, end));
+        match marker {
+            0xE0 if layout.insert_at == position => layout.insert_at = end,
+            0xE1 if layout.exif.is_none() && bytes[position + 4..end].starts_with(EXIF_HEADER) => {
+                layout.exif = Some((position, end))
+            }
+            _ => (),
+        }
+        position = end;
+    }
+
+    Ok(layout)
+}
+
+// geotag_jpeg is a function that returns the JPEG file with the geotag in its Exif segment.
+fn geotag_jpeg(bytes: &[u8], geotag: &Geotag) -> io::Result<Vec<u8>> {
+    let layout = jpeg_layout(bytes)?;
+
+    let mut tiff = match layout.exif {
+        Some((start, end)) => Tiff::parse(bytes[start + 4 + EXIF_HEADER.len()..end].to_vec())?,
+        None => Tiff::empty(),
+    };
+    tiff.set_geotag(geotag)?;
+
+    let length = 2 + EXIF_HEADER.len() + tiff.bytes.len();
+    if length > MAX_SEGMENT_SIZE {
+        return Err(invalid_data("Exif segment too large for a JPEG file"));
+    }
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend((length as u16).to_be_bytes());
+    segment.extend(EXIF_HEADER);
+    segment.extend(&tiff.bytes);
+
+    let (start, end) = layout.exif.unwrap_or((layout.insert_at, layout.insert_at));
+    let mut output = Vec::with_capacity(bytes.len() + segment.len());
+    output.extend(&bytes[..start]);
+    output.extend(segment);
+    output.extend(&bytes[end..]);
+    Ok(output)
+}
+
+// Define the file formats the writer knows about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Jpeg,
+    Tiff,
+    // Anything else, which only gets an XMP sidecar.
+    Other,
+}
+
+// detect_format is a function that detects the format of a file from its magic bytes.
+pub fn detect_format(bytes: &[u8]) -> Format {
+    match bytes.get(0..4) {
+        Some([0xFF, 0xD8, ..]) => Format::Jpeg,
+        Some(b"II*\0") | Some(b"MM\0*") => Format::Tiff,
+        _ => Format::Other,
+    }
+}
+
+// geotagged_bytes is a function that returns the file content with the geotag written in place.
+fn geotagged_bytes(bytes: &[u8], geotag: &Geotag) -> io::Result<Vec<u8>> {
+    match detect_format(bytes) {
+        Format::Jpeg => geotag_jpeg(bytes, geotag),
+        Format::Tiff => {
+            let mut tiff = Tiff::parse(bytes.to_vec())?;
+            tiff.set_geotag(geotag)?;
+            Ok(tiff.bytes)
+        }
+        Format::Other => Err(invalid_data("Format can't be rewritten safely")),
+    }
+}
+
+// Tags compared to report the changes of a geotag.
+const DIFF_TAGS: [Tag; 5] = [
+    Tag::GPSLatitude,
+    Tag::GPSLongitude,
+    Tag::GPSAltitude,
+    Tag::GPSMapDatum,
+    Tag::OffsetTimeOriginal,
+];
+
+// read_fields is a function that reads the displayed values of the compared tags.
+fn read_fields(bytes: &[u8]) -> Vec<(String, String)> {
+    let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) else {
+        return Vec::new();
+    };
+
+    DIFF_TAGS
+        .iter()
+        .filter_map(|&tag| {
+            // The displayed unit includes the N/S, E/W and above/below sea level references.
+            let field = exif
+                .get_field(tag, In::PRIMARY)
+                .map(|field| field.display_value().with_unit(&exif).to_string())?;
+            Some((tag.to_string(), field))
+        })
+        .collect()
+}
+
+// diff_fields is a function that lists the compared tags whose value changed.
+fn diff_fields(old: &[(String, String)], new: &[(String, String)]) -> Vec<FieldChange> {
+    new.iter()
+        .filter_map(|(field, value)| {
+            let old = old.iter().find(|(name, _)| name == field).map(|(_, v)| v.clone());
+            if old.as_ref() == Some(value) {
+                return None;
+            }
+            Some(FieldChange {
+                field: field.clone(),
+                old,
+                new: value.clone(),
+            })
+        })
+        .collect()
+}
+
+// xmp_coordinate is a function that formats a coordinate as an XMP GPSCoordinate ("DDD,MM.mmmmmmK").
+// Like dms, the value is rounded to the written precision first so that the minutes never read 60.
+fn xmp_coordinate(value: f64, positive: char, negative: char) -> String {
+    let total = (value.abs() * 60.0 * 1_000_000.0).round() as u64;
+    let degrees = total / (60 * 1_000_000);
+    let minutes = (total % (60 * 1_000_000)) as f64 / 1_000_000.0;
+    let reference = if value < 0.0 { negative } else { positive };
+    format!("{},{:.6}{}", degrees, minutes, reference)
+}
+
+// sidecar_fields is a function that lists the XMP properties written for a geotag.
+fn sidecar_fields(geotag: &Geotag) -> Vec<(String, String)> {
+    let mut fields = vec![
+        ("exif:GPSVersionID".to_string(), "2.3.0.0".to_string()),
+        ("exif:GPSLatitude".to_string(), xmp_coordinate(geotag.lat, 'N', 'S')),
+        ("exif:GPSLongitude".to_string(), xmp_coordinate(geotag.lon, 'E', 'W')),
+        ("exif:GPSMapDatum".to_string(), "WGS-84".to_string()),
+    ];
+    if let Some(altitude) = geotag.altitude {
+        fields.push((
+            "exif:GPSAltitudeRef".to_string(),
+            u8::from(altitude < 0.0).to_string(),
+        ));
+        fields.push((
+            "exif:GPSAltitude".to_string(),
+            format!("{}/1000", (altitude.abs() * 1000.0).round() as u32),
+        ));
+    }
+    if let Some(offset_time) = &geotag.offset_time {
+        fields.push(("exif:OffsetTimeOriginal".to_string(), offset_time.clone()));
+    }
+    fields
+}
+
+// sidecar_document is a function that renders an XMP sidecar with the given properties.
+fn sidecar_document(fields: &[(String, String)]) -> String {
+    let properties: String = fields
+        .iter()
+        .map(|(name, value)| format!("\n    {}=\"{}\"", name, value))
+        .collect();
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+         <rdf:Description rdf:about=\"\"\n    \
+         xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"{}/>\n\
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>\n",
+        properties
+    )
+}
+
+// Namespaces of RDF and of the Exif properties in XMP.
+const RDF_NAMESPACE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const EXIF_NAMESPACE: &str = "http://ns.adobe.com/exif/1.0/";
+
+// attribute_end is a function that returns where an attribute starting at a position of a tag ends.
+fn attribute_end(text: &str, start: usize) -> Option<usize> {
+    let equals = start + text[start..].find('=')?;
+    let quote_at = equals + 1 + text[equals + 1..].find(['"', '\''])?;
+    let quote = &text[quote_at..quote_at + 1];
+    let close = quote_at + 1 + text[quote_at + 1..].find(quote)?;
+    Some(close + 1)
+}
+
+// merge_sidecar is a function that writes the XMP properties of a geotag into an existing sidecar,
+// keeping everything else other applications stored in it. The GPS properties it already holds,
+// as attributes or elements, are replaced.
+// Parameters:
+// - existing: The content of the sidecar.
+// - fields: The properties of the geotag.
+// Returns:
+// - io::Result<String>: The merged sidecar, or an std::io::Error if it isn't an XMP packet
+//   that can be merged safely.
+fn merge_sidecar(existing: &str, fields: &[(String, String)]) -> io::Result<String> {
+    let document = roxmltree::Document::parse(existing)
+        .map_err(|error| invalid_data(format!("Existing sidecar can't be read: {}", error)))?;
+    let description = document
+        .descendants()
+        .find(|node| node.has_tag_name((RDF_NAMESPACE, "Description")))
+        .ok_or_else(|| invalid_data("Existing sidecar has no rdf:Description"))?;
+
+    // The properties written are removed wherever they are, along with the other GPS properties.
+    let replaced = |namespace: Option<&str>, name: &str| {
+        namespace == Some(EXIF_NAMESPACE)
+            && (name.starts_with("GPS")
+                || fields.iter().any(|(field, _)| field.strip_prefix("exif:") == Some(name)))
+    };
+    let mut removed: Vec<(usize, usize)> = Vec::new();
+    for node in document.descendants().filter(|node| node.is_element()) {
+        // The content of a removed element goes with it.
+        if removed.last().is_some_and(|&(_, end)| node.range().start < end) {
+            continue;
+        }
+        if replaced(node.tag_name().namespace(), node.tag_name().name()) {
+            removed.push((node.range().start, node.range().end));
+            continue;
+        }
+        for attribute in node.attributes() {
+            if replaced(attribute.namespace(), attribute.name()) {
+                let start = attribute.position();
+                let end = attribute_end(existing, start)
+                    .ok_or_else(|| invalid_data("Existing sidecar can't be read"))?;
+                removed.push((start, end));
+            }
+        }
+    }
+
+    // The new properties go right after the name of the first rdf:Description tag.
+    let tag_start = description.range().start;
+    let name_end = existing[tag_start..]
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .map(|end| tag_start + end)
+        .ok_or_else(|| invalid_data("Existing sidecar can't be read"))?;
+    let mut properties = match description.lookup_namespace_uri(Some("exif")) {
+        Some(EXIF_NAMESPACE) => String::new(),
+        None => format!("\n    xmlns:exif=\"{}\"", EXIF_NAMESPACE),
+        Some(_) => return Err(invalid_data("Existing sidecar binds exif: to another namespace")),
+    };
+    properties.extend(fields.iter().map(|(name, value)| format!("\n    {}=\"{}\"", name, value)));
+
+    // Apply the changes from the end, so that the positions before them stay valid.
+    let mut edits: Vec<(usize, usize, &str)> = removed.into_iter().map(|(start, end)| (start, end, "")).collect();
+    edits.push((name_end, name_end, &properties));
+    edits.sort_by_key(|&(start, _, _)| std::cmp::Reverse(start));
+    let mut merged = existing.to_string();
+    for (start, end, text) in edits {
+        merged.replace_range(start..end, text);
+    }
+    Ok(merged)
+}
+
+// sidecar_path is a function that returns the XMP sidecar path of a file (IMG_001.jpg -> IMG_001.xmp).
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("xmp")
+}
+
+// replace_file is a function that atomically replaces a file with new content,
+// optionally keeping the original with a .bak suffix.
+// An existing backup is never replaced, so that the first one keeps the true original;
+// later ones get a version (.bak.1, .bak.2, ...).
+// Returns the path of the backup, if one was made.
+fn replace_file(path: &Path, content: &[u8], backup: bool) -> io::Result<Option<PathBuf>> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| invalid_data(format!("Bad file name {}", path.display())))?;
+    let temporary = path.with_file_name(format!(".{}.geotag.tmp", name));
+
+    // Write the new content next to the file so that the rename stays on one filesystem.
+    let written = (|| {
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temporary, metadata.permissions())?;
+        }
+        Ok::<(), io::Error>(())
+    })();
+    if let Err(error) = written {
+        let _ = fs::remove_file(&temporary);
+        return Err(error);
+    }
+
+    let backup_path = match backup && path.exists() {
+        true => match keep_backup(path, name) {
+            Ok(backup_path) => Some(backup_path),
+            Err(error) => {
+                let _ = fs::remove_file(&temporary);
+                return Err(error);
+            }
+        },
+        false => None,
+    };
+
+    if let Err(error) = fs::rename(&temporary, path) {
+        let _ = fs::remove_file(&temporary);
+        return Err(error);
+    }
+    Ok(backup_path)
+}
+
+// keep_backup is a function that keeps the current content of a file under the first free backup name.
+// Parameters:
+// - path: The file.
+// - name: The file name of the file.
+// Returns:
+// - io::Result<PathBuf>: The path of the backup, or an std::io::Error.
+fn keep_backup(path: &Path, name: &str) -> io::Result<PathBuf> {
+    let mut version = 0;
+    loop {
+        let backup_path = match version {
+            0 => path.with_file_name(format!("{}.bak", name)),
+            version => path.with_file_name(format!("{}.bak.{}", name, version)),
+        };
+        // A hard link keeps the original inode once the file is replaced; copy where links fail.
+        // Neither replaces an existing backup.
+        let kept = match fs::hard_link(path, &backup_path) {
+            Err(error) if error.kind() != io::ErrorKind::AlreadyExists => copy_new(path, &backup_path),
+            linked => linked,
+        };
+        match kept {
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => version += 1,
+            kept => return kept.map(|()| backup_path),
+        }
+    }
+}
+
+// copy_new is a function that copies a file to a path that must not exist yet, with its permissions.
+fn copy_new(path: &Path, target: &Path) -> io::Result<()> {
+    let mut copy = fs::OpenOptions::new().write(true).create_new(true).open(target)?;
+    let copied = io::copy(&mut fs::File::open(path)?, &mut copy)
+        .and_then(|_| fs::set_permissions(target, fs::metadata(path)?.permissions()));
+    if copied.is_err() {
+        let _ = fs::remove_file(target);
+    }
+    copied
+}
+
+// write_geotag is a function that persists a geotag into an image file or its XMP sidecar.
+// JPEG and TIFF-based files are rewritten in place unless a sidecar is requested;
+// other formats always get a sidecar.
+// Parameters:
+// - path: The image file.
+// - geotag: The position to write.
+// - options: Sidecar, backup and dry-run options.
+// Returns:
+// - io::Result<WriteReport>: What was changed, or an std::io::Error.
+pub fn write_geotag(path: &Path, geotag: &Geotag, options: &WriteOptions) -> io::Result<WriteReport> {
+    let bytes = fs::read(path)?;
+    let old_fields = read_fields(&bytes);
+
+    if !options.sidecar && detect_format(&bytes) != Format::Other {
+        let content = geotagged_bytes(&bytes, geotag)?;
+        let mut report = WriteReport {
+            target: path.to_path_buf(),
+            changes: diff_fields(&old_fields, &read_fields(&content)),
+            written: false,
+            backup: None,
+        };
+        if !options.dry_run {
+            report.backup = replace_file(path, &content, options.backup)?;
+            report.written = true;
+        }
+        return Ok(report);
+    }
+
+    let fields = sidecar_fields(geotag);
+    let target = sidecar_path(path);
+    let old_fields: Vec<(String, String)> = old_fields
+        .into_iter()
+        .map(|(field, value)| (format!("exif:{}", field), value))
+        .collect();
+    let mut report = WriteReport {
+        changes: diff_fields(&old_fields, &fields),
+        target,
+        written: false,
+        backup: None,
+    };
+    // An existing sidecar can hold edits of other applications, so the properties are merged into it.
+    let content = match fs::read_to_string(&report.target) {
+        Ok(existing) => merge_sidecar(&existing, &fields)?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => sidecar_document(&fields),
+        Err(error) => return Err(error),
+    };
+    if !options.dry_run {
+        report.backup = replace_file(&report.target, content.as_bytes(), options.backup)?;
+        report.written = true;
+    }
+    Ok(report)
+}
+
+// format_offset_time is a function that formats a UTC offset in seconds as "+HH:MM".
+pub fn format_offset_time(offset: i64) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60)
+}
+
+// write_back is a function that persists the inferred position of a scanned photo into the file:
+// only messages positioned from a track log or a location history are written.
+// The state of a rewritten photo is read again, so that the state cached with the message is
+// the one of the file as it is now.
+// Parameters:
+// - message: The message of the photo, keyed by file path.
+// - offset_time: The UTC offset of the camera clock, if known.
+// - options: Sidecar, backup and dry-run options.
+pub fn write_back(message: &mut Message, offset_time: Option<&str>, options: &WriteOptions) {
+    // Archive entries and remote files are left as they are.
+    if archive::is_entry(&message.key) || media_source::is_remote(&message.key) {
+        return;
+    }
+    let value = &message.value;
+    if !matches!(value["position_source"].as_str(), Some("track") | Some("history")) {
+        return;
+    }
+    let (Some(lat), Some(lon)) = (value["lat"].as_f64(), value["long"].as_f64()) else {
+        return;
+    };
+    // GPS altitudes are already in the file.
+    let altitude = match value["altitude_source"].as_str() {
+        Some("gps") | None => None,
+        Some(_) => value["altitude"].as_f64(),
+    };
+
+    let geotag = Geotag {
+        lat,
+        lon,
+        altitude,
+        offset_time: offset_time.map(|offset| offset.to_string()),
+    };
+    match write_geotag(Path::new(&message.key), &geotag, options) {
+        Ok(report) => {
+            log_report(&report);
+            let rewritten = report.written && report.target == Path::new(&message.key);
+            if let (true, Some(state)) = (rewritten, &mut message.source) {
+                if let Err(error) = state.refresh() {
+                    logger::log_error(&format!("Error while reading the state of {}: {}", message.key, error));
+                }
+            }
+        }
+        Err(error) => logger::log_error(&format!(
+            "Error while writing geotag to {}: {}",
+            message.key, error
+        )),
+    }
+}
+
+// log_report is a function that logs the outcome of writing a geotag.
+pub fn log_report(report: &WriteReport) {
+    if report.written {
+        logger::log_info(&format!("Geotag written to {}", report.target.display()));
+    }
+    if !report.changes.is_empty() {
+        logger::log_info(&report.diff());
+    }
+    if let Some(backup) = &report.backup {
+        logger::log_info(&format!("Original kept as {}", backup.display()));
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::get_exif;
+    use tiff::decoder::{Decoder, DecodingResult};
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    // Define a sample geotag.
+    fn geotag() -> Geotag {
+        Geotag {
+            lat: 45.043938,
+            lon: 39.032085,
+            altitude: Some(27.813),
+            offset_time: Some("+03:00".to_string()),
+        }
+    }
+
+    // jpeg_with_datetime is a helper that builds a JPEG with an APP0 segment
+    // and an Exif segment holding only a DateTime.
+    fn jpeg_with_datetime() -> Vec<u8> {
+        let mut tiff = Tiff::empty();
+        let ifd0 = tiff
+            .append_ifd(vec![ascii_entry(0x0132, "2021:01:04 14:49:57")], 0)
+            .unwrap();
+        tiff.set_ifd0(ifd0);
+
+        let mut bytes = vec![0xFF, 0xD8];
+        bytes.extend([0xFF, 0xE0, 0, 16]);
+        bytes.extend(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        bytes.extend([0xFF, 0xE1]);
+        bytes.extend(((2 + EXIF_HEADER.len() + tiff.bytes.len()) as u16).to_be_bytes());
+        bytes.extend(EXIF_HEADER);
+        bytes.extend(&tiff.bytes);
+        bytes.extend([0xFF, 0xD9]);
+        bytes
+    }
+
+    // Define a test function for geotagging a photo in the southern and western hemispheres, below sea level.
+    #[test]
+    fn test_write_southern_western() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.jpg");
+        fs::write(&path, jpeg_with_datetime()).unwrap();
+        let geotag = Geotag { lat: -33.86, lon: -70.65, altitude: Some(-5.5), offset_time: None };
+        write_geotag(&path, &geotag, &WriteOptions::default()).unwrap();
+
+        // Assert that the references are read back as the signs of the position.
+        let filename = path.display().to_string();
+        let photo = &get_exif(&filename).unwrap()[&filename];
+        assert!((photo.lat() + 33.86).abs() < 1e-5);
+        assert!((photo.long() + 70.65).abs() < 1e-5);
+    }
+
+    // Define a test function for geotagging a JPEG file in place.
+    #[test]
+    fn test_write_jpeg() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.jpg");
+        fs::write(&path, jpeg_with_datetime()).unwrap();
+
+        let report = write_geotag(&path, &geotag(), &WriteOptions::default()).unwrap();
+        assert!(report.written);
+        assert_eq!(report.backup, None);
+        assert_eq!(report.changes.len(), 5);
+        assert_eq!(report.changes[0].old, None);
+
+        // The new tags are readable and the existing ones are kept.
+        let filename = path.display().to_string();
+        let photo = &get_exif(&filename).unwrap()[&filename];
+        assert!((photo.lat() - 45.043938).abs() < 1e-5);
+        assert!((photo.long() - 39.032085).abs() < 1e-5);
+        assert!(!photo.timestamp().is_empty());
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(&bytes[0..6], &[0xFF, 0xD8, 0xFF, 0xE0, 0, 16]);
+        let fields = read_fields(&bytes);
+        assert!(fields.contains(&("OffsetTimeOriginal".to_string(), "\"+03:00\"".to_string())));
+
+        // Writing the same geotag again changes nothing.
+        let report = write_geotag(&path, &geotag(), &WriteOptions::default()).unwrap();
+        assert!(report.changes.is_empty());
+    }
+
+    // Define a test function for rewriting the geotag of a JPEG file.
+    #[test]
+    fn test_rewrite_jpeg() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.jpg");
+        fs::write(&path, jpeg_with_datetime()).unwrap();
+        write_geotag(&path, &geotag(), &WriteOptions::default()).unwrap();
+        let first = fs::read(&path).unwrap();
+
+        let moved = Geotag {
+            lat: -33.85,
+            lon: -70.5,
+            ..geotag()
+        };
+        write_geotag(&path, &moved, &WriteOptions::default()).unwrap();
+        let second = fs::read(&path).unwrap();
+
+        // Assert that the file doesn't grow and keeps none of the previous coordinates.
+        assert_eq!(second.len(), first.len());
+        let tiff = Tiff::empty();
+        for value in [geotag().lat, geotag().lon] {
+            let raw = tiff.encode_rationals(&dms(value));
+            assert!(first.windows(raw.len()).any(|window| window == raw));
+            assert!(!second.windows(raw.len()).any(|window| window == raw));
+        }
+        let fields = read_fields(&second);
+        assert!(fields[0].1.ends_with(" S") && fields[1].1.ends_with(" W"));
+        assert!(fields.contains(&("OffsetTimeOriginal".to_string(), "\"+03:00\"".to_string())));
+        let filename = path.display().to_string();
+        assert!(!get_exif(&filename).unwrap()[&filename].timestamp().is_empty());
+
+        // Assert that the scrub finds nothing left behind.
+        let layout = jpeg_layout(&second).unwrap();
+        let (start, end) = layout.exif.unwrap();
+        let mut tiff = Tiff::parse(second[start + 4 + EXIF_HEADER.len()..end].to_vec()).unwrap();
+        assert!(!tiff.scrub().unwrap());
+    }
+
+    // Define a test function for a JPEG file without an Exif segment.
+    #[test]
+    fn test_write_jpeg_without_exif() {
+        let bytes = geotagged_bytes(&[0xFF, 0xD8, 0xFF, 0xD9], &geotag()).unwrap();
+        assert_eq!(&bytes[0..4], &[0xFF, 0xD8, 0xFF, 0xE1]);
+        assert_eq!(read_fields(&bytes).len(), 5);
+    }
+
+    // Define a test function for geotagging a TIFF file in place.
+    #[test]
+    fn test_write_tiff() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.tif");
+        {
+            let mut tiff = TiffEncoder::new(fs::File::create(&path).unwrap()).unwrap();
+            tiff.write_image::<colortype::Gray8>(2, 2, &[1, 2, 3, 4]).unwrap();
+        }
+
+        let geotag = Geotag {
+            lat: -33.85,
+            lon: -70.5,
+            altitude: None,
+            offset_time: None,
+        };
+        write_geotag(&path, &geotag, &WriteOptions::default()).unwrap();
+        let length = fs::metadata(&path).unwrap().len();
+
+        // Writing again reuses the space of the replaced IFDs.
+        write_geotag(&path, &geotag, &WriteOptions::default()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+
+        let fields = read_fields(&fs::read(&path).unwrap());
+        assert_eq!(fields[0].0, "GPSLatitude");
+        assert!(fields[0].1.ends_with(" S"));
+        assert!(fields[1].1.ends_with(" W"));
+
+        // The image data is still intact.
+        let mut decoder = Decoder::new(fs::File::open(&path).unwrap()).unwrap();
+        assert!(matches!(decoder.read_image().unwrap(), DecodingResult::U8(data) if data == [1, 2, 3, 4]));
+    }
+
+    // Define a test function for the dry-run and backup options.
+    #[test]
+    fn test_dry_run_and_backup() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.jpg");
+        let original = jpeg_with_datetime();
+        fs::write(&path, &original).unwrap();
+
+        let dry_run = WriteOptions {
+            dry_run: true,
+            ..WriteOptions::default()
+        };
+        let report = write_geotag(&path, &geotag(), &dry_run).unwrap();
+        assert!(!report.written);
+        assert!(report.diff().contains("GPSLatitude: (none) -> 45 deg 2 min 38.1768 sec N"));
+        assert_eq!(fs::read(&path).unwrap(), original);
+
+        let backup = WriteOptions {
+            backup: true,
+            ..WriteOptions::default()
+        };
+        let report = write_geotag(&path, &geotag(), &backup).unwrap();
+        let backup_path = directory.path().join("photo.jpg.bak");
+        assert_eq!(report.backup, Some(backup_path.clone()));
+        assert_eq!(fs::read(&backup_path).unwrap(), original);
+        assert_ne!(fs::read(&path).unwrap(), original);
+
+        // Assert that another write keeps the first backup, and versions the next one.
+        let geotagged = fs::read(&path).unwrap();
+        let moved = Geotag { lat: -33.85, ..geotag() };
+        let report = write_geotag(&path, &moved, &backup).unwrap();
+        let versioned = directory.path().join("photo.jpg.bak.1");
+        assert_eq!(report.backup, Some(versioned.clone()));
+        assert_eq!(fs::read(&backup_path).unwrap(), original);
+        assert_eq!(fs::read(&versioned).unwrap(), geotagged);
+    }
+
+    // Define a test function for the rounding of degrees, minutes and seconds.
+    #[test]
+    fn test_dms() {
+        assert_eq!(dms(45.5), [(45, 1), (30, 1), (0, 10000)]);
+        assert_eq!(dms(-45.043938), [(45, 1), (2, 1), (381768, 10000)]);
+        // Assert that seconds rounded up to 60 carry into the minutes and the degrees.
+        assert_eq!(dms(45.999999999), [(46, 1), (0, 1), (0, 10000)]);
+        assert_eq!(dms(45.49999999), [(45, 1), (30, 1), (0, 10000)]);
+        assert_eq!(xmp_coordinate(45.9999999999, 'N', 'S'), "46,0.000000N");
+        assert_eq!(xmp_coordinate(-70.5, 'E', 'W'), "70,30.000000W");
+    }
+
+    // Define a test function for writing an XMP sidecar.
+    #[test]
+    fn test_write_sidecar() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.heic");
+        fs::write(&path, b"\0\0\0\x18ftypheic").unwrap();
+
+        let report = write_geotag(&path, &geotag(), &WriteOptions::default()).unwrap();
+        assert_eq!(report.target, directory.path().join("photo.xmp"));
+
+        let sidecar = fs::read_to_string(&report.target).unwrap();
+        assert!(sidecar.contains("exif:GPSLatitude=\"45,2.636280N\""));
+        assert!(sidecar.contains("exif:GPSLongitude=\"39,1.925100E\""));
+        assert!(sidecar.contains("exif:GPSAltitude=\"27813/1000\""));
+        assert!(sidecar.contains("exif:OffsetTimeOriginal=\"+03:00\""));
+        // The image itself is untouched.
+        assert_eq!(fs::read(&path).unwrap(), b"\0\0\0\x18ftypheic");
+    }
+
+    // Define a test function for merging a geotag into an existing sidecar.
+    #[test]
+    fn test_merge_sidecar() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.heic");
+        fs::write(&path, b"\0\0\0\x18ftypheic").unwrap();
+        let existing = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+             <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+             <rdf:Description rdf:about=\"\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n\
+             xmlns:exif=\"http://ns.adobe.com/exif/1.0/\" xmp:Rating=\"4\" exif:GPSLatitude='1,1.0N'>\n\
+             <exif:GPSLongitude>2,2.0E</exif:GPSLongitude>\n\
+             <dc:subject xmlns:dc=\"http://purl.org/dc/elements/1.1/\"><rdf:Bag><rdf:li>beach</rdf:li></rdf:Bag></dc:subject>\n\
+             </rdf:Description>\n\
+             </rdf:RDF>\n\
+             </x:xmpmeta>\n";
+        fs::write(directory.path().join("photo.xmp"), existing).unwrap();
+
+        let report = write_geotag(&path, &geotag(), &WriteOptions::default()).unwrap();
+        let sidecar = fs::read_to_string(&report.target).unwrap();
+
+        // Assert that the other properties are kept and the old position is replaced.
+        assert!(sidecar.contains("xmp:Rating=\"4\""));
+        assert!(sidecar.contains("<rdf:li>beach</rdf:li>"));
+        assert!(!sidecar.contains("1,1.0N") && !sidecar.contains("2,2.0E"));
+        assert!(sidecar.contains("exif:GPSLatitude=\"45,2.636280N\""));
+        assert!(sidecar.contains("exif:GPSLongitude=\"39,1.925100E\""));
+        assert_eq!(sidecar.matches("xmlns:exif").count(), 1);
+        roxmltree::Document::parse(&sidecar).unwrap();
+
+        // Assert that a sidecar that isn't XMP is left alone.
+        fs::write(&report.target, "not xml").unwrap();
+        assert!(write_geotag(&path, &geotag(), &WriteOptions::default()).is_err());
+        assert_eq!(fs::read_to_string(&report.target).unwrap(), "not xml");
+    }
+
+    // Define a test function for formatting UTC offsets.
+    #[test]
+    fn test_format_offset_time() {
+        assert_eq!(format_offset_time(10800), "+03:00");
+        assert_eq!(format_offset_time(-16200), "-04:30");
+        assert_eq!(format_offset_time(0), "+00:00");
+    }
+}
@@ -0,0 +1,515 @@
+// Import necessary modules from the project.
+use crate::archive;
+use crate::config::ScanConfig;
+
+// Import necessary modules from the standard library and external crates.
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use walkdir::{DirEntry, WalkDir};
+
+// Number of bytes read to recognise a media file.
+pub const SNIFF_LENGTH: usize = 16;
+// Largest archive descended into when no limit is set: 4 GiB.
+const DEFAULT_MAX_ARCHIVE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+// ISO base media brands of the HEIF and AVIF images kamadak-exif can read.
+const HEIF_BRANDS: [&[u8; 4]; 10] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+// Define the scan options selecting the files to extract.
+// Unset options (empty lists and None) fall back to the next source: request, command line, configuration.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterOptions {
+    // Glob patterns a file path, relative to the scanned directory, must match.
+    pub include: Vec<String>,
+    // Glob patterns of the files and directories to skip.
+    pub exclude: Vec<String>,
+    // Extensions a file must have, without the dot and case insensitive.
+    pub extensions: Vec<String>,
+    // Skip the files and directories whose name starts with a dot.
+    pub skip_hidden: Option<bool>,
+    // Largest directory depth to descend into, the scanned directory being 0.
+    pub max_depth: Option<usize>,
+    // Follow symbolic links.
+    pub follow_symlinks: Option<bool>,
+    // Stay on the file system of the scanned directory.
+    pub same_file_system: Option<bool>,
+    // Size bounds of a file, in bytes.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    // Only extract files whose first bytes look like a supported media format.
+    pub sniff: Option<bool>,
+    // Descend into ZIP and TAR archives, whose entries are filtered like files.
+    pub archives: Option<bool>,
+    // Largest archive to descend into, in bytes.
+    pub max_archive_size: Option<u64>,
+}
+
+// split_list is a function that splits a comma-separated configuration value.
+fn split_list(value: &Option<String>) -> Vec<String> {
+    value
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl FilterOptions {
+    // Read the filter options of the scan configuration.
+    pub fn from_config(config: &ScanConfig) -> FilterOptions {
+        FilterOptions {
+            include: split_list(&config.include),
+            exclude: split_list(&config.exclude),
+            extensions: split_list(&config.extensions),
+            skip_hidden: config.skip_hidden,
+            max_depth: config.max_depth,
+            follow_symlinks: config.follow_symlinks,
+            same_file_system: config.same_file_system,
+            min_size: config.min_size,
+            max_size: config.max_size,
+            sniff: config.sniff,
+            archives: config.archives,
+            max_archive_size: config.max_archive_size,
+        }
+    }
+
+    // Get these options with the options set in others taking precedence.
+    pub fn overridden_by(&self, others: &FilterOptions) -> FilterOptions {
+        let list = |mine: &Vec<String>, theirs: &Vec<String>| {
+            if theirs.is_empty() {
+                mine.clone()
+            } else {
+                theirs.clone()
+            }
+        };
+        FilterOptions {
+            include: list(&self.include, &others.include),
+            exclude: list(&self.exclude, &others.exclude),
+            extensions: list(&self.extensions, &others.extensions),
+            skip_hidden: others.skip_hidden.or(self.skip_hidden),
+            max_depth: others.max_depth.or(self.max_depth),
+            follow_symlinks: others.follow_symlinks.or(self.follow_symlinks),
+            same_file_system: others.same_file_system.or(self.same_file_system),
+            min_size: others.min_size.or(self.min_size),
+            max_size: others.max_size.or(self.max_size),
+            sniff: others.sniff.or(self.sniff),
+            archives: others.archives.or(self.archives),
+            max_archive_size: others.max_archive_size.or(self.max_archive_size),
+        }
+    }
+}
+
+// glob_set is a function that compiles glob patterns, or returns None when there are none.
+fn glob_set(patterns: &[String]) -> io::Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+}
+
+// is_media is a function that checks whether the first bytes of a file look like a format
+// kamadak-exif can read: JPEG, TIFF (and TIFF-based raw files), PNG, WebP, HEIF and AVIF.
+pub fn is_media(header: &[u8]) -> bool {
+    header.starts_with(&[0xFF, 0xD8, 0xFF])
+        || header.starts_with(b"II*\0")
+        || header.starts_with(b"MM\0*")
+        || header.starts_with(b"\x89PNG\r\n\x1a\n")
+        || (header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WEBP")
+        || (header.len() >= 12
+            && &header[4..8] == b"ftyp"
+            && HEIF_BRANDS.iter().any(|brand| &header[8..12] == *brand))
+}
+
+// sniff is a function that reads the first bytes of a file and checks whether it looks like a media file.
+fn sniff(path: &Path) -> bool {
+    let mut header = Vec::with_capacity(SNIFF_LENGTH);
+    File::open(path)
+        .and_then(|file| file.take(SNIFF_LENGTH as u64).read_to_end(&mut header))
+        .map(|_| is_media(&header))
+        .unwrap_or(false)
+}
+
+// Define the compiled filter selecting the files to extract.
+// The default filter keeps every file.
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    extensions: Vec<String>,
+    skip_hidden: bool,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    same_file_system: bool,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    sniff: bool,
+    archives: bool,
+    max_archive_size: u64,
+}
+
+impl FileFilter {
+    // Compile filter options; unset options don't filter anything, except sniffing which is on by default.
+    pub fn new(options: &FilterOptions) -> io::Result<FileFilter> {
+        Ok(FileFilter {
+            include: glob_set(&options.include)?,
+            exclude: glob_set(&options.exclude)?,
+            extensions: options
+                .extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                .collect(),
+            skip_hidden: options.skip_hidden.unwrap_or(false),
+            max_depth: options.max_depth,
+            follow_symlinks: options.follow_symlinks.unwrap_or(false),
+            same_file_system: options.same_file_system.unwrap_or(false),
+            min_size: options.min_size,
+            max_size: options.max_size,
+            sniff: options.sniff.unwrap_or(true),
+            archives: options.archives.unwrap_or(false),
+            max_archive_size: options.max_archive_size.unwrap_or(DEFAULT_MAX_ARCHIVE_SIZE),
+        })
+    }
+
+    // Create a directory walker with the depth, symbolic link and file system options.
+    pub fn walker(&self, directory: &str) -> WalkDir {
+        let mut walker = WalkDir::new(directory)
+            .follow_links(self.follow_symlinks)
+            .same_file_system(self.same_file_system);
+        if let Some(max_depth) = self.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        walker
+    }
+
+    // Check whether an entry, file or directory, is worth visiting; skipped directories aren't descended into.
+    pub fn keep_entry(&self, root: &Path, entry: &DirEntry) -> bool {
+        // The scanned directory itself is always visited.
+        if entry.depth() == 0 {
+            return true;
+        }
+        if self.skip_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+            return false;
+        }
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        !self
+            .exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(relative))
+    }
+
+    // Check whether a file has to be extracted.
+    pub fn keep_file(&self, root: &Path, entry: &DirEntry) -> bool {
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        self.keep_relative(relative, entry.path(), || entry.metadata().ok().map(|metadata| metadata.len()))
+    }
+
+    // Check whether a file found outside of a walk, such as by a watcher, has to be extracted:
+    // the directories above it must be visited too.
+    pub fn keep_path(&self, root: &Path, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        self.keep_ancestors(relative)
+            && self.keep_relative(relative, path, || std::fs::metadata(path).ok().map(|metadata| metadata.len()))
+    }
+
+    // Check whether a file listed by a remote source has to be extracted, given its path relative to the
+    // scanned location; its contents are checked once read with keep_contents.
+    pub fn keep_listed(&self, relative: &str, size: u64) -> bool {
+        let relative = Path::new(relative);
+        self.keep_ancestors(relative) && self.keep_checks(relative, relative, || Some(size))
+    }
+
+    // Check the depth of a file and whether the directories above it would be visited by a walk.
+    fn keep_ancestors(&self, relative: &Path) -> bool {
+        if self
+            .max_depth
+            .is_some_and(|max_depth| relative.components().count() > max_depth)
+        {
+            return false;
+        }
+        let mut visited = PathBuf::new();
+        for component in relative.components() {
+            visited.push(component);
+            if self.skip_hidden && component.as_os_str().to_string_lossy().starts_with('.') {
+                return false;
+            }
+            if self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(&visited)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Check whether a file is an archive to descend into.
+    pub fn is_archive(&self, path: &Path) -> bool {
+        self.archives && archive::kind(path).is_some()
+    }
+
+    // Check whether an entry of an archive has to be read, before reading it: the globs apply
+    // to its virtual path relative to the scanned directory, and the other checks to the entry.
+    // Its contents are checked once read with keep_contents.
+    pub fn keep_archive_entry(&self, root: &Path, path: &str, size: u64) -> bool {
+        let relative = Path::new(path).strip_prefix(root).unwrap_or(Path::new(path));
+        let entry = path.split_once(archive::SEPARATOR).map_or("", |(_, entry)| entry);
+        if self.skip_hidden && entry.split('/').any(|name| name.starts_with('.')) {
+            return false;
+        }
+        if self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(relative)) {
+            return false;
+        }
+        self.keep_checks(relative, Path::new(path), || Some(size))
+    }
+
+    // Check whether the contents of files are sniffed, so that remote sources only fetch their first bytes when needed.
+    pub fn sniffs(&self) -> bool {
+        self.sniff
+    }
+
+    // Check whether the first bytes of an archive entry or remote file look like a media file, if asked to.
+    pub fn keep_contents(&self, contents: &[u8]) -> bool {
+        !self.sniff || is_media(&contents[..contents.len().min(SNIFF_LENGTH)])
+    }
+
+    // Check the include globs, extensions, size and contents of a file;
+    // archives are kept when they aren't too large, as their entries are checked instead.
+    fn keep_relative<S>(&self, relative: &Path, path: &Path, size: S) -> bool
+    where
+        S: FnOnce() -> Option<u64>,
+    {
+        if self.is_archive(path) {
+            return size().is_some_and(|size| size <= self.max_archive_size);
+        }
+        // Read the first bytes last, as it is the only check opening the file.
+        self.keep_checks(relative, path, size) && (!self.sniff || sniff(path))
+    }
+
+    // Check the include globs, extensions and size of a file.
+    fn keep_checks<S>(&self, relative: &Path, path: &Path, size: S) -> bool
+    where
+        S: FnOnce() -> Option<u64>,
+    {
+        if let Some(include) = &self.include {
+            if !include.is_match(relative) {
+                return false;
+            }
+        }
+
+        if !self.extensions.is_empty() {
+            let extension = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase());
+            if !extension.is_some_and(|extension| self.extensions.contains(&extension)) {
+                return false;
+            }
+        }
+
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let Some(size) = size() else {
+                return false;
+            };
+            if self.min_size.is_some_and(|min_size| size < min_size)
+                || self.max_size.is_some_and(|max_size| size > max_size)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // kept is a helper that lists the files a filter keeps in a directory, relative to it.
+    fn kept(directory: &Path, options: &FilterOptions) -> Vec<String> {
+        let filter = FileFilter::new(options).unwrap();
+        let mut files: Vec<String> = filter
+            .walker(directory.to_str().unwrap())
+            .into_iter()
+            .filter_entry(|entry| filter.keep_entry(directory, entry))
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_type().is_dir() && filter.keep_file(directory, entry))
+            .map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(directory)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    // library is a helper that creates a small photo library with clutter.
+    fn library() -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        std::fs::create_dir_all(root.join("2021/raw")).unwrap();
+        std::fs::create_dir_all(root.join(".thumbnails")).unwrap();
+        std::fs::write(root.join("2021/a.jpg"), [0xFF, 0xD8, 0xFF, 0xE0, 0, 16]).unwrap();
+        std::fs::write(
+            root.join("2021/B.JPG"),
+            [0xFF, 0xD8, 0xFF, 0xE1, 0, 2, 0, 0, 0, 0],
+        )
+        .unwrap();
+        std::fs::write(root.join("2021/raw/c.dng"), b"II*\0\x08\0\0\0").unwrap();
+        std::fs::write(root.join("2021/notes.pdf"), b"%PDF-1.7").unwrap();
+        std::fs::write(root.join("2021/fake.jpg"), b"not a photo").unwrap();
+        std::fs::write(root.join(".thumbnails/t.jpg"), [0xFF, 0xD8, 0xFF, 0xE0]).unwrap();
+        std::fs::write(root.join(".DS_Store"), b"\0\0\0\x01Bud1").unwrap();
+        directory
+    }
+
+    // Define a test function for the default filter options.
+    #[test]
+    fn test_sniff() {
+        // Assert that ISO base media files are recognised by their brand: HEIF, not MP4 or Canon CR3,
+        // which kamadak-exif can't read.
+        assert!(is_media(b"\0\0\0\x18ftypheic\0\0\0\0"));
+        assert!(!is_media(b"\0\0\0\x18ftypcrx \0\0\0\x01"));
+        assert!(!is_media(b"\0\0\0\x18ftypisom\0\0\x02\0"));
+
+        let library = library();
+        assert_eq!(
+            kept(library.path(), &FilterOptions::default()),
+            vec![
+                ".thumbnails/t.jpg",
+                "2021/B.JPG",
+                "2021/a.jpg",
+                "2021/raw/c.dng"
+            ]
+        );
+        assert_eq!(
+            kept(
+                library.path(),
+                &FilterOptions {
+                    sniff: Some(false),
+                    ..FilterOptions::default()
+                }
+            )
+            .len(),
+            7
+        );
+    }
+
+    // Define a test function for the globs, extensions and hidden files.
+    #[test]
+    fn test_filter() {
+        let library = library();
+        let options = FilterOptions {
+            exclude: vec!["**/raw".to_string()],
+            extensions: vec!["JPG".to_string(), ".dng".to_string()],
+            skip_hidden: Some(true),
+            ..FilterOptions::default()
+        };
+        assert_eq!(
+            kept(library.path(), &options),
+            vec!["2021/B.JPG", "2021/a.jpg"]
+        );
+
+        let options = FilterOptions {
+            include: vec!["2021/*.{jpg,JPG}".to_string()],
+            min_size: Some(8),
+            ..FilterOptions::default()
+        };
+        assert_eq!(kept(library.path(), &options), vec!["2021/B.JPG"]);
+
+        let options = FilterOptions {
+            max_depth: Some(2),
+            max_size: Some(6),
+            ..FilterOptions::default()
+        };
+        assert_eq!(
+            kept(library.path(), &options),
+            vec![".thumbnails/t.jpg", "2021/a.jpg"]
+        );
+    }
+
+    // Define a test function for checking single files, as a watcher does.
+    #[test]
+    fn test_keep_path() {
+        let library = library();
+        let root = library.path();
+        let files: Vec<PathBuf> = WalkDir::new(root)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect();
+
+        // Assert that single files are kept like the files of a walk.
+        for options in [
+            FilterOptions::default(),
+            FilterOptions {
+                exclude: vec!["**/raw".to_string()],
+                skip_hidden: Some(true),
+                ..FilterOptions::default()
+            },
+            FilterOptions {
+                max_depth: Some(2),
+                include: vec!["**/*.jpg".to_string()],
+                ..FilterOptions::default()
+            },
+        ] {
+            let filter = FileFilter::new(&options).unwrap();
+            let mut single: Vec<String> = files
+                .iter()
+                .filter(|path| filter.keep_path(root, path))
+                .map(|path| path.strip_prefix(root).unwrap().display().to_string())
+                .collect();
+            single.sort();
+            assert_eq!(single, kept(root, &options));
+        }
+        assert!(!FileFilter::default().keep_path(root, Path::new("/elsewhere/a.jpg")));
+    }
+
+    // Define a test function for the precedence of the option sources.
+    #[test]
+    fn test_overridden_by() {
+        let config = FilterOptions {
+            exclude: vec!["**/.git".to_string()],
+            skip_hidden: Some(true),
+            max_depth: Some(3),
+            ..FilterOptions::default()
+        };
+        let request = FilterOptions {
+            skip_hidden: Some(false),
+            min_size: Some(1024),
+            ..FilterOptions::default()
+        };
+        assert_eq!(
+            config.overridden_by(&request),
+            FilterOptions {
+                exclude: vec!["**/.git".to_string()],
+                skip_hidden: Some(false),
+                max_depth: Some(3),
+                min_size: Some(1024),
+                ..FilterOptions::default()
+            }
+        );
+        assert!(FileFilter::new(&FilterOptions {
+            include: vec!["[".to_string()],
+            ..FilterOptions::default()
+        })
+        .is_err());
+    }
+}
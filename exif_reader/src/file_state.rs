@@ -0,0 +1,562 @@
+// Import necessary modules from the project.
+use crate::archive;
+use crate::config::CacheConfig;
+use crate::logger;
+use crate::media_source::MediaSource;
+use crate::message::{EventType, Message};
+
+// Import necessary modules from the standard library and external crates.
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use openssl::sha::Sha256;
+use rusqlite::{params, Connection, OptionalExtension};
+
+// Define the state of a file when it was last scanned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileState {
+    pub path: String,
+    pub size: u64,
+    // Modification time, in nanoseconds since the Unix epoch.
+    pub mtime: i64,
+    // SHA-256 of the file contents, in hex; empty when the scan didn't need it.
+    pub content_hash: String,
+    // SHA-256 of the last payload produced for the file, if any, in hex.
+    pub payload_hash: Option<String>,
+    // Fingerprint of the enrichers and privacy zones the file was extracted with, if known.
+    pub settings: Option<String>,
+}
+
+impl FileState {
+    // refresh is a method that reads the state of a local file again once it was rewritten, such as by
+    // a write-back, so that the next scan doesn't take the write for a change; the contents are only
+    // hashed again if they were hashed before.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let (size, mtime) = stat(&self.path)?;
+        if !self.content_hash.is_empty() {
+            self.content_hash = content_hash(Path::new(&self.path))?;
+        }
+        self.size = size;
+        self.mtime = mtime;
+        Ok(())
+    }
+}
+
+// Define the result of comparing a file with its cached state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    // The file is the same as when it was last scanned.
+    Unchanged,
+    // The file changed; its current state keeps the last payload hash.
+    Changed(FileState),
+    // The file wasn't scanned before.
+    New(FileState),
+}
+
+// hex_digest is a function that formats a SHA-256 digest in hex.
+fn hex_digest(digest: [u8; 32]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// contents_hash is a function that hashes contents read into memory.
+pub fn contents_hash(contents: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(contents);
+    hex_digest(hasher.finish())
+}
+
+// payload_hash is a function that hashes a payload, to recognise payloads that were already produced.
+pub fn payload_hash(payload: &str) -> String {
+    contents_hash(payload.as_bytes())
+}
+
+// settings_fingerprint is a function that fingerprints settings the payloads depend on, such as those of
+// an enricher, with the size and modification time of their data files, so that a changed data file counts too.
+// Parameters:
+// - settings: The settings, in any stable text form.
+// - files: The data files.
+// Returns:
+// - String: The SHA-256 of the settings and of the state of the files, in hex.
+pub fn settings_fingerprint<P: AsRef<Path>>(settings: &str, files: &[P]) -> String {
+    let mut fingerprint = settings.to_string();
+    for file in files {
+        let path = file.as_ref();
+        let stat = fs::metadata(path).ok().map(|metadata| {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_nanos());
+            (metadata.len(), mtime)
+        });
+        fingerprint.push_str(&format!("\n{} {:?}", path.display(), stat));
+    }
+    payload_hash(&fingerprint)
+}
+
+// stat is a function that returns the size of a file and its modification time in nanoseconds.
+pub fn stat(path: &str) -> io::Result<(u64, i64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or_default();
+    Ok((metadata.len(), mtime))
+}
+
+// content_hash is a function that hashes the contents of a file.
+pub fn content_hash(path: &Path) -> io::Result<String> {
+    reader_hash(File::open(path)?)
+}
+
+// reader_hash is a function that hashes everything a reader reads, such as a remote file.
+pub fn reader_hash<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(hex_digest(hasher.finish())),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+// Define the persistent store of file states, shared by the extraction threads.
+pub struct StateCache {
+    connection: Mutex<Connection>,
+}
+
+impl fmt::Debug for StateCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateCache").finish_non_exhaustive()
+    }
+}
+
+impl StateCache {
+    // Open the SQLite store of file states, creating it if needed.
+    pub fn open(path: &str) -> rusqlite::Result<StateCache> {
+        StateCache::with_connection(Connection::open(path)?)
+    }
+
+    // Open the store of file states configured for the scans.
+    pub fn from_config(config: &CacheConfig) -> rusqlite::Result<StateCache> {
+        StateCache::open(&config.path)
+    }
+
+    // Create the schema of the store on an open connection.
+    fn with_connection(connection: Connection) -> rusqlite::Result<StateCache> {
+        // Every file is written on its own, so trade durability of the last writes for speed.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS file_state (
+                path TEXT PRIMARY KEY,
+                size INTEGER NOT NULL,
+                mtime INTEGER NOT NULL,
+                content_hash TEXT NOT NULL,
+                payload_hash TEXT
+            );
+            CREATE INDEX IF NOT EXISTS file_state_content_hash ON file_state (content_hash);",
+        )?;
+        // Stores created before the settings were fingerprinted get the column, empty, so their files
+        // are extracted again once.
+        let columns: Vec<String> = connection
+            .prepare("SELECT name FROM pragma_table_info('file_state')")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        if !columns.iter().any(|column| column == "settings") {
+            connection.execute_batch("ALTER TABLE file_state ADD COLUMN settings TEXT;")?;
+        }
+        Ok(StateCache {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Get the cached state of a file.
+    pub fn get(&self, path: &str) -> rusqlite::Result<Option<FileState>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT path, size, mtime, content_hash, payload_hash, settings FROM file_state WHERE path = ?1",
+                params![path],
+                |row| {
+                    Ok(FileState {
+                        path: row.get(0)?,
+                        size: row.get::<_, i64>(1)? as u64,
+                        mtime: row.get(2)?,
+                        content_hash: row.get(3)?,
+                        payload_hash: row.get(4)?,
+                        settings: row.get(5)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    // Save the state of a file, replacing the previous one.
+    pub fn put(&self, state: &FileState) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO file_state (path, size, mtime, content_hash, payload_hash, settings)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                state.path,
+                state.size as i64,
+                state.mtime,
+                state.content_hash,
+                state.payload_hash,
+                state.settings
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Remove the state of a file.
+    pub fn remove(&self, path: &str) -> rusqlite::Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM file_state WHERE path = ?1", params![path])?;
+        Ok(())
+    }
+
+    // moved_from is a method that looks for the previous path of a new file: a removed file
+    // with the same contents.
+    // Parameters:
+    // - state: The state of the new file.
+    // - source: The source of the file, which tells the removed files.
+    // Returns:
+    // - rusqlite::Result<Option<String>>: The previous path, if the file was moved.
+    pub fn moved_from(&self, state: &FileState, source: &dyn MediaSource) -> rusqlite::Result<Option<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare_cached("SELECT path FROM file_state WHERE content_hash = ?1 AND path != ?2")?;
+        let paths = statement
+            .query_map(params![state.content_hash, state.path], |row| {
+                row.get::<_, String>(0)
+            })?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        // The states of archives aren't files that can move.
+        Ok(paths
+            .into_iter()
+            .find(|path| !path.ends_with(archive::SEPARATOR) && source.is_gone(path)))
+    }
+
+    // missing is a method that lists the files of a directory that were removed since they were scanned.
+    // Parameters:
+    // - directory: The scanned directory.
+    // - source: The source of the directory, which tells the removed files.
+    // Returns:
+    // - rusqlite::Result<Vec<String>>: The paths of the removed files.
+    pub fn missing(&self, directory: &str, source: &dyn MediaSource) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT path FROM file_state")?;
+        let mut missing = Vec::new();
+        for path in statement.query_map([], |row| row.get::<_, String>(0))? {
+            let path = path?;
+            // Archive entries are under the directory of their archive, or the archive itself.
+            if Path::new(archive::archive_of(&path)).starts_with(directory) && source.is_gone(&path) {
+                missing.push(path);
+            }
+        }
+        // The states of removed archives have no message to wait for, so they are dropped right away.
+        let archives: Vec<String> = missing.extract_if(.., |path| path.ends_with(archive::SEPARATOR)).collect();
+        for path in archives {
+            connection.execute("DELETE FROM file_state WHERE path = ?1", params![path])?;
+        }
+        Ok(missing)
+    }
+
+    // entries is a method that lists the cached entries of an archive.
+    // Parameters:
+    // - archive: The path of the archive.
+    // Returns:
+    // - rusqlite::Result<Vec<String>>: The virtual paths of the entries, without the state of the archive itself.
+    pub fn entries(&self, archive: &str) -> rusqlite::Result<Vec<String>> {
+        let prefix = archive::virtual_path(archive, "");
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare_cached("SELECT path FROM file_state WHERE substr(path, 1, length(?1)) = ?1 AND path != ?1")?;
+        let paths = statement
+            .query_map(params![prefix], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(paths)
+    }
+
+    // Update the cache once a message is delivered: removed and moved files lose their
+    // previous state, and the others get their current state saved.
+    pub fn delivered(&self, message: &Message) {
+        let removed = match (message.event, &message.moved_from) {
+            (EventType::Deleted, _) | (EventType::Moved, None) if message.is_tombstone() => {
+                Some(message.key.as_str())
+            }
+            (EventType::Moved, Some(moved_from)) => Some(moved_from.as_str()),
+            _ => None,
+        };
+        if let Some(path) = removed {
+            if let Err(error) = self.remove(path) {
+                logger::log_error(&format!(
+                    "Error while removing the state of {}: {}",
+                    path, error
+                ));
+            }
+        }
+        if let Some(state) = &message.source {
+            self.save(state);
+        }
+    }
+
+    // Save the state of a file, logging failures: a missing state only costs a rescan of the file.
+    pub fn save(&self, state: &FileState) {
+        if let Err(error) = self.put(state) {
+            logger::log_error(&format!(
+                "Error while caching the state of {}: {}",
+                state.path, error
+            ));
+        }
+    }
+
+    // check is a method that compares a file with its cached state. The contents are only hashed
+    // when the size or modification time changed, and a file touched without being changed
+    // gets its new modification time cached.
+    // Parameters:
+    // - path: The path of the file.
+    // - full: Whether to treat unchanged files as changed, to extract them again.
+    // - settings: The fingerprint of the enrichers and privacy zones of the scan; a file extracted
+    //   with other ones is changed.
+    // Returns:
+    // - io::Result<Check>: Whether the file changed since it was last scanned, or an std::io::Error
+    //   if it can't be read.
+    pub fn check(&self, path: &str, full: bool, settings: &str) -> io::Result<Check> {
+        let (size, mtime) = stat(path)?;
+        self.check_with(path, size, mtime, full, settings, || content_hash(Path::new(path)))
+    }
+
+    // check_contents is a method that compares a file read into memory, such as an archive entry,
+    // with its cached state, like check does for files on disk.
+    pub fn check_contents(
+        &self,
+        path: &str,
+        size: u64,
+        mtime: i64,
+        contents: &[u8],
+        full: bool,
+        settings: &str,
+    ) -> io::Result<Check> {
+        self.check_with(path, size, mtime, full, settings, || Ok(contents_hash(contents)))
+    }
+
+    // check_with is a method that compares the size, modification time and then contents of a file,
+    // wherever it is stored, with its cached state.
+    // Parameters:
+    // - path: The path or location of the file.
+    // - size, mtime: The size of the file and its modification time in nanoseconds.
+    // - full: Whether to treat unchanged files as changed, to extract them again.
+    // - settings: The fingerprint of the enrichers and privacy zones of the scan.
+    // - hash: Fingerprints the contents, only when the size or modification time changed.
+    // Returns:
+    // - io::Result<Check>: Whether the file changed since it was last scanned, or the error of the fingerprint.
+    pub fn check_with<H>(&self, path: &str, size: u64, mtime: i64, full: bool, settings: &str, hash: H) -> io::Result<Check>
+    where
+        H: FnOnce() -> io::Result<String>,
+    {
+        let cached = self.get(path).map_err(io::Error::other)?;
+        // A file extracted with other enrichers or privacy zones may get another payload.
+        let full = full || cached.as_ref().is_some_and(|cached| cached.settings.as_deref() != Some(settings));
+
+        if let Some(cached) = &cached {
+            if !full && cached.size == size && cached.mtime == mtime {
+                return Ok(Check::Unchanged);
+            }
+        }
+
+        let state = FileState {
+            path: path.to_string(),
+            size,
+            mtime,
+            content_hash: hash()?,
+            payload_hash: cached
+                .as_ref()
+                .and_then(|cached| cached.payload_hash.clone()),
+            settings: Some(settings.to_string()),
+        };
+        match cached {
+            Some(cached) if !full && cached.content_hash == state.content_hash => {
+                self.save(&state);
+                Ok(Check::Unchanged)
+            }
+            Some(_) => Ok(Check::Changed(state)),
+            None => Ok(Check::New(state)),
+        }
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::media_source::LocalFs;
+
+    // Define a test function for detecting changed files.
+    #[test]
+    fn test_check() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = StateCache::open(directory.path().join("cache.db").to_str().unwrap()).unwrap();
+        let path = directory.path().join("photo.jpg");
+        let path = path.to_str().unwrap();
+        fs::write(path, "first").unwrap();
+
+        // Assert that a new file is changed, and unchanged once its state is saved.
+        let state = match cache.check(path, false, "").unwrap() {
+            Check::New(state) => state,
+            _ => panic!("Expected a new file"),
+        };
+        assert_eq!(state.payload_hash, None);
+        let produced = FileState {
+            payload_hash: Some(payload_hash("{}")),
+            ..state
+        };
+        cache.save(&produced);
+        assert_eq!(cache.check(path, false, "").unwrap(), Check::Unchanged);
+
+        // Assert that a file touched without being changed is still unchanged.
+        let touched = FileState {
+            mtime: 0,
+            ..produced
+        };
+        cache.save(&touched);
+        assert_eq!(cache.check(path, false, "").unwrap(), Check::Unchanged);
+        assert_ne!(cache.get(path).unwrap().unwrap().mtime, 0);
+
+        // Assert that a full scan treats the unchanged file as changed.
+        assert!(matches!(
+            cache.check(path, true, "").unwrap(),
+            Check::Changed(_)
+        ));
+
+        // Assert that a file extracted with other enrichers or privacy zones is changed.
+        assert!(matches!(
+            cache.check(path, false, "other").unwrap(),
+            Check::Changed(state) if state.settings.as_deref() == Some("other")
+        ));
+
+        // Assert that a changed file keeps the hash of the last payload.
+        fs::write(path, "second").unwrap();
+        match cache.check(path, false, "").unwrap() {
+            Check::Changed(state) => assert_eq!(state.payload_hash, Some(payload_hash("{}"))),
+            _ => panic!("Expected a changed file"),
+        }
+    }
+
+    // Define a test function for detecting moved and removed files.
+    #[test]
+    fn test_moved_and_missing() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = StateCache::open(directory.path().join("cache.db").to_str().unwrap()).unwrap();
+        let photos = directory.path().join("photos");
+        fs::create_dir(&photos).unwrap();
+        let path = |name: &str| photos.join(name).to_str().unwrap().to_string();
+        let new_state = |name: &str| match cache.check(&path(name), false, "").unwrap() {
+            Check::New(state) => state,
+            _ => panic!("Expected a new file"),
+        };
+
+        // Scan two photos, then rename one and remove the other.
+        fs::write(path("a.jpg"), "a").unwrap();
+        fs::write(path("b.jpg"), "b").unwrap();
+        cache.save(&new_state("a.jpg"));
+        cache.save(&new_state("b.jpg"));
+        fs::rename(path("a.jpg"), path("c.jpg")).unwrap();
+        fs::remove_file(path("b.jpg")).unwrap();
+
+        // Assert that the renamed photo is found by its contents.
+        let moved = new_state("c.jpg");
+        assert_eq!(cache.moved_from(&moved, &LocalFs).unwrap(), Some(path("a.jpg")));
+        let mut missing = cache.missing(photos.to_str().unwrap(), &LocalFs).unwrap();
+        missing.sort();
+        assert_eq!(missing, vec![path("a.jpg"), path("b.jpg")]);
+
+        // Assert that delivering the moved message and the tombstone updates the cache.
+        let mut message = Message::tombstone(&path("c.jpg"), EventType::Created);
+        message.value = serde_json::json!({});
+        message.set_moved_from(&path("a.jpg"));
+        message.source = Some(moved);
+        cache.delivered(&message);
+        cache.delivered(&Message::tombstone(&path("b.jpg"), EventType::Deleted));
+        assert!(cache.missing(photos.to_str().unwrap(), &LocalFs).unwrap().is_empty());
+        assert!(cache.get(&path("c.jpg")).unwrap().is_some());
+
+        // Assert that the tombstone of a published photo now withheld keeps its state, without payload.
+        let published = FileState { payload_hash: Some("payload".to_string()), ..cache.get(&path("c.jpg")).unwrap().unwrap() };
+        cache.save(&published);
+        let mut withheld = Message::tombstone(&path("c.jpg"), EventType::Withheld);
+        withheld.source = Some(FileState { payload_hash: None, ..published });
+        cache.delivered(&withheld);
+        assert_eq!(cache.get(&path("c.jpg")).unwrap().unwrap().payload_hash, None);
+
+        // Assert that the entries of an archive are removed with it.
+        fs::write(path("backup.zip"), "zip").unwrap();
+        let entry = format!("{}!/d.jpg", path("backup.zip"));
+        match cache.check_contents(&entry, 1, 0, b"d", false, "").unwrap() {
+            Check::New(state) => cache.save(&state),
+            _ => panic!("Expected a new entry"),
+        }
+        let root = format!("{}!/", path("backup.zip"));
+        match cache.check(&path("backup.zip"), false, "").unwrap() {
+            Check::New(state) => cache.save(&FileState { path: root.clone(), ..state }),
+            _ => panic!("Expected a new archive"),
+        }
+        assert!(cache.missing(photos.to_str().unwrap(), &LocalFs).unwrap().is_empty());
+        assert_eq!(cache.entries(&path("backup.zip")).unwrap(), vec![entry.clone()]);
+
+        // Assert that the state of a removed archive is dropped without a tombstone.
+        fs::remove_file(path("backup.zip")).unwrap();
+        assert_eq!(cache.missing(&path("backup.zip"), &LocalFs).unwrap(), vec![entry]);
+        assert!(cache.get(&root).unwrap().is_none());
+    }
+
+    // Define a test function for fingerprinting settings with their data files.
+    #[test]
+    fn test_settings_fingerprint() {
+        let directory = tempfile::tempdir().unwrap();
+        let track = directory.path().join("track.gpx");
+        fs::write(&track, "first").unwrap();
+
+        // Assert that the fingerprint changes with the settings and with the data files.
+        let first = settings_fingerprint("track 0 0", &[&track]);
+        assert_eq!(first, settings_fingerprint("track 0 0", &[&track]));
+        assert_ne!(first, settings_fingerprint("track 1000 0", &[&track]));
+        fs::write(&track, "second").unwrap();
+        assert_ne!(first, settings_fingerprint("track 0 0", &[&track]));
+    }
+
+    // Define a test function for opening a store created before the settings were cached.
+    #[test]
+    fn test_old_store() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("cache.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE file_state (path TEXT PRIMARY KEY, size INTEGER NOT NULL, mtime INTEGER NOT NULL,
+                    content_hash TEXT NOT NULL, payload_hash TEXT);
+                INSERT INTO file_state VALUES ('photo.jpg', 1, 0, 'hash', NULL);",
+            )
+            .unwrap();
+
+        // Assert that the files of the old store have no settings, so they are extracted again.
+        let cache = StateCache::open(path.to_str().unwrap()).unwrap();
+        let state = cache.get("photo.jpg").unwrap().unwrap();
+        assert_eq!(state.settings, None);
+        assert!(matches!(
+            cache.check_with("photo.jpg", 1, 0, false, "", || Ok("hash".to_string())).unwrap(),
+            Check::Changed(_)
+        ));
+        StateCache::open(path.to_str().unwrap()).unwrap();
+    }
+}
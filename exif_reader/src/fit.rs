@@ -0,0 +1,277 @@
+// Import necessary modules from the project.
+use crate::track::TrackPoint;
+use crate::utils::invalid_data;
+
+// Import necessary modules from the standard library.
+use std::collections::HashMap;
+use std::io;
+
+// Seconds between the Unix epoch and the FIT epoch (1989-12-31T00:00:00Z).
+const FIT_EPOCH_OFFSET: i64 = 631_065_600;
+// Global message number of the FIT "record" message.
+const RECORD_MESSAGE: u16 = 20;
+// Field numbers used from the FIT profile.
+const FIELD_POSITION_LAT: u8 = 0;
+const FIELD_POSITION_LONG: u8 = 1;
+const FIELD_ALTITUDE: u8 = 2;
+const FIELD_ENHANCED_ALTITUDE: u8 = 78;
+const FIELD_TIMESTAMP: u8 = 253;
+
+// Define the layout of a field in a FIT data message.
+#[derive(Debug, Clone, Copy)]
+struct FieldDefinition {
+    number: u8,
+    size: usize,
+}
+
+// Define the layout of a FIT data message, as announced by its definition message.
+#[derive(Debug, Clone)]
+struct MessageDefinition {
+    global: u16,
+    big_endian: bool,
+    fields: Vec<FieldDefinition>,
+    developer_size: usize,
+}
+
+// take is a function that reads the next `size` bytes of the file.
+fn take<'a>(bytes: &'a [u8], position: &mut usize, size: usize) -> io::Result<&'a [u8]> {
+    let slice = bytes
+        .get(*position..*position + size)
+        .ok_or_else(|| invalid_data("Truncated FIT file"))?;
+    *position += size;
+    Ok(slice)
+}
+
+// read_u32 is a function that decodes a 4-byte unsigned value in the message byte order.
+fn read_u32(raw: &[u8], big_endian: bool) -> u32 {
+    let raw = [raw[0], raw[1], raw[2], raw[3]];
+    if big_endian {
+        u32::from_be_bytes(raw)
+    } else {
+        u32::from_le_bytes(raw)
+    }
+}
+
+// read_u16 is a function that decodes a 2-byte unsigned value in the message byte order.
+fn read_u16(raw: &[u8], big_endian: bool) -> u16 {
+    let raw = [raw[0], raw[1]];
+    if big_endian {
+        u16::from_be_bytes(raw)
+    } else {
+        u16::from_le_bytes(raw)
+    }
+}
+
+// semicircles_to_degrees is a function that converts a FIT position to degrees.
+fn semicircles_to_degrees(value: i32) -> f64 {
+    value as f64 * 180.0 / 2f64.powi(31)
+}
+
+// expand_timestamp is a function that resolves a compressed 5-bit timestamp offset
+// against the last full timestamp seen.
+fn expand_timestamp(last: u32, offset: u8) -> u32 {
+    let offset = offset as u32;
+    let base = last & !0x1F;
+    if offset >= (last & 0x1F) {
+        base + offset
+    } else {
+        base + offset + 0x20
+    }
+}
+
+// parse_fit is a function that reads the positioned "record" messages of a FIT activity file.
+// Parameters:
+// - bytes: The content of the FIT file.
+// Returns:
+// - io::Result<Vec<TrackPoint>>: The track points of the file, or an std::io::Error.
+pub fn parse_fit(bytes: &[u8]) -> io::Result<Vec<TrackPoint>> {
+    if bytes.len() < 12 || &bytes[8..12] != b".FIT" {
+        return Err(invalid_data("Not a FIT file"));
+    }
+    let header_size = bytes[0] as usize;
+    let data_size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+    let end = header_size + data_size;
+    if end > bytes.len() {
+        return Err(invalid_data("Truncated FIT file"));
+    }
+
+    let mut definitions: HashMap<u8, MessageDefinition> = HashMap::new();
+    let mut last_timestamp: Option<u32> = None;
+    let mut points = Vec::new();
+    let mut position = header_size;
+
+    while position < end {
+        let header = take(bytes, &mut position, 1)?[0];
+
+        // Definition messages announce the layout of the following data messages.
+        if header & 0xC0 == 0x40 {
+            let fixed = take(bytes, &mut position, 5)?;
+            let big_endian = fixed[1] == 1;
+            let count = fixed[4] as usize;
+            let fields = take(bytes, &mut position, count * 3)?
+                .chunks_exact(3)
+                .map(|field| FieldDefinition {
+                    number: field[0],
+                    size: field[1] as usize,
+                })
+                .collect();
+
+            let mut developer_size = 0;
+            if header & 0x20 != 0 {
+                let count = take(bytes, &mut position, 1)?[0] as usize;
+                developer_size = take(bytes, &mut position, count * 3)?
+                    .chunks_exact(3)
+                    .map(|field| field[1] as usize)
+                    .sum();
+            }
+
+            definitions.insert(
+                header & 0x0F,
+                MessageDefinition {
+                    global: read_u16(&fixed[2..4], big_endian),
+                    big_endian,
+                    fields,
+                    developer_size,
+                },
+            );
+            continue;
+        }
+
+        // Data messages use either a normal or a compressed timestamp header.
+        let (local, mut timestamp) = if header & 0x80 != 0 {
+            let timestamp = last_timestamp.map(|last| expand_timestamp(last, header & 0x1F));
+            ((header >> 5) & 0x03, timestamp)
+        } else {
+            (header & 0x0F, None)
+        };
+        let definition = definitions
+            .get(&local)
+            .ok_or_else(|| invalid_data("FIT data message without definition"))?;
+
+        let (mut lat, mut lon, mut altitude, mut enhanced_altitude) = (None, None, None, None);
+        for field in &definition.fields {
+            let raw = take(bytes, &mut position, field.size)?;
+            match (field.number, field.size) {
+                (FIELD_TIMESTAMP, 4) => {
+                    let value = read_u32(raw, definition.big_endian);
+                    if value != u32::MAX {
+                        timestamp = Some(value);
+                    }
+                }
+                (FIELD_POSITION_LAT, 4) => {
+                    lat =
+                        Some(read_u32(raw, definition.big_endian) as i32).filter(|&v| v != i32::MAX)
+                }
+                (FIELD_POSITION_LONG, 4) => {
+                    lon =
+                        Some(read_u32(raw, definition.big_endian) as i32).filter(|&v| v != i32::MAX)
+                }
+                (FIELD_ALTITUDE, 2) => {
+                    altitude = Some(read_u16(raw, definition.big_endian) as u32)
+                        .filter(|&v| v != u16::MAX as u32)
+                }
+                (FIELD_ENHANCED_ALTITUDE, 4) => {
+                    enhanced_altitude =
+                        Some(read_u32(raw, definition.big_endian)).filter(|&v| v != u32::MAX)
+                }
+                _ => (),
+            }
+        }
+        take(bytes, &mut position, definition.developer_size)?;
+
+        if timestamp.is_some() {
+            last_timestamp = timestamp;
+        }
+
+        if definition.global == RECORD_MESSAGE {
+            if let (Some(time), Some(lat), Some(lon)) = (timestamp, lat, lon) {
+                points.push(TrackPoint {
+                    time: (time as i64 + FIT_EPOCH_OFFSET) * 1000,
+                    lat: semicircles_to_degrees(lat),
+                    lon: semicircles_to_degrees(lon),
+                    // Altitudes are stored with a scale of 5 and an offset of 500 m.
+                    altitude: enhanced_altitude
+                        .or(altitude)
+                        .map(|v| v as f64 / 5.0 - 500.0),
+                });
+            }
+        }
+    }
+
+    Ok(points)
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // degrees_to_semicircles is a helper that converts degrees to a FIT position.
+    fn degrees_to_semicircles(value: f64) -> i32 {
+        (value * 2f64.powi(31) / 180.0).round() as i32
+    }
+
+    // fit_file is a helper that wraps FIT records with a file header.
+    fn fit_file(records: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![12, 0x10, 0, 0];
+        bytes.extend((records.len() as u32).to_le_bytes());
+        bytes.extend(b".FIT");
+        bytes.extend(records);
+        // The trailing CRC is not checked.
+        bytes.extend([0, 0]);
+        bytes
+    }
+
+    // Define a test function for reading record messages from a FIT file.
+    #[test]
+    fn test_parse_fit() {
+        let mut records = Vec::new();
+
+        // Definition of local message 0: record with timestamp, position and altitude.
+        records.extend([0x40, 0, 0]);
+        records.extend(RECORD_MESSAGE.to_le_bytes());
+        records.extend([4, 253, 4, 0x86, 0, 4, 0x85, 1, 4, 0x85, 2, 2, 0x84]);
+
+        // A full record at 2021-01-04T14:49:00Z.
+        let time = (1609771740 - FIT_EPOCH_OFFSET) as u32;
+        records.push(0x00);
+        records.extend(time.to_le_bytes());
+        records.extend(degrees_to_semicircles(45.0).to_le_bytes());
+        records.extend(degrees_to_semicircles(39.0).to_le_bytes());
+        records.extend((((10.0 + 500.0) * 5.0) as u16).to_le_bytes());
+
+        // A record with a compressed timestamp 3 seconds later and an invalid timestamp field.
+        let offset = ((time + 3) & 0x1F) as u8;
+        records.push(0x80 | offset);
+        records.extend(u32::MAX.to_le_bytes());
+        records.extend(degrees_to_semicircles(-45.5).to_le_bytes());
+        records.extend(degrees_to_semicircles(-39.5).to_le_bytes());
+        records.extend(u16::MAX.to_le_bytes());
+
+        let points = parse_fit(&fit_file(&records)).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].time, 1609771740000);
+        assert!((points[0].lat - 45.0).abs() < 1e-6);
+        assert!((points[0].lon - 39.0).abs() < 1e-6);
+        assert_eq!(points[0].altitude, Some(10.0));
+
+        assert_eq!(points[1].time, 1609771743000);
+        assert!((points[1].lat + 45.5).abs() < 1e-6);
+        assert_eq!(points[1].altitude, None);
+    }
+
+    // Define a test function for expanding compressed timestamps.
+    #[test]
+    fn test_expand_timestamp() {
+        assert_eq!(expand_timestamp(0x100, 0x05), 0x105);
+        // The offset wraps around the 5-bit window.
+        assert_eq!(expand_timestamp(0x11E, 0x02), 0x122);
+    }
+
+    // Define a test function for rejecting files that are not FIT files.
+    #[test]
+    fn test_not_fit() {
+        assert!(parse_fit(b"<gpx></gpx>").is_err());
+        assert!(parse_fit(&fit_file(&[0x00, 1, 2, 3])).is_err());
+    }
+}
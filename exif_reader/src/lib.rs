@@ -0,0 +1,29 @@
+// Declare the modules of the library, shared by the binary and the benchmarks.
+pub mod archive;
+pub mod cli;
+pub mod config;
+pub mod dead_letter;
+pub mod directory_reader;
+pub mod elevation;
+pub mod enricher;
+pub mod exif_writer;
+pub mod file_filter;
+pub mod file_state;
+pub mod fit;
+pub mod location_history;
+pub mod logger;
+pub mod media_source;
+pub mod message;
+pub mod postgis;
+pub mod privacy;
+pub mod producer;
+pub mod redaction;
+pub mod s3;
+pub mod scan;
+pub mod scan_report;
+pub mod sftp;
+pub mod sink;
+pub mod spool;
+pub mod track;
+pub mod utils;
+pub mod watch;
@@ -0,0 +1,479 @@
+// Import necessary modules from the project.
+use crate::config::LocationHistoryConfig;
+use crate::enricher::Enricher;
+use crate::file_state::settings_fingerprint;
+use crate::logger;
+use crate::message::PhotoData;
+use crate::utils::invalid_data;
+
+// Import necessary modules from the standard library and external crates.
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use chrono::DateTime;
+use serde_json::Value;
+
+// Define a position of a location history, valid from `start` to `end`
+// (milliseconds since the Unix epoch; equal for a single fix).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryEntry {
+    pub start: i64,
+    pub end: i64,
+    pub lat: f64,
+    pub lon: f64,
+    // Accuracy radius in metres, when the history records one.
+    pub accuracy: Option<f64>,
+}
+
+impl HistoryEntry {
+    // Create a HistoryEntry for a single fix.
+    fn fix(time: i64, lat: f64, lon: f64, accuracy: Option<f64>) -> HistoryEntry {
+        HistoryEntry {
+            start: time,
+            end: time,
+            lat,
+            lon,
+            accuracy,
+        }
+    }
+
+    // Get the time distance between the entry and the given time.
+    fn distance(&self, time: i64) -> i64 {
+        if time < self.start {
+            self.start - time
+        } else if time > self.end {
+            time - self.end
+        } else {
+            0
+        }
+    }
+}
+
+// parse_time is a function that parses an RFC 3339 string or a Unix time number
+// (seconds, or milliseconds for large values) into milliseconds since the Unix epoch.
+fn parse_time(value: &Value) -> Option<i64> {
+    match value {
+        Value::String(text) => DateTime::parse_from_rfc3339(text.trim())
+            .ok()
+            .map(|time| time.timestamp_millis())
+            .or_else(|| text.trim().parse().ok()),
+        Value::Number(number) => {
+            let number = number.as_f64()?;
+            if number.abs() > 1e11 {
+                Some(number as i64)
+            } else {
+                Some((number * 1000.0) as i64)
+            }
+        }
+        _ => None,
+    }
+}
+
+// parse_lat_lng is a function that parses a "45.0439380°, 39.0320850°" or "geo:45.04,39.03" position.
+fn parse_lat_lng(value: &Value) -> Option<(f64, f64)> {
+    let text = value.as_str()?.trim();
+    let text = text.strip_prefix("geo:").unwrap_or(text);
+    let mut parts = text
+        .split(',')
+        .map(|part| part.trim().trim_end_matches('°').parse::<f64>());
+    let lat = parts.next()?.ok()?;
+    let lon = parts.next()?.ok()?;
+    Some((lat, lon))
+}
+
+// parse_e7 is a function that reads a position stored as integers in units of 1e-7 degrees.
+fn parse_e7(value: &Value, lat_key: &str, lon_key: &str) -> Option<(f64, f64)> {
+    let lat = value.get(lat_key)?.as_f64()? / 1e7;
+    let lon = value.get(lon_key)?.as_f64()? / 1e7;
+    Some((lat, lon))
+}
+
+// parse_records is a function that reads the "locations" of a Google Records.json export.
+fn parse_records(locations: &[Value]) -> Vec<HistoryEntry> {
+    locations
+        .iter()
+        .filter_map(|location| {
+            let (lat, lon) = parse_e7(location, "latitudeE7", "longitudeE7")?;
+            let time = location
+                .get("timestamp")
+                .or_else(|| location.get("timestampMs"))
+                .and_then(parse_time)?;
+            let accuracy = location.get("accuracy").and_then(Value::as_f64);
+            Some(HistoryEntry::fix(time, lat, lon, accuracy))
+        })
+        .collect()
+}
+
+// parse_semantic_segments is a function that reads the timeline paths, visits and raw signals
+// of a Google Timeline export.
+fn parse_semantic_segments(root: &Value) -> Vec<HistoryEntry> {
+    let mut entries = Vec::new();
+
+    for segment in root["semanticSegments"].as_array().into_iter().flatten() {
+        // A timeline path is a list of timed points.
+        for point in segment["timelinePath"].as_array().into_iter().flatten() {
+            if let (Some((lat, lon)), Some(time)) =
+                (parse_lat_lng(&point["point"]), parse_time(&point["time"]))
+            {
+                entries.push(HistoryEntry::fix(time, lat, lon, None));
+            }
+        }
+
+        // A visit is a single place for the whole segment.
+        let place = &segment["visit"]["topCandidate"]["placeLocation"]["latLng"];
+        if let (Some((lat, lon)), Some(start), Some(end)) = (
+            parse_lat_lng(place),
+            parse_time(&segment["startTime"]),
+            parse_time(&segment["endTime"]),
+        ) {
+            entries.push(HistoryEntry {
+                start,
+                end,
+                lat,
+                lon,
+                accuracy: None,
+            });
+        }
+    }
+
+    for signal in root["rawSignals"].as_array().into_iter().flatten() {
+        let position = &signal["position"];
+        if let (Some((lat, lon)), Some(time)) = (
+            parse_lat_lng(&position["LatLng"]),
+            parse_time(&position["timestamp"]),
+        ) {
+            let accuracy = position["accuracyMeters"].as_f64();
+            entries.push(HistoryEntry::fix(time, lat, lon, accuracy));
+        }
+    }
+
+    entries
+}
+
+// parse_geojson is a function that reads a GeoJSON timeline: Point features with a time property,
+// and LineString features with per-coordinate times in a "coordTimes" or "times" property.
+fn parse_geojson(root: &Value) -> Vec<HistoryEntry> {
+    let mut entries = Vec::new();
+    let features = match root["type"].as_str() {
+        Some("FeatureCollection") => root["features"].as_array().cloned().unwrap_or_default(),
+        Some("Feature") => vec![root.clone()],
+        _ => Vec::new(),
+    };
+
+    for feature in &features {
+        let properties = &feature["properties"];
+        let geometry = &feature["geometry"];
+        let accuracy = properties["accuracy"].as_f64();
+        let position = |coordinates: &Value| -> Option<(f64, f64)> {
+            Some((coordinates[1].as_f64()?, coordinates[0].as_f64()?))
+        };
+
+        match geometry["type"].as_str() {
+            Some("Point") => {
+                let time = ["time", "timestamp", "datetime"]
+                    .iter()
+                    .find_map(|key| parse_time(&properties[*key]));
+                if let (Some((lat, lon)), Some(time)) = (position(&geometry["coordinates"]), time) {
+                    entries.push(HistoryEntry::fix(time, lat, lon, accuracy));
+                }
+            }
+            Some("LineString") => {
+                let times = properties["coordTimes"]
+                    .as_array()
+                    .or_else(|| properties["times"].as_array());
+                let coordinates = geometry["coordinates"].as_array();
+                if let (Some(times), Some(coordinates)) = (times, coordinates) {
+                    for (time, coordinates) in times.iter().zip(coordinates) {
+                        if let (Some((lat, lon)), Some(time)) =
+                            (position(coordinates), parse_time(time))
+                        {
+                            entries.push(HistoryEntry::fix(time, lat, lon, accuracy));
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    entries
+}
+
+// parse_history is a function that reads the entries of a location history document,
+// detecting its format from its top-level keys.
+pub fn parse_history(root: &Value) -> io::Result<Vec<HistoryEntry>> {
+    if let Some(locations) = root["locations"].as_array() {
+        Ok(parse_records(locations))
+    } else if root.get("semanticSegments").is_some() || root.get("rawSignals").is_some() {
+        Ok(parse_semantic_segments(root))
+    } else if root.get("type").is_some() {
+        Ok(parse_geojson(root))
+    } else {
+        Err(invalid_data("Unknown location history format"))
+    }
+}
+
+// Define a time-sorted index over one or more location histories.
+#[derive(Debug, Clone, Default)]
+pub struct LocationHistory {
+    // Single fixes, sorted by time.
+    fixes: Vec<HistoryEntry>,
+    // Visits spanning a time range, sorted by start time.
+    visits: Vec<HistoryEntry>,
+}
+
+impl LocationHistory {
+    // Create a LocationHistory from unsorted entries.
+    pub fn new(entries: Vec<HistoryEntry>) -> LocationHistory {
+        let (mut visits, mut fixes): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|entry| entry.end > entry.start);
+        fixes.sort_by_key(|entry| entry.start);
+        visits.sort_by_key(|entry| entry.start);
+        LocationHistory { fixes, visits }
+    }
+
+    // Load a LocationHistory from the given JSON or GeoJSON files.
+    pub fn open(files: &[String]) -> io::Result<LocationHistory> {
+        let mut entries = Vec::new();
+        for file in files {
+            let root: Value = serde_json::from_reader(BufReader::new(File::open(Path::new(file))?))
+                .map_err(invalid_data)?;
+            let file_entries = parse_history(&root)?;
+            logger::log_info(&format!(
+                "Loaded {} location history entries from {}",
+                file_entries.len(),
+                file
+            ));
+            entries.extend(file_entries);
+        }
+        Ok(LocationHistory::new(entries))
+    }
+
+    // Get the number of entries in the index.
+    pub fn len(&self) -> usize {
+        self.fixes.len() + self.visits.len()
+    }
+
+    // Check whether the index has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Find the entry closest in time to the given time (milliseconds since the Unix epoch),
+    // if it is at most max_distance milliseconds away.
+    pub fn lookup(&self, time: i64, max_distance: i64) -> Option<HistoryEntry> {
+        // The nearest fix is either just before or just after the given time.
+        let index = self.fixes.partition_point(|entry| entry.start <= time);
+        let mut candidates: Vec<&HistoryEntry> = Vec::new();
+        candidates.extend(index.checked_sub(1).and_then(|i| self.fixes.get(i)));
+        candidates.extend(self.fixes.get(index));
+
+        // Visits don't overlap, so only the last one starting before the time can contain it.
+        let index = self.visits.partition_point(|entry| entry.start <= time);
+        candidates.extend(index.checked_sub(1).and_then(|i| self.visits.get(i)));
+        candidates.extend(self.visits.get(index));
+
+        candidates
+            .into_iter()
+            .filter(|entry| entry.distance(time) <= max_distance)
+            .min_by_key(|entry| entry.distance(time))
+            .copied()
+    }
+}
+
+// Define an enricher that falls back to a location history for photos without a position.
+#[derive(Debug)]
+pub struct LocationHistoryEnricher {
+    history: LocationHistory,
+    max_time_distance: i64,
+    clock_offset: i64,
+    // The history files and settings, fingerprinted.
+    fingerprint: String,
+}
+
+impl LocationHistoryEnricher {
+    // Create a LocationHistoryEnricher from its configuration.
+    pub fn from_config(config: &LocationHistoryConfig) -> io::Result<LocationHistoryEnricher> {
+        let files: Vec<String> = config
+            .files
+            .split(',')
+            .map(|file| file.trim().to_string())
+            .filter(|file| !file.is_empty())
+            .collect();
+
+        let history = LocationHistory::open(&files)?;
+        logger::log_info(&format!(
+            "Indexed {} location history entries",
+            history.len()
+        ));
+
+        let settings = format!("location_history {} {}", config.max_time_distance, config.clock_offset);
+        Ok(LocationHistoryEnricher {
+            history,
+            max_time_distance: config.max_time_distance * 1000,
+            clock_offset: config.clock_offset * 1000,
+            fingerprint: settings_fingerprint(&settings, &files),
+        })
+    }
+}
+
+impl Enricher for LocationHistoryEnricher {
+    fn enrich(&self, data: &mut PhotoData) {
+        if data.has_position() {
+            return;
+        }
+        let Some(time) = parse_time(&Value::from(data.timestamp())) else {
+            return;
+        };
+
+        if let Some(entry) = self
+            .history
+            .lookup(time + self.clock_offset, self.max_time_distance)
+        {
+            data.set_history_position(
+                entry.lat as f32,
+                entry.lon as f32,
+                entry.accuracy.map(|accuracy| accuracy as f32),
+            );
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{Message, PositionSource};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    // Define a test function for reading a Records.json export.
+    #[test]
+    fn test_parse_records() {
+        let root = json!({"locations": [
+            {"latitudeE7": 450439380, "longitudeE7": 390320850, "accuracy": 20,
+             "timestamp": "2021-01-04T14:49:57.000Z"},
+            {"latitudeE7": 450000000, "longitudeE7": 390000000, "timestampMs": "1609771800000"},
+            {"latitudeE7": 450000000, "longitudeE7": 390000000}
+        ]});
+
+        let entries = parse_history(&root).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0],
+            HistoryEntry::fix(1609771797000, 45.043938, 39.032085, Some(20.0))
+        );
+        assert_eq!(entries[1].start, 1609771800000);
+        assert_eq!(entries[1].accuracy, None);
+    }
+
+    // Define a test function for reading semantic segments.
+    #[test]
+    fn test_parse_semantic_segments() {
+        let root = json!({
+            "semanticSegments": [
+                {"startTime": "2021-01-04T14:00:00.000+03:00", "endTime": "2021-01-04T15:00:00.000+03:00",
+                 "timelinePath": [{"point": "45.0439380°, 39.0320850°", "time": "2021-01-04T14:10:00.000+03:00"}]},
+                {"startTime": "2021-01-04T15:00:00.000+03:00", "endTime": "2021-01-04T18:00:00.000+03:00",
+                 "visit": {"topCandidate": {"placeLocation": {"latLng": "45.1°, 39.1°"}}}}
+            ],
+            "rawSignals": [
+                {"position": {"LatLng": "45.2°, 39.2°", "accuracyMeters": 12,
+                              "timestamp": "2021-01-04T19:00:00.000+03:00"}}
+            ]
+        });
+
+        let history = LocationHistory::new(parse_history(&root).unwrap());
+        assert_eq!(history.len(), 3);
+
+        // Inside the visit, hours away from any fix.
+        let entry = history.lookup(1609767000000, 60000).unwrap();
+        assert_eq!((entry.lat, entry.lon), (45.1, 39.1));
+
+        // The raw signal carries an accuracy.
+        let entry = history.lookup(1609776000000 + 60000, 120000).unwrap();
+        assert_eq!(entry.accuracy, Some(12.0));
+    }
+
+    // Define a test function for reading a GeoJSON timeline.
+    #[test]
+    fn test_parse_geojson() {
+        let root = json!({"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {"time": "2021-01-04T14:49:00Z", "accuracy": 5},
+             "geometry": {"type": "Point", "coordinates": [39.0, 45.0]}},
+            {"type": "Feature", "properties": {"coordTimes": [1609771800, 1609771860]},
+             "geometry": {"type": "LineString", "coordinates": [[39.1, 45.1, 10], [39.2, 45.2, 12]]}}
+        ]});
+
+        let entries = parse_history(&root).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[0],
+            HistoryEntry::fix(1609771740000, 45.0, 39.0, Some(5.0))
+        );
+        assert_eq!(
+            entries[2],
+            HistoryEntry::fix(1609771860000, 45.2, 39.2, None)
+        );
+
+        assert!(parse_history(&json!({"something": []})).is_err());
+    }
+
+    // Define a test function for binary searching the nearest fix.
+    #[test]
+    fn test_lookup() {
+        let history = LocationHistory::new(vec![
+            HistoryEntry::fix(3000, 3.0, 3.0, None),
+            HistoryEntry::fix(1000, 1.0, 1.0, None),
+            HistoryEntry::fix(2000, 2.0, 2.0, None),
+        ]);
+
+        assert_eq!(history.lookup(1400, 500).unwrap().lat, 1.0);
+        assert_eq!(history.lookup(1600, 500).unwrap().lat, 2.0);
+        assert_eq!(history.lookup(3400, 500).unwrap().lat, 3.0);
+        assert_eq!(history.lookup(3600, 500), None);
+        assert_eq!(history.lookup(0, 500), None);
+    }
+
+    // Define a test function for geotagging a photo from a location history.
+    #[test]
+    fn test_location_history_enricher() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("Records.json");
+        std::fs::write(
+            &path,
+            json!({"locations": [{"latitudeE7": 450439380, "longitudeE7": 390320850,
+                                  "accuracy": 20, "timestamp": "2021-01-04T14:49:57Z"}]})
+            .to_string(),
+        )
+        .unwrap();
+
+        let enricher = LocationHistoryEnricher::from_config(&LocationHistoryConfig {
+            files: path.display().to_string(),
+            max_time_distance: 600,
+            clock_offset: 0,
+        })
+        .unwrap();
+
+        let mut photo = PhotoData::default();
+        photo.build("DateTime", "2021-01-04 14:55:00");
+        enricher.enrich(&mut photo);
+        assert_eq!(photo.position_source(), Some(PositionSource::History));
+
+        let message = Message::new(HashMap::from([("title".to_string(), photo)]));
+        assert_eq!(message.value["position_source"], "history");
+        assert_eq!(message.value["accuracy"], 20.0);
+
+        // Too far from the only fix.
+        let mut photo = PhotoData::default();
+        photo.build("DateTime", "2021-01-04 15:55:00");
+        enricher.enrich(&mut photo);
+        assert_eq!(photo.position_source(), None);
+    }
+}
@@ -1,30 +1,106 @@
-// Import necessary modules and libraries.
-mod config;
-mod directory_reader;
-mod message;
-mod producer;
-mod utils;
-mod logger;
+// Import the modules of the library.
+use exif_reader::{cli, config, enricher, exif_writer, file_filter, logger, redaction, scan_report, track, watch};
 
-
-// Import the 'produce' function from the 'producer' module.
-use producer::produce;
+// Import the scan pipeline, shared with the benchmarks.
+use config::TrackConfig;
+use enricher::Enricher;
+use exif_reader::scan::{log_outcome, offset_time, open_track, scan, walk_options, Files, ScanEnrichers, ScanSettings};
+use file_filter::FilterOptions;
+use scan_report::ScanReport;
+use track::TrackOptions;
 
 // Import dotenv for environment variable loading, tonic for gRPC, and other modules.
 use dotenv::dotenv;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
 use tonic::{transport::Server, Request, Response};
 
 // Import the generated gRPC code for ExifReaders service.
 use exif_readers::exif_readers_server::{ExifReaders, ExifReadersServer};
-use exif_readers::{ExifReaderRequest, ExifReadersReply};
+use exif_readers::{ExifReaderRequest, ExifReadersReply, FileFailure, PublishFailure, WalkError};
 
 pub mod exif_readers {
     tonic::include_proto!("exif_readers");
 }
 
 // Define a struct for the ExifReaderService.
-#[derive(Debug, Default)]
-pub struct ExifReaderService {}
+pub struct ExifReaderService {
+    // Defaults for track log correlation requests.
+    track_config: TrackConfig,
+    // Enrichers applied to every extracted photo.
+    enrichers: Arc<Vec<Box<dyn Enricher>>>,
+    // Settings of the scans.
+    settings: Arc<ScanSettings>,
+}
+
+impl ExifReaderService {
+    // Create a new ExifReaderService with the provided enrichers and scan settings.
+    pub fn new(track_config: TrackConfig, enrichers: Arc<Vec<Box<dyn Enricher>>>, settings: Arc<ScanSettings>) -> Self {
+        ExifReaderService {
+            track_config,
+            enrichers,
+            settings,
+        }
+    }
+}
+
+// reply is a function that converts the report of a scan into the gRPC reply.
+fn reply(report: ScanReport) -> ExifReadersReply {
+    ExifReadersReply {
+        walk_errors: report
+            .walk_errors
+            .into_iter()
+            .map(|error| WalkError {
+                path: error.path.unwrap_or_default(),
+                error: error.error,
+            })
+            .collect(),
+        scan_id: report.scan_id,
+        dead_letters: report.dead_letters as u64,
+        spooled: report.spooled as u64,
+        spool_bytes: report.spool_bytes,
+        spool_age_secs: report.spool_age_secs,
+        directory: report.directory,
+        started_at: report.started_at,
+        elapsed_ms: report.elapsed_ms,
+        files_seen: report.files_seen as u64,
+        unchanged: report.unchanged as u64,
+        moved: report.moved as u64,
+        deleted: report.deleted as u64,
+        parsed: report.parsed as u64,
+        with_gps: report.with_gps as u64,
+        without_gps: report.without_gps as u64,
+        unsupported: report.unsupported as u64,
+        errored: report.errored as u64,
+        published: report.published as u64,
+        failed_to_publish: report.failed_to_publish as u64,
+        withheld: report.withheld as u64,
+        duplicates: report.duplicates as u64,
+        publish_failures: report
+            .publish_failures
+            .into_iter()
+            .map(|failure| PublishFailure {
+                key: failure.key,
+                reason: failure.reason,
+            })
+            .collect(),
+        failures: report
+            .failures
+            .into_iter()
+            .map(|failure| FileFailure {
+                path: failure.path,
+                // The JSON name of the failure, such as "unsupported".
+                failure: serde_json::to_value(failure.failure)
+                    .ok()
+                    .and_then(|value| value.as_str().map(str::to_string))
+                    .unwrap_or_default(),
+                reason: failure.reason,
+            })
+            .collect(),
+        error: report.error,
+    }
+}
 
 // Implement the gRPC service trait for ExifReaderService.
 #[tonic::async_trait]
@@ -39,28 +115,69 @@ impl ExifReaders for ExifReaderService {
         logger::log_info("Starting request from remote client");
         
         // Extract the 'directory_name' from the gRPC request.
-        let directory_name = &request.into_inner().directory_name;
+        let request = request.into_inner();
+        let directory_name = &request.directory_name;
         
         // Log a debug message containing the 'directory_name'.
         logger::log_debug("{directory_name}");
 
-        // Attempt to retrieve messages by walking the specified directory.
-        if let Ok(messages) = directory_reader::walking(directory_name) {
-            // Produce the retrieved messages.
-            let result = produce(messages).await;
-
-            // Check the result of message production.
-            if result.is_err() {
-                // Log an error message if message production fails.
-                logger::log_error(&format!("Error while producing messages, {:?}", result));
-            } else {
-                // Log an informational message if message production is successful.
-                logger::log_info("Successfully delivering messages");
-            }
-        }
+        // Select the files with the filter options of the request.
+        let filter = FilterOptions {
+            include: request.include,
+            exclude: request.exclude,
+            extensions: request.extensions,
+            skip_hidden: request.skip_hidden,
+            max_depth: request.max_depth.map(|depth| depth as usize),
+            follow_symlinks: request.follow_symlinks,
+            same_file_system: request.same_file_system,
+            min_size: request.min_size,
+            max_size: request.max_size,
+            sniff: request.sniff,
+            archives: request.archives,
+            max_archive_size: request.max_archive_size,
+        };
+        let walk_options = walk_options(&self.settings, &filter, request.full_rescan)
+            .map_err(|error| tonic::Status::invalid_argument(error.to_string()))?;
+
+        // Load the track logs given with the request, only from the track directory.
+        let files = track::requested_files(&request.track_files, self.track_config.directory.as_deref())
+            .map_err(|error| match error.kind() {
+                std::io::ErrorKind::PermissionDenied => tonic::Status::permission_denied(error.to_string()),
+                _ => tonic::Status::invalid_argument(error.to_string()),
+            })?;
+        let options = TrackOptions {
+            files,
+            clock_offset: request.clock_offset_seconds,
+            max_gap: (request.max_gap_seconds > 0).then_some(request.max_gap_seconds as i64),
+        };
+        let track = open_track(&options, &self.track_config)
+            .map_err(|error| tonic::Status::invalid_argument(error.to_string()))?;
+        // Write to the sink of the request, or the default one.
+        let sink = self
+            .settings
+            .sinks
+            .get(request.sink.as_deref())
+            .map_err(|error| tonic::Status::invalid_argument(error.to_string()))?;
 
-        // Return a gRPC response indicating the completion of the request.
-        Ok(Response::new(ExifReadersReply {}))
+        // Check the result of the scan.
+        let enrichers = ScanEnrichers {
+            track: track.map(Arc::new),
+            configured: self.enrichers.clone(),
+        };
+        let report = scan(
+            directory_name,
+            Files::Directory,
+            enrichers,
+            None,
+            walk_options,
+            sink.as_ref(),
+            &self.settings,
+        )
+        .await;
+        log_outcome(&report);
+
+        // Return a gRPC response with the report of the scan.
+        Ok(Response::new(reply(report)))
     }
 }
 
@@ -72,26 +189,167 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Initialize the logger.
     logger::init_logger();
-    
+
+    // Run the commands that don't publish anything without reading the configuration.
+    let command = match cli::parse() {
+        cli::Command::Geotag {
+            path,
+            geotag,
+            options,
+        } => {
+            let report = exif_writer::write_geotag(std::path::Path::new(&path), &geotag, &options)?;
+            exif_writer::log_report(&report);
+            return Ok(());
+        }
+        cli::Command::Redact {
+            source,
+            destination,
+            options,
+            report,
+        } => {
+            let redaction = redaction::redact_directory(&source, &destination, &options)?;
+            redaction.log();
+            if let Some(report) = report {
+                std::fs::write(report, serde_json::to_string_pretty(&redaction)?)?;
+            }
+            return Ok(());
+        }
+        command => command,
+    };
+
     // Log an informational message indicating the start of the service.
     logger::log_info("Start service");
 
-    // Retrieve gRPC configuration from environment variables.
-    let grpc_conf = config::Config::from_env().unwrap();
-    let addr = format!("{}:{}", grpc_conf.grpcserver.server, grpc_conf.grpcserver.port).parse().unwrap();
+    // Retrieve configuration from environment variables; a broken one stops the service before it starts.
+    let grpc_conf = config::Config::from_env()
+        .map_err(|error| format!("Error while reading the configuration, {}", error))?;
+    let enrichers = Arc::new(enricher::from_config(&grpc_conf)?);
+    let mut settings = ScanSettings::from_config(&grpc_conf)?;
+    let sinks = settings.sinks.clone();
+
+    // Run a single command from the command line instead of serving, if asked to.
+    match command {
+        cli::Command::Scan {
+            directory,
+            track,
+            write_back,
+            threads,
+            ordered,
+            strict,
+            filter,
+            report,
+            full,
+            sink,
+        } => {
+            let offset_time = offset_time(&track);
+            let track = open_track(&track, &grpc_conf.track)?;
+            let write_back = write_back.map(|options| (options, offset_time));
+            // Command line options override the configuration.
+            settings.walk_options.threads = threads.unwrap_or(settings.walk_options.threads);
+            settings.walk_options.ordered = ordered.unwrap_or(settings.walk_options.ordered);
+            settings.walk_options.strict = strict.unwrap_or(settings.walk_options.strict);
+            settings.report_dir = report.or(settings.report_dir);
+            let walk_options = walk_options(&settings, &filter, full)?;
+            let enrichers = ScanEnrichers {
+                track: track.map(Arc::new),
+                configured: enrichers,
+            };
+            let sink = settings.sinks.get(sink.as_deref())?;
+            let settings = Arc::new(settings);
+            let report = scan(&directory, Files::Directory, enrichers, write_back, walk_options, sink.as_ref(), &settings).await;
+            sink.flush();
+            return match report.error {
+                Some(error) => Err(error.into()),
+                None if report.failed_to_publish > 0 => {
+                    Err(format!("{} messages failed to publish", report.failed_to_publish).into())
+                }
+                None => Ok(()),
+            };
+        }
+        cli::Command::Watch {
+            directory,
+            settle_ms,
+            threads,
+            filter,
+            sink,
+        } => {
+            let sink = settings.sinks.get(sink.as_deref())?;
+            settings.walk_options.threads = threads.unwrap_or(settings.walk_options.threads);
+            let walk_options = walk_options(&settings, &filter, false)?;
+            let enrichers = ScanEnrichers {
+                track: None,
+                configured: enrichers,
+            };
+            let settings = Arc::new(settings);
+            // Watch before the first scan, so that the files changing during the scan aren't missed.
+            let settle = Duration::from_millis(settle_ms.unwrap_or(grpc_conf.watch.settle_ms));
+            let mut watcher = watch::Watcher::new(&directory, settle)?;
+            logger::log_info(&format!("Watching {}", directory));
+
+            // Catch up on the files changed while nothing was watching; with a file-state cache
+            // only those are produced.
+            let report = scan(&directory, Files::Directory, enrichers.clone(), None, walk_options.clone(), sink.as_ref(), &settings).await;
+            log_outcome(&report);
+            // Wait for changes on a thread of their own, so that stopping doesn't wait for the next change.
+            let (changed, mut changes) = mpsc::channel(1);
+            std::thread::spawn(move || loop {
+                let batch = watcher.next_batch();
+                let stopped = batch.is_err();
+                if changed.blocking_send(batch).is_err() || stopped {
+                    break;
+                }
+            });
+            loop {
+                // Scan the changes until interrupted.
+                let changes = tokio::select! {
+                    changes = changes.recv() => changes,
+                    _ = tokio::signal::ctrl_c() => break,
+                };
+                let Some(changes) = changes else {
+                    break;
+                };
+                // Scan the whole directory again once events were lost.
+                let files = match changes? {
+                    watch::Changes::Files(files) => Files::Changed(files),
+                    watch::Changes::Rescan => {
+                        logger::log_info(&format!("Watch events were lost, scanning {} again", directory));
+                        Files::Directory
+                    }
+                };
+                let report = scan(&directory, files, enrichers.clone(), None, walk_options.clone(), sink.as_ref(), &settings).await;
+                log_outcome(&report);
+            }
+            // Deliver the messages still queued before exiting.
+            logger::log_info(&format!("Stopping the watch of {}", directory));
+            sink.flush();
+            return Ok(());
+        }
+        // Serve otherwise; geotag and redact already returned.
+        _ => (),
+    }
+
+    let addr: std::net::SocketAddr = format!("{}:{}", grpc_conf.grpcserver.server, grpc_conf.grpcserver.port)
+        .parse()
+        .map_err(|error| format!("Invalid gRPC server address, {}", error))?;
 
-    // Create an instance of the ExifReaderService.
-    let serv = ExifReaderService::default();
+    // Create an instance of the ExifReaderService with the configured enrichers.
+    let serv = ExifReaderService::new(grpc_conf.track, enrichers, Arc::new(settings));
     
     // Log an informational message indicating the start of the gRPC server.
     logger::log_info(&format!("Start gRPC server on {}:{}", grpc_conf.grpcserver.server, grpc_conf.grpcserver.port));
     
-    // Start the gRPC server and serve on the specified address.
+    // Start the gRPC server and serve on the specified address until interrupted.
     Server::builder()
         .add_service(ExifReadersServer::new(serv))
-        .serve(addr)
+        .serve_with_shutdown(addr, async {
+            let _ = tokio::signal::ctrl_c().await;
+            logger::log_info("Stopping gRPC server");
+        })
         .await?;
 
+    // Deliver the messages still queued before exiting.
+    sinks.flush();
+
     // Return a successful result.
     Ok(())
 }
@@ -99,8 +357,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 // Define a module for testing.
 #[cfg(test)]
 mod test {
-    use crate::directory_reader::walking;
-    use crate::producer::produce;
+    use exif_reader::config::Config;
+    use exif_reader::scan::{scan, walk_options, Files, ScanEnrichers, ScanSettings};
+    use exif_reader::sink::KAFKA;
+    use std::sync::Arc;
 
     // Define an integration test function.
     #[tokio::test]
@@ -108,12 +368,16 @@ mod test {
         // Define a test directory.
         let directory = "../test_data/";
 
-        // Retrieve messages by walking the test directory.
-        let messages = walking(directory);
-        assert!(matches!(messages, Ok(_)));
-
-        // Produce the retrieved messages.
-        let result = produce(messages.unwrap()).await;
-        assert!(matches!(result, Ok(_)));
+        // Scan the test directory into Kafka, as the service does.
+        let settings = Arc::new(ScanSettings::from_config(&Config::from_env().unwrap()).unwrap());
+        let walk_options = walk_options(&settings, &Default::default(), true).unwrap();
+        let enrichers = ScanEnrichers {
+            track: None,
+            configured: Arc::new(Vec::new()),
+        };
+        let sink = settings.sinks.get(Some(KAFKA)).unwrap();
+        let report = scan(directory, Files::Directory, enrichers, None, walk_options, sink.as_ref(), &settings).await;
+        assert!(report.error.is_none());
+        assert_eq!(report.failed_to_publish, 0);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,264 @@
+// Import necessary modules from the project.
+use crate::archive;
+use crate::config::Config;
+use crate::file_filter::FileFilter;
+use crate::s3::S3Source;
+use crate::sftp::SftpSource;
+
+// Import necessary modules from the standard library and external crates.
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+// Define a file listed by a media source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceEntry {
+    // The location of the file, such as /photos/a.jpg or s3://bucket/photos/a.jpg; used as the record path and Kafka key.
+    pub path: String,
+    // The size of the file, in bytes.
+    pub size: u64,
+    // The modification time of the file, in nanoseconds since the Unix epoch.
+    pub mtime: i64,
+    // A fingerprint of the contents given by the source, such as an S3 ETag, if any.
+    pub etag: Option<String>,
+}
+
+// MediaSource is a trait for the places photos are scanned from.
+pub trait MediaSource: Send + Sync {
+    // List the files under a location, recursively.
+    fn list<'a>(
+        &'a self,
+        root: &str,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<SourceEntry>> + Send + 'a>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} can't be listed", root),
+        ))
+    }
+
+    // Get the size and modification time of a file.
+    fn stat(&self, path: &str) -> io::Result<SourceEntry>;
+
+    // Read a file from an offset, up to a length or to its end.
+    fn open_range(
+        &self,
+        path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send + 'static>>;
+
+    // Check whether a file was removed; files that can't be checked aren't.
+    fn is_gone(&self, path: &str) -> bool {
+        matches!(self.stat(path), Err(error) if error.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+// Define the local file system source. Local directories are walked by directory_reader::walking_each,
+// which also descends into archives, so this source only reads files and tells removed ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFs;
+
+// modified is a function that returns the modification time of a local file, in nanoseconds.
+fn modified(metadata: &fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or_default()
+}
+
+impl MediaSource for LocalFs {
+    fn stat(&self, path: &str) -> io::Result<SourceEntry> {
+        let metadata = fs::metadata(path)?;
+        Ok(SourceEntry {
+            path: path.to_string(),
+            size: metadata.len(),
+            mtime: modified(&metadata),
+            etag: None,
+        })
+    }
+
+    fn open_range(
+        &self,
+        path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send + 'static>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(match length {
+            Some(length) => Box::new(file.take(length)),
+            None => Box::new(file),
+        })
+    }
+
+    // Archive entries are gone with their archive.
+    fn is_gone(&self, path: &str) -> bool {
+        let path = archive::archive_of(path);
+        matches!(fs::symlink_metadata(path), Err(error) if error.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+// is_remote is a function that checks whether a location is a URL rather than a local path.
+pub fn is_remote(location: &str) -> bool {
+    location.contains("://")
+}
+
+// Define the remote sources a scan can read from, by the scheme of its location.
+#[derive(Default)]
+pub struct Sources {
+    s3: Option<crate::config::S3Config>,
+    sftp: Option<crate::config::SftpConfig>,
+}
+
+impl Sources {
+    // Read the configuration of the remote sources.
+    pub fn from_config(config: &Config) -> Sources {
+        Sources {
+            s3: config.s3.clone(),
+            sftp: config.sftp.clone(),
+        }
+    }
+
+    // open is a method that returns the source of a location.
+    // Parameters:
+    // - location: A local directory, or an s3://bucket/prefix or sftp://host/directory URL.
+    // Returns:
+    // - io::Result<Option<Arc<dyn MediaSource>>>: The remote source, None for a local directory,
+    //   or an std::io::Error if the scheme is unknown or not configured, or the source can't be reached.
+    pub fn open(&self, location: &str) -> io::Result<Option<Arc<dyn MediaSource>>> {
+        let Some((scheme, _)) = location.split_once("://") else {
+            return Ok(None);
+        };
+        let unconfigured = |name: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't configured to scan {}", name, location),
+            )
+        };
+        match scheme {
+            "s3" => {
+                let config = self.s3.as_ref().ok_or_else(|| unconfigured("S3"))?;
+                Ok(Some(Arc::new(S3Source::new(config))))
+            }
+            "sftp" => {
+                let config = self.sftp.as_ref().ok_or_else(|| unconfigured("SFTP"))?;
+                Ok(Some(Arc::new(SftpSource::connect(config, location)?)))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown scheme {} of {}", scheme, location),
+            )),
+        }
+    }
+}
+
+// Define a seekable reader of a file of a media source, reading ranges as needed,
+// so that only the parts of a photo holding its EXIF data are fetched.
+pub struct RangeReader<'a> {
+    source: &'a dyn MediaSource,
+    path: String,
+    size: u64,
+    position: u64,
+    // The reader of the range starting at the position, opened on the first read.
+    reader: Option<Box<dyn Read + Send>>,
+}
+
+impl<'a> RangeReader<'a> {
+    // Create a reader of a listed file.
+    pub fn new(source: &'a dyn MediaSource, entry: &SourceEntry) -> RangeReader<'a> {
+        RangeReader {
+            source,
+            path: entry.path.clone(),
+            size: entry.size,
+            position: 0,
+            reader: None,
+        }
+    }
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => self
+                .reader
+                .insert(self.source.open_range(&self.path, self.position, None)?),
+        };
+        let read = reader.read(buffer)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for RangeReader<'_> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the file",
+            )
+        })?;
+        // Moving elsewhere needs another range.
+        if position != self.position {
+            self.position = position;
+            self.reader = None;
+        }
+        Ok(position)
+    }
+}
+
+// keep is a function that checks whether a listed file has to be extracted, with the filter
+// applied to its path relative to the scanned location.
+pub fn keep(filter: &FileFilter, root: &str, entry: &SourceEntry) -> bool {
+    let relative = entry
+        .path
+        .strip_prefix(root)
+        .unwrap_or(&entry.path)
+        .trim_start_matches('/');
+    filter.keep_listed(relative, entry.size)
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Define a test function for reading local files by range.
+    #[test]
+    fn test_range_reader() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.jpg");
+        fs::write(&path, b"0123456789").unwrap();
+        let path = path.to_str().unwrap();
+        let entry = LocalFs.stat(path).unwrap();
+        assert_eq!(entry.size, 10);
+
+        // Assert that reads continue after seeks, from the start and from the end.
+        let mut reader = RangeReader::new(&LocalFs, &entry);
+        let mut buffer = [0; 3];
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"012");
+        reader.seek(SeekFrom::End(-2)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"89");
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+
+        // Assert that the local source tells removed files.
+        assert!(!LocalFs.is_gone(path));
+        fs::remove_file(path).unwrap();
+        assert!(LocalFs.is_gone(path));
+        assert!(!is_remote(path) && is_remote("s3://photos/2014"));
+    }
+}
@@ -4,20 +4,79 @@ extern crate exif;
 use std::{
     collections::HashMap,
     fmt::{Debug, Display},
+    io::{BufRead, Seek},
     str::FromStr,
 };
 
+use crate::file_state::FileState;
 use crate::utils::{convert_coordinate, convert_time_to_iso_format};
 use exif::{In, Tag};
 use regex::Regex;
+use serde::Serialize;
 use serde_json::json;
 
+// Define where the altitude of a photo comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AltitudeSource {
+    // Altitude read from the GPSAltitude EXIF tag.
+    Gps,
+    // Ground elevation looked up in a local DEM tile.
+    Dem,
+    // Elevation interpolated from a GPS track log.
+    Track,
+}
+
+// Define where the position of a photo comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionSource {
+    // Position read from the GPS EXIF tags.
+    Exif,
+    // Position interpolated from a GPS track log.
+    Track,
+    // Position looked up in a phone location history.
+    History,
+}
+
+// Define what happened to the file of a message since the last scan.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventType {
+    // The file is new; without a file-state cache every file is new.
+    Created,
+    // The file changed.
+    Updated,
+    // The file was removed.
+    Deleted,
+    // The file was moved or renamed without being changed.
+    Moved,
+    // The file, published before, is now inside a privacy zone that drops it.
+    Withheld,
+}
+
+impl Display for EventType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            EventType::Created => "created",
+            EventType::Updated => "updated",
+            EventType::Deleted => "deleted",
+            EventType::Moved => "moved",
+            EventType::Withheld => "withheld",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 // Define a struct to hold photo data.
 #[derive(Debug, Clone)]
 pub struct PhotoData {
     lat: f32,
     long: f32,
     altitude: f32,
+    altitude_source: Option<AltitudeSource>,
+    position_source: Option<PositionSource>,
+    accuracy: Option<f32>,
     name: String,
     path: String,
     timestamp: String,
@@ -31,6 +90,9 @@ impl Default for PhotoData {
             lat: 0.0,
             long: 0.0,
             altitude: 0.0,
+            altitude_source: None,
+            position_source: None,
+            accuracy: None,
             name: "name".to_string(),
             path: "path".to_string(),
             timestamp: "".to_string(),
@@ -47,38 +109,115 @@ impl PhotoData {
             lat: 0.0,
             long: 0.0,
             altitude: 0.0,
-            name: name,
-            path: path,
+            altitude_source: None,
+            position_source: None,
+            accuracy: None,
+            name,
+            path,
             timestamp: "".to_string(),
         }
     }
 
     // Set the longitude value of PhotoData.
     fn set_long(&mut self, row_long: &str) {
-        self.long = convert_coordinate(row_long)
+        self.long = convert_coordinate(row_long);
+        self.position_source = Some(PositionSource::Exif);
     }
 
     // Set the latitude value of PhotoData.
     fn set_lat(&mut self, row_lat: &str) {
-        self.lat = convert_coordinate(row_lat)
+        self.lat = convert_coordinate(row_lat);
+        self.position_source = Some(PositionSource::Exif);
     }
 
-    // Set the altitude value of PhotoData.
+    // Set the altitude value of PhotoData; altitudes below sea level, from GPSAltitudeRef, are negative.
     fn set_altitude(&mut self, row_altitude: &str) {
         // Define a regular expression pattern for altitude extraction.
         let re = Regex::new(r"^(\d*[.]?\d+)").unwrap();
         for r in re.captures_iter(row_altitude) {
-            self.altitude = FromStr::from_str(&r[1]).unwrap();
+            let altitude: f32 = FromStr::from_str(&r[1]).unwrap();
+            self.altitude = if row_altitude.ends_with("below sea level") { -altitude } else { altitude };
+            self.altitude_source = Some(AltitudeSource::Gps);
+        }
+    }
+
+    // Get the latitude value of PhotoData.
+    pub fn lat(&self) -> f32 {
+        self.lat
+    }
+
+    // Get the longitude value of PhotoData.
+    pub fn long(&self) -> f32 {
+        self.long
+    }
+
+    // Get the timestamp of PhotoData in ISO 8601 format (empty if unknown).
+    pub fn timestamp(&self) -> &str {
+        &self.timestamp
+    }
+
+    // Get the altitude source of PhotoData, if an altitude is known.
+    pub fn altitude_source(&self) -> Option<AltitudeSource> {
+        self.altitude_source
+    }
+
+    // Get the position source of PhotoData, if a position is known.
+    pub fn position_source(&self) -> Option<PositionSource> {
+        self.position_source
+    }
+
+    // Check whether PhotoData carries a position.
+    pub fn has_position(&self) -> bool {
+        self.position_source.is_some()
+    }
+
+    // Set the position of PhotoData from a GPS track log.
+    // The altitude is only taken from the track when the photo has none.
+    pub fn set_track_position(&mut self, lat: f32, long: f32, altitude: Option<f32>) {
+        self.lat = lat;
+        self.long = long;
+        self.position_source = Some(PositionSource::Track);
+        if let (None, Some(altitude)) = (self.altitude_source, altitude) {
+            self.altitude = altitude;
+            self.altitude_source = Some(AltitudeSource::Track);
         }
     }
 
+    // Set the position of PhotoData from a location history, with its accuracy radius in metres.
+    pub fn set_history_position(&mut self, lat: f32, long: f32, accuracy: Option<f32>) {
+        self.lat = lat;
+        self.long = long;
+        self.position_source = Some(PositionSource::History);
+        self.accuracy = accuracy;
+    }
+
+    // Replace the altitude of PhotoData with a ground elevation from a DEM tile.
+    pub fn set_dem_altitude(&mut self, altitude: f32) {
+        self.altitude = altitude;
+        self.altitude_source = Some(AltitudeSource::Dem);
+    }
+
+    // Shift the altitude of PhotoData by the given offset, keeping its source.
+    pub fn shift_altitude(&mut self, offset: f32) {
+        self.altitude += offset;
+    }
+
     // Build PhotoData attributes based on provided tags and values.
-    fn build(&mut self, tags: &str, values: &str) {
+    pub fn build(&mut self, tags: &str, values: &str) {
         match tags {
             "GPSLatitude" => self.set_lat(values),
             "GPSLongitude" => self.set_long(values),
             "GPSAltitude" => self.set_altitude(values),
-            "DateTime" => self.timestamp = convert_time_to_iso_format(values),
+            // The capture time is preferred; the time the file was last changed is only a fallback,
+            // also taken when the capture time is zeroed or unreadable.
+            "DateTimeOriginal" => {
+                if let Some(timestamp) = convert_time_to_iso_format(values) {
+                    self.timestamp = timestamp;
+                }
+            }
+            "DateTime" if self.timestamp.is_empty() => {
+                self.timestamp = convert_time_to_iso_format(values).unwrap_or_default();
+            }
             &_ => (),
         };
     }
@@ -99,6 +238,14 @@ impl Display for PhotoData {
 pub struct Message {
     pub key: String,
     pub value: serde_json::Value,
+    // Kafka headers sent along with the payload, such as the applied privacy policy.
+    pub headers: Vec<(String, String)>,
+    // The state of the source file, cached once the message is delivered.
+    pub source: Option<FileState>,
+    // What happened to the file since the last scan.
+    pub event: EventType,
+    // The previous path of a moved file.
+    pub moved_from: Option<String>,
 }
 
 // Implement methods for the Message struct.
@@ -118,44 +265,96 @@ impl Message {
                 "lat": data.lat,
                 "long": data.long,
                 "altitude": data.altitude,
+                "altitude_source": data.altitude_source,
+                "position_source": data.position_source,
+                "accuracy": data.accuracy,
                 "tmstmp": data.timestamp,
             });
         }
 
         Message {
             key: title,
-            value: value,
+            value,
+            headers: vec![("event_type".to_string(), EventType::Created.to_string())],
+            source: None,
+            event: EventType::Created,
+            moved_from: None,
+        }
+    }
+
+    // Create a tombstone: a message without payload that removes its key from compacted topics.
+    // The event is sent in the event_type header.
+    pub fn tombstone(key: &str, event: EventType) -> Self {
+        Message {
+            key: key.to_string(),
+            value: serde_json::Value::Null,
+            headers: vec![("event_type".to_string(), event.to_string())],
+            source: None,
+            event,
+            moved_from: None,
         }
     }
+
+    // Check whether the message is a tombstone.
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_null()
+    }
+
+    // Set a header, replacing the previous value of the header.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(header, _)| header != name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    // Set what happened to the file since the last scan. The event is only sent in the event_type header,
+    // so that the payload of a file stays the same whatever happened to it.
+    pub fn set_event(&mut self, event: EventType) {
+        self.event = event;
+        self.set_header("event_type", &event.to_string());
+    }
+
+    // Mark the file as moved from its previous path, sent in the moved_from header.
+    pub fn set_moved_from(&mut self, path: &str) {
+        self.set_event(EventType::Moved);
+        self.moved_from = Some(path.to_string());
+        self.set_header("moved_from", path);
+    }
 }
 
 // Define a function to extract EXIF data from a photo file.
 pub fn get_exif(filename: &str) -> Result<HashMap<String, PhotoData>, exif::Error> {
+    let file = std::fs::File::open(filename)?;
+    get_exif_from(filename, &mut std::io::BufReader::new(&file))
+}
+
+// Define a function to extract EXIF data from a photo read from elsewhere, such as an archive entry;
+// the photo is recorded under the provided path.
+pub fn get_exif_from<R: BufRead + Seek>(
+    path: &str,
+    reader: &mut R,
+) -> Result<HashMap<String, PhotoData>, exif::Error> {
     // Define an array of EXIF tags to extract.
     let exif_tags = [
         Tag::GPSLatitude,
         Tag::GPSLongitude,
         Tag::GPSAltitude,
+        Tag::DateTimeOriginal,
         Tag::DateTime,
     ];
 
     let mut photo: HashMap<String, PhotoData> = HashMap::new();
 
-    for path in [filename] {
-        let file = std::fs::File::open(path)?;
-        let mut bufreader = std::io::BufReader::new(&file);
-        let exifreader = exif::Reader::new();
-        let exif = exifreader.read_from_container(&mut bufreader)?;
+    let exifreader = exif::Reader::new();
+    let exif = exifreader.read_from_container(reader)?;
 
-        let data = photo
-            .entry(path.to_string())
-            .or_insert(PhotoData::new(path.to_string(), path.to_string()));
+    let data = photo
+        .entry(path.to_string())
+        .or_insert(PhotoData::new(path.to_string(), path.to_string()));
 
-        for &tag in exif_tags.iter() {
-            if let Some(field) = exif.get_field(tag, In::PRIMARY) {
-                let f = field.display_value().with_unit(&exif).to_string();
-                data.build(&format!("{}", tag), &f);
-            }
+    for &tag in exif_tags.iter() {
+        if let Some(field) = exif.get_field(tag, In::PRIMARY) {
+            let f = field.display_value().with_unit(&exif).to_string();
+            data.build(&format!("{}", tag), &f);
         }
     }
 
@@ -165,15 +364,17 @@ pub fn get_exif(filename: &str) -> Result<HashMap<String, PhotoData>, exif::Erro
 // Define a module for testing.
 #[cfg(test)]
 mod test {
-    use crate::message::{get_exif, PhotoData};
+    use std::collections::HashMap;
+
+    use crate::message::{get_exif, get_exif_from, AltitudeSource, EventType, Message, PhotoData};
 
     // Define a test function for extracting EXIF data.
     #[test]
     fn test_get_exif() {
         // Define a test photo file.
         let filename = "../test_data/test_1.jpg";
-        let filedata = get_exif(&filename);
-        assert!(matches!(filedata, Ok(_)));
+        let filedata = get_exif(filename);
+        assert!(filedata.is_ok());
         
         let filedata = filedata.unwrap();
         let filename = "../test_data/test_1.jpg";
@@ -190,8 +391,8 @@ mod test {
     #[test]
     fn test_wrong_directory() {
         let filename = "../../test_data/test_1.jpg";
-        let filedata = get_exif(&filename);
-        assert!(matches!(filedata, Err(_)));
+        let filedata = get_exif(filename);
+        assert!(filedata.is_err());
     }
 
     // Define a test function for default PhotoData values.
@@ -204,5 +405,112 @@ mod test {
         assert_eq!(photo_data.long, 0.0);
         assert_eq!(photo_data.lat, 0.0);
     }
-}
 
+    // Define a test function for the altitude source of a message.
+    #[test]
+    fn test_altitude_source() {
+        let mut photo_data = PhotoData::default();
+        assert_eq!(photo_data.altitude_source(), None);
+
+        // Reading GPSAltitude marks the altitude as coming from GPS.
+        photo_data.build("GPSAltitude", "27.813 m");
+        assert_eq!(photo_data.altitude_source(), Some(AltitudeSource::Gps));
+
+        // A DEM altitude replaces it and is reported in the message.
+        photo_data.set_dem_altitude(31.5);
+        let message = Message::new(HashMap::from([("title".to_string(), photo_data)]));
+        assert_eq!(message.value["altitude"], 31.5);
+        assert_eq!(message.value["altitude_source"], "dem");
+    }
+
+    // Define a test function for the references of the GPS position.
+    #[test]
+    fn test_position_reference() {
+        // Assert that southern latitudes, western longitudes and altitudes below sea level are negative.
+        let mut photo_data = PhotoData::default();
+        photo_data.build("GPSLatitude", "33 deg 45 min 0 sec S");
+        photo_data.build("GPSLongitude", "70 deg 30 min 0 sec W");
+        photo_data.build("GPSAltitude", "5.5 meters below sea level");
+        assert_eq!((photo_data.lat(), photo_data.long(), photo_data.altitude), (-33.75, -70.5, -5.5));
+
+        // Assert that northern latitudes, eastern longitudes and altitudes above sea level are positive.
+        photo_data.build("GPSLatitude", "33 deg 45 min 0 sec N");
+        photo_data.build("GPSLongitude", "70 deg 30 min 0 sec E");
+        photo_data.build("GPSAltitude", "5.5 meters above sea level");
+        assert_eq!((photo_data.lat(), photo_data.long(), photo_data.altitude), (33.75, 70.5, 5.5));
+    }
+
+    // Define a test function for the event of a message.
+    #[test]
+    fn test_event_headers() {
+        let created = Message::new(HashMap::from([("title".to_string(), PhotoData::default())]));
+        let mut moved = Message::new(HashMap::from([("title".to_string(), PhotoData::default())]));
+        moved.set_event(EventType::Updated);
+        moved.set_moved_from("previous");
+
+        // Assert that the event is only in the headers, so that the payload stays the same.
+        assert_eq!(created.value, moved.value);
+        assert_eq!(created.headers, vec![("event_type".to_string(), "created".to_string())]);
+        assert_eq!(
+            moved.headers,
+            vec![
+                ("event_type".to_string(), "moved".to_string()),
+                ("moved_from".to_string(), "previous".to_string()),
+            ]
+        );
+    }
+
+    // Define a test function for the capture time of a photo.
+    #[test]
+    fn test_capture_time() {
+        // Assert that DateTimeOriginal is preferred to DateTime, whatever the order of the tags.
+        let mut photo_data = PhotoData::default();
+        photo_data.build("DateTime", "2021-01-05 10:00:00");
+        photo_data.build("DateTimeOriginal", "2021-01-04 14:49:57");
+        assert_eq!(photo_data.timestamp(), "2021-01-04T14:49:57+00:00");
+        photo_data.build("DateTime", "2021-01-05 10:00:00");
+        assert_eq!(photo_data.timestamp(), "2021-01-04T14:49:57+00:00");
+
+        // Assert that DateTime is used without DateTimeOriginal.
+        let mut photo_data = PhotoData::default();
+        photo_data.build("DateTime", "2021-01-05 10:00:00");
+        assert_eq!(photo_data.timestamp(), "2021-01-05T10:00:00+00:00");
+
+        // Assert that a zeroed DateTimeOriginal falls back to DateTime, whatever the order of the tags.
+        let mut photo_data = PhotoData::default();
+        photo_data.build("DateTimeOriginal", "0000-00-00 00:00:00");
+        assert_eq!(photo_data.timestamp(), "");
+        photo_data.build("DateTime", "2021-01-05 10:00:00");
+        assert_eq!(photo_data.timestamp(), "2021-01-05T10:00:00+00:00");
+        photo_data.build("DateTimeOriginal", "0000-00-00 00:00:00");
+        assert_eq!(photo_data.timestamp(), "2021-01-05T10:00:00+00:00");
+    }
+
+    // Define a test function for a photo of a camera without a clock, with a zeroed DateTimeOriginal.
+    #[test]
+    fn test_zeroed_capture_time() {
+        // A little-endian TIFF: IFD0 holds DateTime and the Exif IFD pointer, the Exif IFD holds DateTimeOriginal.
+        let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+        tiff.extend(2u16.to_le_bytes());
+        for (tag, kind, count, value) in [(0x0132u16, 2u16, 20u32, 38u32), (0x8769, 4, 1, 58)] {
+            tiff.extend(tag.to_le_bytes());
+            tiff.extend(kind.to_le_bytes());
+            tiff.extend(count.to_le_bytes());
+            tiff.extend(value.to_le_bytes());
+        }
+        tiff.extend(0u32.to_le_bytes());
+        tiff.extend(b"2021:01:05 10:00:00\0");
+        tiff.extend(1u16.to_le_bytes());
+        for value in [0x9003u16, 2] {
+            tiff.extend(value.to_le_bytes());
+        }
+        tiff.extend(20u32.to_le_bytes());
+        tiff.extend(76u32.to_le_bytes());
+        tiff.extend(0u32.to_le_bytes());
+        tiff.extend(b"0000:00:00 00:00:00\0");
+
+        // Assert that the photo is read with the time it was last changed, rather than panicking.
+        let photo = get_exif_from("zeroed.tif", &mut std::io::Cursor::new(tiff)).unwrap();
+        assert_eq!(photo["zeroed.tif"].timestamp(), "2021-01-05T10:00:00+00:00");
+    }
+}
@@ -0,0 +1,422 @@
+// Import necessary modules from the project.
+use crate::config::PostgisSinkConfig;
+use crate::logger;
+use crate::message::Message;
+use crate::producer::Delivery;
+use crate::sink::{next_batch, written, Sink, POSTGIS};
+
+// Import necessary modules from the standard library and external crates.
+use bytes::Bytes;
+use chrono::DateTime;
+use futures::future::BoxFuture;
+use futures::SinkExt;
+use std::collections::HashMap;
+use std::io;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, NoTls};
+
+// The migrations of the PostGIS schema, applied in order of version.
+const MIGRATIONS: [(i32, &str); 1] = [(
+    1,
+    include_str!("../migrations/postgis/001_photo_locations.sql"),
+)];
+
+// Key of the advisory lock held while migrating, so that two instances don't migrate at once.
+const MIGRATION_LOCK: i64 = 0x6578_6966_5f67_6973;
+
+// The staging table the batches are copied into, emptied at every commit.
+const STAGING: &str = "CREATE TEMP TABLE IF NOT EXISTS photo_locations_staging (
+        content_hash TEXT,
+        path TEXT,
+        lat DOUBLE PRECISION,
+        long DOUBLE PRECISION,
+        altitude DOUBLE PRECISION,
+        altitude_source TEXT,
+        position_source TEXT,
+        accuracy REAL,
+        taken_at TIMESTAMPTZ,
+        payload JSONB
+    ) ON COMMIT DELETE ROWS";
+
+const COPY: &str =
+    "COPY photo_locations_staging (content_hash, path, lat, long, altitude, altitude_source, \
+    position_source, accuracy, taken_at, payload) FROM STDIN";
+
+// Remove the rows of the staged paths whose file has other contents now, such as an edited photo,
+// so that a path has a single row.
+const REPLACED: &str = "DELETE FROM photo_locations p
+    USING photo_locations_staging s
+    WHERE p.path = s.path AND p.content_hash <> s.content_hash";
+
+// Move the staged rows into the table; a known content hash gets the new path and position.
+const UPSERT: &str =
+    "INSERT INTO photo_locations (content_hash, path, location, altitude_source, position_source,
+        accuracy, taken_at, payload, updated_at)
+    SELECT content_hash, path,
+        CASE WHEN lat IS NULL THEN NULL
+            ELSE ST_SetSRID(ST_MakePoint(long, lat, COALESCE(altitude, 0)), 4326)::geography END,
+        altitude_source, position_source, accuracy, taken_at, payload, now()
+    FROM photo_locations_staging
+    ON CONFLICT (content_hash) DO UPDATE SET
+        path = EXCLUDED.path,
+        location = EXCLUDED.location,
+        altitude_source = EXCLUDED.altitude_source,
+        position_source = EXCLUDED.position_source,
+        accuracy = EXCLUDED.accuracy,
+        taken_at = EXCLUDED.taken_at,
+        payload = EXCLUDED.payload,
+        updated_at = EXCLUDED.updated_at";
+
+// migrate is a function that applies the migrations the database doesn't have yet, in a single transaction.
+// Parameters:
+// - client: The connection to the database.
+// Returns:
+// - Result<(), tokio_postgres::Error>: Ok once the schema is up to date, or the error of the failed migration.
+async fn migrate(client: &mut Client) -> Result<(), tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .await?;
+    transaction
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS exif_reader_migrations (
+                version INTEGER PRIMARY KEY,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+    let applied: i32 = transaction
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0) FROM exif_reader_migrations",
+            &[],
+        )
+        .await?
+        .get(0);
+    for (version, migration) in MIGRATIONS.iter().filter(|(version, _)| *version > applied) {
+        logger::log_info(&format!("Applying PostGIS migration {}", version));
+        transaction.batch_execute(migration).await?;
+        transaction
+            .execute(
+                "INSERT INTO exif_reader_migrations (version) VALUES ($1)",
+                &[version],
+            )
+            .await?;
+    }
+    transaction.commit().await
+}
+
+// content_hash is a function that returns the key of the row of a message: the hash of the file contents
+// computed when it was extracted, which every message extracted for this sink carries.
+fn content_hash(message: &Message) -> Option<String> {
+    message
+        .source
+        .as_ref()
+        .map(|state| state.content_hash.clone())
+        .filter(|hash| !hash.is_empty())
+}
+
+// copy_field is a function that writes a field in the text format of COPY, \N for NULL.
+fn copy_field(field: Option<String>) -> String {
+    match field {
+        Some(field) => field
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r"),
+        None => "\\N".to_string(),
+    }
+}
+
+// copy_rows is a function that turns a batch of messages into the rows copied into the staging table,
+// and the paths removed by its tombstones. A content hash seen twice keeps the last message;
+// messages without content hash have no row.
+// Parameters:
+// - batch: The messages.
+// Returns:
+// - (Vec<String>, Vec<String>): The removed paths, and the rows as lines of COPY text.
+fn copy_rows(batch: &[Message]) -> (Vec<String>, Vec<String>) {
+    let mut removed = Vec::new();
+    let mut rows: Vec<String> = Vec::new();
+    let mut hashes: HashMap<String, usize> = HashMap::new();
+    for message in batch {
+        if message.is_tombstone() {
+            removed.push(message.key.clone());
+            continue;
+        }
+        let value = &message.value;
+        let text = |name: &str| value[name].as_str().map(str::to_string);
+        let number = |name: &str| value[name].as_f64().map(|number| number.to_string());
+        // Photos without a position have no location.
+        let positioned = !value["position_source"].is_null();
+        let Some(hash) = content_hash(message) else {
+            continue;
+        };
+        let fields = [
+            Some(hash.clone()),
+            Some(message.key.clone()),
+            number("lat").filter(|_| positioned),
+            number("long").filter(|_| positioned),
+            number("altitude"),
+            text("altitude_source"),
+            text("position_source"),
+            number("accuracy"),
+            text("tmstmp")
+                .and_then(|taken_at| DateTime::parse_from_rfc3339(&taken_at).ok())
+                .map(|taken_at| taken_at.to_rfc3339()),
+            Some(value.to_string()),
+        ];
+        let mut row = fields.map(copy_field).join("\t");
+        row.push('\n');
+        match hashes.get(&hash) {
+            Some(index) => rows[*index] = row,
+            None => {
+                hashes.insert(hash, rows.len());
+                rows.push(row);
+            }
+        }
+    }
+    (removed, rows)
+}
+
+// Define the sink upserting the messages into a PostGIS table, keyed by the content hash of their file,
+// so that small deployments can store positions without Kafka.
+pub struct PostgisSink {
+    url: String,
+    batch_size: usize,
+    // The connection, opened and migrated on the first batch, and opened again once lost.
+    client: Mutex<Option<Client>>,
+}
+
+impl PostgisSink {
+    // Create the sink of a database, connected on the first batch.
+    pub fn new(config: &PostgisSinkConfig) -> PostgisSink {
+        PostgisSink {
+            url: config.url.clone(),
+            batch_size: config.batch_size.max(1),
+            client: Mutex::new(None),
+        }
+    }
+
+    // connect is a method that opens a connection to the database and brings its schema up to date.
+    async fn connect(&self) -> Result<Client, tokio_postgres::Error> {
+        let (mut client, connection) = tokio_postgres::connect(&self.url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(error) = connection.await {
+                logger::log_error(&format!("PostGIS connection closed, {}", error));
+            }
+        });
+        migrate(&mut client).await?;
+        Ok(client)
+    }
+
+    // write_batch is a method that writes a batch in a single transaction: the paths of its tombstones are
+    // deleted first, then its rows are copied into the staging table, the rows of their paths with other
+    // contents are deleted, and they are upserted, so that a moved file keeps its row under its new path
+    // and an edited file replaces its previous row.
+    // Parameters:
+    // - batch: The messages.
+    // Returns:
+    // - Result<(), tokio_postgres::Error>: Ok once the transaction is committed, or the error that aborted it.
+    async fn write_batch(&self, batch: &[Message]) -> Result<(), tokio_postgres::Error> {
+        let mut client = self.client.lock().await;
+        let client = match client.take() {
+            Some(open) if !open.is_closed() => client.insert(open),
+            _ => client.insert(self.connect().await?),
+        };
+        let (removed, rows) = copy_rows(batch);
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(STAGING).await?;
+        if !removed.is_empty() {
+            transaction
+                .execute(
+                    "DELETE FROM photo_locations WHERE path = ANY($1)",
+                    &[&removed],
+                )
+                .await?;
+        }
+        if !rows.is_empty() {
+            let copy = transaction.copy_in::<_, Bytes>(COPY).await?;
+            futures::pin_mut!(copy);
+            copy.send(Bytes::from(rows.concat())).await?;
+            copy.finish().await?;
+            transaction.batch_execute(REPLACED).await?;
+            transaction.batch_execute(UPSERT).await?;
+        }
+        transaction.commit().await
+    }
+}
+
+impl Sink for PostgisSink {
+    fn name(&self) -> &'static str {
+        POSTGIS
+    }
+
+    fn keys_by_content(&self) -> bool {
+        true
+    }
+
+    fn write_stream<'a>(
+        &'a self,
+        mut messages: Receiver<Message>,
+        delivery: &'a mut Delivery,
+        delivered: &'a mut (dyn FnMut(&Message) + Send),
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut first_error = None;
+            while let Some(batch) = next_batch(&mut messages, self.batch_size).await {
+                // Rows are keyed by content hash, so the messages without one fail on their own.
+                let (batch, unkeyed): (Vec<Message>, Vec<Message>) =
+                    batch.into_iter().partition(|message| message.is_tombstone() || content_hash(message).is_some());
+                if !unkeyed.is_empty() {
+                    let error = io::Error::new(io::ErrorKind::InvalidData, "The message has no content hash");
+                    if let Err(error) = written(&unkeyed, Err(error), delivery, delivered) {
+                        first_error.get_or_insert(error);
+                    }
+                }
+                if batch.is_empty() {
+                    continue;
+                }
+                let result = self.write_batch(&batch).await.map_err(io::Error::other);
+                if let Err(error) = written(&batch, result, delivery, delivered) {
+                    first_error.get_or_insert(error);
+                }
+            }
+            first_error.map_or(Ok(()), Err)
+        })
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::file_state::FileState;
+    use crate::message::EventType;
+    use tokio::sync::mpsc;
+
+    // photo is a helper that builds the message of a photo with its content hash.
+    fn photo(path: &str, hash: &str, position: Option<(f64, f64, f64)>) -> Message {
+        let mut message = Message::tombstone(path, EventType::Created);
+        message.value = match position {
+            Some((lat, long, altitude)) => serde_json::json!({
+                "lat": lat,
+                "long": long,
+                "altitude": altitude,
+                "position_source": "exif",
+                "tmstmp": "2023-05-01T12:00:00+00:00",
+            }),
+            None => {
+                serde_json::json!({ "lat": 0.0, "long": 0.0, "position_source": null, "tmstmp": "" })
+            }
+        };
+        message.source = Some(FileState {
+            path: path.to_string(),
+            size: 1,
+            mtime: 0,
+            content_hash: hash.to_string(),
+            payload_hash: None,
+            settings: None,
+        });
+        message
+    }
+
+    // Define a test function for building the copied rows of a batch.
+    #[test]
+    fn test_copy_rows() {
+        let batch = vec![
+            photo("photos/a\tb.jpg", "1111", Some((45.0, 39.0, 27.5))),
+            photo("photos/c.jpg", "2222", None),
+            Message::tombstone("photos/old.jpg", EventType::Deleted),
+            // The same contents under a new path replace the first row.
+            photo("photos/d.jpg", "1111", Some((46.0, 40.0, 10.0))),
+            // A message without content hash has no row.
+            Message {
+                source: None,
+                ..photo("photos/e.jpg", "3333", None)
+            },
+        ];
+        let (removed, rows) = copy_rows(&batch);
+        assert_eq!(removed, vec!["photos/old.jpg"]);
+        assert_eq!(rows.len(), 2);
+
+        // Assert that the fields are escaped, and that a photo without position has no location.
+        let fields: Vec<&str> = rows[0].trim_end().split('\t').collect();
+        assert_eq!(fields[..5], ["1111", "photos/d.jpg", "46", "40", "10"]);
+        assert_eq!(fields[8], "2023-05-01T12:00:00+00:00");
+        let fields: Vec<&str> = rows[1].trim_end().split('\t').collect();
+        assert_eq!(fields[..4], ["2222", "photos/c.jpg", "\\N", "\\N"]);
+        assert_eq!(fields[8], "\\N");
+        assert_eq!(copy_field(Some("a\tb\\c".to_string())), "a\\tb\\\\c");
+    }
+
+    // Define a test function for upserting photos into PostGIS, against the db_test database.
+    #[tokio::test]
+    async fn test_postgis_sink() {
+        let config = Config::from_env().unwrap().sink.postgis.unwrap();
+        let sink = PostgisSink::new(&config);
+        let hash = format!("{:064x}", rand::random::<u128>());
+        let write = |messages: Vec<Message>| async {
+            let (sender, receiver) = mpsc::channel(messages.len());
+            for message in messages {
+                sender.try_send(message).unwrap();
+            }
+            drop(sender);
+            let mut delivery = Delivery::default();
+            let result = sink
+                .write_stream(receiver, &mut delivery, &mut |_| ())
+                .await;
+            (result, delivery)
+        };
+
+        // Assert that the photo is inserted with its position.
+        let (result, delivery) =
+            write(vec![photo("test/a.jpg", &hash, Some((45.0, 39.0, 27.5)))]).await;
+        assert!(result.is_ok());
+        assert_eq!(delivery.published, 1);
+        let (client, connection) = tokio_postgres::connect(&config.url, NoTls).await.unwrap();
+        tokio::spawn(connection);
+        let query = "SELECT path, ST_Y(location::geometry), ST_Z(location::geometry) FROM photo_locations WHERE content_hash = $1";
+        let row = client.query_one(query, &[&hash]).await.unwrap();
+        assert_eq!(row.get::<_, String>(0), "test/a.jpg");
+        assert_eq!(row.get::<_, f64>(1), 45.0);
+        assert_eq!(row.get::<_, f64>(2), 27.5);
+
+        // Assert that a moved photo keeps its row under its new path, and that tombstones remove it.
+        let (result, _) = write(vec![
+            photo("test/b.jpg", &hash, Some((45.0, 39.0, 27.5))),
+            Message::tombstone("test/a.jpg", EventType::Moved),
+        ])
+        .await;
+        assert!(result.is_ok());
+        let row = client.query_one(query, &[&hash]).await.unwrap();
+        assert_eq!(row.get::<_, String>(0), "test/b.jpg");
+        let (result, _) = write(vec![Message::tombstone("test/b.jpg", EventType::Deleted)]).await;
+        assert!(result.is_ok());
+        assert!(client.query_opt(query, &[&hash]).await.unwrap().is_none());
+
+        // Assert that an edited photo replaces the row of its previous contents at the same path.
+        let edited = format!("{:064x}", rand::random::<u128>());
+        let (result, _) = write(vec![photo("test/c.jpg", &hash, Some((45.0, 39.0, 27.5)))]).await;
+        assert!(result.is_ok());
+        let (result, _) = write(vec![photo("test/c.jpg", &edited, Some((45.0, 39.0, 30.0)))]).await;
+        assert!(result.is_ok());
+        let rows = client
+            .query("SELECT content_hash FROM photo_locations WHERE path = 'test/c.jpg'", &[])
+            .await
+            .unwrap();
+        let hashes: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        assert_eq!(hashes, vec![edited]);
+
+        // Assert that a message without content hash fails on its own.
+        let (result, delivery) = write(vec![
+            Message { source: None, ..photo("test/d.jpg", &hash, None) },
+            Message::tombstone("test/c.jpg", EventType::Deleted),
+        ])
+        .await;
+        assert!(result.is_err());
+        assert_eq!((delivery.published, delivery.failed), (1, 1));
+        assert!(client.query_opt(query, &[&hash]).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,431 @@
+// Import necessary modules from the project.
+use crate::config::PrivacyConfig;
+use crate::file_state::payload_hash;
+use crate::logger;
+use crate::message::Message;
+use crate::utils::invalid_data;
+
+// Import necessary modules from the standard library and external crates.
+use std::fs;
+use std::io;
+
+use openssl::sha::Sha256;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use serde_json::json;
+
+// Mean radius of the Earth, in metres.
+const EARTH_RADIUS: f64 = 6_371_000.0;
+// Length of a degree of latitude, in metres.
+const METRES_PER_DEGREE: f64 = 111_320.0;
+// Number of random points tried when jittering within a polygon.
+const JITTER_ATTEMPTS: usize = 1000;
+// Headers recording the applied policy and the zone.
+const POLICY_HEADER: &str = "privacy_policy";
+const ZONE_HEADER: &str = "privacy_zone";
+
+// Define what happens to a message taken inside a zone.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    // The message isn't published.
+    Drop,
+    // The position is replaced with the centroid of the zone.
+    Snap,
+    // The position is replaced with a random point within the zone.
+    Jitter,
+}
+
+impl Policy {
+    // Get the name of the policy, as recorded in the message header.
+    fn name(&self) -> &'static str {
+        match self {
+            Policy::Drop => "drop",
+            Policy::Snap => "snap",
+            Policy::Jitter => "jitter",
+        }
+    }
+}
+
+// Define the area of a zone, with coordinates as [lat, lon] in decimal degrees.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum Shape {
+    // A circle with a radius in metres.
+    Circle { center: [f64; 2], radius: f64 },
+    // A polygon, closed implicitly.
+    Polygon { points: Vec<[f64; 2]> },
+}
+
+// Define a privacy zone.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Zone {
+    pub name: String,
+    #[serde(flatten)]
+    pub shape: Shape,
+    pub policy: Policy,
+}
+
+// distance is a function that returns the great-circle distance between two points, in metres.
+fn distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+impl Zone {
+    // Check whether a point is inside the zone.
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match &self.shape {
+            Shape::Circle { center, radius } => distance(center[0], center[1], lat, lon) <= *radius,
+            Shape::Polygon { points } => {
+                // Count the edges crossed by a ray going east from the point.
+                let mut inside = false;
+                let mut previous = points.len().wrapping_sub(1);
+                for (index, point) in points.iter().enumerate() {
+                    let other = points[previous];
+                    if (point[0] > lat) != (other[0] > lat)
+                        && lon
+                            < (other[1] - point[1]) * (lat - point[0]) / (other[0] - point[0])
+                                + point[1]
+                    {
+                        inside = !inside;
+                    }
+                    previous = index;
+                }
+                inside
+            }
+        }
+    }
+
+    // Get the centroid of the zone.
+    pub fn centroid(&self) -> (f64, f64) {
+        match &self.shape {
+            Shape::Circle { center, .. } => (center[0], center[1]),
+            Shape::Polygon { points } => {
+                // Area-weighted centroid, falling back to the mean of the vertices for degenerate polygons.
+                let mut area = 0.0;
+                let (mut lat, mut lon) = (0.0, 0.0);
+                for (index, point) in points.iter().enumerate() {
+                    let next = points[(index + 1) % points.len()];
+                    let cross = point[1] * next[0] - next[1] * point[0];
+                    area += cross;
+                    lat += (point[0] + next[0]) * cross;
+                    lon += (point[1] + next[1]) * cross;
+                }
+                if area.abs() < f64::EPSILON {
+                    let count = points.len() as f64;
+                    return (
+                        points.iter().map(|point| point[0]).sum::<f64>() / count,
+                        points.iter().map(|point| point[1]).sum::<f64>() / count,
+                    );
+                }
+                (lat / (3.0 * area), lon / (3.0 * area))
+            }
+        }
+    }
+
+    // Get the largest distance between the centroid and the edge of the zone, in metres:
+    // how far a position moved to the centroid, or anywhere within the zone, may be from the photo.
+    pub fn extent(&self) -> f64 {
+        match &self.shape {
+            Shape::Circle { radius, .. } => *radius,
+            Shape::Polygon { points } => {
+                let (lat, lon) = self.centroid();
+                points
+                    .iter()
+                    .map(|point| distance(lat, lon, point[0], point[1]))
+                    .fold(0.0, f64::max)
+            }
+        }
+    }
+
+    // Get a random point within the zone.
+    pub fn random_point<R: Rng>(&self, rng: &mut R) -> (f64, f64) {
+        match &self.shape {
+            Shape::Circle { center, radius } => {
+                // The square root keeps the points uniform over the area of the disc.
+                let distance = radius * rng.gen::<f64>().sqrt();
+                let bearing = rng.gen_range(0.0..std::f64::consts::TAU);
+                let lat = center[0] + distance * bearing.cos() / METRES_PER_DEGREE;
+                let lon = center[1]
+                    + distance * bearing.sin() / (METRES_PER_DEGREE * center[0].to_radians().cos());
+                (lat, lon)
+            }
+            Shape::Polygon { points } => {
+                // Sample the bounding box until a point falls inside the polygon.
+                let min_lat = points.iter().map(|point| point[0]).fold(f64::MAX, f64::min);
+                let max_lat = points.iter().map(|point| point[0]).fold(f64::MIN, f64::max);
+                let min_lon = points.iter().map(|point| point[1]).fold(f64::MAX, f64::min);
+                let max_lon = points.iter().map(|point| point[1]).fold(f64::MIN, f64::max);
+                for _ in 0..JITTER_ATTEMPTS {
+                    let lat = min_lat + (max_lat - min_lat) * rng.gen::<f64>();
+                    let lon = min_lon + (max_lon - min_lon) * rng.gen::<f64>();
+                    if self.contains(lat, lon) {
+                        return (lat, lon);
+                    }
+                }
+                self.centroid()
+            }
+        }
+    }
+}
+
+// Define the privacy zones checked before messages are published.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrivacyZones {
+    zones: Vec<Zone>,
+    // Secret keying the jitter of every photo.
+    jitter_key: String,
+}
+
+impl PrivacyZones {
+    // Create privacy zones, checking that they are valid.
+    pub fn new(zones: Vec<Zone>) -> io::Result<PrivacyZones> {
+        for zone in &zones {
+            let valid = match &zone.shape {
+                Shape::Circle { radius, .. } => *radius > 0.0,
+                Shape::Polygon { points } => points.len() >= 3,
+            };
+            if !valid {
+                return Err(invalid_data(format!("Invalid privacy zone {}", zone.name)));
+            }
+        }
+        // Without a configured key, the jitter is keyed by the zones, which are just as private.
+        let jitter_key = payload_hash(&format!("{:?}", zones));
+        Ok(PrivacyZones { zones, jitter_key })
+    }
+
+    // Load the privacy zones from the JSON file of the configuration.
+    pub fn from_config(config: &PrivacyConfig) -> io::Result<PrivacyZones> {
+        let zones: Vec<Zone> =
+            serde_json::from_slice(&fs::read(&config.zones_file)?).map_err(invalid_data)?;
+        logger::log_info(&format!(
+            "Loaded {} privacy zones from {}",
+            zones.len(),
+            config.zones_file
+        ));
+        let mut zones = PrivacyZones::new(zones)?;
+        if let Some(jitter_key) = &config.jitter_key {
+            zones.jitter_key = jitter_key.clone();
+        }
+        Ok(zones)
+    }
+
+    // A fingerprint of the zones and jitter key: the cached files withheld or blurred by other zones
+    // are extracted again.
+    pub fn fingerprint(&self) -> String {
+        payload_hash(&format!("{:?}\n{}", self.zones, self.jitter_key))
+    }
+
+    // jitter_rng is a method that returns the random generator of the jitter of a photo, seeded with
+    // a keyed hash of its path, so that every publication of the photo gets the same point and the
+    // true position can't be narrowed down by averaging them.
+    fn jitter_rng(&self, path: &str) -> StdRng {
+        let mut hasher = Sha256::new();
+        hasher.update(&(self.jitter_key.len() as u64).to_le_bytes());
+        hasher.update(self.jitter_key.as_bytes());
+        hasher.update(path.as_bytes());
+        StdRng::from_seed(hasher.finish())
+    }
+
+    // Get the first zone containing a point.
+    pub fn zone_at(&self, lat: f64, lon: f64) -> Option<&Zone> {
+        self.zones.iter().find(|zone| zone.contains(lat, lon))
+    }
+
+    // apply is a method that applies the policies of the zones to a message about to be published.
+    // Messages without a position are left as they are. A moved position no longer tells the altitude,
+    // which is removed, nor the accuracy, which becomes the extent of the zone.
+    // Parameters:
+    // - message: The message about to be published.
+    // Returns:
+    // - Option<Message>: The message to publish, with the applied policy recorded in its headers,
+    //   or None if it must be dropped.
+    pub fn apply(&self, mut message: Message) -> Option<Message> {
+        if message.value["position_source"].is_null() {
+            return Some(message);
+        }
+        let (Some(lat), Some(lon)) = (message.value["lat"].as_f64(), message.value["long"].as_f64())
+        else {
+            return Some(message);
+        };
+        let Some(zone) = self.zone_at(lat, lon) else {
+            return Some(message);
+        };
+
+        logger::log_debug(&format!(
+            "Applying privacy policy {} of zone {} to {}",
+            zone.policy.name(),
+            zone.name,
+            message.key
+        ));
+        let (lat, lon) = match zone.policy {
+            Policy::Drop => return None,
+            Policy::Snap => zone.centroid(),
+            Policy::Jitter => zone.random_point(&mut self.jitter_rng(&message.key)),
+        };
+        message.value["lat"] = json!(lat);
+        message.value["long"] = json!(lon);
+        message.value["altitude"] = serde_json::Value::Null;
+        message.value["altitude_source"] = serde_json::Value::Null;
+        message.value["accuracy"] = json!(zone.extent());
+        message
+            .headers
+            .push((POLICY_HEADER.to_string(), zone.policy.name().to_string()));
+        message
+            .headers
+            .push((ZONE_HEADER.to_string(), zone.name.clone()));
+        Some(message)
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::EventType;
+
+    // message is a helper that builds a message positioned at the given point.
+    fn message(key: &str, lat: f64, lon: f64) -> Message {
+        Message {
+            key: key.to_string(),
+            value: json!({"lat": lat, "long": lon, "altitude": 27.5, "altitude_source": "gps", "position_source": "exif"}),
+            headers: Vec::new(),
+            source: None,
+            event: EventType::Created,
+            moved_from: None,
+        }
+    }
+
+    // zones is a helper that builds a circle around a home and a square office.
+    fn zones() -> PrivacyZones {
+        serde_json::from_str::<Vec<Zone>>(
+            r#"[
+                {"name": "home", "shape": "circle", "center": [45.0439, 39.0321], "radius": 200, "policy": "snap"},
+                {"name": "office", "shape": "polygon", "points": [[45.1, 39.1], [45.1, 39.2], [45.2, 39.2], [45.2, 39.1]], "policy": "jitter"},
+                {"name": "clinic", "shape": "circle", "center": [46.0, 40.0], "radius": 50, "policy": "drop"}
+            ]"#,
+        )
+        .map(|zones| PrivacyZones::new(zones).unwrap())
+        .unwrap()
+    }
+
+    // Define a test function for the zone shapes.
+    #[test]
+    fn test_contains() {
+        let zones = zones();
+        assert_eq!(zones.zone_at(45.0445, 39.0325).unwrap().name, "home");
+        assert!(zones.zone_at(45.0480, 39.0321).is_none());
+        assert_eq!(zones.zone_at(45.15, 39.15).unwrap().name, "office");
+        assert!(zones.zone_at(45.15, 39.25).is_none());
+        let (lat, lon) = zones.zones[1].centroid();
+        assert!((lat - 45.15).abs() < 1e-9 && (lon - 39.15).abs() < 1e-9);
+    }
+
+    // Define a test function for the policies.
+    #[test]
+    fn test_apply() {
+        let messages = vec![
+            message("home.jpg", 45.0445, 39.0325),
+            message("office.jpg", 45.15, 39.15),
+            message("clinic.jpg", 46.0, 40.0),
+            message("beach.jpg", 44.0, 38.0),
+            Message {
+                key: "unknown.jpg".to_string(),
+                value: json!({"lat": 46.0, "long": 40.0, "position_source": null}),
+                headers: Vec::new(),
+                source: None,
+                event: EventType::Created,
+                moved_from: None,
+            },
+        ];
+
+        let zones = zones();
+        let messages: Vec<Message> = messages
+            .into_iter()
+            .filter_map(|message| zones.apply(message))
+            .collect();
+        let keys: Vec<&str> = messages.iter().map(|message| message.key.as_str()).collect();
+        assert_eq!(keys, vec!["home.jpg", "office.jpg", "beach.jpg", "unknown.jpg"]);
+
+        // Snapped to the centre of the circle.
+        assert_eq!(messages[0].value["lat"], json!(45.0439));
+        assert_eq!(messages[0].value["long"], json!(39.0321));
+        assert_eq!(
+            messages[0].headers,
+            vec![
+                ("privacy_policy".to_string(), "snap".to_string()),
+                ("privacy_zone".to_string(), "home".to_string())
+            ]
+        );
+
+        // The altitude is removed and the accuracy is the extent of the zone.
+        assert!(messages[0].value["altitude"].is_null());
+        assert!(messages[0].value["altitude_source"].is_null());
+        assert_eq!(messages[0].value["accuracy"], json!(200.0));
+        assert!(messages[1].value["altitude"].is_null());
+        let extent = messages[1].value["accuracy"].as_f64().unwrap();
+        assert!((extent - distance(45.15, 39.15, 45.1, 39.1)).abs() < 0.01);
+
+        // Jittered within the office, and the policy isn't part of the payload.
+        let (lat, lon) = (
+            messages[1].value["lat"].as_f64().unwrap(),
+            messages[1].value["long"].as_f64().unwrap(),
+        );
+        assert!(zones.zones[1].contains(lat, lon));
+        assert_ne!((lat, lon), (45.15, 39.15));
+        assert!(!messages[1].value.to_string().contains("jitter"));
+
+        // Messages outside the zones or without a position are untouched.
+        assert_eq!(messages[2].value["lat"], json!(44.0));
+        assert_eq!(messages[2].value["altitude"], json!(27.5));
+        assert!(messages[2].headers.is_empty());
+        assert!(messages[3].headers.is_empty());
+    }
+
+    // Define a test function for the jitter of a photo published again.
+    #[test]
+    fn test_jitter_stable() {
+        let point = |zones: &PrivacyZones, key: &str| {
+            let message = zones.apply(message(key, 45.15, 39.15)).unwrap();
+            (message.value["lat"].as_f64().unwrap(), message.value["long"].as_f64().unwrap())
+        };
+
+        // Assert that a photo gets the same point every time, and that other photos and keys get others.
+        let zones = zones();
+        assert_eq!(point(&zones, "office.jpg"), point(&zones, "office.jpg"));
+        assert_ne!(point(&zones, "office.jpg"), point(&zones, "desk.jpg"));
+        let keyed = PrivacyZones { jitter_key: "secret".to_string(), ..zones.clone() };
+        assert_ne!(point(&keyed, "office.jpg"), point(&zones, "office.jpg"));
+        assert_ne!(keyed.fingerprint(), zones.fingerprint());
+    }
+
+    // Define a test function for jitter within a circle.
+    #[test]
+    fn test_jitter_circle() {
+        let zone = &zones().zones[0];
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let (lat, lon) = zone.random_point(&mut rng);
+            // Allow for the flat-earth approximation.
+            assert!(distance(45.0439, 39.0321, lat, lon) <= 201.0);
+        }
+    }
+
+    // Define a test function for invalid zones.
+    #[test]
+    fn test_invalid_zone() {
+        let zone = Zone {
+            name: "line".to_string(),
+            shape: Shape::Polygon {
+                points: vec![[45.0, 39.0], [45.1, 39.1]],
+            },
+            policy: Policy::Drop,
+        };
+        assert!(PrivacyZones::new(vec![zone]).is_err());
+    }
+}
@@ -1,66 +1,711 @@
 // Import required modules and structs from the project.
-use crate::config::Config;
+use crate::config::KafkaConfig;
+use crate::dead_letter::{self, DeadLetter};
 use crate::message::Message;
 use crate::logger;
+use crate::scan_report::PublishFailure;
+use crate::spool::Spool;
 
-// Import necessary modules from the rdkafka crate.
+// Import necessary modules from the standard library and the rdkafka crate.
+use futures::stream::{FuturesUnordered, StreamExt};
 use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
 use rdkafka::message::OwnedHeaders;
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer as _};
+use std::io;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 
-// Asynchronously produce Kafka messages.
-pub async fn produce(messages: Vec<Message>) -> Result<(), rdkafka::error::KafkaError> {
-    // Load Kafka configuration from environment.
-    let config = Config::from_env().unwrap();
+// Delivery errors of brokers that are down, unreachable or moving partitions: the message may be taken later.
+// Other errors, such as oversized or invalid messages and authorization failures, are permanent.
+const RETRIABLE_ERRORS: [RDKafkaErrorCode; 26] = [
+    RDKafkaErrorCode::BrokerDestroy,
+    RDKafkaErrorCode::BrokerTransportFailure,
+    RDKafkaErrorCode::Resolve,
+    RDKafkaErrorCode::MessageTimedOut,
+    RDKafkaErrorCode::AllBrokersDown,
+    RDKafkaErrorCode::OperationTimedOut,
+    RDKafkaErrorCode::QueueFull,
+    RDKafkaErrorCode::NodeUpdate,
+    RDKafkaErrorCode::WaitingForCoordinator,
+    RDKafkaErrorCode::TimedOutQueue,
+    RDKafkaErrorCode::Interrupted,
+    RDKafkaErrorCode::Retry,
+    RDKafkaErrorCode::PurgeQueue,
+    RDKafkaErrorCode::PurgeInflight,
+    RDKafkaErrorCode::UnknownTopicOrPartition,
+    RDKafkaErrorCode::LeaderNotAvailable,
+    RDKafkaErrorCode::NotLeaderForPartition,
+    RDKafkaErrorCode::RequestTimedOut,
+    RDKafkaErrorCode::BrokerNotAvailable,
+    RDKafkaErrorCode::ReplicaNotAvailable,
+    RDKafkaErrorCode::NetworkException,
+    RDKafkaErrorCode::CoordinatorLoadInProgress,
+    RDKafkaErrorCode::CoordinatorNotAvailable,
+    RDKafkaErrorCode::NotCoordinator,
+    RDKafkaErrorCode::NotEnoughReplicas,
+    RDKafkaErrorCode::NotEnoughReplicasAfterAppend,
+];
+// Scan identifier of the dead letters of spooled messages, which outlive their scan.
+const SPOOL_SCAN_ID: &str = "spool";
 
-    // Create a Kafka producer using the provided configuration.
-    let producer: &FutureProducer = &ClientConfig::new()
-        .set("bootstrap.servers", &config.kafka.bootstrapserver)
-        .set("message.timeout.ms", format!("{}", config.kafka.timeout))
-        .create()
-        .expect("Producer creation error");
+// is_retriable is a function that tells whether a delivery error may go away once the brokers are back,
+// so that the message is worth spooling; errors without a code, such as a cancelled delivery, are.
+pub fn is_retriable(error: &KafkaError) -> bool {
+    error.rdkafka_error_code().is_none_or(|code| RETRIABLE_ERRORS.contains(&code))
+}
 
-    // Log the start of message production to Kafka.
-    logger::log_info(&format!(
-        "Start producing messages to Kafka on {}",
-        config.kafka.bootstrapserver
-    ));
+// Define how many messages of a stream were delivered.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Delivery {
+    // Messages acknowledged by Kafka.
+    pub published: usize,
+    // Messages that failed.
+    pub failed: usize,
+    // Messages written to the spool, to be replayed to Kafka later.
+    pub spooled: usize,
+    // The messages that failed, and why.
+    pub failures: Vec<PublishFailure>,
+}
 
-    // Iterate through the list of messages to be produced.
-    for message in messages {
-        // Log the message value being produced.
-        logger::log_info(&format!("{:?}", message.value));
-
-        // Create a Kafka FutureRecord with message payload, key, and headers.
-        let producing_message = producer
-            .send(
-                FutureRecord::to(&config.kafka.topics)
-                    .payload(&format!("{}", message.value))
-                    .key(&format!("{}", message.key))
-                    .headers(OwnedHeaders::new().add(&message.key, "exif_data")),
-                Duration::from_secs(0),
-            )
-            .await;
-
-        // Handle the result of message production.
-        match producing_message {
-            Ok((int_value, long_value)) => {
-                // Log successful message delivery.
-                logger::log_debug(&format!("{:?} {} {}", producing_message, int_value, long_value));
+// Define the Kafka producer shared by every scan, created once at startup so that
+// its connections and in-flight queue outlive single requests.
+#[derive(Clone)]
+pub struct Producer {
+    producer: FutureProducer,
+    // The topic the messages are produced to.
+    topic: String,
+    // The brokers, for the logs.
+    bootstrapserver: String,
+    // How long a message may wait for delivery, also used when flushing.
+    timeout: Duration,
+    // Number of messages sent without waiting for their delivery report.
+    in_flight: usize,
+    // The topic receiving the dead letters, if any.
+    dlq_topic: Option<String>,
+    // Held during the transaction of a scan, as a producer has one transaction at a time;
+    // None unless the producer is transactional. The scans waiting for it don't hold a thread.
+    transaction: Option<Arc<Mutex<()>>>,
+    // The disk spool of the messages Kafka couldn't take, if any.
+    spool: Option<Arc<Spool>>,
+}
+
+// Define the transaction of a scan: the messages produced until it is committed are only seen
+// by read_committed consumers once it is. Without transactions, committing and aborting do nothing.
+pub struct Transaction {
+    producer: FutureProducer,
+    // How long committing or aborting may wait for the brokers.
+    timeout: Duration,
+    // Keeps the other scans from starting a transaction meanwhile.
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Transaction {
+    // commit is a method that makes the messages of the transaction visible, or aborts it
+    // if Kafka refuses to commit, such as after a failed message or once the transaction timed out.
+    pub async fn commit(self) -> KafkaResult<()> {
+        if self.guard.is_none() {
+            return Ok(());
+        }
+        let (producer, timeout) = (self.producer.clone(), self.timeout);
+        if let Err(error) = blocking(move || producer.commit_transaction(timeout)).await {
+            self.abort().await?;
+            return Err(error);
+        }
+        logger::log_debug("Committed the transaction of the scan");
+        Ok(())
+    }
+
+    // abort is a method that discards the messages of the transaction.
+    pub async fn abort(self) -> KafkaResult<()> {
+        if self.guard.is_none() {
+            return Ok(());
+        }
+        logger::log_info("Aborting the transaction of the scan");
+        let (producer, timeout) = (self.producer, self.timeout);
+        blocking(move || producer.abort_transaction(timeout)).await
+    }
+}
+
+// blocking is a function that runs a call waiting for the brokers, such as committing a transaction,
+// on the blocking threads of the runtime rather than on one of its workers.
+async fn blocking<T, F>(call: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(call)
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+}
+
+// Accepted values of the typed producer settings.
+const ACKS: [&str; 4] = ["0", "1", "all", "-1"];
+const COMPRESSION_TYPES: [&str; 5] = ["none", "gzip", "snappy", "lz4", "zstd"];
+const SECURITY_PROTOCOLS: [&str; 4] = ["plaintext", "ssl", "sasl_plaintext", "sasl_ssl"];
+const SASL_MECHANISMS: [&str; 3] = ["PLAIN", "SCRAM-SHA-256", "SCRAM-SHA-512"];
+
+// invalid_setting is a function that builds the error of a producer setting that can't be used.
+fn invalid_setting(name: &str, value: &str, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid Kafka setting {}={}: {}", name, value, reason),
+    )
+}
+
+// client_config is a function that builds the librdkafka configuration of the producer:
+// the passthrough properties first, then the typed settings, which are checked here.
+// Parameters:
+// - config: The Kafka configuration.
+// Returns:
+// - io::Result<ClientConfig>: The librdkafka configuration, or an std::io::Error for a typed setting
+//   with an unknown value or settings that don't go together. Unknown properties are refused by librdkafka
+//   once the producer is created.
+fn client_config(config: &KafkaConfig) -> io::Result<ClientConfig> {
+    let mut client = ClientConfig::new();
+    for (name, value) in config.properties() {
+        client.set(name, value);
+    }
+    client
+        .set("bootstrap.servers", &config.bootstrapserver)
+        .set("message.timeout.ms", format!("{}", config.timeout));
+
+    if let Some(acks) = &config.acks {
+        if !ACKS.contains(&acks.as_str()) {
+            return Err(invalid_setting("acks", acks, "expected 0, 1, all or -1"));
+        }
+        client.set("acks", acks);
+    }
+    if let Some(compression) = &config.compression_type {
+        if !COMPRESSION_TYPES.contains(&compression.as_str()) {
+            return Err(invalid_setting("compression.type", compression, "expected none, gzip, snappy, lz4 or zstd"));
+        }
+        client.set("compression.type", compression);
+    }
+    if let Some(linger) = config.linger_ms {
+        client.set("linger.ms", linger.to_string());
+    }
+    if let Some(batch_size) = config.batch_size {
+        if batch_size == 0 {
+            return Err(invalid_setting("batch.size", "0", "expected a positive size"));
+        }
+        client.set("batch.size", batch_size.to_string());
+    }
+    // Transactions drop the duplicates of retried messages with the idempotent producer.
+    let idempotence = match (&config.transactional_id, config.enable_idempotence) {
+        (Some(transactional_id), Some(false)) => {
+            return Err(invalid_setting("transactional.id", transactional_id, "transactions need enable.idempotence"));
+        }
+        (Some(transactional_id), _) => {
+            client.set("transactional.id", transactional_id);
+            Some(true)
+        }
+        (None, idempotence) => idempotence,
+    };
+    if let Some(transaction_timeout) = config.transaction_timeout_ms {
+        let value = transaction_timeout.to_string();
+        if config.transactional_id.is_none() {
+            return Err(invalid_setting("transaction.timeout.ms", &value, "needs transactional.id"));
+        }
+        // librdkafka refuses messages that may wait longer than their transaction.
+        if transaction_timeout == 0 || transaction_timeout < config.timeout.max(0) as u64 {
+            return Err(invalid_setting(
+                "transaction.timeout.ms",
+                &value,
+                "expected a positive timeout, at least message.timeout.ms",
+            ));
+        }
+        client.set("transaction.timeout.ms", value);
+    }
+    if let Some(idempotence) = idempotence {
+        // Idempotence needs every in-sync replica to acknowledge.
+        if idempotence && client.get("acks").is_some_and(|acks| acks != "all" && acks != "-1") {
+            return Err(invalid_setting(
+                "enable.idempotence",
+                "true",
+                "acks must be all or -1",
+            ));
+        }
+        client.set("enable.idempotence", idempotence.to_string());
+    }
+    if let Some(client_id) = &config.client_id {
+        client.set("client.id", client_id);
+    }
+    set_security(&mut client, config)?;
+    Ok(client)
+}
+
+// set_security is a function that adds the security settings of the producer to its configuration,
+// checking that they go together and that the certificate files exist.
+// Parameters:
+// - client: The librdkafka configuration.
+// - config: The Kafka configuration.
+// Returns:
+// - io::Result<()>: Ok, or an std::io::Error for an unknown protocol or mechanism, missing credentials
+//   or a missing file.
+fn set_security(client: &mut ClientConfig, config: &KafkaConfig) -> io::Result<()> {
+    let protocol = config.security_protocol.as_deref().unwrap_or("plaintext").to_lowercase();
+    if !SECURITY_PROTOCOLS.contains(&protocol.as_str()) {
+        return Err(invalid_setting(
+            "security.protocol",
+            &protocol,
+            "expected plaintext, ssl, sasl_plaintext or sasl_ssl",
+        ));
+    }
+    let sasl = protocol.starts_with("sasl_");
+    let ssl = protocol.ends_with("ssl");
+    if config.security_protocol.is_some() {
+        client.set("security.protocol", &protocol);
+    }
+
+    // SASL needs a mechanism and credentials, and nothing else takes them.
+    let credentials = [&config.sasl_mechanism, &config.sasl_username, &config.sasl_password];
+    if sasl {
+        let mechanism = config.sasl_mechanism.as_deref().unwrap_or("PLAIN").to_uppercase();
+        if !SASL_MECHANISMS.contains(&mechanism.as_str()) {
+            return Err(invalid_setting(
+                "sasl.mechanism",
+                &mechanism,
+                "expected PLAIN, SCRAM-SHA-256 or SCRAM-SHA-512",
+            ));
+        }
+        let (Some(username), Some(password)) = (&config.sasl_username, &config.sasl_password) else {
+            return Err(invalid_setting("security.protocol", &protocol, "SASL needs a username and a password"));
+        };
+        client
+            .set("sasl.mechanism", mechanism)
+            .set("sasl.username", username)
+            .set("sasl.password", password);
+    } else if credentials.iter().any(|setting| setting.is_some()) {
+        return Err(invalid_setting("security.protocol", &protocol, "SASL settings need sasl_plaintext or sasl_ssl"));
+    }
+
+    // TLS files are checked now rather than on the first connection.
+    let files = [
+        ("ssl.ca.location", &config.ssl_ca_location),
+        ("ssl.certificate.location", &config.ssl_certificate_location),
+        ("ssl.key.location", &config.ssl_key_location),
+    ];
+    for (name, path) in files {
+        let Some(path) = path else {
+            continue;
+        };
+        if !ssl {
+            return Err(invalid_setting(name, path, "TLS settings need ssl or sasl_ssl"));
+        }
+        if !std::path::Path::new(path).is_file() {
+            return Err(invalid_setting(name, path, "no such file"));
+        }
+        client.set(name, path);
+    }
+    // A client certificate goes with its key.
+    if config.ssl_certificate_location.is_some() != config.ssl_key_location.is_some() {
+        return Err(invalid_setting(
+            "ssl.certificate.location",
+            config.ssl_certificate_location.as_deref().unwrap_or_default(),
+            "a client certificate needs ssl.key.location, and a key needs a certificate",
+        ));
+    }
+    if let Some(password) = &config.ssl_key_password {
+        client.set("ssl.key.password", password);
+    }
+    Ok(())
+}
+
+impl Producer {
+    // from_config is a function that creates the producer from the Kafka configuration.
+    // Parameters:
+    // - config: The brokers, topic, message timeout and producer settings.
+    // Returns:
+    // - io::Result<Producer>: The producer, or an std::io::Error if the configuration is invalid.
+    //   A transactional producer registers its transactional id with the brokers, so they must be reachable.
+    pub fn from_config(config: &KafkaConfig) -> io::Result<Producer> {
+        let producer: FutureProducer = client_config(config)?.create().map_err(io::Error::other)?;
+        let timeout = Duration::from_millis(config.timeout.max(0) as u64);
+        // Fence the previous instances with the same transactional id, and abort what they left open.
+        // The brokers check the transaction timeout against their transaction.max.timeout.ms then.
+        if config.transactional_id.is_some() {
+            producer.init_transactions(timeout).map_err(|error| {
+                match error.rdkafka_error_code() {
+                    Some(RDKafkaErrorCode::InvalidTransactionTimeout) => invalid_setting(
+                        "transaction.timeout.ms",
+                        &config.transaction_timeout_ms.map_or("60000".to_string(), |timeout| timeout.to_string()),
+                        "above the transaction.max.timeout.ms of the brokers",
+                    ),
+                    _ => io::Error::other(error),
+                }
+            })?;
+        }
+        Ok(Producer {
+            producer,
+            topic: config.topics.clone(),
+            bootstrapserver: config.bootstrapserver.clone(),
+            timeout,
+            in_flight: config.in_flight.max(1),
+            dlq_topic: config.dlq_topic.clone(),
+            transaction: config.transactional_id.as_ref().map(|_| Arc::new(Mutex::new(()))),
+            spool: None,
+        })
+    }
+
+    // with_spool is a method that spools the messages Kafka fails to deliver instead of failing them,
+    // and keeps spooling new messages while older ones wait to be replayed, so that their order is kept.
+    // Returns:
+    // - io::Result<Producer>: The producer, or an std::io::Error if it is transactional: a spooled message
+    //   would count as delivered while outside the transaction of its scan, which could then be committed
+    //   without it, and its replay would wait for the transaction of the scan to end.
+    pub fn with_spool(mut self, spool: Arc<Spool>) -> io::Result<Producer> {
+        if self.is_transactional() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "KAFKA.TRANSACTIONAL_ID and SPOOL.DIRECTORY can't be used together: a transaction is aborted when Kafka is unavailable",
+            ));
+        }
+        self.spool = Some(spool);
+        Ok(self)
+    }
+
+    // The topic receiving the dead letters, if any.
+    pub fn dead_letter_topic(&self) -> Option<&str> {
+        self.dlq_topic.as_deref()
+    }
+
+    // Check whether every scan is published in a transaction.
+    pub fn is_transactional(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    // begin is a method that starts the transaction of a scan, waiting for the transaction
+    // of another scan to end first.
+    // Returns:
+    // - KafkaResult<Transaction>: The transaction, or a KafkaError if Kafka refuses to start it.
+    pub async fn begin(&self) -> KafkaResult<Transaction> {
+        let guard = match &self.transaction {
+            Some(transaction) => {
+                let guard = transaction.clone().lock_owned().await;
+                self.producer.begin_transaction()?;
+                Some(guard)
             }
-            Err((kafka_error, _)) => {
-                // Log and return an error in case of delivery failure.
+            None => None,
+        };
+        Ok(Transaction {
+            producer: self.producer.clone(),
+            timeout: self.timeout,
+            guard,
+        })
+    }
+
+    // flush is a method that waits for the messages still queued to be delivered, up to the message timeout,
+    // and logs the ones that weren't.
+    pub fn flush(&self) {
+        logger::log_info(&format!("Flushing the messages queued for Kafka on {}", self.bootstrapserver));
+        self.producer.flush(self.timeout);
+        let undelivered = self.producer.in_flight_count();
+        if undelivered > 0 {
+            logger::log_error(&format!("{} messages weren't delivered to Kafka before stopping", undelivered));
+        }
+    }
+}
+
+// produce_dead_letters is a function that publishes the dead letters of a scan to the dead-letter topic,
+// in their own transaction if the producer is transactional, so that they are kept when the scan is aborted.
+// Dead letters are never spooled.
+// Parameters:
+// - producer: The shared Kafka producer.
+// - letters: The records of the failures of the scan.
+// Returns:
+// - KafkaResult<usize>: The number of records published, 0 without a dead-letter topic,
+//   or the first delivery error.
+pub async fn produce_dead_letters(producer: &Producer, letters: &[DeadLetter]) -> KafkaResult<usize> {
+    let (Some(topic), false) = (&producer.dlq_topic, letters.is_empty()) else {
+        return Ok(0);
+    };
+    let transaction = producer.begin().await?;
+    let letters = queued(letters.iter().map(DeadLetter::to_message).collect());
+    let mut delivery = Delivery::default();
+    match produce_to(producer, topic, None, letters, &mut delivery, |_| ()).await {
+        Ok(()) => transaction.commit().await.map(|()| delivery.published),
+        Err(kafka_error) => {
+            for failure in &delivery.failures {
                 logger::log_error(&format!(
-                    "Error in delivering message to Kafka topic {:?}",
-                    kafka_error
+                    "Error in delivering the dead letter of {} to Kafka topic {}: {}",
+                    failure.key, topic, failure.reason
                 ));
-                return Err(kafka_error);
             }
+            transaction.abort().await?;
+            Err(kafka_error)
         }
     }
+}
+
+// queued is a function that puts messages on a channel of their own, closed once they are read,
+// so that they are produced like a stream.
+fn queued(messages: Vec<Message>) -> Receiver<Message> {
+    let (sender, receiver) = mpsc::channel(messages.len().max(1));
+    for message in messages {
+        // The channel is large enough for every message, so this never waits.
+        let _ = sender.try_send(message);
+    }
+    receiver
+}
+
+// Asynchronously produce Kafka messages, in a single transaction if the producer is transactional.
+pub async fn produce(producer: &Producer, messages: Vec<Message>) -> Result<(), rdkafka::error::KafkaError> {
+    let receiver = queued(messages);
+    let transaction = producer.begin().await?;
+    match produce_stream(producer, receiver, &mut Delivery::default(), |_| ()).await {
+        Ok(()) => transaction.commit().await,
+        Err(error) => {
+            transaction.abort().await?;
+            Err(error)
+        }
+    }
+}
+
+// send is a function that sends a message to Kafka and waits for its delivery report.
+// Parameters:
+// - producer: The shared Kafka producer.
+// - topic: The topic of the message.
+// - message: The message; tombstones are sent without payload, so that compacted topics drop their key.
+// Returns:
+// - (Message, KafkaResult<(i32, i64)>): The message, with its partition and offset once acknowledged,
+//   or the KafkaError that prevented its delivery.
+async fn send(producer: &Producer, topic: &str, message: Message) -> (Message, KafkaResult<(i32, i64)>) {
+    // Log the message value being produced.
+    logger::log_info(&format!("{:?}", message.value));
+
+    // Add the message headers after the default one.
+    let headers = message
+        .headers
+        .iter()
+        .fold(OwnedHeaders::new().add(&message.key, "exif_data"), |headers, (key, value)| {
+            headers.add(key, value)
+        });
+
+    // Create a Kafka FutureRecord with message payload, key, and headers.
+    let payload = (!message.is_tombstone()).then(|| format!("{}", message.value));
+    let mut record = FutureRecord::to(topic)
+        .key(&message.key)
+        .headers(headers);
+    if let Some(payload) = &payload {
+        record = record.payload(payload);
+    }
+    let result = producer
+        .producer
+        .send(record, Duration::from_secs(0))
+        .await
+        .map_err(|(kafka_error, _)| kafka_error);
+    (message, result)
+}
+
+// produce_stream is a function that produces Kafka messages as they arrive on a channel,
+// until every sender is dropped.
+// Up to the in-flight window of the producer, messages are sent without waiting for the delivery
+// report of the previous ones; the reports are gathered as they come.
+// A failed message doesn't stop the others: it is counted and listed in the delivery.
+// With a spool, messages Kafka may take later are spooled instead, and so are new messages while the spool
+// isn't empty; spooled messages count as delivered. Messages Kafka refused for good still fail, so that they
+// reach the dead-letter topic.
+// Parameters:
+// - producer: The shared Kafka producer.
+// - messages: The receiving end of a bounded channel; its capacity gives the backpressure.
+// - delivery: Counts the published and failed messages, and lists the failed ones.
+// - delivered: Called with every message acknowledged by Kafka.
+// Returns:
+// - Result<(), rdkafka::error::KafkaError>: Ok once the channel is closed and every message delivered,
+//   or the first delivery error once every delivery report came.
+pub async fn produce_stream<F>(
+    producer: &Producer,
+    messages: Receiver<Message>,
+    delivery: &mut Delivery,
+    delivered: F,
+) -> Result<(), rdkafka::error::KafkaError>
+where
+    F: FnMut(&Message),
+{
+    let spool = producer.spool.as_deref();
+    produce_to(producer, &producer.topic, spool, messages, delivery, delivered).await
+}
+
+// produce_to is a function that produces the messages of a channel to a topic, like produce_stream,
+// with the spool to use, if any.
+async fn produce_to<F>(
+    producer: &Producer,
+    topic: &str,
+    spool: Option<&Spool>,
+    mut messages: Receiver<Message>,
+    delivery: &mut Delivery,
+    mut delivered: F,
+) -> Result<(), rdkafka::error::KafkaError>
+where
+    F: FnMut(&Message),
+{
+    // Log the start of message production to Kafka.
+    logger::log_info(&format!(
+        "Start producing messages to Kafka topic {} on {}",
+        topic, producer.bootstrapserver
+    ));
+
+    let mut in_flight = FuturesUnordered::new();
+    let mut open = true;
+    let mut first_error = None;
+    loop {
+        tokio::select! {
+            // Send the next message while the window has room.
+            message = messages.recv(), if open && in_flight.len() < producer.in_flight => match message {
+                // Keep the message behind the ones waiting in the spool.
+                Some(message) if spool.is_some_and(|spool| !spool.is_empty()) => {
+                    spool_message(spool, message, "Kafka is unavailable", delivery, &mut delivered);
+                }
+                Some(message) => in_flight.push(send(producer, topic, message)),
+                None => open = false,
+            },
+            // Handle the delivery reports as they come.
+            Some((message, result)) = in_flight.next(), if !in_flight.is_empty() => match result {
+                Ok((partition, offset)) => {
+                    // Log successful message delivery.
+                    logger::log_debug(&format!("Delivered {} to partition {} at offset {}", message.key, partition, offset));
+                    delivery.published += 1;
+                    delivered(&message);
+                }
+                // Spool the message to replay it later, unless Kafka will never take it.
+                Err(kafka_error) if spool.is_some() && is_retriable(&kafka_error) => {
+                    spool_message(spool, message, &kafka_error.to_string(), delivery, &mut delivered);
+                }
+                Err(kafka_error) => {
+                    // Record the failure and go on with the other messages.
+                    logger::log_debug(&format!(
+                        "Error in delivering message {} to Kafka topic {:?}",
+                        message.key, kafka_error
+                    ));
+                    delivery.failed += 1;
+                    delivery.failures.push(PublishFailure {
+                        reason: kafka_error.to_string(),
+                        payload: (!message.is_tombstone()).then_some(message.value),
+                        key: message.key,
+                    });
+                    first_error.get_or_insert(kafka_error);
+                }
+            },
+            // The channel is closed and every message has its report.
+            else => break,
+        }
+    }
+
+    match first_error {
+        Some(kafka_error) => Err(kafka_error),
+        None => Ok(()),
+    }
+}
+
+// spool_message is a function that writes a message Kafka couldn't take to the spool of the producer.
+// A message that can't be spooled either is counted as failed.
+// Parameters:
+// - spool: The spool of the producer, if any.
+// - message: The message.
+// - reason: Why the message isn't sent to Kafka.
+// - delivery: Counts the spooled and failed messages.
+// - delivered: Called with the message once it is spooled.
+fn spool_message<F>(spool: Option<&Spool>, message: Message, reason: &str, delivery: &mut Delivery, delivered: &mut F)
+where
+    F: FnMut(&Message),
+{
+    let Some(spool) = spool else {
+        return;
+    };
+    match spool.append(&message) {
+        Ok(()) => {
+            logger::log_debug(&format!("Spooled message {}: {}", message.key, reason));
+            delivery.spooled += 1;
+            delivered(&message);
+        }
+        Err(error) => {
+            logger::log_error(&format!("Error in spooling message {}: {}", message.key, error));
+            delivery.failed += 1;
+            delivery.failures.push(PublishFailure {
+                reason: format!("{}; spooling failed: {}", reason, error),
+                payload: (!message.is_tombstone()).then_some(message.value),
+                key: message.key,
+            });
+        }
+    }
+}
 
-    // Return Ok if all messages are successfully produced.
+// replay_spool is a function that publishes the spooled messages to Kafka in the order they were spooled,
+// a batch at a time; transactional producers have no spool. A batch is removed from the spool
+// once every message of it is delivered, so a message may be published twice but never lost.
+// Messages Kafka refuses for good are moved to the dead-letter topic instead, or dropped and logged
+// without one, so that they don't hold back the spool.
+// Parameters:
+// - producer: The shared Kafka producer.
+// - spool: The spool of the producer.
+// Returns:
+// - io::Result<usize>: The number of messages replayed, or an std::io::Error once a batch fails
+//   while Kafka is unavailable; the batch is replayed again the next time.
+pub async fn replay_spool(producer: &Producer, spool: &Spool) -> io::Result<usize> {
+    let mut replayed = 0;
+    while let Some(mut batch) = spool.read_batch(producer.in_flight)? {
+        let sends = std::mem::take(&mut batch.messages)
+            .into_iter()
+            .map(|message| send(producer, &producer.topic, message));
+        let results = futures::future::join_all(sends).await;
+        let retriable = results
+            .iter()
+            .find(|(_, result)| matches!(result, Err(kafka_error) if is_retriable(kafka_error)));
+        if let Some((message, Err(kafka_error))) = retriable {
+            return Err(io::Error::other(format!(
+                "Error in replaying spooled message {} to Kafka: {}",
+                message.key, kafka_error
+            )));
+        }
+
+        let mut rejected = Vec::new();
+        for (message, result) in results {
+            match result {
+                Ok(_) => replayed += 1,
+                Err(kafka_error) => rejected.push(PublishFailure {
+                    reason: kafka_error.to_string(),
+                    payload: (!message.is_tombstone()).then_some(message.value),
+                    key: message.key,
+                }),
+            }
+        }
+        if !rejected.is_empty() {
+            reject_spooled(producer, &rejected).await?;
+        }
+        spool.consumed(&batch)?;
+    }
+    Ok(replayed)
+}
+
+// reject_spooled is a function that moves the spooled messages Kafka refused for good to the dead-letter topic.
+// Without a dead-letter topic, or when the dead-letter topic refuses them too, they are logged and dropped.
+// Parameters:
+// - producer: The shared Kafka producer.
+// - rejected: The refused messages, with the reason.
+// Returns:
+// - io::Result<()>: Ok once they are dealt with, or an std::io::Error if the dead-letter topic is unavailable.
+async fn reject_spooled(producer: &Producer, rejected: &[PublishFailure]) -> io::Result<()> {
+    let letters: Vec<DeadLetter> = dead_letter::from_publish_failures(rejected, SPOOL_SCAN_ID).collect();
+    let dropped = match produce_dead_letters(producer, &letters).await {
+        Ok(0) => "no dead-letter topic is set".to_string(),
+        Ok(_) => return Ok(()),
+        Err(kafka_error) if is_retriable(&kafka_error) => {
+            return Err(io::Error::other(format!(
+                "Error in moving refused spooled messages to the dead-letter topic: {}",
+                kafka_error
+            )))
+        }
+        Err(kafka_error) => format!("the dead-letter topic refused them: {}", kafka_error),
+    };
+    for failure in rejected {
+        logger::log_error(&format!(
+            "Dropping spooled message {} that Kafka refused ({}), since {}",
+            failure.key, failure.reason, dropped
+        ));
+    }
     Ok(())
 }
 
@@ -69,8 +714,14 @@ pub async fn produce(messages: Vec<Message>) -> Result<(), rdkafka::error::Kafka
 mod test {
     use std::collections::HashMap;
 
-    use crate::message::{Message, PhotoData};
-    use crate::producer::produce;
+    use crate::config::{Config, KafkaConfig, Property};
+    use crate::message::{EventType, Message, PhotoData};
+    use crate::producer::{client_config, is_retriable, produce, produce_stream, replay_spool, Delivery, Producer};
+    use crate::spool::Spool;
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
 
     // Test the produce function.
     #[tokio::test]
@@ -82,12 +733,271 @@ mod test {
         let mut messages: Vec<Message> = Vec::new();
         photo_data
             .entry("title".to_string())
-            .or_insert(PhotoData::default());
+            .or_default();
         let message = Message::new(photo_data);
         messages.push(message);
 
-        // Call the produce function and assert that it returns Ok.
-        let p = produce(messages).await;
-        assert!(matches!(p, Ok(_)))
+        // Create the producer, call the produce function and assert that it returns Ok.
+        let producer = Producer::from_config(&Config::from_env().unwrap().kafka.unwrap()).unwrap();
+        let p = produce(&producer, messages).await;
+        assert!(p.is_ok())
+    }
+
+    // Define a test function for building and checking the producer settings.
+    #[test]
+    fn test_client_config() {
+        // Nested properties get their dotted librdkafka names back.
+        let keepalive = Property::Nested(
+            [("enable".to_string(), Property::Value("true".to_string()))].into(),
+        );
+        let config = KafkaConfig {
+            bootstrapserver: "localhost:9092".to_string(),
+            topics: "photos".to_string(),
+            timeout: 5000,
+            acks: Some("all".to_string()),
+            compression_type: Some("gzip".to_string()),
+            linger_ms: Some(20),
+            enable_idempotence: Some(true),
+            client_id: Some("exif_reader".to_string()),
+            properties: [
+                ("socket".to_string(), Property::Nested([("keepalive".to_string(), keepalive)].into())),
+                ("linger".to_string(), Property::Nested([("ms".to_string(), Property::Value("5".to_string()))].into())),
+            ]
+            .into(),
+            ..KafkaConfig::default()
+        };
+        let client = client_config(&config).unwrap();
+        assert_eq!(client.get("socket.keepalive.enable"), Some("true"));
+        assert_eq!(client.get("compression.type"), Some("gzip"));
+        assert_eq!(client.get("enable.idempotence"), Some("true"));
+        // Assert that the typed settings take precedence over the passthrough properties.
+        assert_eq!(client.get("linger.ms"), Some("20"));
+        assert!(Producer::from_config(&config).is_ok());
+
+        // Assert that unknown values, conflicting settings and unknown properties are refused.
+        let invalid = [
+            KafkaConfig { acks: Some("most".to_string()), ..KafkaConfig::default() },
+            KafkaConfig { compression_type: Some("brotli".to_string()), ..KafkaConfig::default() },
+            KafkaConfig { acks: Some("1".to_string()), enable_idempotence: Some(true), ..KafkaConfig::default() },
+            KafkaConfig {
+                transactional_id: Some("exif_reader".to_string()),
+                enable_idempotence: Some(false),
+                ..KafkaConfig::default()
+            },
+            KafkaConfig { transaction_timeout_ms: Some(60000), ..KafkaConfig::default() },
+            KafkaConfig {
+                timeout: 5000,
+                transactional_id: Some("exif_reader".to_string()),
+                transaction_timeout_ms: Some(1000),
+                ..KafkaConfig::default()
+            },
+        ];
+        for config in &invalid {
+            assert!(client_config(config).is_err());
+        }
+        let unknown = KafkaConfig {
+            properties: [("lingering".to_string(), Property::Value("5".to_string()))].into(),
+            ..KafkaConfig::default()
+        };
+        assert!(Producer::from_config(&unknown).is_err());
+
+        // Assert that transactions turn idempotence on.
+        let transactional = KafkaConfig {
+            transactional_id: Some("exif_reader".to_string()),
+            ..KafkaConfig::default()
+        };
+        let client = client_config(&transactional).unwrap();
+        assert_eq!(client.get("transactional.id"), Some("exif_reader"));
+        assert_eq!(client.get("enable.idempotence"), Some("true"));
+        assert_eq!(client.get("transaction.timeout.ms"), None);
+
+        // Assert that the transaction timeout is passed on.
+        let timed = KafkaConfig {
+            timeout: 5000,
+            transaction_timeout_ms: Some(300000),
+            ..transactional
+        };
+        assert_eq!(client_config(&timed).unwrap().get("transaction.timeout.ms"), Some("300000"));
+    }
+
+    // Define a test function for the SASL and TLS settings.
+    #[test]
+    fn test_security_config() {
+        let directory = tempfile::tempdir().unwrap();
+        let ca = directory.path().join("ca.pem");
+        std::fs::write(&ca, "").unwrap();
+        let ca = ca.to_str().unwrap().to_string();
+
+        // Assert that SCRAM over TLS gets its mechanism, credentials and CA.
+        let config = KafkaConfig {
+            security_protocol: Some("SASL_SSL".to_string()),
+            sasl_mechanism: Some("scram-sha-512".to_string()),
+            sasl_username: Some("exif_reader".to_string()),
+            sasl_password: Some("secret".to_string()),
+            ssl_ca_location: Some(ca.clone()),
+            ..KafkaConfig::default()
+        };
+        let client = client_config(&config).unwrap();
+        assert_eq!(client.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(client.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(client.get("sasl.username"), Some("exif_reader"));
+        assert_eq!(client.get("ssl.ca.location"), Some(ca.as_str()));
+
+        // Assert that missing credentials and files, and settings of another protocol, are refused.
+        let invalid = [
+            KafkaConfig { security_protocol: Some("sasl_ssl".to_string()), ..KafkaConfig::default() },
+            KafkaConfig { security_protocol: Some("kerberos".to_string()), ..KafkaConfig::default() },
+            KafkaConfig { sasl_username: Some("exif_reader".to_string()), ..KafkaConfig::default() },
+            KafkaConfig { ssl_ca_location: Some(ca.clone()), ..KafkaConfig::default() },
+            KafkaConfig {
+                security_protocol: Some("ssl".to_string()),
+                ssl_ca_location: Some(format!("{}.missing", ca)),
+                ..KafkaConfig::default()
+            },
+            KafkaConfig {
+                security_protocol: Some("ssl".to_string()),
+                ssl_certificate_location: Some(ca.clone()),
+                ..KafkaConfig::default()
+            },
+        ];
+        for config in &invalid {
+            assert!(client_config(config).is_err());
+        }
+    }
+
+    // Define a test function for spooling the messages of an unreachable broker.
+    #[tokio::test]
+    async fn test_spool_undelivered() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = Arc::new(
+            Spool::open(&crate::config::SpoolConfig {
+                directory: directory.path().to_str().unwrap().to_string(),
+                segment_size: 1024,
+                replay_interval_ms: 0,
+            })
+            .unwrap(),
+        );
+        // Nothing listens on this port, so every message times out.
+        let config = KafkaConfig {
+            bootstrapserver: "127.0.0.1:1".to_string(),
+            topics: "photos".to_string(),
+            timeout: 100,
+            ..KafkaConfig::default()
+        };
+        let producer = Producer::from_config(&config).unwrap().with_spool(spool.clone()).unwrap();
+
+        // Assert that the undelivered messages are spooled rather than failed.
+        let messages = vec![Message::new(HashMap::new()), Message::new(HashMap::new())];
+        assert!(produce(&producer, messages).await.is_ok());
+        assert!(!spool.is_empty());
+        assert!(spool.stats().unwrap().bytes > 0);
+
+        // Assert that a failed replay keeps the messages for the next one.
+        assert!(replay_spool(&producer, &spool).await.is_err());
+        assert_eq!(spool.read_batch(10).unwrap().unwrap().messages.len(), 2);
+    }
+
+    // Define a test function for the classification of delivery errors.
+    #[test]
+    fn test_is_retriable() {
+        assert!(is_retriable(&KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut)));
+        assert!(is_retriable(&KafkaError::MessageProduction(RDKafkaErrorCode::AllBrokersDown)));
+        assert!(is_retriable(&KafkaError::Canceled));
+        assert!(!is_retriable(&KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge)));
+        assert!(!is_retriable(&KafkaError::MessageProduction(RDKafkaErrorCode::TopicAuthorizationFailed)));
+    }
+
+    // Define a test function for messages Kafka refuses for good, with a spool.
+    #[tokio::test]
+    async fn test_spool_refused() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = Arc::new(
+            Spool::open(&crate::config::SpoolConfig {
+                directory: directory.path().to_str().unwrap().to_string(),
+                segment_size: 1 << 20,
+                replay_interval_ms: 0,
+            })
+            .unwrap(),
+        );
+        // The client refuses messages larger than 1000 bytes before reaching any broker.
+        let config = KafkaConfig {
+            bootstrapserver: "127.0.0.1:1".to_string(),
+            topics: "photos".to_string(),
+            timeout: 100,
+            properties: [(
+                "message".to_string(),
+                Property::Nested(
+                    [(
+                        "max".to_string(),
+                        Property::Nested([("bytes".to_string(), Property::Value("1000".to_string()))].into()),
+                    )]
+                    .into(),
+                ),
+            )]
+            .into(),
+            ..KafkaConfig::default()
+        };
+        let producer = Producer::from_config(&config).unwrap().with_spool(spool.clone()).unwrap();
+        let oversized = || {
+            let mut message = Message::new(HashMap::new());
+            message.key = "oversized.jpg".to_string();
+            message.value = serde_json::json!({ "padding": "x".repeat(2000) });
+            message
+        };
+
+        // Assert that an oversized message fails instead of being spooled.
+        let mut delivery = Delivery::default();
+        let (sender, receiver) = mpsc::channel(1);
+        sender.send(oversized()).await.unwrap();
+        drop(sender);
+        assert!(produce_stream(&producer, receiver, &mut delivery, |_| ()).await.is_err());
+        assert_eq!(delivery.failures.len(), 1);
+        assert_eq!(delivery.failures[0].key, "oversized.jpg");
+        assert!(spool.is_empty());
+
+        // Assert that a replay drops a refused message, without a dead-letter topic, instead of retrying it forever.
+        spool.append(&oversized()).unwrap();
+        assert_eq!(replay_spool(&producer, &spool).await.unwrap(), 0);
+        assert!(spool.is_empty());
+    }
+
+    // Define a test function for the in-flight window and the failures of an unreachable broker.
+    #[tokio::test]
+    async fn test_produce_stream_failures() {
+        // Nothing listens on this port, so every message times out.
+        let config = KafkaConfig {
+            bootstrapserver: "127.0.0.1:1".to_string(),
+            topics: "photos".to_string(),
+            timeout: 100,
+            in_flight: 2,
+            ..KafkaConfig::default()
+        };
+        let producer = Producer::from_config(&config).unwrap();
+        let (sender, receiver) = mpsc::channel(1);
+        let stream = tokio::spawn(async move {
+            let mut delivery = Delivery::default();
+            let mut delivered = 0;
+            let result = produce_stream(&producer, receiver, &mut delivery, |_| delivered += 1).await;
+            (result, delivery, delivered)
+        });
+
+        // Assert that two messages are in flight and one waits on the channel until a delivery report comes.
+        for key in ["a", "b", "c"] {
+            sender.send(Message::tombstone(key, EventType::Deleted)).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(sender.try_send(Message::tombstone("d", EventType::Deleted)).is_err());
+        for key in ["d", "e"] {
+            sender.send(Message::tombstone(key, EventType::Deleted)).await.unwrap();
+        }
+        drop(sender);
+
+        // Assert that every message failed and is listed, and that none counted as delivered.
+        let (result, delivery, delivered) = stream.await.unwrap();
+        assert!(result.is_err());
+        assert_eq!((delivery.published, delivery.failed, delivered), (0, 5, 0));
+        let mut keys: Vec<&str> = delivery.failures.iter().map(|failure| failure.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "c", "d", "e"]);
     }
 }
@@ -0,0 +1,778 @@
+// Import necessary modules from the project.
+use crate::directory_reader;
+use crate::exif_writer::{
+    detect_format, dms, jpeg_layout, Format, Tiff, EXIF_HEADER, TAG_EXIF_IFD, TAG_GPS_IFD,
+};
+use crate::logger;
+use crate::utils::invalid_data;
+
+// Import necessary modules from the standard library and external crates.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+// GPS tags kept when coordinates are coarsened: version, references, latitude, longitude and datum.
+const COARSE_GPS_TAGS: [u16; 6] = [0x0000, 0x0001, 0x0002, 0x0003, 0x0004, 0x0012];
+// GPS tags holding the latitude and the longitude.
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+// TIFF tag holding an XMP packet.
+const TAG_XMP: u16 = 0x02BC;
+// IFD0 tag holding the camera serial number (DNG).
+const TAG_CAMERA_SERIAL_NUMBER: u16 = 0xC62F;
+// Exif tags that can identify a camera: maker note, body and lens serial numbers.
+const SERIAL_TAGS: [u16; 3] = [0x927C, 0xA431, 0xA435];
+// Identifiers of the standard and extended XMP APP1 segments.
+const XMP_HEADERS: [&[u8]; 2] = [
+    b"http://ns.adobe.com/xap/1.0/\0",
+    b"http://ns.adobe.com/xmp/extension/\0",
+];
+// TIFF tags holding IPTC data and Photoshop resources, which can repeat the location.
+const TAG_IPTC: u16 = 0x83BB;
+const TAG_PHOTOSHOP: u16 = 0x8649;
+// Identifier of the APP2 segment indexing the images of a multi-picture file (MPO).
+const MPF_HEADER: &[u8] = b"MPF\0";
+// Identifier of the APP13 segment holding Photoshop resources.
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+// Photoshop resources holding IPTC data and its digest.
+const RESOURCE_IPTC: u16 = 0x0404;
+const RESOURCE_IPTC_DIGEST: u16 = 0x0425;
+// Photoshop resources holding Exif data and an XMP packet, which can't be rewritten safely.
+const LOCATED_RESOURCES: [(u16, &str); 3] = [(0x0422, "Exif"), (0x0423, "Exif"), (0x0424, "XMP")];
+// IPTC application records naming the location: content location code and name, city, sublocation,
+// province or state, country code and country name.
+const IPTC_LOCATION_DATASETS: [u8; 7] = [26, 27, 90, 92, 95, 100, 101];
+
+// Define what happens to the location of the copies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedactMode {
+    // Remove every GPS tag.
+    Strip,
+    // Snap latitude and longitude to a grid with the given cell size, in degrees,
+    // and remove the other GPS tags.
+    Coarsen(f64),
+}
+
+// Define the options of a redaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedactOptions {
+    pub mode: RedactMode,
+    // Also remove serial numbers and maker notes.
+    pub strip_serials: bool,
+}
+
+// Define the outcome of redacting a single file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileRedaction {
+    pub source: String,
+    // The redacted copy, if one was written.
+    pub copy: Option<String>,
+    pub changes: Vec<String>,
+    // Why no copy was written.
+    pub skipped: Option<String>,
+}
+
+// Define the report of a redaction.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RedactionReport {
+    pub files: Vec<FileRedaction>,
+}
+
+impl RedactionReport {
+    // Log what was changed in every file.
+    pub fn log(&self) {
+        for file in &self.files {
+            match (&file.copy, &file.skipped) {
+                (Some(copy), _) if file.changes.is_empty() => {
+                    logger::log_info(&format!("{} copied to {} unchanged", file.source, copy))
+                }
+                (Some(copy), _) => logger::log_info(&format!(
+                    "{} copied to {}: {}",
+                    file.source,
+                    copy,
+                    file.changes.join(", ")
+                )),
+                (None, skipped) => logger::log_info(&format!(
+                    "{} skipped: {}",
+                    file.source,
+                    skipped.as_deref().unwrap_or("unknown reason")
+                )),
+            }
+        }
+    }
+}
+
+// tag_name is a function that returns the name of a removed tag for the report.
+fn tag_name(tag: u16) -> String {
+    match tag {
+        0x927C => "MakerNote".to_string(),
+        0xA431 => "BodySerialNumber".to_string(),
+        0xA435 => "LensSerialNumber".to_string(),
+        TAG_CAMERA_SERIAL_NUMBER => "CameraSerialNumber".to_string(),
+        tag => format!("tag {:#06x}", tag),
+    }
+}
+
+// snap is a function that snaps a coordinate, given as degree, minute and second rationals,
+// to a grid with the given cell size.
+fn snap(values: &[(u32, u32)], grid: f64) -> f64 {
+    let value: f64 = values
+        .iter()
+        .zip([1.0, 60.0, 3600.0])
+        .map(|(&(numerator, denominator), unit)| {
+            if denominator == 0 {
+                0.0
+            } else {
+                numerator as f64 / denominator as f64 / unit
+            }
+        })
+        .sum();
+    (value / grid).round() * grid
+}
+
+// redact_tiff is a function that redacts a TIFF structure in place.
+// Removed values and unreferenced data are zeroed, so nothing of them is left in the copy.
+// Parameters:
+// - tiff: The TIFF structure of a TIFF file or of a JPEG Exif segment.
+// - options: The redaction options.
+// Returns:
+// - io::Result<Vec<String>>: The changes made, or an std::io::Error if the structure is malformed.
+fn redact_tiff(tiff: &mut Tiff, options: &RedactOptions) -> io::Result<Vec<String>> {
+    let ifd0 = tiff.ifd0()?;
+    let mut changes: Vec<String> = Vec::new();
+
+    if let Some(gps) = tiff.sub_ifd(ifd0, TAG_GPS_IFD)? {
+        match options.mode {
+            RedactMode::Strip => {
+                tiff.remove_entries(gps, |_| true)?;
+                tiff.remove_entries(ifd0, |tag| tag == TAG_GPS_IFD)?;
+                changes.push("GPS removed".to_string());
+            }
+            RedactMode::Coarsen(grid) => {
+                let removed = tiff.remove_entries(gps, |tag| !COARSE_GPS_TAGS.contains(&tag))?;
+                for tag in [TAG_GPS_LATITUDE, TAG_GPS_LONGITUDE] {
+                    let Some(values) = tiff.rationals(gps, tag)? else {
+                        continue;
+                    };
+                    // A coordinate that can't be rewritten in place is removed instead.
+                    if !tiff.set_rationals(gps, tag, &dms(snap(&values, grid)))? {
+                        tiff.remove_entries(gps, |candidate| candidate == tag)?;
+                    }
+                }
+                changes.push(format!("GPS snapped to a {} degree grid", grid));
+                if !removed.is_empty() {
+                    changes.push(format!("{} other GPS tags removed", removed.len()));
+                }
+            }
+        }
+    }
+
+    if options.strip_serials {
+        let mut removed = tiff.remove_entries(ifd0, |tag| tag == TAG_CAMERA_SERIAL_NUMBER)?;
+        if let Some(exif) = tiff.sub_ifd(ifd0, TAG_EXIF_IFD)? {
+            removed.extend(tiff.remove_entries(exif, |tag| SERIAL_TAGS.contains(&tag))?);
+        }
+        changes.extend(
+            removed
+                .into_iter()
+                .map(|tag| format!("{} removed", tag_name(tag))),
+        );
+    }
+
+    // XMP packets can repeat the location and the serial numbers, and can't be rewritten safely.
+    if !tiff.remove_entries(ifd0, |tag| tag == TAG_XMP)?.is_empty() {
+        changes.push("XMP packet removed".to_string());
+    }
+    // So can IPTC data and Photoshop resources.
+    if !tiff.remove_entries(ifd0, |tag| tag == TAG_IPTC || tag == TAG_PHOTOSHOP)?.is_empty() {
+        changes.push("IPTC data removed".to_string());
+    }
+
+    // IFDs replaced by earlier edits are no longer referenced, but can still hold a location.
+    if tiff.scrub()? {
+        changes.push("Unreferenced data cleared".to_string());
+    }
+
+    Ok(changes)
+}
+
+// redact_iptc is a function that removes the location datasets from IPTC data.
+// Parameters:
+// - data: The IPTC datasets.
+// Returns:
+// - io::Result<(Vec<u8>, usize)>: The remaining datasets and the number of removed ones,
+//   or an std::io::Error if the datasets are malformed.
+fn redact_iptc(data: &[u8]) -> io::Result<(Vec<u8>, usize)> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut removed = 0;
+    let mut position = 0;
+    while position < data.len() {
+        let dataset = &data[position..];
+        // Datasets can be followed by padding.
+        if dataset.iter().all(|&byte| byte == 0) {
+            output.extend(dataset);
+            break;
+        }
+        if dataset[0] != 0x1C || dataset.len() < 5 {
+            return Err(invalid_data("Malformed IPTC dataset"));
+        }
+        // An extended dataset gives the size of its length first.
+        let length = u16::from_be_bytes([dataset[3], dataset[4]]) as usize;
+        let (header, length) = if length & 0x8000 == 0 {
+            (5, length)
+        } else {
+            let size = length & 0x7FFF;
+            let bytes = dataset
+                .get(5..5 + size)
+                .filter(|bytes| bytes.len() <= 8)
+                .ok_or_else(|| invalid_data("Malformed IPTC dataset"))?;
+            (5 + size, bytes.iter().fold(0usize, |length, &byte| (length << 8) | byte as usize))
+        };
+        let end = header
+            .checked_add(length)
+            .filter(|&end| end <= dataset.len())
+            .ok_or_else(|| invalid_data("Truncated IPTC dataset"))?;
+        if dataset[1] == 2 && IPTC_LOCATION_DATASETS.contains(&dataset[2]) {
+            removed += 1;
+        } else {
+            output.extend(&dataset[..end]);
+        }
+        position += end;
+    }
+    Ok((output, removed))
+}
+
+// redact_photoshop is a function that removes the location from the Photoshop resources of an APP13
+// segment: the IPTC location datasets, and the Exif and XMP resources.
+// Parameters:
+// - resources: The resource blocks following the Photoshop header.
+// Returns:
+// - io::Result<(Vec<u8>, Vec<String>)>: The redacted resource blocks and the changes made,
+//   or an std::io::Error if the blocks are malformed.
+fn redact_photoshop(resources: &[u8]) -> io::Result<(Vec<u8>, Vec<String>)> {
+    let mut output = Vec::with_capacity(resources.len());
+    let mut changes: Vec<String> = Vec::new();
+    let mut position = 0;
+    while position < resources.len() {
+        let block = &resources[position..];
+        if !block.starts_with(b"8BIM") || block.len() < 8 {
+            return Err(invalid_data("Malformed Photoshop resource"));
+        }
+        let id = u16::from_be_bytes([block[4], block[5]]);
+        // The name is a Pascal string and the data are padded to an even size.
+        let size_at = 6 + ((block[6] as usize + 2) & !1);
+        let size = block
+            .get(size_at..size_at + 4)
+            .map(|size| u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize)
+            .ok_or_else(|| invalid_data("Truncated Photoshop resource"))?;
+        let data_start = size_at + 4;
+        if data_start + size > block.len() {
+            return Err(invalid_data("Truncated Photoshop resource"));
+        }
+        let data = &block[data_start..data_start + size];
+        let end = (data_start + size + (size & 1)).min(block.len());
+        position += end;
+
+        if let Some((_, name)) = LOCATED_RESOURCES.iter().find(|(resource, _)| *resource == id) {
+            changes.push(format!("Photoshop {} resource removed", name));
+            continue;
+        }
+        match id {
+            // The digest of the IPTC data would no longer match, and tells nothing.
+            RESOURCE_IPTC_DIGEST => (),
+            RESOURCE_IPTC => {
+                let (iptc, removed) = redact_iptc(data)?;
+                if removed > 0 {
+                    changes.push(format!("{} IPTC location fields removed", removed));
+                }
+                output.extend(&block[..size_at]);
+                output.extend((iptc.len() as u32).to_be_bytes());
+                output.extend(&iptc);
+                if iptc.len() % 2 == 1 {
+                    output.push(0);
+                }
+            }
+            _ => output.extend(&block[..end]),
+        }
+    }
+    Ok((output, changes))
+}
+
+// image_end is a function that finds the end of the image of a JPEG file: the end of its EOI marker,
+// past the entropy-coded data and the segments between the scans of a progressive image.
+// Parameters:
+// - bytes: The content of the file.
+// - position: Where the first scan, or the EOI marker, starts.
+// Returns:
+// - usize: The end of the image; the end of the file if it has no EOI marker.
+fn image_end(bytes: &[u8], mut position: usize) -> usize {
+    while position + 2 <= bytes.len() {
+        if bytes[position] != 0xFF {
+            position += 1;
+            continue;
+        }
+        match bytes[position + 1] {
+            0xD9 => return position + 2,
+            // Fill bytes.
+            0xFF => position += 1,
+            // Stuffed bytes and restart markers inside the entropy-coded data.
+            0x00 | 0x01 | 0xD0..=0xD7 => position += 2,
+            _ if position + 4 > bytes.len() => break,
+            _ => position += 2 + u16::from_be_bytes([bytes[position + 2], bytes[position + 3]]) as usize,
+        }
+    }
+    bytes.len()
+}
+
+// redact_jpeg is a function that returns a redacted copy of a JPEG file.
+// Embedded images and vendor data following the image, such as the secondary images of an MPO file
+// or depth maps, carry their own metadata, so they are removed with the index of the MPF segment.
+fn redact_jpeg(bytes: &[u8], options: &RedactOptions) -> io::Result<(Vec<u8>, Vec<String>)> {
+    let layout = jpeg_layout(bytes)?;
+    let mut output: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut changes: Vec<String> = Vec::new();
+    let mut cursor = 2;
+    output.extend(&bytes[..2]);
+
+    for (marker, start, end) in layout.segments {
+        output.extend(&bytes[cursor..start]);
+        cursor = end;
+        let payload = &bytes[start + 4..end];
+
+        if marker == 0xE1 && XMP_HEADERS.iter().any(|header| payload.starts_with(header)) {
+            changes.push("XMP packet removed".to_string());
+            continue;
+        }
+        if marker == 0xE1 && payload.starts_with(EXIF_HEADER) {
+            // The structure keeps its size, so the segment header stays valid.
+            let mut tiff = Tiff::parse(payload[EXIF_HEADER.len()..].to_vec())?;
+            changes.extend(redact_tiff(&mut tiff, options)?);
+            output.extend(&bytes[start..start + 4 + EXIF_HEADER.len()]);
+            output.extend(tiff.into_bytes());
+            continue;
+        }
+        if marker == 0xE2 && payload.starts_with(MPF_HEADER) {
+            changes.push("MPF index removed".to_string());
+            continue;
+        }
+        if marker == 0xED && payload.starts_with(PHOTOSHOP_HEADER) {
+            // Resources that can't be read can't be redacted either.
+            let Ok((resources, removed)) = redact_photoshop(&payload[PHOTOSHOP_HEADER.len()..]) else {
+                changes.push("Unreadable Photoshop segment removed".to_string());
+                continue;
+            };
+            if removed.is_empty() {
+                output.extend(&bytes[start..end]);
+                continue;
+            }
+            changes.extend(removed);
+            let length = 2 + PHOTOSHOP_HEADER.len() + resources.len();
+            output.extend([0xFF, 0xED]);
+            output.extend((length as u16).to_be_bytes());
+            output.extend(PHOTOSHOP_HEADER);
+            output.extend(resources);
+            continue;
+        }
+        output.extend(&bytes[start..end]);
+    }
+
+    let end = image_end(bytes, cursor);
+    output.extend(&bytes[cursor..end]);
+    let trailer = &bytes[end..];
+    if trailer.iter().any(|&byte| byte != 0) {
+        let images = trailer.windows(3).filter(|window| window == &[0xFF, 0xD8, 0xFF]).count();
+        changes.push(match images {
+            0 => format!("{} bytes of trailing data removed", trailer.len()),
+            1 => "1 embedded image removed".to_string(),
+            images => format!("{} embedded images removed", images),
+        });
+    } else {
+        output.extend(trailer);
+    }
+
+    Ok((output, changes))
+}
+
+// redact_bytes is a function that returns a redacted copy of a JPEG or TIFF-based file.
+// Parameters:
+// - bytes: The content of the file.
+// - options: The redaction options.
+// Returns:
+// - io::Result<(Vec<u8>, Vec<String>)>: The redacted content and the changes made,
+//   or an std::io::Error if the format isn't supported.
+pub fn redact_bytes(bytes: &[u8], options: &RedactOptions) -> io::Result<(Vec<u8>, Vec<String>)> {
+    match detect_format(bytes) {
+        Format::Jpeg => redact_jpeg(bytes, options),
+        Format::Tiff => {
+            let mut tiff = Tiff::parse(bytes.to_vec())?;
+            let changes = redact_tiff(&mut tiff, options)?;
+            Ok((tiff.into_bytes(), changes))
+        }
+        Format::Other => Err(invalid_data("Unsupported format")),
+    }
+}
+
+// redact_directory is a function that writes redacted copies of the photos of a directory
+// into another directory, keeping their relative paths. The originals are only read.
+// Files that can't be redacted are not copied.
+// Parameters:
+// - source: The directory to redact, traversed like a scan.
+// - destination: The directory receiving the copies, which must not overlap the source.
+// - options: The redaction options.
+// Returns:
+// - io::Result<RedactionReport>: What was changed in every file, or an std::io::Error.
+pub fn redact_directory(
+    source: &str,
+    destination: &str,
+    options: &RedactOptions,
+) -> io::Result<RedactionReport> {
+    if let RedactMode::Coarsen(grid) = options.mode {
+        if grid.is_nan() || grid <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The grid size must be positive",
+            ));
+        }
+    }
+
+    // Copies written inside the source could be picked up again, or overwrite originals.
+    let source_root = fs::canonicalize(source)?;
+    fs::create_dir_all(destination)?;
+    let destination_root = fs::canonicalize(destination)?;
+    if destination_root.starts_with(&source_root) || source_root.starts_with(&destination_root) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The destination must be outside the source directory",
+        ));
+    }
+
+    let mut report = RedactionReport::default();
+    for file in directory_reader::files(source, true)? {
+        let path = Path::new(&file);
+        // A single file is copied into the destination directly.
+        let relative = match path.strip_prefix(source) {
+            Ok(relative) if relative.as_os_str().is_empty() => {
+                PathBuf::from(path.file_name().unwrap_or_default())
+            }
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => PathBuf::from(path.file_name().unwrap_or_default()),
+        };
+        let copy = destination_root.join(relative);
+
+        let result = fs::read(path)
+            .and_then(|bytes| redact_bytes(&bytes, options))
+            .and_then(|(content, changes)| {
+                if let Some(parent) = copy.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&copy, content)?;
+                Ok(changes)
+            });
+
+        report.files.push(match result {
+            Ok(changes) => FileRedaction {
+                source: file,
+                copy: Some(copy.display().to_string()),
+                changes,
+                skipped: None,
+            },
+            Err(error) => FileRedaction {
+                source: file,
+                copy: None,
+                changes: Vec::new(),
+                skipped: Some(error.to_string()),
+            },
+        });
+    }
+
+    Ok(report)
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exif_writer::{write_geotag, Geotag, WriteOptions};
+    use exif::{In, Tag};
+    use std::io::Cursor;
+
+    // geotagged_jpeg is a helper that writes a JPEG geotagged with an altitude into a directory.
+    fn geotagged_jpeg(directory: &Path) -> PathBuf {
+        let path = directory.join("photo.jpg");
+        fs::write(&path, [0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+        let geotag = Geotag {
+            lat: 45.043938,
+            lon: -39.032085,
+            altitude: Some(27.813),
+            offset_time: None,
+        };
+        write_geotag(&path, &geotag, &WriteOptions::default()).unwrap();
+        path
+    }
+
+    // field is a helper that reads the displayed value of a tag.
+    fn field(bytes: &[u8], tag: Tag) -> Option<String> {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(bytes))
+            .unwrap();
+        exif.get_field(tag, In::PRIMARY)
+            .map(|field| field.display_value().with_unit(&exif).to_string())
+    }
+
+    // Define a test function for stripping GPS from copies.
+    #[test]
+    fn test_strip() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = geotagged_jpeg(source.path());
+        fs::create_dir(source.path().join("notes")).unwrap();
+        fs::write(source.path().join("notes/readme.txt"), "not a photo").unwrap();
+        let original = fs::read(&path).unwrap();
+
+        let options = RedactOptions {
+            mode: RedactMode::Strip,
+            strip_serials: false,
+        };
+        let mut report = redact_directory(
+            source.path().to_str().unwrap(),
+            destination.path().to_str().unwrap(),
+            &options,
+        )
+        .unwrap();
+        report.files.sort_by(|a, b| a.source.cmp(&b.source));
+
+        // The photo is copied without GPS; the other file is skipped.
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(
+            report.files[0].skipped.as_deref(),
+            Some("Unsupported format")
+        );
+        assert_eq!(report.files[1].changes, vec!["GPS removed".to_string()]);
+        let copy = fs::read(destination.path().join("photo.jpg")).unwrap();
+        assert_eq!(copy.len(), original.len());
+        assert_eq!(field(&copy, Tag::GPSLatitude), None);
+        assert_eq!(field(&copy, Tag::GPSAltitude), None);
+        // Nothing of the coordinates is left in the copy.
+        assert!(!copy.windows(6).any(|window| window == b"WGS-84"));
+
+        // The original is untouched.
+        assert_eq!(fs::read(&path).unwrap(), original);
+    }
+
+    // Define a test function for coarsening GPS in copies.
+    #[test]
+    fn test_coarsen() {
+        let source = tempfile::tempdir().unwrap();
+        let path = geotagged_jpeg(source.path());
+
+        let options = RedactOptions {
+            mode: RedactMode::Coarsen(0.1),
+            strip_serials: false,
+        };
+        let (copy, changes) = redact_bytes(&fs::read(&path).unwrap(), &options).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                "GPS snapped to a 0.1 degree grid".to_string(),
+                "2 other GPS tags removed".to_string()
+            ]
+        );
+        assert_eq!(
+            field(&copy, Tag::GPSLatitude).as_deref(),
+            Some("45 deg 0 min 0 sec N")
+        );
+        assert_eq!(
+            field(&copy, Tag::GPSLongitude).as_deref(),
+            Some("39 deg 0 min 0 sec W")
+        );
+        assert_eq!(field(&copy, Tag::GPSAltitude), None);
+    }
+
+    // Define a test function for stripping serial numbers.
+    #[test]
+    fn test_strip_serials() {
+        // A little-endian TIFF with an Exif IFD holding a BodySerialNumber.
+        let mut bytes: Vec<u8> = b"II*\0\x08\0\0\0".to_vec();
+        bytes.extend([1, 0, 0x69, 0x87, 4, 0, 1, 0, 0, 0, 26, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend([1, 0, 0x31, 0xA4, 2, 0, 9, 0, 0, 0, 44, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend(b"SN123456\0");
+
+        let options = RedactOptions {
+            mode: RedactMode::Strip,
+            strip_serials: true,
+        };
+        let (copy, changes) = redact_bytes(&bytes, &options).unwrap();
+        assert_eq!(changes, vec!["BodySerialNumber removed".to_string()]);
+        assert_eq!(copy.len(), bytes.len());
+        assert!(!copy.windows(8).any(|window| window == b"SN123456"));
+    }
+
+    // Define a test function for stripping a TIFF holding a GPS IFD left behind by an earlier edit.
+    #[test]
+    fn test_strip_orphaned_gps() {
+        let rational = |numerator: u32, denominator: u32| {
+            let mut raw = numerator.to_le_bytes().to_vec();
+            raw.extend(denominator.to_le_bytes());
+            raw
+        };
+        // A little-endian TIFF whose IFD0 points to the GPS IFD at 68, after an orphaned one at 26.
+        let mut bytes: Vec<u8> = b"II*\0\x08\0\0\0".to_vec();
+        bytes.extend([1, 0, 0x25, 0x88, 4, 0, 1, 0, 0, 0, 68, 0, 0, 0, 0, 0, 0, 0]);
+        for (offset, seconds) in [(44, 381768), (86, 123456)] {
+            bytes.extend([1, 0, 2, 0, 5, 0, 3, 0, 0, 0, offset, 0, 0, 0, 0, 0, 0, 0]);
+            bytes.extend(rational(45, 1));
+            bytes.extend(rational(2, 1));
+            bytes.extend(rational(seconds, 10000));
+        }
+
+        let options = RedactOptions {
+            mode: RedactMode::Strip,
+            strip_serials: false,
+        };
+        let (copy, changes) = redact_bytes(&bytes, &options).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                "GPS removed".to_string(),
+                "Unreferenced data cleared".to_string()
+            ]
+        );
+        assert_eq!(copy.len(), bytes.len());
+        // Neither the live nor the orphaned coordinates are left in the copy.
+        for seconds in [381768, 123456] {
+            let raw = rational(seconds, 10000);
+            assert!(!copy.windows(raw.len()).any(|window| window == raw));
+        }
+    }
+
+    // Define a test function for a multi-picture file whose secondary image is geotagged.
+    #[test]
+    fn test_strip_mpf() {
+        let source = tempfile::tempdir().unwrap();
+        let secondary = fs::read(geotagged_jpeg(source.path())).unwrap();
+        // A primary image with an MPF index, a scan with stuffed and restart bytes, then the secondary image.
+        let mut bytes: Vec<u8> = vec![0xFF, 0xD8];
+        bytes.extend([0xFF, 0xE2, 0, 14]);
+        bytes.extend(b"MPF\0II*\0\x08\0\0\0");
+        bytes.extend([0xFF, 0xDA, 0, 8, 1, 1, 0, 0, 0x3F, 0]);
+        bytes.extend([0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56]);
+        bytes.extend([0xFF, 0xD9]);
+        let primary = bytes.len() - 16;
+        bytes.extend(&secondary);
+
+        let options = RedactOptions {
+            mode: RedactMode::Coarsen(0.1),
+            strip_serials: false,
+        };
+        let (copy, changes) = redact_bytes(&bytes, &options).unwrap();
+        assert_eq!(
+            changes,
+            vec!["MPF index removed".to_string(), "1 embedded image removed".to_string()]
+        );
+        // Only the primary image is left, without the index.
+        assert_eq!(copy.len(), primary);
+        assert!(copy.ends_with(&[0x56, 0xFF, 0xD9]));
+        assert!(!copy.windows(6).any(|window| window == b"WGS-84"));
+    }
+
+    // Define a test function for the IPTC location in the Photoshop segment of a JPEG.
+    #[test]
+    fn test_strip_photoshop() {
+        let dataset = |number: u8, value: &[u8]| {
+            let mut raw = vec![0x1C, 2, number];
+            raw.extend((value.len() as u16).to_be_bytes());
+            raw.extend(value);
+            raw
+        };
+        let resource = |id: u16, data: &[u8]| {
+            let mut raw = b"8BIM".to_vec();
+            raw.extend(id.to_be_bytes());
+            raw.extend([0, 0]);
+            raw.extend((data.len() as u32).to_be_bytes());
+            raw.extend(data);
+            if data.len() % 2 == 1 {
+                raw.push(0);
+            }
+            raw
+        };
+        // IPTC data with a title, a city and a country, its digest, and an XMP packet.
+        let iptc = [dataset(5, b"Sunset"), dataset(90, b"Krasnodar"), dataset(101, b"Russia")].concat();
+        let mut segment = PHOTOSHOP_HEADER.to_vec();
+        segment.extend(resource(RESOURCE_IPTC, &iptc));
+        segment.extend(resource(RESOURCE_IPTC_DIGEST, &[7; 16]));
+        segment.extend(resource(0x0424, b"<x:xmpmeta>45.0439</x:xmpmeta>"));
+        let mut bytes: Vec<u8> = vec![0xFF, 0xD8, 0xFF, 0xED];
+        bytes.extend(((segment.len() + 2) as u16).to_be_bytes());
+        bytes.extend(&segment);
+        bytes.extend([0xFF, 0xD9]);
+
+        let options = RedactOptions {
+            mode: RedactMode::Strip,
+            strip_serials: false,
+        };
+        let (copy, changes) = redact_bytes(&bytes, &options).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                "2 IPTC location fields removed".to_string(),
+                "Photoshop XMP resource removed".to_string()
+            ]
+        );
+        // The segment is rebuilt with the title only.
+        let layout = jpeg_layout(&copy).unwrap();
+        let (_, start, end) = layout.segments[0];
+        let expected = [PHOTOSHOP_HEADER.to_vec(), resource(RESOURCE_IPTC, &dataset(5, b"Sunset"))].concat();
+        assert_eq!(&copy[start + 4..end], expected.as_slice());
+        assert!(copy.ends_with(&[0xFF, 0xD9]));
+    }
+
+    // Define a test function for leaving the data of a maker note alone.
+    #[test]
+    fn test_keep_maker_note_data() {
+        // A little-endian TIFF with an Exif IFD holding a maker note whose value points to data after it.
+        let mut bytes: Vec<u8> = b"II*\0\x08\0\0\0".to_vec();
+        bytes.extend([1, 0, 0x69, 0x87, 4, 0, 1, 0, 0, 0, 26, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend([1, 0, 0x7C, 0x92, 7, 0, 8, 0, 0, 0, 44, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend([2, 0, 0, 0, 52, 0, 0, 0]);
+        bytes.extend(b"LENSDATA");
+
+        // Assert that the data the maker note points to is kept, unless the maker note is removed.
+        let options = RedactOptions {
+            mode: RedactMode::Strip,
+            strip_serials: false,
+        };
+        let (copy, changes) = redact_bytes(&bytes, &options).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(copy, bytes);
+        let options = RedactOptions {
+            strip_serials: true,
+            ..options
+        };
+        let (copy, changes) = redact_bytes(&bytes, &options).unwrap();
+        assert_eq!(
+            changes,
+            vec!["MakerNote removed".to_string(), "Unreferenced data cleared".to_string()]
+        );
+        assert!(!copy.windows(8).any(|window| window == b"LENSDATA"));
+    }
+
+    // Define a test function for a destination inside the source directory.
+    #[test]
+    fn test_overlapping_destination() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = source.path().join("redacted");
+        let options = RedactOptions {
+            mode: RedactMode::Strip,
+            strip_serials: false,
+        };
+
+        let result = redact_directory(
+            source.path().to_str().unwrap(),
+            destination.to_str().unwrap(),
+            &options,
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,559 @@
+// Import necessary modules from the project.
+use crate::config::{Config, TrackConfig};
+use crate::dead_letter;
+use crate::directory_reader::{self, WalkOptions};
+use crate::enricher::Enricher;
+use crate::exif_writer::{self, WriteOptions};
+use crate::file_filter::{FileFilter, FilterOptions};
+use crate::file_state::{payload_hash, StateCache};
+use crate::logger;
+use crate::media_source::{LocalFs, MediaSource, Sources};
+use crate::message::{EventType, Message};
+use crate::privacy::PrivacyZones;
+use crate::producer::{produce_dead_letters, replay_spool, Delivery, Producer};
+use crate::scan_report::ScanReport;
+use crate::sink::{Sink, Sinks};
+use crate::spool::Spool;
+use crate::track::{TrackEnricher, TrackOptions};
+
+// Import necessary modules from the standard library and external crates.
+use std::collections::HashSet;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+// Define the settings shared by every scan.
+pub struct ScanSettings {
+    // Extraction threads and output order.
+    pub walk_options: WalkOptions,
+    // The files to extract, before the options of each scan.
+    pub filter: FilterOptions,
+    // Number of extracted messages waiting to be produced before extraction pauses.
+    pub buffer: usize,
+    // Directory receiving the JSON report of every scan, if set.
+    pub report_dir: Option<String>,
+    // Zones checked before messages are published.
+    pub privacy: PrivacyZones,
+    // The remote sources of s3:// and sftp:// scans.
+    pub sources: Sources,
+    // The sinks the scans write to, Kafka with its shared producer among them if configured.
+    pub sinks: Sinks,
+    // The disk spool of the Kafka producer, if any.
+    pub spool: Option<Arc<Spool>>,
+}
+
+impl ScanSettings {
+    // from_config is a function that creates the settings of the scans from the configuration: the file-state cache,
+    // the privacy zones, the remote sources and the sinks, Kafka among them if it is configured.
+    // With a spool, the thread replaying it to Kafka is started too, so it must be called within a Tokio runtime.
+    // Parameters:
+    // - config: The configuration of the service.
+    // Returns:
+    // - io::Result<ScanSettings>: The settings, or an std::io::Error if the cache, the privacy zones, the Kafka producer,
+    //   its spool or the default sink can't be set up.
+    pub fn from_config(config: &Config) -> io::Result<ScanSettings> {
+        let privacy = match &config.privacy {
+            Some(config) => PrivacyZones::from_config(config)?,
+            None => PrivacyZones::default(),
+        };
+        let cache = match &config.cache {
+            Some(config) => Some(Arc::new(StateCache::from_config(config).map_err(io::Error::other)?)),
+            None => None,
+        };
+        // A broken Kafka configuration stops the service before it accepts any request.
+        let mut producer = config.kafka.as_ref().map(Producer::from_config).transpose()?;
+        // Spool what Kafka can't take, and replay it in the background once the brokers are back.
+        let spool = match (&config.spool, &mut producer) {
+            (Some(config), Some(kafka)) => {
+                let spool = Arc::new(Spool::open(config)?);
+                *kafka = kafka.clone().with_spool(spool.clone())?;
+                replay_in_background(kafka, &spool, Duration::from_millis(config.replay_interval_ms.max(1)));
+                Some(spool)
+            }
+            _ => None,
+        };
+        Ok(ScanSettings {
+            walk_options: WalkOptions {
+                threads: config.scan.threads,
+                ordered: config.scan.ordered,
+                strict: config.scan.strict,
+                cache,
+                ..WalkOptions::default()
+            },
+            filter: FilterOptions::from_config(&config.scan),
+            buffer: config.scan.buffer,
+            report_dir: config.scan.report_dir.clone(),
+            privacy,
+            sources: Sources::from_config(config),
+            sinks: Sinks::from_config(&config.sink, producer)?,
+            spool,
+        })
+    }
+}
+
+// replay_in_background is a function that starts the thread replaying the spool to Kafka,
+// which wakes up at every interval while the service runs and logs the size and age of the spool.
+// Parameters:
+// - producer: The shared Kafka producer.
+// - spool: The spool of the producer.
+// - interval: How long to wait between replays.
+fn replay_in_background(producer: &Producer, spool: &Arc<Spool>, interval: Duration) {
+    let (producer, spool, runtime) = (producer.clone(), spool.clone(), Handle::current());
+    std::thread::spawn(move || loop {
+        if !spool.is_empty() {
+            match runtime.block_on(replay_spool(&producer, &spool)) {
+                Ok(replayed) => logger::log_info(&format!("Replayed {} spooled messages to Kafka", replayed)),
+                Err(error) => logger::log_debug(&format!("Kafka is still unavailable, {}", error)),
+            }
+            match spool.stats() {
+                Ok(stats) if stats.bytes > 0 => logger::log_info(&format!(
+                    "Spool holds {} bytes, the oldest message waits for {} s",
+                    stats.bytes, stats.age_secs
+                )),
+                Ok(_) => (),
+                Err(error) => logger::log_error(&format!("Error while measuring the spool, {}", error)),
+            }
+        }
+        std::thread::sleep(interval);
+    });
+}
+
+// open_track is a function that loads the track logs of a scan, if any were given.
+pub fn open_track(options: &TrackOptions, config: &TrackConfig) -> io::Result<Option<TrackEnricher>> {
+    if options.files.is_empty() {
+        return Ok(None);
+    }
+    TrackEnricher::open(options, config).map(Some)
+}
+
+// Define the enrichers of a scan, owned so that the extraction can run on its own thread.
+#[derive(Clone)]
+pub struct ScanEnrichers {
+    // The track log of the scan, if any.
+    pub track: Option<Arc<TrackEnricher>>,
+    // The configured enrichers.
+    pub configured: Arc<Vec<Box<dyn Enricher>>>,
+}
+
+impl ScanEnrichers {
+    // list is a method that lists the enrichers of the scan: the track log first,
+    // so that the configured enrichers see the inferred positions.
+    pub fn list(&self) -> Vec<&dyn Enricher> {
+        let mut scan: Vec<&dyn Enricher> = Vec::new();
+        if let Some(track) = &self.track {
+            scan.push(track.as_ref());
+        }
+        scan.extend(self.configured.iter().map(|enricher| enricher.as_ref()));
+        scan
+    }
+}
+
+// offset_time is a function that returns the UTC offset of the camera clock, known when photos
+// are correlated with track logs.
+pub fn offset_time(options: &TrackOptions) -> Option<String> {
+    (!options.files.is_empty()).then(|| exif_writer::format_offset_time(-options.clock_offset))
+}
+
+// walk_options is a function that returns the walk options of a scan, with its filter options
+// taking precedence over the configured ones.
+pub fn walk_options(settings: &ScanSettings, filter: &FilterOptions, full: bool) -> io::Result<WalkOptions> {
+    Ok(WalkOptions {
+        filter: FileFilter::new(&settings.filter.overridden_by(filter))?,
+        full: full || settings.walk_options.full,
+        ..settings.walk_options.clone()
+    })
+}
+
+// Define the files of a scan.
+pub enum Files {
+    // Every file of the directory.
+    Directory,
+    // The files of the directory a watcher saw changing, removed ones included.
+    Changed(Vec<String>),
+}
+
+// Define what the extraction of a scan counts besides its report.
+#[derive(Default)]
+struct Extracted {
+    // Messages withheld by the privacy zones.
+    withheld: AtomicUsize,
+    // Messages left unsent once the sink stopped.
+    unsent: AtomicUsize,
+    // Payloads already produced by the previous scan.
+    duplicates: AtomicUsize,
+    // Tombstones of removed files.
+    deleted: AtomicUsize,
+}
+
+// scan is a function that walks a directory and streams the extracted messages to a sink, such as Kafka:
+// extraction hands every message over a bounded channel to the sink, and pauses while the channel is full.
+// Inferred positions are optionally written back into the photos, and the privacy zones
+// are applied right before the messages enter the channel.
+// With a file-state cache, moved files also remove their previous key, and the files removed
+// since the last scan are sent as tombstones once the walk is over.
+// Scans of changed files only extract those files, and only look for removed files among them.
+// A location such as s3://bucket/prefix or sftp://host/directory is scanned through its media source.
+// With a transactional Kafka producer, the scan is published in a transaction committed once every message
+// is delivered, and aborted if the walk or a message failed; the cache is only updated once it is committed.
+// Returns the report of the scan; its error tells why the scan stopped early, if it did.
+pub async fn scan(
+    directory: &str,
+    files: Files,
+    enrichers: ScanEnrichers,
+    write_back: Option<(WriteOptions, Option<String>)>,
+    walk_options: WalkOptions,
+    sink: &dyn Sink,
+    settings: &Arc<ScanSettings>,
+) -> ScanReport {
+    let (sender, receiver) = mpsc::channel(settings.buffer.max(1));
+    // Files cached with other enrichers or privacy zones are extracted again.
+    let fingerprints: Vec<String> = enrichers
+        .list()
+        .iter()
+        .map(|enricher| enricher.fingerprint())
+        .chain([settings.privacy.fingerprint()])
+        .collect();
+    let walk_options = WalkOptions {
+        settings: payload_hash(&fingerprints.join("\n")),
+        hash_contents: sink.keys_by_content(),
+        ..walk_options
+    };
+    // Used when the walk fails before reporting anything.
+    let started = ScanReport::new(directory);
+    let whole = matches!(files, Files::Directory);
+    let cache = walk_options.cache.clone();
+    let mut delivery = Delivery::default();
+    // Delivered messages whose state is cached once the transaction is committed.
+    let mut uncommitted: Vec<Message> = Vec::new();
+
+    // Wait for the transaction of another scan to end, if there is one.
+    let producer = sink.producer();
+    let transactional = producer.is_some_and(Producer::is_transactional);
+    let begun = match producer {
+        Some(producer) => producer.begin().await.map(Some),
+        None => Ok(None),
+    };
+    let transaction = match begun {
+        Ok(transaction) => transaction,
+        Err(error) => {
+            let mut report = started;
+            report.error = Some(format!("Error while starting the Kafka transaction, {}", error));
+            report.finish();
+            report.log();
+            return report;
+        }
+    };
+
+    // Extract on the blocking threads of the runtime while this task writes to the sink.
+    let (directory_name, settings_shared) = (directory.to_string(), settings.clone());
+    let walker = tokio::task::spawn_blocking(move || {
+        let (directory, settings) = (directory_name.as_str(), settings_shared.as_ref());
+        let cache = walk_options.cache.as_deref();
+        let enrichers = enrichers.list();
+        let extracted = Extracted::default();
+        // Previous paths of the moved files, which aren't deleted.
+        let moved: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+        let send = |message: Message| {
+            let sent = sender.blocking_send(message).is_ok();
+            if !sent {
+                extracted.unsent.fetch_add(1, Ordering::Relaxed);
+            }
+            sent
+        };
+        let each = |mut message: Message| {
+            // Persist the inferred position, if asked to; the message then carries the state of the rewritten file.
+            if let Some((options, offset_time)) = &write_back {
+                exif_writer::write_back(&mut message, offset_time.as_deref(), options);
+            }
+            // Stop extracting once the sink is gone.
+            let source = message.source.clone();
+            let (key, withheld_moved_from) = (message.key.clone(), message.moved_from.clone());
+            match settings.privacy.apply(message) {
+                Some(mut message) => {
+                    // Don't produce the payload produced last time again.
+                    if let (Some(cache), Some(state)) = (cache, &mut message.source) {
+                        let hash = payload_hash(&message.value.to_string());
+                        if !walk_options.full && state.payload_hash.as_deref() == Some(hash.as_str()) {
+                            cache.save(state);
+                            extracted.duplicates.fetch_add(1, Ordering::Relaxed);
+                            return true;
+                        }
+                        state.payload_hash = Some(hash);
+                    }
+                    // A moved file also removes its previous key from compacted topics.
+                    match message.moved_from.clone() {
+                        Some(moved_from) => {
+                            moved.lock().unwrap().insert(moved_from.clone());
+                            send(message) && send(Message::tombstone(&moved_from, EventType::Moved))
+                        }
+                        None => send(message),
+                    }
+                }
+                None => {
+                    // Withheld files are skipped until they change.
+                    extracted.withheld.fetch_add(1, Ordering::Relaxed);
+                    let (Some(cache), Some(mut state)) = (cache, source) else {
+                        return true;
+                    };
+                    // A file published before, at its path or at the one it was moved from,
+                    // is removed from compacted topics; its state is cached once that is delivered.
+                    let published = match withheld_moved_from {
+                        Some(moved_from) => {
+                            moved.lock().unwrap().insert(moved_from.clone());
+                            Some(Message::tombstone(&moved_from, EventType::Moved))
+                        }
+                        None if state.payload_hash.take().is_some() => Some(Message::tombstone(&key, EventType::Withheld)),
+                        None => None,
+                    };
+                    match published {
+                        Some(mut tombstone) => {
+                            tombstone.source = Some(state);
+                            send(tombstone)
+                        }
+                        None => {
+                            cache.save(&state);
+                            true
+                        }
+                    }
+                }
+            }
+        };
+        // Remote locations are listed and read through their source.
+        let remote = match files {
+            Files::Directory => settings.sources.open(directory),
+            Files::Changed(_) => Ok(None),
+        };
+        let source: Arc<dyn MediaSource> = match &remote {
+            Ok(Some(source)) => source.clone(),
+            _ => Arc::new(LocalFs),
+        };
+        let (walked, removed) = match (remote, &files) {
+            (Err(error), _) => (Err(error), vec![]),
+            (Ok(Some(_)), _) => (
+                directory_reader::walking_source_each(source.as_ref(), directory, &enrichers, &walk_options, each),
+                vec![directory],
+            ),
+            (Ok(None), Files::Directory) => (
+                directory_reader::walking_each(directory, &enrichers, &walk_options, each).map_err(Into::into),
+                vec![directory],
+            ),
+            (Ok(None), Files::Changed(files)) => (
+                Ok(directory_reader::extracting_each(directory, files, &enrichers, &walk_options, each)),
+                // Removed files and directories are the changed paths that are gone.
+                files
+                    .iter()
+                    .filter(|path| !std::path::Path::new(path).exists())
+                    .map(String::as_str)
+                    .collect(),
+            ),
+        };
+
+        // Send tombstones for the files removed since the last scan, once every file was seen.
+        if let (Ok(_), Some(cache)) = (&walked, cache) {
+            let moved = moved.lock().unwrap();
+            'removed: for removed in removed {
+                match cache.missing(removed, source.as_ref()) {
+                    Ok(missing) => {
+                        for path in missing.iter().filter(|path| !moved.contains(*path)) {
+                            if !send(Message::tombstone(path, EventType::Deleted)) {
+                                break 'removed;
+                            }
+                            extracted.deleted.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Err(error) => logger::log_error(&format!("Error while looking for removed files, {}", error)),
+                }
+            }
+        }
+        (walked, extracted)
+    });
+
+    // Cache the state of the files once their messages are delivered, or committed.
+    let mut delivered = |message: &Message| match cache.as_deref() {
+        Some(_) if transactional => uncommitted.push(Message {
+            // Only the cached fields are kept meanwhile.
+            value: if message.is_tombstone() { serde_json::Value::Null } else { serde_json::json!({}) },
+            headers: Vec::new(),
+            key: message.key.clone(),
+            source: message.source.clone(),
+            event: message.event,
+            moved_from: message.moved_from.clone(),
+        }),
+        Some(cache) => cache.delivered(message),
+        None => (),
+    };
+    let produced = sink.write_stream(receiver, &mut delivery, &mut delivered).await;
+    let (walked, extracted) = walker
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()));
+
+    let mut report = walked.unwrap_or_else(|error| {
+        let mut report = started;
+        report.error = Some(error.to_string());
+        report
+    });
+    report.published = delivery.published;
+    report.spooled = delivery.spooled;
+    report.failed_to_publish = delivery.failed + extracted.unsent.into_inner();
+    report.withheld = extracted.withheld.into_inner();
+    report.duplicates = extracted.duplicates.into_inner();
+    // Entries removed from archives are already counted by the walk.
+    report.deleted += extracted.deleted.into_inner();
+    // Failed messages don't stop the scan; they are listed in the report.
+    report.publish_failures = delivery.failures;
+    if let Err(error) = &produced {
+        logger::log_error(&format!("Error while writing to the {} sink, {}", sink.name(), error));
+    }
+
+    // Publish the whole scan, or nothing of it.
+    if let (true, Some(transaction)) = (transactional, transaction) {
+        let complete = report.error.is_none() && produced.is_ok() && report.failed_to_publish == 0;
+        let ended = match complete {
+            true => transaction.commit().await.map(|()| true),
+            false => transaction.abort().await.map(|()| false),
+        };
+        match ended {
+            Ok(true) => {
+                if let Some(cache) = &cache {
+                    uncommitted.iter().for_each(|message| cache.delivered(message));
+                }
+            }
+            // Nothing of an aborted scan is seen, so it is published again by the next one.
+            ended => {
+                report.failed_to_publish += report.published;
+                report.published = 0;
+                let reason = match ended {
+                    Err(error) => format!("the Kafka transaction failed, {}", error),
+                    _ => "the Kafka transaction was aborted".to_string(),
+                };
+                report.error = Some(match report.error.take() {
+                    Some(error) => format!("{}; {}", error, reason),
+                    None => reason,
+                });
+            }
+        }
+    }
+
+    // Send the files without message and the undelivered messages to the dead-letter topic, if any.
+    if let Some(producer) = producer.filter(|producer| producer.dead_letter_topic().is_some()) {
+        let letters = dead_letter::from_report(&report);
+        match produce_dead_letters(producer, &letters).await {
+            Ok(published) => report.dead_letters = published,
+            Err(error) => logger::log_error(&format!("Error while publishing dead letters, {}", error)),
+        }
+    }
+    if let (Some(spool), Some(_)) = (&settings.spool, producer) {
+        match spool.stats() {
+            Ok(stats) => (report.spool_bytes, report.spool_age_secs) = (stats.bytes, stats.age_secs),
+            Err(error) => logger::log_error(&format!("Error while measuring the spool, {}", error)),
+        }
+    }
+    report.finish();
+    report.log();
+
+    // Keep the report next to the previous ones, if asked to; watched changes are only logged.
+    if let (Some(report_dir), true) = (&settings.report_dir, whole) {
+        match report.write_to(std::path::Path::new(report_dir)) {
+            Ok(path) => logger::log_info(&format!("Scan report written to {}", path.display())),
+            Err(error) => logger::log_error(&format!("Error while writing the scan report, {}", error)),
+        }
+    }
+    report
+}
+
+// log_outcome is a function that logs whether a scan failed or some of its messages did;
+// the failures themselves are listed in its report.
+pub fn log_outcome(report: &ScanReport) {
+    match &report.error {
+        // Log an error message if the scan fails.
+        Some(error) => logger::log_error(&format!("Error while scanning directory, {}", error)),
+        // Log an error message if messages failed to publish.
+        None if report.failed_to_publish > 0 => logger::log_error(&format!(
+            "{} messages failed to publish",
+            report.failed_to_publish
+        )),
+        // Log an informational message if message production is successful.
+        None => logger::log_info("Successfully delivering messages"),
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{FileSinkConfig, SinkConfig};
+    use crate::exif_writer::{write_geotag, Geotag};
+
+    // settings is a function that returns the settings of scans writing to an NDJSON file, with a file-state cache.
+    fn settings(output: &std::path::Path) -> ScanSettings {
+        let sink = SinkConfig {
+            default: Some(crate::sink::FILE.to_string()),
+            file: Some(FileSinkConfig {
+                path: output.join("photos.ndjson").display().to_string(),
+                max_bytes: u64::MAX,
+                max_files: 0,
+            }),
+            webhook: None,
+            postgis: None,
+        };
+        let cache = StateCache::open(output.join("cache.db").to_str().unwrap()).unwrap();
+        ScanSettings {
+            walk_options: WalkOptions {
+                cache: Some(Arc::new(cache)),
+                ..WalkOptions::default()
+            },
+            filter: FilterOptions::default(),
+            buffer: 2,
+            report_dir: None,
+            privacy: PrivacyZones::default(),
+            sources: Sources::default(),
+            sinks: Sinks::from_config(&sink, None).unwrap(),
+            spool: None,
+        }
+    }
+
+    // Define a test function for scanning a directory into a sink.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scan() {
+        let photos = tempfile::tempdir().unwrap();
+        for index in 0..5 {
+            let path = photos.path().join(format!("photo_{}.jpg", index));
+            std::fs::write(&path, [0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+            let geotag = Geotag { lat: 45.0 + index as f64, lon: 39.0, altitude: None, offset_time: None };
+            write_geotag(&path, &geotag, &WriteOptions::default()).unwrap();
+        }
+        let output = tempfile::tempdir().unwrap();
+        let settings = Arc::new(settings(output.path()));
+        let sink = settings.sinks.get(None).unwrap();
+        let enrichers = ScanEnrichers {
+            track: None,
+            configured: Arc::new(Vec::new()),
+        };
+        let directory = photos.path().to_str().unwrap();
+        let run = |files: Files| {
+            let walk_options = walk_options(&settings, &FilterOptions::default(), false).unwrap();
+            scan(directory, files, enrichers.clone(), None, walk_options, sink.as_ref(), &settings)
+        };
+        let lines = || std::fs::read_to_string(output.path().join("photos.ndjson")).unwrap().lines().count();
+
+        // Assert that every photo goes through the bounded channel into the sink.
+        let report = run(Files::Directory).await;
+        assert!(report.error.is_none());
+        assert_eq!((report.files_seen, report.published), (5, 5));
+        assert_eq!(lines(), 5);
+
+        // Assert that a rescan skips the photos that didn't change.
+        let report = run(Files::Directory).await;
+        assert_eq!((report.unchanged, report.published), (5, 0));
+
+        // Assert that a removed photo seen by a watcher is sent as a tombstone.
+        let removed = photos.path().join("photo_0.jpg");
+        std::fs::remove_file(&removed).unwrap();
+        let report = run(Files::Changed(vec![removed.display().to_string()])).await;
+        assert_eq!((report.deleted, report.published), (1, 1));
+        assert_eq!(lines(), 6);
+    }
+}
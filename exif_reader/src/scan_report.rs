@@ -0,0 +1,274 @@
+// Import necessary modules from the project.
+use crate::logger;
+
+// Import necessary modules from the standard library and external crates.
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+// Define an entry of a directory that couldn't be read during a scan.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WalkError {
+    // The path of the entry, when known.
+    pub path: Option<String>,
+    // Why the entry couldn't be read.
+    pub error: String,
+}
+
+impl WalkError {
+    // Create a WalkError from a directory traversal error.
+    pub fn new(error: &walkdir::Error) -> WalkError {
+        WalkError {
+            path: error.path().map(|path| path.display().to_string()),
+            error: error.to_string(),
+        }
+    }
+}
+
+// Define what went wrong with a file that didn't give a message.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Failure {
+    // The file isn't a format with readable EXIF data, or has none.
+    Unsupported,
+    // The file couldn't be read or its EXIF data is broken.
+    Error,
+}
+
+// Define a file that didn't give a message, and why.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileFailure {
+    pub path: String,
+    pub failure: Failure,
+    pub reason: String,
+    // SHA-256 of the file contents, when it was computed for the cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+// Define a message that Kafka didn't acknowledge, and why.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PublishFailure {
+    // The key of the message, which is the path of its file.
+    pub key: String,
+    pub reason: String,
+    // The payload of the message, None for tombstones; sent to the dead-letter topic, not reported.
+    #[serde(skip)]
+    pub payload: Option<serde_json::Value>,
+}
+
+// Define what happened to the files of a scan.
+// Only the files that didn't give a message are listed; they aren't capped, since every one of them
+// is also sent to the dead-letter topic, so a directory of unreadable files gives a report as long.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ScanReport {
+    // A random identifier of the scan, found in its dead letters.
+    pub scan_id: String,
+    // The scanned directory.
+    pub directory: String,
+    // When the scan started, in RFC 3339 format.
+    pub started_at: String,
+    // How long the scan took, in milliseconds.
+    pub elapsed_ms: u64,
+    // Files that passed the filters.
+    pub files_seen: usize,
+    // Files skipped because they didn't change since the last scan.
+    pub unchanged: usize,
+    // Files moved or renamed, and removed, since the last scan.
+    pub moved: usize,
+    pub deleted: usize,
+    // Files whose EXIF data was read, with and without a position.
+    pub parsed: usize,
+    pub with_gps: usize,
+    pub without_gps: usize,
+    // Files without readable EXIF data.
+    pub unsupported: usize,
+    // Files that couldn't be read.
+    pub errored: usize,
+    // Messages delivered to Kafka, not delivered, and dropped by a privacy zone.
+    pub published: usize,
+    pub failed_to_publish: usize,
+    pub withheld: usize,
+    // Messages not produced again because their payload didn't change.
+    pub duplicates: usize,
+    // Messages written to the disk spool, to be replayed to Kafka later.
+    pub spooled: usize,
+    // Size of the spool and age of its oldest message, in seconds, once the scan ended.
+    pub spool_bytes: u64,
+    pub spool_age_secs: u64,
+    // The unsupported and errored files.
+    pub failures: Vec<FileFailure>,
+    // The entries skipped because they couldn't be read.
+    pub walk_errors: Vec<WalkError>,
+    // The messages that failed to publish.
+    pub publish_failures: Vec<PublishFailure>,
+    // Records of the failed files and messages published to the dead-letter topic.
+    pub dead_letters: usize,
+    // Why the scan stopped early, if it did.
+    pub error: Option<String>,
+    // When the scan started, to measure its duration.
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl ScanReport {
+    // Create the report of a scan starting now.
+    pub fn new(directory: &str) -> ScanReport {
+        ScanReport {
+            scan_id: format!("{:032x}", rand::random::<u128>()),
+            directory: directory.to_string(),
+            started_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            started: Some(Instant::now()),
+            ..ScanReport::default()
+        }
+    }
+
+    // Count a file whose EXIF data was read.
+    pub fn parsed(&mut self, has_position: bool) {
+        self.files_seen += 1;
+        self.parsed += 1;
+        if has_position {
+            self.with_gps += 1;
+        } else {
+            self.without_gps += 1;
+        }
+    }
+
+    // Count a file that didn't change since the last scan.
+    pub fn unchanged(&mut self) {
+        self.files_seen += 1;
+        self.unchanged += 1;
+    }
+
+    // Count a file that didn't give a message.
+    pub fn failed(&mut self, path: &str, failure: Failure, reason: String) {
+        self.failed_with_hash(path, failure, reason, None);
+    }
+
+    // Count a file that didn't give a message, with the hash of its contents if known.
+    pub fn failed_with_hash(&mut self, path: &str, failure: Failure, reason: String, hash: Option<String>) {
+        self.files_seen += 1;
+        match failure {
+            Failure::Unsupported => self.unsupported += 1,
+            Failure::Error => self.errored += 1,
+        }
+        self.failures.push(FileFailure {
+            path: path.to_string(),
+            failure,
+            reason,
+            hash,
+        });
+    }
+
+    // Record how long the scan took so far.
+    pub fn finish(&mut self) {
+        if let Some(started) = self.started {
+            self.elapsed_ms = started.elapsed().as_millis() as u64;
+        }
+    }
+
+    // Log the summary of the scan and the files that couldn't be read;
+    // unreadable entries are logged while walking.
+    pub fn log(&self) {
+        for failure in self
+            .failures
+            .iter()
+            .filter(|failure| failure.failure == Failure::Error)
+        {
+            logger::log_error(&format!(
+                "Error while reading {}: {}",
+                failure.path, failure.reason
+            ));
+        }
+        for failure in &self.publish_failures {
+            logger::log_error(&format!(
+                "Error while publishing {}: {}",
+                failure.key, failure.reason
+            ));
+        }
+        logger::log_info(&format!(
+            "Scanned {} in {} ms: {} files, {} unchanged, {} moved, {} deleted, {} with GPS, {} without GPS, {} unsupported, {} errors, \
+             {} published, {} failed to publish, {} withheld, {} duplicates, {} dead letters, {} spooled \
+             (spool of {} bytes, oldest {} s)",
+            self.directory,
+            self.elapsed_ms,
+            self.files_seen,
+            self.unchanged,
+            self.moved,
+            self.deleted,
+            self.with_gps,
+            self.without_gps,
+            self.unsupported,
+            self.errored,
+            self.published,
+            self.failed_to_publish,
+            self.withheld,
+            self.duplicates,
+            self.dead_letters,
+            self.spooled,
+            self.spool_bytes,
+            self.spool_age_secs
+        ));
+    }
+
+    // Write the report as JSON into a directory, named after the start and identifier of the scan
+    // so that scans started within the same second don't overwrite each other's reports.
+    // Returns the path of the written file.
+    pub fn write_to(&self, directory: &Path) -> io::Result<PathBuf> {
+        let started = DateTime::parse_from_rfc3339(&self.started_at)
+            .map(|started| started.format("%Y%m%dT%H%M%SZ").to_string())
+            .unwrap_or_default();
+        std::fs::create_dir_all(directory)?;
+        let path = directory.join(format!("scan_{}_{}.json", started, self.scan_id));
+        let mut file = std::fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(path)
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Define a test function for counting files and writing the report.
+    #[test]
+    fn test_report() {
+        let mut report = ScanReport::new("photos");
+        report.parsed(true);
+        report.parsed(false);
+        report.failed(
+            "photos/notes.txt",
+            Failure::Unsupported,
+            "Unknown image format".to_string(),
+        );
+        report.failed("photos/broken.jpg", Failure::Error, "Truncated".to_string());
+        report.finish();
+
+        // Assert that every file is counted once.
+        assert_eq!(report.files_seen, 4);
+        assert_eq!(
+            (report.parsed, report.with_gps, report.without_gps),
+            (2, 1, 1)
+        );
+        assert_eq!((report.unsupported, report.errored), (1, 1));
+        assert_eq!(report.failures[1].path, "photos/broken.jpg");
+
+        // Assert that the JSON report is written with the counts and failures.
+        let directory = tempfile::tempdir().unwrap();
+        let path = report.write_to(directory.path()).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["files_seen"], 4);
+        assert_eq!(json["failures"][0]["failure"], "unsupported");
+        assert!(json.get("started").is_none());
+
+        // Assert that the reports of scans started in the same second get their own files.
+        let other = ScanReport { scan_id: "other".to_string(), ..report.clone() };
+        assert_ne!(other.write_to(directory.path()).unwrap(), path);
+        assert_eq!(report.write_to(directory.path()).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+}
@@ -0,0 +1,410 @@
+// Import necessary modules from the project.
+use crate::config::SftpConfig;
+use crate::media_source::{MediaSource, SourceEntry};
+
+// Import necessary modules from the standard library and external crates.
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::TcpStream;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, Session, Sftp};
+
+// Define the parts of an sftp://[user@]host[:port]/directory location.
+#[derive(Debug, PartialEq)]
+struct Location<'a> {
+    // The user and host part, as written in the location.
+    authority: &'a str,
+    user: Option<&'a str>,
+    host: &'a str,
+    port: Option<u16>,
+    path: String,
+}
+
+// parse_location is a function that splits an SFTP location into its parts.
+fn parse_location(location: &str) -> io::Result<Location<'_>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not an SFTP location: {}", location),
+        )
+    };
+    let rest = location.strip_prefix("sftp://").ok_or_else(invalid)?;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (user, address) = match authority.rsplit_once('@') {
+        Some((user, address)) => (Some(user), address),
+        None => (None, authority),
+    };
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port.parse().map_err(|_| invalid())?)),
+        None => (address, None),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Location {
+        authority,
+        user,
+        host,
+        port,
+        path: format!("/{}", path),
+    })
+}
+
+// Define an authenticated session with an SFTP server.
+struct Connection {
+    sftp: Sftp,
+    // The SFTP channel needs its session alive.
+    _session: Session,
+}
+
+// connect_to is a function that opens an authenticated session with the server of a location.
+// Parameters:
+// - config: The credentials, port, allowed hosts and known hosts.
+// - location: The scanned location; a user or port in it takes precedence over the configuration.
+// Returns:
+// - io::Result<Connection>: The session, or an std::io::Error if the server isn't allowed or can't be reached,
+//   its host key isn't known or authentication fails.
+fn connect_to(config: &SftpConfig, location: &Location) -> io::Result<Connection> {
+    let port = location.port.unwrap_or(config.port);
+    let user = location.user.unwrap_or(&config.user);
+
+    // Only servers named in the configuration are contacted, since the location may come from a gRPC request.
+    if !config.allows(location.host) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} isn't an allowed SFTP host", location.host),
+        ));
+    }
+
+    let mut session = Session::new()?;
+    session.set_tcp_stream(TcpStream::connect((location.host, port))?);
+    session.handshake()?;
+
+    // Refuse the server unless its host key is in the known_hosts file, before sending any credentials.
+    let mut known = session.known_hosts()?;
+    known.read_file(Path::new(&config.known_hosts), KnownHostFileKind::OpenSSH)?;
+    let (key, _) = session.host_key().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The SFTP server sent no host key",
+        )
+    })?;
+    if !matches!(
+        known.check_port(location.host, port, key),
+        CheckResult::Match
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Unknown host key for {}", location.host),
+        ));
+    }
+
+    match (&config.private_key, &config.password) {
+        (Some(private_key), passphrase) => session.userauth_pubkey_file(
+            user,
+            None,
+            Path::new(private_key),
+            passphrase.as_deref(),
+        )?,
+        (None, Some(password)) => session.userauth_password(user, password)?,
+        (None, None) => session.userauth_agent(user)?,
+    }
+
+    Ok(Connection {
+        sftp: session.sftp()?,
+        _session: session,
+    })
+}
+
+// Define the idle sessions of a source. A session serves one worker thread at a time,
+// so that the extraction threads don't queue up on a single connection.
+type Pool = Arc<Mutex<Vec<Connection>>>;
+
+// Define a session taken from the pool, put back when dropped.
+struct Pooled {
+    connection: Option<Connection>,
+    pool: Pool,
+}
+
+impl Deref for Pooled {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.lock().unwrap().push(connection);
+        }
+    }
+}
+
+// Define a remote file read by range, holding its session until it is dropped.
+struct PooledFile {
+    // Declared first, so that the file is closed before its session goes back to the pool.
+    file: Box<dyn Read + Send>,
+    _connection: Pooled,
+}
+
+impl Read for PooledFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buffer)
+    }
+}
+
+// Define a source reading a directory of an SFTP server, at locations like sftp://host/directory.
+// Sessions are opened as the worker threads need them and reused for the rest of the scan.
+pub struct SftpSource {
+    config: SftpConfig,
+    // The scanned location, which the sessions are opened for.
+    root: String,
+    // The user and host part of the locations of the server.
+    authority: String,
+    pool: Pool,
+}
+
+impl SftpSource {
+    // connect is a function that opens a first session with the server of a location.
+    // Parameters:
+    // - config: The credentials, port, allowed hosts and known hosts.
+    // - location: The scanned location; a user or port in it takes precedence over the configuration.
+    // Returns:
+    // - io::Result<SftpSource>: The source, or an std::io::Error if the server isn't allowed or can't be reached,
+    //   its host key isn't known or authentication fails.
+    pub fn connect(config: &SftpConfig, location: &str) -> io::Result<SftpSource> {
+        let parsed = parse_location(location)?;
+        let connection = connect_to(config, &parsed)?;
+        Ok(SftpSource {
+            config: config.clone(),
+            root: location.to_string(),
+            authority: parsed.authority.to_string(),
+            pool: Arc::new(Mutex::new(vec![connection])),
+        })
+    }
+
+    // Take an idle session, or open another one when all are busy.
+    fn connection(&self) -> io::Result<Pooled> {
+        let idle = self.pool.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => connect_to(&self.config, &parse_location(&self.root)?)?,
+        };
+        Ok(Pooled {
+            connection: Some(connection),
+            pool: self.pool.clone(),
+        })
+    }
+
+    // Get the location of a path of the server.
+    fn location(&self, path: &Path) -> String {
+        format!("sftp://{}{}", self.authority, path.display())
+    }
+
+    // Get the path of a location of the server.
+    fn path(&self, location: &str) -> io::Result<PathBuf> {
+        Ok(PathBuf::from(parse_location(location)?.path))
+    }
+
+    // Build the entry of a file from its attributes.
+    fn entry(&self, path: &Path, stat: &FileStat) -> SourceEntry {
+        SourceEntry {
+            path: self.location(path),
+            size: stat.size.unwrap_or_default(),
+            mtime: stat.mtime.unwrap_or_default() as i64 * 1_000_000_000,
+            etag: None,
+        }
+    }
+
+    // List a directory: its files, and its subdirectories to descend into, in name order.
+    fn read_directory(&self, directory: &Path) -> io::Result<(Vec<SourceEntry>, Vec<PathBuf>)> {
+        let mut children = self.connection()?.sftp.readdir(directory)?;
+        children.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut files = Vec::new();
+        let mut directories = Vec::new();
+        for (path, stat) in children {
+            if stat.is_dir() {
+                directories.push(path);
+            } else if stat.is_file() {
+                files.push(self.entry(&path, &stat));
+            }
+        }
+        Ok((files, directories))
+    }
+}
+
+// Define the listing of a directory: its files, and its subdirectories to descend into.
+type Listing = (Vec<SourceEntry>, Vec<PathBuf>);
+
+// descend is a function that lists the files of a directory tree depth first, in name order,
+// reading the subdirectories as the iterator reaches them.
+// Parameters:
+// - listing: The listing of the top directory.
+// - read_directory: Lists a subdirectory.
+// Returns:
+// - impl Iterator<Item = io::Result<SourceEntry>>: The files, and the errors of the subdirectories that can't be listed.
+fn descend<'a, R>(
+    listing: Listing,
+    read_directory: R,
+) -> impl Iterator<Item = io::Result<SourceEntry>> + Send + 'a
+where
+    R: Fn(&Path) -> io::Result<Listing> + Send + 'a,
+{
+    let (files, directories) = listing;
+    let mut files: VecDeque<SourceEntry> = files.into();
+    let mut pending: Vec<PathBuf> = directories.into_iter().rev().collect();
+    std::iter::from_fn(move || loop {
+        if let Some(file) = files.pop_front() {
+            return Some(Ok(file));
+        }
+        let directory = pending.pop()?;
+        match read_directory(&directory) {
+            Ok((listed, directories)) => {
+                files.extend(listed);
+                pending.extend(directories.into_iter().rev());
+            }
+            Err(error) => return Some(Err(error)),
+        }
+    })
+}
+
+impl MediaSource for SftpSource {
+    fn list<'a>(
+        &'a self,
+        root: &str,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<SourceEntry>> + Send + 'a>> {
+        // The scanned directory is read right away, so that a missing one fails the scan.
+        let listing = self.read_directory(&self.path(root)?)?;
+        Ok(Box::new(descend(listing, move |directory| {
+            self.read_directory(directory)
+        })))
+    }
+
+    fn stat(&self, path: &str) -> io::Result<SourceEntry> {
+        let remote = self.path(path)?;
+        let stat = self.connection()?.sftp.stat(&remote)?;
+        Ok(self.entry(&remote, &stat))
+    }
+
+    fn open_range(
+        &self,
+        path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send + 'static>> {
+        // The session stays with the file until it is read, and goes back to the pool afterwards.
+        let connection = self.connection()?;
+        let mut file = connection.sftp.open(self.path(path)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let file: Box<dyn Read + Send> = match length {
+            Some(length) => Box::new(file.take(length)),
+            None => Box::new(file),
+        };
+        Ok(Box::new(PooledFile {
+            file,
+            _connection: connection,
+        }))
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Define a test function for reading SFTP locations.
+    #[test]
+    fn test_parse_location() {
+        assert_eq!(
+            parse_location("sftp://photos@nas.local:2222/volume1/photos").unwrap(),
+            Location {
+                authority: "photos@nas.local:2222",
+                user: Some("photos"),
+                host: "nas.local",
+                port: Some(2222),
+                path: "/volume1/photos".to_string(),
+            }
+        );
+        assert_eq!(parse_location("sftp://nas.local").unwrap().path, "/");
+        assert!(parse_location("sftp://nas.local:ssh/photos").is_err());
+        assert!(parse_location("s3://photos").is_err());
+    }
+
+    // Define a test function for refusing servers that aren't configured.
+    #[test]
+    fn test_connect_unknown_host() {
+        let config = SftpConfig {
+            user: "photos".to_string(),
+            password: Some("secret".to_string()),
+            private_key: None,
+            port: 22,
+            known_hosts: "/nonexistent/known_hosts".to_string(),
+            hosts: "nas.local, backup.local".to_string(),
+        };
+        assert!(config.allows("NAS.local") && config.allows("backup.local"));
+
+        // Assert that a host missing from the configuration is refused without connecting.
+        let error = SftpSource::connect(&config, "sftp://127.0.0.1:1/photos")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        let error = SftpSource::connect(&config, "sftp://nas.local.attacker.example/photos")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Assert that an allowed host is contacted, here without a server listening.
+        let config = SftpConfig {
+            hosts: "127.0.0.1".to_string(),
+            ..config
+        };
+        let error = SftpSource::connect(&config, "sftp://127.0.0.1:1/photos")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    // Define a test function for listing a directory tree.
+    #[test]
+    fn test_descend() {
+        let entry = |path: &str| SourceEntry {
+            path: path.to_string(),
+            size: 1,
+            mtime: 0,
+            etag: None,
+        };
+        let top = (
+            vec![entry("/photos/a.jpg")],
+            vec![PathBuf::from("/photos/2014"), PathBuf::from("/photos/2015")],
+        );
+        let read_directory = |directory: &Path| match directory.to_str().unwrap() {
+            "/photos/2014" => Ok((
+                vec![entry("/photos/2014/b.jpg")],
+                vec![PathBuf::from("/photos/2014/05")],
+            )),
+            "/photos/2014/05" => Ok((vec![entry("/photos/2014/05/c.jpg")], Vec::new())),
+            _ => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        };
+
+        // Assert that the files are listed depth first, and that an unreadable directory is reported in its place.
+        let listed: Vec<io::Result<SourceEntry>> = descend(top, read_directory).collect();
+        let paths: Vec<Option<&str>> = listed
+            .iter()
+            .map(|entry| entry.as_ref().ok().map(|entry| entry.path.as_str()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                Some("/photos/a.jpg"),
+                Some("/photos/2014/b.jpg"),
+                Some("/photos/2014/05/c.jpg"),
+                None
+            ]
+        );
+    }
+}
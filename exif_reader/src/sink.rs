@@ -0,0 +1,655 @@
+// Import necessary modules from the project.
+use crate::config::{FileSinkConfig, SinkConfig, WebhookSinkConfig};
+use crate::logger;
+use crate::message::Message;
+use crate::postgis::PostgisSink;
+use crate::producer::{produce_stream, Delivery, Producer};
+use crate::scan_report::PublishFailure;
+
+// Import necessary modules from the standard library and external crates.
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+
+// Names the sinks are selected by, in the configuration and in requests.
+pub const STDOUT: &str = "stdout";
+pub const FILE: &str = "file";
+pub const KAFKA: &str = "kafka";
+pub const WEBHOOK: &str = "webhook";
+pub const POSTGIS: &str = "postgis";
+
+// Sink is a trait for the places the messages of a scan are written to.
+pub trait Sink: Send + Sync {
+    // The name the sink is selected by.
+    fn name(&self) -> &'static str;
+
+    // write_stream is a method that writes messages as they arrive on a channel, until every sender is dropped.
+    // A failed message doesn't stop the others: it is counted and listed in the delivery.
+    // Parameters:
+    // - messages: The receiving end of a bounded channel; its capacity gives the backpressure.
+    // - delivery: Counts the written and failed messages, and lists the failed ones.
+    // - delivered: Called with every message once it is written.
+    // Returns:
+    // - io::Result<()>: Ok once the channel is closed and every message written, or the first error.
+    fn write_stream<'a>(
+        &'a self,
+        messages: Receiver<Message>,
+        delivery: &'a mut Delivery,
+        delivered: &'a mut (dyn FnMut(&Message) + Send),
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    // Write what the sink still buffers, before stopping.
+    fn flush(&self) {}
+
+    // The Kafka producer behind the sink, which publishes transactions and dead letters; None for other sinks.
+    fn producer(&self) -> Option<&Producer> {
+        None
+    }
+
+    // Whether the sink keys its rows by the hash of the file contents, which scans then compute for every file.
+    fn keys_by_content(&self) -> bool {
+        false
+    }
+}
+
+// record is a function that builds the JSON record of a message written by the stdout, file and webhook sinks:
+// its key, its payload, null for tombstones, and its headers.
+pub fn record(message: &Message) -> Value {
+    let headers: serde_json::Map<String, Value> = message
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+        .collect();
+    json!({
+        "key": message.key,
+        "value": if message.is_tombstone() { Value::Null } else { message.value.clone() },
+        "headers": headers,
+    })
+}
+
+// failure is a function that lists a message that couldn't be written in the delivery.
+fn failure(delivery: &mut Delivery, message: &Message, reason: String) {
+    delivery.failed += 1;
+    delivery.failures.push(PublishFailure {
+        key: message.key.clone(),
+        reason,
+        payload: (!message.is_tombstone()).then(|| message.value.clone()),
+    });
+}
+
+// write_each is a function that writes the messages of a channel one at a time, for the sinks of JSON lines.
+// Parameters:
+// - messages: The receiving end of the channel.
+// - delivery: Counts the written and failed messages.
+// - delivered: Called with every message once it is written.
+// - write: Writes a message.
+// Returns:
+// - io::Result<()>: Ok once the channel is closed, or the first error once every message was tried.
+async fn write_each<W>(
+    mut messages: Receiver<Message>,
+    delivery: &mut Delivery,
+    delivered: &mut (dyn FnMut(&Message) + Send),
+    mut write: W,
+) -> io::Result<()>
+where
+    W: FnMut(&Message) -> io::Result<()>,
+{
+    let mut first_error = None;
+    while let Some(message) = messages.recv().await {
+        match write(&message) {
+            Ok(()) => {
+                delivery.published += 1;
+                delivered(&message);
+            }
+            Err(error) => {
+                logger::log_debug(&format!(
+                    "Error in writing message {}: {}",
+                    message.key, error
+                ));
+                failure(delivery, &message, error.to_string());
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+// next_batch is a function that waits for a message, then takes the messages already waiting on the channel,
+// up to a batch size.
+// Returns:
+// - Option<Vec<Message>>: The batch, or None once the channel is closed and empty.
+pub async fn next_batch(messages: &mut Receiver<Message>, size: usize) -> Option<Vec<Message>> {
+    let mut batch = vec![messages.recv().await?];
+    while batch.len() < size {
+        match messages.try_recv() {
+            Ok(message) => batch.push(message),
+            Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+// written is a function that counts a batch written at once: every message of it was delivered, or every one failed.
+// Parameters:
+// - batch: The messages of the batch.
+// - result: The result of writing the batch.
+// - delivery: Counts the written and failed messages.
+// - delivered: Called with every message once it is written.
+// Returns:
+// - io::Result<()>: The result of writing the batch.
+pub fn written(
+    batch: &[Message],
+    result: io::Result<()>,
+    delivery: &mut Delivery,
+    delivered: &mut (dyn FnMut(&Message) + Send),
+) -> io::Result<()> {
+    match &result {
+        Ok(()) => {
+            delivery.published += batch.len();
+            batch.iter().for_each(delivered);
+        }
+        Err(error) => {
+            logger::log_debug(&format!("Error in writing {} messages: {}", batch.len(), error));
+            for message in batch {
+                failure(delivery, message, error.to_string());
+            }
+        }
+    }
+    result
+}
+
+// Define the sink printing every message to the standard output as a JSON line, for debugging.
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &'static str {
+        STDOUT
+    }
+
+    fn write_stream<'a>(
+        &'a self,
+        messages: Receiver<Message>,
+        delivery: &'a mut Delivery,
+        delivered: &'a mut (dyn FnMut(&Message) + Send),
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(write_each(messages, delivery, delivered, |message| {
+            writeln!(io::stdout().lock(), "{}", record(message))
+        }))
+    }
+
+    fn flush(&self) {
+        if let Err(error) = io::stdout().flush() {
+            logger::log_error(&format!(
+                "Error while flushing the standard output, {}",
+                error
+            ));
+        }
+    }
+}
+
+// Define the sink appending every message to an NDJSON file, rotated once it grows past its size.
+pub struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    // The open file and its size.
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl FileSink {
+    // Create the sink of a file, opened on the first message.
+    pub fn new(config: &FileSinkConfig) -> FileSink {
+        FileSink {
+            path: PathBuf::from(&config.path),
+            max_bytes: config.max_bytes.max(1),
+            max_files: config.max_files,
+            file: Mutex::new(None),
+        }
+    }
+
+    // Get the path of a rotated file, such as photos.ndjson.1 for the newest.
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    // Open the file for appending, creating its directory if needed.
+    fn open(&self) -> io::Result<(File, u64)> {
+        if let Some(parent) = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    // Shift the rotated files by one, dropping the oldest, and move the file to the first one.
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for index in (1..self.max_files).rev() {
+            let rotated = self.rotated(index);
+            if rotated.exists() {
+                fs::rename(rotated, self.rotated(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    // write is a method that appends a message to the file, rotating it first if the message doesn't fit.
+    fn write(&self, message: &Message) -> io::Result<()> {
+        let mut line = record(message).to_string().into_bytes();
+        line.push(b'\n');
+        let length = line.len() as u64;
+
+        let mut current = self.file.lock().unwrap();
+        let (file, size) = match current.take() {
+            Some((_, size)) if size > 0 && size + length > self.max_bytes => {
+                self.rotate()?;
+                current.insert(self.open()?)
+            }
+            Some(open) => current.insert(open),
+            None => current.insert(self.open()?),
+        };
+        file.write_all(&line)?;
+        *size += length;
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &'static str {
+        FILE
+    }
+
+    fn write_stream<'a>(
+        &'a self,
+        messages: Receiver<Message>,
+        delivery: &'a mut Delivery,
+        delivered: &'a mut (dyn FnMut(&Message) + Send),
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(write_each(messages, delivery, delivered, |message| {
+            self.write(message)
+        }))
+    }
+
+    fn flush(&self) {
+        if let Some((file, _)) = &*self.file.lock().unwrap() {
+            if let Err(error) = file.sync_data() {
+                logger::log_error(&format!(
+                    "Error while syncing {}, {}",
+                    self.path.display(),
+                    error
+                ));
+            }
+        }
+    }
+}
+
+// Define the sink POSTing the messages to an HTTP endpoint, as JSON arrays of records.
+pub struct WebhookSink {
+    url: String,
+    authorization: Option<String>,
+    batch_size: usize,
+    agent: ureq::Agent,
+}
+
+impl WebhookSink {
+    // Create the sink of an endpoint.
+    pub fn new(config: &WebhookSinkConfig) -> WebhookSink {
+        WebhookSink {
+            url: config.url.clone(),
+            authorization: config.authorization.clone(),
+            batch_size: config.batch_size.max(1),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_millis(config.timeout_ms))
+                .build(),
+        }
+    }
+
+    // post is a method that sends a batch of messages in a single request.
+    // The request blocks, so it runs on the blocking threads of the runtime rather than on a worker.
+    // Returns:
+    // - io::Result<()>: Ok once the endpoint answers with a success status, or an std::io::Error.
+    async fn post(&self, batch: &[Message]) -> io::Result<()> {
+        let body = Value::Array(batch.iter().map(record).collect()).to_string();
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        let url = self.url.clone();
+        tokio::task::spawn_blocking(move || match request.send_string(&body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, _)) => Err(io::Error::other(format!(
+                "Webhook {} answered with status {}",
+                url, status
+            ))),
+            Err(error) => Err(io::Error::other(format!(
+                "Webhook {} is unreachable: {}",
+                url, error
+            ))),
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> &'static str {
+        WEBHOOK
+    }
+
+    // Send the messages waiting on the channel together, up to the batch size.
+    fn write_stream<'a>(
+        &'a self,
+        mut messages: Receiver<Message>,
+        delivery: &'a mut Delivery,
+        delivered: &'a mut (dyn FnMut(&Message) + Send),
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut first_error = None;
+            while let Some(batch) = next_batch(&mut messages, self.batch_size).await {
+                if let Err(error) = written(&batch, self.post(&batch).await, delivery, delivered) {
+                    first_error.get_or_insert(error);
+                }
+            }
+            first_error.map_or(Ok(()), Err)
+        })
+    }
+}
+
+// Write to Kafka with the shared producer, in the transaction of the scan if it is transactional.
+impl Sink for Producer {
+    fn name(&self) -> &'static str {
+        KAFKA
+    }
+
+    fn write_stream<'a>(
+        &'a self,
+        messages: Receiver<Message>,
+        delivery: &'a mut Delivery,
+        delivered: &'a mut (dyn FnMut(&Message) + Send),
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            produce_stream(self, messages, delivery, delivered)
+                .await
+                .map_err(io::Error::other)
+        })
+    }
+
+    fn flush(&self) {
+        Producer::flush(self);
+    }
+
+    fn producer(&self) -> Option<&Producer> {
+        Some(self)
+    }
+}
+
+// Define the configured sinks, by name, and the one of the scans that don't choose.
+#[derive(Clone)]
+pub struct Sinks {
+    default: String,
+    sinks: BTreeMap<&'static str, Arc<dyn Sink>>,
+}
+
+impl Sinks {
+    // from_config is a function that creates the configured sinks; the standard output is always available.
+    // Parameters:
+    // - config: The default sink and the settings of the file and webhook sinks.
+    // - producer: The Kafka producer, if Kafka is configured.
+    // Returns:
+    // - io::Result<Sinks>: The sinks, or an std::io::Error if the default sink isn't available.
+    pub fn from_config(config: &SinkConfig, producer: Option<Producer>) -> io::Result<Sinks> {
+        let mut sinks: BTreeMap<&'static str, Arc<dyn Sink>> = BTreeMap::new();
+        sinks.insert(STDOUT, Arc::new(StdoutSink));
+        if let Some(file) = &config.file {
+            sinks.insert(FILE, Arc::new(FileSink::new(file)));
+        }
+        if let Some(webhook) = &config.webhook {
+            sinks.insert(WEBHOOK, Arc::new(WebhookSink::new(webhook)));
+        }
+        if let Some(postgis) = &config.postgis {
+            sinks.insert(POSTGIS, Arc::new(PostgisSink::new(postgis)));
+        }
+        let has_kafka = producer.is_some();
+        if let Some(producer) = producer {
+            sinks.insert(KAFKA, Arc::new(producer));
+        }
+        let default = match &config.default {
+            Some(default) => default.to_lowercase(),
+            None if has_kafka => KAFKA.to_string(),
+            None => STDOUT.to_string(),
+        };
+        let sinks = Sinks { default, sinks };
+        sinks.get(None)?;
+        Ok(sinks)
+    }
+
+    // get is a method that returns a sink by name.
+    // Parameters:
+    // - name: The name of the sink, or None for the default one.
+    // Returns:
+    // - io::Result<Arc<dyn Sink>>: The sink, or an std::io::Error if it is unknown or not configured.
+    pub fn get(&self, name: Option<&str>) -> io::Result<Arc<dyn Sink>> {
+        let name = name.map_or(self.default.clone(), str::to_lowercase);
+        self.sinks.get(name.as_str()).cloned().ok_or_else(|| {
+            let available: Vec<&str> = self.sinks.keys().copied().collect();
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown or unconfigured sink {}, expected one of {}",
+                    name,
+                    available.join(", ")
+                ),
+            )
+        })
+    }
+
+    // flush is a method that writes what every sink still buffers, before stopping.
+    pub fn flush(&self) {
+        self.sinks.values().for_each(|sink| sink.flush());
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::EventType;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    // run is a helper that writes messages to a sink.
+    // Returns the delivery, the result and the keys of the delivered messages.
+    async fn run(
+        sink: &dyn Sink,
+        messages: Vec<Message>,
+    ) -> (Delivery, io::Result<()>, Vec<String>) {
+        let (sender, receiver) = mpsc::channel(messages.len().max(1));
+        for message in messages {
+            sender.try_send(message).unwrap();
+        }
+        drop(sender);
+        let mut delivery = Delivery::default();
+        let mut keys = Vec::new();
+        let result = sink
+            .write_stream(receiver, &mut delivery, &mut |message| {
+                keys.push(message.key.clone())
+            })
+            .await;
+        (delivery, result, keys)
+    }
+
+    // messages is a helper that builds a message per key, the last one a tombstone.
+    fn messages(keys: &[&str]) -> Vec<Message> {
+        keys.iter()
+            .enumerate()
+            .map(|(index, key)| match index + 1 == keys.len() {
+                true => Message::tombstone(key, EventType::Deleted),
+                false => {
+                    let mut message = Message::new(HashMap::new());
+                    message.key = key.to_string();
+                    message.value = json!({ "lat": 45.0 });
+                    message
+                }
+            })
+            .collect()
+    }
+
+    // fake_webhook is a helper that answers every request with a status, and sends the bodies it receives.
+    // Returns the URL of the server and the receiving end of the bodies.
+    fn fake_webhook(status: &'static str) -> (String, std::sync::mpsc::Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/photos", listener.local_addr().unwrap());
+        let (sender, bodies) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(": ") {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let _ = sender.send(serde_json::from_slice(&body).unwrap());
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    // Define a test function for writing messages to an NDJSON file with rotation.
+    #[tokio::test]
+    async fn test_file_sink() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("out").join("photos.ndjson");
+        let sink = FileSink::new(&FileSinkConfig {
+            path: path.to_str().unwrap().to_string(),
+            // Rotate after every record.
+            max_bytes: 1,
+            max_files: 2,
+        });
+        let (delivery, result, keys) =
+            run(&sink, messages(&["a.jpg", "b.jpg", "c.jpg", "d.jpg"])).await;
+        sink.flush();
+
+        // Assert that every message is written and delivered, in order.
+        assert!(result.is_ok());
+        assert_eq!(delivery.published, 4);
+        assert_eq!(keys, vec!["a.jpg", "b.jpg", "c.jpg", "d.jpg"]);
+
+        // Assert that the newest records are kept in the file and its rotations, and the oldest dropped.
+        let read = |path: PathBuf| -> Value {
+            serde_json::from_str(fs::read_to_string(path).unwrap().trim_end()).unwrap()
+        };
+        let last = read(path.clone());
+        assert_eq!(last["key"], "d.jpg");
+        assert_eq!(last["value"], Value::Null);
+        assert_eq!(last["headers"]["event_type"], "deleted");
+        assert_eq!(read(sink.rotated(1))["key"], "c.jpg");
+        assert_eq!(read(sink.rotated(2))["value"]["lat"], 45.0);
+        assert!(!sink.rotated(3).exists());
+    }
+
+    // Define a test function for posting messages to a webhook.
+    #[tokio::test]
+    async fn test_webhook_sink() {
+        let (url, bodies) = fake_webhook("200 OK");
+        let sink = WebhookSink::new(&WebhookSinkConfig {
+            url,
+            authorization: None,
+            batch_size: 2,
+            timeout_ms: 5000,
+        });
+        let (delivery, result, keys) = run(&sink, messages(&["a.jpg", "b.jpg", "c.jpg"])).await;
+
+        // Assert that the messages are posted in batches of two.
+        assert!(result.is_ok());
+        assert_eq!(delivery.published, 3);
+        assert_eq!(keys.len(), 3);
+        let first = bodies.recv().unwrap();
+        assert_eq!(first.as_array().unwrap().len(), 2);
+        assert_eq!(first[0]["key"], "a.jpg");
+        assert_eq!(bodies.recv().unwrap()[0]["value"], Value::Null);
+
+        // Assert that the messages refused by the endpoint are listed as failed.
+        let (url, _bodies) = fake_webhook("500 Internal Server Error");
+        let sink = WebhookSink::new(&WebhookSinkConfig {
+            url,
+            authorization: Some("Bearer token".to_string()),
+            batch_size: 10,
+            timeout_ms: 5000,
+        });
+        let (delivery, result, keys) = run(&sink, messages(&["a.jpg", "b.jpg"])).await;
+        assert!(result.is_err());
+        assert_eq!(delivery.failed, 2);
+        assert_eq!(delivery.failures[0].payload, Some(json!({ "lat": 45.0 })));
+        assert!(keys.is_empty());
+    }
+
+    // Define a test function for selecting sinks.
+    #[test]
+    fn test_sinks() {
+        let config = SinkConfig {
+            default: None,
+            file: Some(FileSinkConfig {
+                path: "photos.ndjson".to_string(),
+                max_bytes: 1024,
+                max_files: 1,
+            }),
+            webhook: None,
+            postgis: None,
+        };
+        // Assert that the standard output is the default without Kafka.
+        let sinks = Sinks::from_config(&config, None).unwrap();
+        assert_eq!(sinks.get(None).unwrap().name(), STDOUT);
+        assert_eq!(sinks.get(Some("FILE")).unwrap().name(), FILE);
+        assert!(sinks.get(Some("file")).unwrap().producer().is_none());
+
+        // Assert that unconfigured sinks are refused.
+        assert!(sinks.get(Some("webhook")).is_err());
+        assert!(sinks.get(Some("kafka")).is_err());
+        let config = SinkConfig {
+            default: Some("kafka".to_string()),
+            ..SinkConfig::default()
+        };
+        assert!(Sinks::from_config(&config, None).is_err());
+    }
+}
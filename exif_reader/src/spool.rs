@@ -0,0 +1,370 @@
+// Import necessary modules from the project.
+use crate::config::SpoolConfig;
+use crate::logger;
+use crate::message::{EventType, Message};
+
+// Import necessary modules from the standard library and external crates.
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// Names of the segment files, such as segment_00000000000000000001.ndjson, and of the replay cursor.
+const SEGMENT_PREFIX: &str = "segment_";
+const SEGMENT_EXTENSION: &str = ".ndjson";
+const CURSOR: &str = "cursor";
+
+// Define a spooled message, one JSON line of a segment.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    // The payload of the message, None for tombstones.
+    payload: Option<serde_json::Value>,
+    headers: Vec<(String, String)>,
+    // When the message was spooled, in milliseconds since the Unix epoch.
+    spooled_at: i64,
+}
+
+impl Record {
+    // Rebuild the message of the record.
+    fn into_message(self) -> Message {
+        Message {
+            key: self.key,
+            value: self.payload.unwrap_or_default(),
+            headers: self.headers,
+            source: None,
+            event: EventType::Created,
+            moved_from: None,
+        }
+    }
+}
+
+// Define the size and age of the messages waiting in the spool.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SpoolStats {
+    // Bytes of the messages not replayed yet.
+    pub bytes: u64,
+    // Age of the oldest message not replayed yet, in seconds.
+    pub age_secs: u64,
+}
+
+// Define messages read from the spool, consumed once they are replayed.
+pub struct Batch {
+    segment: u64,
+    // The offset after the last message of the batch.
+    end: u64,
+    pub messages: Vec<Message>,
+}
+
+// Define the position of the replay: the next message is at the offset of the oldest segment
+// if it is the segment of the cursor, or at its start otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Cursor {
+    segment: u64,
+    offset: u64,
+}
+
+// Define a durable spool of the messages Kafka couldn't take: append-only segment files of JSON lines,
+// replayed in order once the brokers are back.
+pub struct Spool {
+    directory: PathBuf,
+    segment_size: u64,
+    // The segment messages are appended to, with its sequence number and size.
+    writer: Mutex<Option<(u64, File, u64)>>,
+    // Whether messages wait to be replayed; new messages are spooled behind them to keep the order.
+    pending: AtomicBool,
+}
+
+impl Spool {
+    // open is a function that opens the spool of a directory, with the messages left by a previous run.
+    // Parameters:
+    // - config: The directory and segment size of the spool.
+    // Returns:
+    // - io::Result<Spool>: The spool, or an std::io::Error if the directory can't be created or listed.
+    pub fn open(config: &SpoolConfig) -> io::Result<Spool> {
+        fs::create_dir_all(&config.directory)?;
+        let spool = Spool {
+            directory: PathBuf::from(&config.directory),
+            segment_size: config.segment_size.max(1),
+            writer: Mutex::new(None),
+            pending: AtomicBool::new(false),
+        };
+        spool
+            .pending
+            .store(!spool.segments()?.is_empty(), Ordering::SeqCst);
+        Ok(spool)
+    }
+
+    // Get the path of a segment.
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.directory.join(format!(
+            "{}{:020}{}",
+            SEGMENT_PREFIX, segment, SEGMENT_EXTENSION
+        ))
+    }
+
+    // List the sequence numbers of the segments, oldest first.
+    fn segments(&self) -> io::Result<Vec<u64>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let name = entry?.file_name();
+            let segment = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+                .and_then(|name| name.strip_suffix(SEGMENT_EXTENSION))
+                .and_then(|segment| segment.parse::<u64>().ok());
+            segments.extend(segment);
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    // Read the replay cursor; a missing cursor starts at the oldest segment.
+    fn cursor(&self) -> io::Result<Cursor> {
+        let cursor = match fs::read_to_string(self.directory.join(CURSOR)) {
+            Ok(cursor) => cursor,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Cursor::default()),
+            Err(error) => return Err(error),
+        };
+        let mut fields = cursor.split_whitespace().map(str::parse::<u64>);
+        match (fields.next(), fields.next()) {
+            (Some(Ok(segment)), Some(Ok(offset))) => Ok(Cursor { segment, offset }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid spool cursor: {}", cursor),
+            )),
+        }
+    }
+
+    // Write the replay cursor, replacing the previous one atomically.
+    fn set_cursor(&self, cursor: Cursor) -> io::Result<()> {
+        let temporary = self.directory.join(format!("{}.tmp", CURSOR));
+        fs::write(&temporary, format!("{} {}", cursor.segment, cursor.offset))?;
+        fs::rename(temporary, self.directory.join(CURSOR))
+    }
+
+    // Check whether every spooled message was replayed.
+    pub fn is_empty(&self) -> bool {
+        !self.pending.load(Ordering::SeqCst)
+    }
+
+    // append is a method that writes a message at the end of the spool, on disk before returning.
+    // A new segment is started after a restart, so that a line torn by a crash is never appended to.
+    // Parameters:
+    // - message: The message to replay later.
+    // Returns:
+    // - io::Result<()>: Ok once the message is synced to disk, or an std::io::Error.
+    pub fn append(&self, message: &Message) -> io::Result<()> {
+        let record = Record {
+            key: message.key.clone(),
+            payload: (!message.is_tombstone()).then(|| message.value.clone()),
+            headers: message.headers.clone(),
+            spooled_at: Utc::now().timestamp_millis(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().unwrap();
+        let (_, file, size) = match writer.take() {
+            Some(active) if active.2 < self.segment_size => writer.insert(active),
+            _ => {
+                let segment = self.segments()?.last().map_or(1, |last| last + 1);
+                let file = OpenOptions::new()
+                    .create_new(true)
+                    .append(true)
+                    .open(self.segment_path(segment))?;
+                writer.insert((segment, file, 0))
+            }
+        };
+        file.write_all(&line)?;
+        file.sync_data()?;
+        *size += line.len() as u64;
+        self.pending.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // read_batch is a method that reads the next messages to replay, in the order they were spooled.
+    // Segments read to their end are removed; corrupt lines are logged and skipped.
+    // Parameters:
+    // - max: The largest number of messages of the batch.
+    // Returns:
+    // - io::Result<Option<Batch>>: The messages, None once the spool is empty, or an std::io::Error.
+    pub fn read_batch(&self, max: usize) -> io::Result<Option<Batch>> {
+        loop {
+            let Some(&segment) = self.segments()?.first() else {
+                // Check again while no message can be appended.
+                let _writer = self.writer.lock().unwrap();
+                if self.segments()?.is_empty() {
+                    self.pending.store(false, Ordering::SeqCst);
+                    return Ok(None);
+                }
+                continue;
+            };
+            let cursor = self.cursor()?;
+            let offset = if cursor.segment == segment {
+                cursor.offset
+            } else {
+                0
+            };
+
+            let mut reader = BufReader::new(File::open(self.segment_path(segment))?);
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut end = offset;
+            let mut messages = Vec::new();
+            let mut line = String::new();
+            while messages.len() < max.max(1) {
+                line.clear();
+                let read = reader.read_line(&mut line)?;
+                // Stop at the end, or at a line still being written.
+                if read == 0 || !line.ends_with('\n') {
+                    break;
+                }
+                end += read as u64;
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(record) => messages.push(record.into_message()),
+                    Err(error) => logger::log_error(&format!(
+                        "Skipping a corrupt record of spool segment {}: {}",
+                        segment, error
+                    )),
+                }
+            }
+            if end > offset {
+                return Ok(Some(Batch {
+                    segment,
+                    end,
+                    messages,
+                }));
+            }
+
+            // The segment is read: remove it, unless messages are still appended to it.
+            let mut writer = self.writer.lock().unwrap();
+            match &*writer {
+                Some((active, _, size)) if *active == segment => {
+                    if *size > end {
+                        continue;
+                    }
+                    *writer = None;
+                }
+                // A line torn by a crash goes with its segment.
+                _ if reader.get_ref().metadata()?.len() > end => logger::log_error(&format!(
+                    "Dropping the incomplete last record of spool segment {}",
+                    segment
+                )),
+                _ => (),
+            }
+            fs::remove_file(self.segment_path(segment))?;
+            self.set_cursor(Cursor::default())?;
+        }
+    }
+
+    // consumed is a method that moves the replay past a batch once its messages are delivered.
+    pub fn consumed(&self, batch: &Batch) -> io::Result<()> {
+        self.set_cursor(Cursor {
+            segment: batch.segment,
+            offset: batch.end,
+        })
+    }
+
+    // stats is a method that measures the messages waiting in the spool.
+    // Returns:
+    // - io::Result<SpoolStats>: Their size and the age of the oldest, or an std::io::Error.
+    pub fn stats(&self) -> io::Result<SpoolStats> {
+        let segments = self.segments()?;
+        let Some(&oldest) = segments.first() else {
+            return Ok(SpoolStats::default());
+        };
+        let cursor = self.cursor()?;
+        let offset = if cursor.segment == oldest {
+            cursor.offset
+        } else {
+            0
+        };
+
+        let mut bytes = 0;
+        for segment in &segments {
+            bytes += fs::metadata(self.segment_path(*segment))?.len();
+        }
+
+        // The oldest message is the next one to replay.
+        let mut reader = BufReader::new(File::open(self.segment_path(oldest))?);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let age_secs = serde_json::from_str::<Record>(&line)
+            .map(|record| (Utc::now().timestamp_millis() - record.spooled_at).max(0) as u64 / 1000)
+            .unwrap_or_default();
+        Ok(SpoolStats {
+            bytes: bytes.saturating_sub(offset),
+            age_secs,
+        })
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Define a test function for spooling messages and reading them back in order.
+    #[test]
+    fn test_spool() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = SpoolConfig {
+            directory: directory.path().to_str().unwrap().to_string(),
+            // Start a segment after every two messages.
+            segment_size: 150,
+            replay_interval_ms: 0,
+        };
+        let spool = Spool::open(&config).unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(spool.stats().unwrap(), SpoolStats::default());
+
+        for key in ["a.jpg", "b.jpg", "c.jpg"] {
+            let mut message = Message::tombstone(key, EventType::Deleted);
+            if key != "c.jpg" {
+                message.value = serde_json::json!({ "title": key });
+            }
+            spool.append(&message).unwrap();
+        }
+        assert!(!spool.is_empty());
+        assert!(spool.segments().unwrap().len() > 1);
+        assert!(spool.stats().unwrap().bytes > 0);
+
+        // Assert that the messages are kept across restarts, and read again until consumed.
+        let spool = Spool::open(&config).unwrap();
+        assert!(!spool.is_empty());
+        let keys = |batch: &Batch| -> Vec<String> {
+            batch
+                .messages
+                .iter()
+                .map(|message| message.key.clone())
+                .collect()
+        };
+        let batch = spool.read_batch(1).unwrap().unwrap();
+        assert_eq!(keys(&batch), vec!["a.jpg"]);
+        assert_eq!(keys(&spool.read_batch(1).unwrap().unwrap()), vec!["a.jpg"]);
+        spool.consumed(&batch).unwrap();
+
+        // Assert that the order, payloads, headers and tombstones are kept across segments.
+        let mut replayed = Vec::new();
+        while let Some(batch) = spool.read_batch(10).unwrap() {
+            spool.consumed(&batch).unwrap();
+            replayed.extend(batch.messages);
+        }
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[0].value["title"], "b.jpg");
+        assert!(replayed[1].is_tombstone());
+        assert_eq!(
+            replayed[1].headers,
+            vec![("event_type".to_string(), "deleted".to_string())]
+        );
+
+        // Assert that replayed segments are removed.
+        assert!(spool.is_empty());
+        assert!(spool.segments().unwrap().is_empty());
+        assert_eq!(spool.stats().unwrap(), SpoolStats::default());
+    }
+}
@@ -0,0 +1,451 @@
+// Import necessary modules from the project.
+use crate::config::TrackConfig;
+use crate::enricher::Enricher;
+use crate::file_state::settings_fingerprint;
+use crate::fit::parse_fit;
+use crate::logger;
+use crate::message::PhotoData;
+use crate::utils::invalid_data;
+
+// Import necessary modules from the standard library and external crates.
+use std::io;
+use std::path::Path;
+
+use chrono::DateTime;
+use roxmltree::{Document, Node};
+
+// Define a timed position read from a track log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackPoint {
+    // Milliseconds since the Unix epoch, UTC.
+    pub time: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub altitude: Option<f64>,
+}
+
+// Define the options of a track log correlation, as given by a request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackOptions {
+    // GPX, KML or FIT files to correlate with.
+    pub files: Vec<String>,
+    // Seconds added to the camera clock to get UTC.
+    pub clock_offset: i64,
+    // Largest gap between two track points to interpolate over, in seconds.
+    pub max_gap: Option<i64>,
+}
+
+// requested_files is a function that resolves the track files given by a gRPC request, which must be
+// inside the configured track directory so that a client can't have the service read any file.
+// Parameters:
+// - files: The files of the request, absolute or relative to the directory.
+// - directory: The configured track directory; without it, requests can't give track files.
+// Returns:
+// - io::Result<Vec<String>>: The canonical paths of the files, or a PermissionDenied std::io::Error
+//   for a file outside the directory.
+pub fn requested_files(files: &[String], directory: Option<&str>) -> io::Result<Vec<String>> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(directory) = directory else {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Track files are only accepted from the directory set in TRACK.DIRECTORY",
+        ));
+    };
+    let directory = Path::new(directory).canonicalize()?;
+    files
+        .iter()
+        .map(|file| {
+            // Symbolic links and .. components are resolved before the path is checked.
+            let path = directory.join(file).canonicalize()?;
+            if !path.starts_with(&directory) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Track file outside of {}: {}", directory.display(), file),
+                ));
+            }
+            Ok(path.display().to_string())
+        })
+        .collect()
+}
+
+// parse_time is a function that parses an RFC 3339 timestamp into milliseconds since the Unix epoch.
+fn parse_time(text: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(text.trim())
+        .ok()
+        .map(|time| time.timestamp_millis())
+}
+
+// child_text is a function that returns the text of the first child element with the given name.
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.has_tag_name(name))
+        .and_then(|child| child.text())
+}
+
+// parse_gpx is a function that reads the timed track points of a GPX document.
+pub fn parse_gpx(text: &str) -> io::Result<Vec<TrackPoint>> {
+    let document = Document::parse(text).map_err(invalid_data)?;
+
+    let points = document
+        .descendants()
+        .filter(|node| node.has_tag_name("trkpt"))
+        .filter_map(|node| {
+            Some(TrackPoint {
+                time: child_text(node, "time").and_then(parse_time)?,
+                lat: node.attribute("lat")?.trim().parse().ok()?,
+                lon: node.attribute("lon")?.trim().parse().ok()?,
+                altitude: child_text(node, "ele").and_then(|ele| ele.trim().parse().ok()),
+            })
+        })
+        .collect();
+
+    Ok(points)
+}
+
+// parse_kml_coordinates is a function that parses a KML "lon,lat[,alt]" or "lon lat [alt]" tuple.
+fn parse_kml_coordinates(text: &str) -> Option<(f64, f64, Option<f64>)> {
+    let mut values = text
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse::<f64>());
+
+    let lon = values.next()?.ok()?;
+    let lat = values.next()?.ok()?;
+    let altitude = values.next().and_then(|value| value.ok());
+    Some((lat, lon, altitude))
+}
+
+// parse_kml is a function that reads the timed positions of a KML document,
+// from gx:Track elements and from time-stamped Point placemarks.
+pub fn parse_kml(text: &str) -> io::Result<Vec<TrackPoint>> {
+    let document = Document::parse(text).map_err(invalid_data)?;
+    let mut points = Vec::new();
+
+    // gx:Track lists its <when> and <gx:coord> elements in matching order.
+    for track in document
+        .descendants()
+        .filter(|node| node.has_tag_name("Track"))
+    {
+        let times = track
+            .children()
+            .filter(|node| node.has_tag_name("when"))
+            .map(|node| node.text().and_then(parse_time));
+        let coordinates = track
+            .children()
+            .filter(|node| node.has_tag_name("coord"))
+            .map(|node| node.text().and_then(parse_kml_coordinates));
+
+        for (time, coordinates) in times.zip(coordinates) {
+            if let (Some(time), Some((lat, lon, altitude))) = (time, coordinates) {
+                points.push(TrackPoint {
+                    time,
+                    lat,
+                    lon,
+                    altitude,
+                });
+            }
+        }
+    }
+
+    // A placemark with a TimeStamp and a Point is a single track point.
+    for placemark in document
+        .descendants()
+        .filter(|node| node.has_tag_name("Placemark"))
+    {
+        let time = placemark
+            .descendants()
+            .find(|node| node.has_tag_name("TimeStamp"))
+            .and_then(|node| child_text(node, "when"))
+            .and_then(parse_time);
+        let coordinates = placemark
+            .descendants()
+            .find(|node| node.has_tag_name("Point"))
+            .and_then(|node| child_text(node, "coordinates"))
+            .and_then(parse_kml_coordinates);
+
+        if let (Some(time), Some((lat, lon, altitude))) = (time, coordinates) {
+            points.push(TrackPoint {
+                time,
+                lat,
+                lon,
+                altitude,
+            });
+        }
+    }
+
+    Ok(points)
+}
+
+// read_track_file is a function that reads the track points of a GPX, KML or FIT file,
+// chosen by the file extension.
+pub fn read_track_file(path: &Path) -> io::Result<Vec<TrackPoint>> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("gpx") => parse_gpx(&std::fs::read_to_string(path)?),
+        Some("kml") => parse_kml(&std::fs::read_to_string(path)?),
+        Some("fit") => parse_fit(&std::fs::read(path)?),
+        _ => Err(invalid_data(format!(
+            "Unsupported track file {}",
+            path.display()
+        ))),
+    }
+}
+
+// Define a time-sorted set of track points from one or more track logs.
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    points: Vec<TrackPoint>,
+}
+
+impl Track {
+    // Create a Track from unsorted points.
+    pub fn new(mut points: Vec<TrackPoint>) -> Track {
+        points.sort_by_key(|point| point.time);
+        Track { points }
+    }
+
+    // Load a Track from the given track files.
+    pub fn open(files: &[String]) -> io::Result<Track> {
+        let mut points = Vec::new();
+        for file in files {
+            let file_points = read_track_file(Path::new(file))?;
+            logger::log_info(&format!(
+                "Loaded {} track points from {}",
+                file_points.len(),
+                file
+            ));
+            points.extend(file_points);
+        }
+        Ok(Track::new(points))
+    }
+
+    // Interpolate the position at the given time (milliseconds since the Unix epoch).
+    // Returns None outside of the track or when the surrounding points are more than max_gap apart.
+    pub fn position_at(&self, time: i64, max_gap: i64) -> Option<TrackPoint> {
+        let index = self.points.partition_point(|point| point.time <= time);
+        let before = self.points.get(index.checked_sub(1)?)?;
+        if before.time == time {
+            return Some(*before);
+        }
+
+        let after = self.points.get(index)?;
+        if after.time - before.time > max_gap {
+            return None;
+        }
+
+        let fraction = (time - before.time) as f64 / (after.time - before.time) as f64;
+        let lerp = |a: f64, b: f64| a + (b - a) * fraction;
+        Some(TrackPoint {
+            time,
+            lat: lerp(before.lat, after.lat),
+            lon: lerp(before.lon, after.lon),
+            altitude: match (before.altitude, after.altitude) {
+                (Some(a), Some(b)) => Some(lerp(a, b)),
+                _ => None,
+            },
+        })
+    }
+}
+
+// Define an enricher that geotags photos without GPS from a track log, by capture time.
+#[derive(Debug)]
+pub struct TrackEnricher {
+    track: Track,
+    clock_offset: i64,
+    max_gap: i64,
+    // The track files and settings, fingerprinted.
+    fingerprint: String,
+}
+
+impl TrackEnricher {
+    // Create a TrackEnricher from the request options, with defaults from the configuration.
+    pub fn open(options: &TrackOptions, config: &TrackConfig) -> io::Result<TrackEnricher> {
+        let (clock_offset, max_gap) = (options.clock_offset * 1000, options.max_gap.unwrap_or(config.max_gap) * 1000);
+        Ok(TrackEnricher {
+            track: Track::open(&options.files)?,
+            clock_offset,
+            max_gap,
+            fingerprint: settings_fingerprint(&format!("track {} {}", clock_offset, max_gap), &options.files),
+        })
+    }
+}
+
+impl Enricher for TrackEnricher {
+    fn enrich(&self, data: &mut PhotoData) {
+        if data.has_position() {
+            return;
+        }
+        let Some(time) = parse_time(data.timestamp()) else {
+            return;
+        };
+
+        if let Some(point) = self
+            .track
+            .position_at(time + self.clock_offset, self.max_gap)
+        {
+            data.set_track_position(
+                point.lat as f32,
+                point.lon as f32,
+                point.altitude.map(|altitude| altitude as f32),
+            );
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{AltitudeSource, PositionSource};
+
+    // Define a sample GPX track.
+    const GPX: &str = r#"<?xml version="1.0"?>
+<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="45.0" lon="39.0"><ele>10</ele><time>2021-01-04T14:49:00Z</time></trkpt>
+    <trkpt lat="45.1" lon="39.2"><ele>30</ele><time>2021-01-04T14:50:00Z</time></trkpt>
+    <trkpt lat="46.0" lon="40.0"><time>2021-01-04T15:50:00Z</time></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+
+    // Define a test function for reading a GPX track.
+    #[test]
+    fn test_parse_gpx() {
+        let points = parse_gpx(GPX).unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!(
+            points[0],
+            TrackPoint {
+                time: 1609771740000,
+                lat: 45.0,
+                lon: 39.0,
+                altitude: Some(10.0)
+            }
+        );
+        assert_eq!(points[2].altitude, None);
+    }
+
+    // Define a test function for reading a KML track and placemark.
+    #[test]
+    fn test_parse_kml() {
+        let kml = r#"<?xml version="1.0"?>
+<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">
+  <Document>
+    <Placemark><gx:Track>
+      <when>2021-01-04T14:49:00Z</when>
+      <when>2021-01-04T14:50:00Z</when>
+      <gx:coord>39.0 45.0 10</gx:coord>
+      <gx:coord>39.2 45.1 30</gx:coord>
+    </gx:Track></Placemark>
+    <Placemark>
+      <TimeStamp><when>2021-01-04T14:51:00+00:00</when></TimeStamp>
+      <Point><coordinates>39.3,45.2</coordinates></Point>
+    </Placemark>
+  </Document>
+</kml>"#;
+
+        let points = parse_kml(kml).unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[1].lat, 45.1);
+        assert_eq!(points[1].altitude, Some(30.0));
+        assert_eq!(points[2].lon, 39.3);
+        assert_eq!(points[2].altitude, None);
+    }
+
+    // Define a test function for interpolating positions along a track.
+    #[test]
+    fn test_position_at() {
+        let track = Track::new(parse_gpx(GPX).unwrap());
+        let start = 1609771740000;
+
+        // Halfway between the first two points.
+        let point = track.position_at(start + 30000, 300000).unwrap();
+        assert!((point.lat - 45.05).abs() < 1e-9);
+        assert!((point.lon - 39.1).abs() < 1e-9);
+        assert_eq!(point.altitude, Some(20.0));
+
+        // The last segment is an hour long, beyond the max gap.
+        assert_eq!(track.position_at(start + 120000, 300000), None);
+        // Before the start and after the end of the track.
+        assert_eq!(track.position_at(start - 1, 300000), None);
+        assert_eq!(track.position_at(start + 7200000, 300000), None);
+    }
+
+    // Define a test function for geotagging a photo from a track.
+    #[test]
+    fn test_track_enricher() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("track.gpx");
+        std::fs::write(&path, GPX).unwrap();
+
+        // The camera clock is set to UTC+3.
+        let enricher = TrackEnricher::open(
+            &TrackOptions {
+                files: vec![path.display().to_string()],
+                clock_offset: -3 * 3600,
+                max_gap: None,
+            },
+            &TrackConfig::default(),
+        )
+        .unwrap();
+
+        let mut photo = PhotoData::default();
+        photo.build("DateTime", "2021-01-04 17:49:30");
+        enricher.enrich(&mut photo);
+        assert_eq!(photo.position_source(), Some(PositionSource::Track));
+        assert_eq!(photo.altitude_source(), Some(AltitudeSource::Track));
+        assert_eq!(
+            photo.to_string(),
+            "lat: 45.05, long: 39.1, alt: 20, name, path, 2021-01-04T17:49:30+00:00"
+        );
+
+        // Photos with GPS are left alone.
+        let mut photo = PhotoData::default();
+        photo.build("DateTime", "2021-01-04 17:49:30");
+        photo.build("GPSLatitude", "10 deg 0 min 0 sec");
+        enricher.enrich(&mut photo);
+        assert_eq!(photo.position_source(), Some(PositionSource::Exif));
+        assert_eq!(photo.lat(), 10.0);
+    }
+
+    // Define a test function for confining the track files of requests to the track directory.
+    #[test]
+    fn test_requested_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let tracks = directory.path().join("tracks");
+        std::fs::create_dir(&tracks).unwrap();
+        std::fs::write(tracks.join("day1.gpx"), GPX).unwrap();
+        std::fs::write(directory.path().join("secret.gpx"), GPX).unwrap();
+        let allowed = Some(tracks.to_str().unwrap());
+        let canonical = tracks.canonicalize().unwrap().join("day1.gpx").display().to_string();
+
+        // Assert that files inside the directory are resolved, relative or absolute.
+        let files = vec!["day1.gpx".to_string(), tracks.join("day1.gpx").display().to_string()];
+        assert_eq!(requested_files(&files, allowed).unwrap(), vec![canonical.clone(), canonical]);
+        assert!(requested_files(&[], None).unwrap().is_empty());
+
+        // Assert that files outside of the directory, or without a directory, are refused.
+        for file in ["../secret.gpx", directory.path().join("secret.gpx").to_str().unwrap()] {
+            let error = requested_files(&[file.to_string()], allowed).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        }
+        let error = requested_files(&["day1.gpx".to_string()], None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    // Define a test function for rejecting unknown track formats.
+    #[test]
+    fn test_unsupported_track_file() {
+        assert!(read_track_file(Path::new("track.csv")).is_err());
+    }
+}
@@ -5,31 +5,35 @@ use std::str::FromStr;
 use chrono::{NaiveDateTime, Utc, TimeZone};
 
 // Function to convert raw coordinates (degrees, minutes, seconds) to decimal degrees.
+// The reference that follows them, from GPSLatitudeRef or GPSLongitudeRef, makes southern
+// and western coordinates negative.
 pub fn convert_coordinate(raw_coord: &str) -> f32 {
     // Define a regular expression to match the expected coordinate format.
-    let re = Regex::new(r"^(\d+) deg (\d+) min (\d*[.]?\d+)").unwrap();
-    
-    // Iterate through captured groups in the regex.
-    for r in re.captures_iter(raw_coord) {
-        // Parse degrees, minutes, and seconds from the captured groups.
-        let deg: f32 = FromStr::from_str(&r[1]).unwrap();
-        let min: f32 = FromStr::from_str(&r[2]).unwrap();
-        let sec: f32 = FromStr::from_str(&r[3]).unwrap();
-        
-        // Calculate and return the decimal coordinate.
-        let coord = deg + min / 60.0 + sec / 3600.0;
-        return coord;
-    }
+    let re = Regex::new(r"^(\d+) deg (\d+) min (\d*[.]?\d+)(?: sec ([NSEW]))?").unwrap();
     
     // Return 0.0 if no valid coordinate is found.
-    return 0.0;
+    let Some(r) = re.captures(raw_coord) else {
+        return 0.0;
+    };
+
+    // Parse degrees, minutes, and seconds from the captured groups.
+    let deg: f32 = FromStr::from_str(&r[1]).unwrap();
+    let min: f32 = FromStr::from_str(&r[2]).unwrap();
+    let sec: f32 = FromStr::from_str(&r[3]).unwrap();
+
+    // Calculate and return the decimal coordinate, signed by its reference.
+    let coord = deg + min / 60.0 + sec / 3600.0;
+    match r.get(4).map(|reference| reference.as_str()) {
+        Some("S") | Some("W") => -coord,
+        _ => coord,
+    }
 }
 
 // Function to convert a timestamp string to ISO 8601 format.
-pub fn convert_time_to_iso_format(tmstmp: &str) -> String {
+// Cameras without a clock write zeroed or blank timestamps, such as "0000-00-00 00:00:00", which give None.
+pub fn convert_time_to_iso_format(tmstmp: &str) -> Option<String> {
     // Parse the timestamp string into a NaiveDateTime.
-    let parsed_time = NaiveDateTime::parse_from_str(tmstmp, "%Y-%m-%d %H:%M:%S")
-        .expect("Failed to parse the input string");
+    let parsed_time = NaiveDateTime::parse_from_str(tmstmp, "%Y-%m-%d %H:%M:%S").ok()?;
 
     // Convert the NaiveDateTime to a DateTime<Utc> (UTC time zone).
     let datetime_utc = Utc.from_utc_datetime(&parsed_time);
@@ -38,7 +42,12 @@ pub fn convert_time_to_iso_format(tmstmp: &str) -> String {
     let iso_format = datetime_utc.to_rfc3339();
 
     // Return the formatted ISO 8601 timestamp.
-    iso_format
+    Some(iso_format)
+}
+
+// Function to wrap a parsing error into an std::io::Error.
+pub fn invalid_data<E: ToString>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
 }
 
 // Test module for the coordinate and timestamp conversion functions.
@@ -49,7 +58,7 @@ mod test {
     // Test coordinate conversion function.
     #[test]
     fn test_coord() {
-        let raw_coord = vec![
+        let raw_coord = [
             "53 deg 43 min 23.808 sec N",
             "41 deg 42 min 34.9 sec N",
         ];
@@ -63,6 +72,10 @@ mod test {
     fn test_wrong_coord() {
         let raw_coords = vec![
             ("53 deg 43 min 23.808 sec N", 53.72328),
+            ("53 deg 43 min 23.808 sec S", -53.72328),
+            ("53 deg 43 min 23.808 sec E", 53.72328),
+            ("53 deg 43 min 23.808 sec W", -53.72328),
+            ("53 deg 43 min 23.808 sec [GPSLatitudeRef missing]", 53.72328),
             ("53", 0.0),
             ("53 deg", 0.0),
             ("deg 43 min 23.808 sec N", 0.0),
@@ -95,7 +108,12 @@ mod test {
 
         for time in times {
             let iso_time = convert_time_to_iso_format(time.0);
-            assert_eq!(iso_time, time.1)
+            assert_eq!(iso_time.as_deref(), Some(time.1))
+        }
+
+        // Assert that zeroed, blank and malformed timestamps are refused rather than panicking.
+        for time in ["0000-00-00 00:00:00", "    -  -     :  :  ", "2021-02-30 14:49:57", ""] {
+            assert_eq!(convert_time_to_iso_format(time), None);
         }
     }
 }
@@ -0,0 +1,208 @@
+// Import necessary modules from the project.
+use crate::logger;
+
+// Import necessary modules from the standard library and external crates.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use walkdir::WalkDir;
+
+// Longest wait for file system events before checking whether the changed files settled.
+const TICK: Duration = Duration::from_millis(250);
+
+// Define a changed path waiting to settle.
+struct Pending {
+    // When the path last changed.
+    changed: Instant,
+    // Its size and modification time then, or None if it was gone.
+    stat: Option<(u64, SystemTime)>,
+}
+
+// stat is a function that returns the size and modification time of a path, or None if it is gone.
+fn stat(path: &Path) -> Option<(u64, SystemTime)> {
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| (metadata.len(), metadata.modified().unwrap_or(UNIX_EPOCH)))
+}
+
+// Define the changes a watcher reports.
+#[derive(Debug, PartialEq)]
+pub enum Changes {
+    // The files that were created, modified or moved in, and the paths that were removed or moved out.
+    Files(Vec<String>),
+    // Events were lost, such as when the event queue overflowed, so the whole directory must be scanned again.
+    Rescan,
+}
+
+// Define a recursive watcher of a directory that reports the files that changed once they settled:
+// no event for a while, and the same size and modification time on two checks in a row,
+// so that files still being written or synced aren't extracted half-way.
+pub struct Watcher {
+    // The watcher stops when dropped.
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    pending: HashMap<PathBuf, Pending>,
+    // How long a path must stay unchanged.
+    settle: Duration,
+    // Whether events were lost since the last batch.
+    rescan: bool,
+}
+
+impl Watcher {
+    // Start watching a directory and its subdirectories.
+    pub fn new(directory: &str, settle: Duration) -> notify::Result<Watcher> {
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // The receiver is only gone once the Watcher is dropped.
+            let _ = sender.send(event);
+        })?;
+        watcher.watch(Path::new(directory), RecursiveMode::Recursive)?;
+        Ok(Watcher {
+            _watcher: watcher,
+            events,
+            pending: HashMap::new(),
+            settle,
+            rescan: false,
+        })
+    }
+
+    // Record the paths of an event: created, modified, renamed and removed paths all wait to settle.
+    // Events may have been lost when the watcher asks for a rescan, or fails, such as on a directory it can't watch.
+    fn record(&mut self, event: notify::Result<Event>) {
+        match event {
+            Ok(event) if event.need_rescan() => self.rescan = true,
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => (),
+            Ok(event) => {
+                for path in event.paths {
+                    let stat = stat(&path);
+                    self.pending.insert(
+                        path,
+                        Pending {
+                            changed: Instant::now(),
+                            stat,
+                        },
+                    );
+                }
+            }
+            Err(error) => {
+                logger::log_error(&format!("Error while watching, {}", error));
+                self.rescan = true;
+            }
+        }
+    }
+
+    // settled is a method that takes the paths that settled out of the pending ones.
+    // Directories are replaced by their files, as the files of a directory moved in have no events.
+    fn settled(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let settle = self.settle;
+        let mut settled = Vec::new();
+        self.pending.retain(|path, pending| {
+            if now.duration_since(pending.changed) < settle {
+                return true;
+            }
+            // A path still changing without events, such as on a network share, waits again.
+            let current = stat(path);
+            if current != pending.stat {
+                pending.stat = current;
+                pending.changed = now;
+                return true;
+            }
+            settled.push(path.clone());
+            false
+        });
+
+        let mut files: Vec<String> = settled
+            .into_iter()
+            .flat_map(|path| match path.is_dir() {
+                true => WalkDir::new(&path)
+                    .into_iter()
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| !entry.file_type().is_dir())
+                    .map(|entry| entry.path().display().to_string())
+                    .collect(),
+                false => vec![path.display().to_string()],
+            })
+            .collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    // next_batch is a method that waits until some changed paths settled, or events were lost.
+    // Returns:
+    // - notify::Result<Changes>: The files that were created, modified or moved in, and the paths
+    //   that were removed or moved out; a rescan once events were lost; or an error if the watcher stopped.
+    pub fn next_batch(&mut self) -> notify::Result<Changes> {
+        loop {
+            match self.events.recv_timeout(TICK.min(self.settle)) {
+                Ok(event) => self.record(event),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(notify::Error::generic("The watcher stopped"))
+                }
+            }
+            if std::mem::take(&mut self.rescan) {
+                return Ok(Changes::Rescan);
+            }
+            let settled = self.settled();
+            if !settled.is_empty() {
+                return Ok(Changes::Files(settled));
+            }
+        }
+    }
+}
+
+// Define a module for testing.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Define a test function for reporting settled files.
+    #[test]
+    fn test_next_batch() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut watcher =
+            Watcher::new(directory.path().to_str().unwrap(), Duration::from_millis(100)).unwrap();
+
+        // Write a photo in two steps, and move a directory with a photo in.
+        let photo = directory.path().join("photo.jpg");
+        fs::write(&photo, [0xFF, 0xD8]).unwrap();
+        fs::write(&photo, [0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(outside.path().join("album")).unwrap();
+        fs::write(outside.path().join("album/inside.jpg"), [0xFF, 0xD8]).unwrap();
+        fs::rename(outside.path().join("album"), directory.path().join("album")).unwrap();
+
+        // Assert that both photos are reported once they settled.
+        let mut files = Vec::new();
+        while files.len() < 2 {
+            match watcher.next_batch().unwrap() {
+                Changes::Files(changed) => files.extend(changed),
+                Changes::Rescan => panic!("No event was lost"),
+            }
+        }
+        files.sort();
+        let expected = [
+            directory.path().join("album/inside.jpg"),
+            directory.path().join("photo.jpg"),
+        ];
+        assert_eq!(files, expected.map(|path| path.display().to_string()));
+
+        // Assert that a removed photo is reported too.
+        fs::remove_file(&photo).unwrap();
+        assert_eq!(
+            watcher.next_batch().unwrap(),
+            Changes::Files(vec![photo.display().to_string()])
+        );
+
+        // Assert that lost events ask for a rescan, once.
+        watcher.record(Ok(Event::new(EventKind::Other).set_flag(notify::event::Flag::Rescan)));
+        assert_eq!(watcher.next_batch().unwrap(), Changes::Rescan);
+        assert!(!watcher.rescan);
+    }
+}
@@ -11,9 +11,9 @@ import (
 )
 
 const addCoordinates = `-- name: AddCoordinates :one
-INSERT INTO geo_data (latitude, longitude, altitude, tmstmp)
-VALUES($1, $2, $3, $4)
-RETURNING id, latitude, longitude, altitude, tmstmp
+INSERT INTO geo_data (latitude, longitude, altitude, tmstmp, photo_key)
+VALUES($1, $2, $3, $4, $5)
+RETURNING id, latitude, longitude, altitude, tmstmp, photo_key
 `
 
 type AddCoordinatesParams struct {
@@ -21,6 +21,7 @@ type AddCoordinatesParams struct {
 	Longitude float64
 	Altitude  float64
 	Tmstmp    time.Time
+	PhotoKey  string
 }
 
 func (q *Queries) AddCoordinates(ctx context.Context, arg AddCoordinatesParams) (GeoDatum, error) {
@@ -29,6 +30,7 @@ func (q *Queries) AddCoordinates(ctx context.Context, arg AddCoordinatesParams)
 		arg.Longitude,
 		arg.Altitude,
 		arg.Tmstmp,
+		arg.PhotoKey,
 	)
 	var i GeoDatum
 	err := row.Scan(
@@ -37,6 +39,17 @@ func (q *Queries) AddCoordinates(ctx context.Context, arg AddCoordinatesParams)
 		&i.Longitude,
 		&i.Altitude,
 		&i.Tmstmp,
+		&i.PhotoKey,
 	)
 	return i, err
 }
+
+const deleteCoordinates = `-- name: DeleteCoordinates :exec
+DELETE FROM geo_data
+WHERE photo_key = $1
+`
+
+func (q *Queries) DeleteCoordinates(ctx context.Context, photoKey string) error {
+	_, err := q.db.ExecContext(ctx, deleteCoordinates, photoKey)
+	return err
+}
@@ -14,4 +14,5 @@ type GeoDatum struct {
 	Longitude float64
 	Altitude  float64
 	Tmstmp    time.Time
+	PhotoKey  string
 }
@@ -9,6 +9,8 @@ type GeoData struct {
 	Longitude float64   `json:"long"`     // Longitude in degrees
 	Altitude  float64   `json:"altitude"` // Altitude in meters
 	Tmstmp    time.Time `json:"tmstmp"`   // Timestamp associated with the data
+	Key       string    `json:"-"`        // Kafka key of the message: the path of the photo
+	Deleted   bool      `json:"-"`        // Whether the message is a tombstone for a deleted or moved photo
 }
 
 // NewGeoData is a constructor function for creating a new GeoData instance.
@@ -15,6 +15,7 @@ import (
 )
 
 var testQueries *database.Queries
+var testConn *sql.DB
 
 func TestMain(m *testing.M) {
 
@@ -25,6 +26,7 @@ func TestMain(m *testing.M) {
 	}
 	defer conn.Close()
 
+	testConn = conn
 	testQueries = database.New(conn)
 
 	exitCode := m.Run()
@@ -42,6 +44,7 @@ func TestAddCoordinates(t *testing.T) {
 		Longitude: longitude,
 		Altitude:  altitude,
 		Tmstmp:    tmstmp,
+		PhotoKey:  "/photos/add.jpg",
 	}
 
 	geoData, err := testQueries.AddCoordinates(context.Background(), arg)
@@ -55,7 +58,29 @@ func TestAddCoordinates(t *testing.T) {
 	require.Equal(t, latitude, geoData.Latitude)
 	require.Equal(t, longitude, geoData.Longitude)
 	require.Equal(t, altitude, geoData.Altitude)
+	require.Equal(t, arg.PhotoKey, geoData.PhotoKey)
 
 	require.NotZero(t, geoData.ID)
 	require.NotZero(t, geoData.Tmstmp)
 }
+
+func TestDeleteCoordinates(t *testing.T) {
+	arg := database.AddCoordinatesParams{
+		Latitude:  59.849815368652344,
+		Longitude: 30.321691513061523,
+		Altitude:  53.83399963378906,
+		Tmstmp:    time.Now(),
+		PhotoKey:  "/photos/delete.jpg",
+	}
+	geoData, err := testQueries.AddCoordinates(context.Background(), arg)
+	require.NoError(t, err)
+
+	// Deleting the key of the photo removes its row.
+	err = testQueries.DeleteCoordinates(context.Background(), arg.PhotoKey)
+	require.NoError(t, err)
+
+	var count int
+	err = testConn.QueryRow("SELECT count(*) FROM geo_data WHERE id = $1", geoData.ID).Scan(&count)
+	require.NoError(t, err)
+	require.Zero(t, count)
+}
@@ -38,31 +38,67 @@ func (api *ApiCfg) RecordCoordinates(conn *sql.DB, ch chan models.GeoData, wg *s
 		conn.Close()
 	}()
 
-	// Iterate over messages received from the channel and insert them into the database.
+	// Iterate over messages received from the channel and record them in the database.
 	for msg := range ch {
-		// Create an argument (database.AddCoordinatesParams) for the database query.
-		arg := database.AddCoordinatesParams{
-			Latitude:  msg.Latitude,
-			Longitude: msg.Longitude,
-			Altitude:  msg.Altitude,
-			Tmstmp:    msg.Tmstmp,
+		if err := api.record(conn, msg); err != nil {
+			return err
 		}
+	}
+
+	// Return nil to indicate successful completion of recording GeoData.
+	return nil
+}
 
-		// Call the AddCoordinates query to insert data into the database.
-		res, err := api.Q.AddCoordinates(
-			context.Background(),
-			arg,
-		)
+// record is a method that records a single GeoData: the row of a deleted or moved photo is removed,
+// and the row of an updated photo is replaced, keyed by the path of the photo.
+// Parameters:
+// - conn: A pointer to a SQL database connection (*sql.DB) for executing SQL queries.
+// - msg: The GeoData received from Kafka.
+// Returns:
+// - An error if any database operation encounters an error; otherwise, nil.
+func (api *ApiCfg) record(conn *sql.DB, msg models.GeoData) error {
+	ctx := context.Background()
+
+	// Tombstones remove the row of their key; rows recorded before the photos were keyed have no key.
+	if msg.Deleted {
+		if msg.Key == "" {
+			return nil
+		}
+		return api.Q.DeleteCoordinates(ctx, msg.Key)
+	}
 
-		// Print the result (res) and return an error if any database operation fails.
-		fmt.Println("res", res)
-		if err != nil {
+	// Replace the previous row of the photo and insert the new one in a single transaction.
+	tx, err := conn.BeginTx(ctx, nil)
+	if err != nil {
+		return err
+	}
+	defer tx.Rollback()
+	q := api.Q.WithTx(tx)
+
+	if msg.Key != "" {
+		if err := q.DeleteCoordinates(ctx, msg.Key); err != nil {
 			return err
 		}
 	}
 
-	// Return nil to indicate successful completion of recording GeoData.
-	return nil
+	// Create an argument (database.AddCoordinatesParams) for the database query.
+	arg := database.AddCoordinatesParams{
+		Latitude:  msg.Latitude,
+		Longitude: msg.Longitude,
+		Altitude:  msg.Altitude,
+		Tmstmp:    msg.Tmstmp,
+		PhotoKey:  msg.Key,
+	}
+
+	// Call the AddCoordinates query to insert data into the database.
+	res, err := q.AddCoordinates(ctx, arg)
+
+	// Print the result (res) and return an error if any database operation fails.
+	fmt.Println("res", res)
+	if err != nil {
+		return err
+	}
+	return tx.Commit()
 }
 
 // Consume is a function that consumes Kafka messages and sends GeoData to a channel.
@@ -105,8 +141,14 @@ func Consume(consumer *kafka.Consumer, topic string, ch chan models.GeoData, wg
 			// Check the type of event.
 			switch e := event.(type) {
 			case *kafka.Message:
-				// Create an empty GeoData struct to store the deserialized data.
-				message := models.GeoData{}
+				// Tombstones, sent without payload for deleted and moved photos, remove the row of their key.
+				if len(e.Value) == 0 {
+					ch <- models.GeoData{Key: string(e.Key), Deleted: true}
+					continue
+				}
+
+				// Create a GeoData struct keyed by the path of the photo to store the deserialized data.
+				message := models.GeoData{Key: string(e.Key)}
 
 				// Deserialize the Kafka message payload (e.Value) into the GeoData struct.
 				if err := json.Unmarshal(e.Value, &message); err != nil {
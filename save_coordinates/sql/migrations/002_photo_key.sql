@@ -0,0 +1,7 @@
+-- +goose Up
+ALTER TABLE geo_data ADD COLUMN photo_key TEXT NOT NULL DEFAULT '';
+CREATE INDEX IF NOT EXISTS geo_data_photo_key ON geo_data (photo_key);
+
+-- +goose Down
+DROP INDEX IF EXISTS geo_data_photo_key;
+ALTER TABLE geo_data DROP COLUMN photo_key;
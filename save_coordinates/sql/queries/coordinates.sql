@@ -1,4 +1,8 @@
 -- name: AddCoordinates :one
-INSERT INTO geo_data (latitude, longitude, altitude, tmstmp)
-VALUES($1, $2, $3, $4)
+INSERT INTO geo_data (latitude, longitude, altitude, tmstmp, photo_key)
+VALUES($1, $2, $3, $4, $5)
 RETURNING *;
+
+-- name: DeleteCoordinates :exec
+DELETE FROM geo_data
+WHERE photo_key = $1;